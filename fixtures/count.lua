@@ -0,0 +1,5 @@
+local n = 0
+while n < 100 do
+    n = n + 1
+end
+print(n)
@@ -0,0 +1,15 @@
+--Takes the other branch of each conditional on the second run
+local function classify(n)
+    if n > 0 then
+        return "positive"
+    end
+    return "other"
+end
+
+if runs == nil then
+    runs = 1
+    classify(1)
+else
+    runs = runs + 1
+    classify(-1)
+end
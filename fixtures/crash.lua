@@ -0,0 +1,8 @@
+local prefix = "item"
+
+local function fail(n, ...)
+    local tag, doubled = prefix, n * 2
+    return doubled + {}
+end
+
+fail(1, "extra", true)
@@ -0,0 +1,12 @@
+local function inner(a)
+    local b = a * 2
+    return b
+end
+
+local function outer(x)
+    local y = inner(x + 1)
+    return y
+end
+
+local result = outer(4)
+print(result)
@@ -0,0 +1,31 @@
+local mt = {__index = function(t, k)
+  return k
+end}
+local pick = function(a, b, c)
+  local x = a and b or c
+  return x
+end
+local wrap = function(a)
+  return setmetatable({a and 1 or 2, b = a == nil}, mt)
+end
+local mixed = function(f)
+  return setmetatable({f(1), x = 1, f(2), y = f(3)}, mt)
+end
+local count = function(n)
+  local i, total = 0, 0
+  while i < n do
+    i = i + 1
+    if i % 2 == 0 then
+      total = total + i
+    end
+  end
+  return total
+end
+local w = wrap(nil)
+print(pick(true, "b", "c"), pick(false, "b", "c"), pick(true, false, "c"))
+print(w[1], w.b, getmetatable(w) == mt, wrap(1)[1], wrap(1).b)
+local m = mixed(function(n)
+  return n * 2
+end)
+print(m[1], m[2], m.x, m.y)
+print(count(10))
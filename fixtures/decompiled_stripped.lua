@@ -0,0 +1,31 @@
+local t1 = {__index = function(a1, a2)
+  return a2
+end}
+local t2 = function(a3, a4, a5)
+  return a3 and a4 or a5
+end
+local t3 = function(a6)
+  return setmetatable({a6 and 1 or 2, b = a6 == nil}, t1)
+end
+local t4 = function(a7)
+  return setmetatable({a7(1), x = 1, a7(2), y = a7(3)}, t1)
+end
+local t5 = function(a8)
+  local t8 = 0
+  local t9 = 0
+  while t8 < a8 do
+    t8 = t8 + 1
+    if t8 % 2 == 0 then
+      t9 = t9 + t8
+    end
+  end
+  return t9
+end
+local t6 = t3(nil)
+print(t2(true, "b", "c"), t2(false, "b", "c"), t2(true, false, "c"))
+print(t6[1], t6.b, getmetatable(t6) == t1, t3(1)[1], t3(1).b)
+local t7 = t4(function(a9)
+  return a9 * 2
+end)
+print(t7[1], t7[2], t7.x, t7.y)
+print(t5(10))
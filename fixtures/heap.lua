@@ -0,0 +1,5 @@
+--The payload is reached through the weak key of seen before cache.entry
+cache = {entry = {payload = {}}}
+archive = {old = {cache.entry.payload}}
+seen = setmetatable({}, {__mode = "k"})
+seen[cache.entry.payload] = true
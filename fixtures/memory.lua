@@ -0,0 +1,12 @@
+--Fills a global table on the first run, and frees it on the second
+if big == nil then
+    big = {}
+    local i = 1
+    while i <= 10000 do
+        big[i] = {i}
+        i = i + 1
+    end
+else
+    big = nil
+    collectgarbage()
+end
@@ -0,0 +1,14 @@
+local function leaf(n)
+    return getmetatable(n) == nil and n or 0
+end
+
+local function outer(n)
+    local total = 0
+    while n > 0 do
+        total = total + leaf(n)
+        n = n - 1
+    end
+    return total
+end
+
+outer(2)
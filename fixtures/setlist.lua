@@ -0,0 +1,17 @@
+local function va(...) return ... end
+
+local t = {va(1, nil, 3)}
+print(#t, t[1], t[2], t[3])
+
+t = {0, va(nil, nil, 4)}
+print(#t, t[1], t[4])
+
+t = {va(1, 2, nil, nil, 5, nil, 7)}
+print(#t, t[5], t[7])
+
+t = {
+    1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
+    21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40,
+    41, 42, 43, 44, 45, 46, 47, 48, 49, 50, va(nil, 52, 53)
+}
+print(#t, t[50], t[51], t[53])
@@ -0,0 +1,7 @@
+local function add(a, b)
+    local c = a + b
+    return c
+end
+
+local x = add(1, 2)
+local y = x * 10
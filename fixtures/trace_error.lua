@@ -0,0 +1,7 @@
+local t
+
+local function field()
+    return t.x
+end
+
+field()
@@ -0,0 +1,6 @@
+local i, sum = 0, 0
+while i < 5000000 do
+  i = i + 1
+  sum = sum + i % 7
+end
+print(sum)
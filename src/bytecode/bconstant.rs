@@ -11,7 +11,11 @@ impl BReadable for LPrimitive {
             1 => Self::BOOL(reader.inner.get_u8() != 0),
             3 => Self::FLOAT(reader.get_lua_float()),
             19 => Self::INT(reader.get_lua_integer()),
-            4 => Self::STRING(reader.get_string().expect("String constant had length 0")),
+            //Short and long strings
+            4 | 20 => {
+                let s = reader.get_string().expect("String constant had length 0");
+                Self::STRING(reader.strings.intern(&s))
+            }
             n => panic!(
                 "Attempted to read constant of unrecognised type indicator {}",
                 n
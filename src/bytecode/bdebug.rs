@@ -12,11 +12,13 @@ impl BReadable for BDebugLineInfo {
     }
 }
 
+/// A local variable, in scope from the instruction at `scope_start` up to
+/// but excluding the one at `scope_end`
 #[derive(Debug)]
 pub struct BDebugLocal {
-    local: String,
-    scope_start: i64,
-    scope_end: i64,
+    pub(crate) local: String,
+    pub(crate) scope_start: i64,
+    pub(crate) scope_end: i64,
 }
 impl BReadable for BDebugLocal {
     fn read(reader: &mut super::breader::BReader) -> Self {
@@ -32,7 +34,7 @@ impl BReadable for BDebugLocal {
 
 #[derive(Debug)]
 pub struct BDebugUpvalue {
-    upvalue: String,
+    pub(crate) upvalue: String,
 }
 impl BReadable for BDebugUpvalue {
     fn read(reader: &mut super::breader::BReader) -> Self {
@@ -1,5 +1,7 @@
 use std::fmt;
 
+use tracing::trace;
+
 use super::{
     bopcode::{Opmode, OPNAMES},
     breader::BReadable,
@@ -11,7 +13,11 @@ const REG_CC_MASK: u32 = 0b00000000011111111100000000000000;
 const REG_BB_MASK: u32 = 0b11111111100000000000000000000000;
 const REG_BX_MASK: u32 = 0b11111111111111111100000000000000;
 
-#[derive(Copy, Clone)]
+/// sBx is stored as an unsigned Bx offset by MAXARG_sBx
+const MAXARG_SBX: i32 = ((1 << 18) - 1) >> 1;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub enum BInstruction {
     ABC {
         line: Option<i64>, //Optional debug line data
@@ -39,7 +45,7 @@ impl BReadable for BInstruction {
 
         //Read opcode
         let opcode = (instruction & OPCODE_MASK) as u8;
-        println!("opcode {}", opcode);
+        trace!(opcode, "reading instruction");
         let opmode = Opmode::from_opcode(opcode);
 
         //Read A reg
@@ -59,7 +65,7 @@ impl BReadable for BInstruction {
                 }
             }
             Opmode::ABx => {
-                let b = ((instruction & REG_BX_MASK) >> 14) as u32;
+                let b = (instruction & REG_BX_MASK) >> 14;
                 Self::ABx {
                     opcode,
                     a,
@@ -68,18 +74,86 @@ impl BReadable for BInstruction {
                 }
             }
             Opmode::AsBx => {
-                //todo signed -> equality or clever bitshift?
-                let b = ((instruction & REG_BX_MASK) >> 14) as u32;
+                let b = ((instruction & REG_BX_MASK) >> 14) as i32 - MAXARG_SBX;
                 Self::AsBx {
                     opcode,
                     a,
-                    b: b as i32,
+                    b,
                     line: None,
                 }
             }
         }
     }
 }
+impl BInstruction {
+    pub fn opcode(&self) -> u8 {
+        match self {
+            BInstruction::ABC { opcode, .. } => *opcode,
+            BInstruction::ABx { opcode, .. } => *opcode,
+            BInstruction::AsBx { opcode, .. } => *opcode,
+        }
+    }
+
+    /// Register operand A, which every format has
+    pub fn a(&self) -> usize {
+        match self {
+            BInstruction::ABC { a, .. } => *a as usize,
+            BInstruction::ABx { a, .. } => *a as usize,
+            BInstruction::AsBx { a, .. } => *a as usize,
+        }
+    }
+
+    /// Operand Ax of EXTRAARG, which spans A and the bits of Bx
+    pub fn ax(&self) -> usize {
+        match self {
+            BInstruction::ABC { a, b, c, .. } => {
+                (*b as usize) << 17 | (*c as usize) << 8 | *a as usize
+            }
+            BInstruction::ABx { a, b, .. } => (*b as usize) << 8 | *a as usize,
+            BInstruction::AsBx { a, b, .. } => ((*b + MAXARG_SBX) as usize) << 8 | *a as usize,
+        }
+    }
+
+    /// Source line of the instruction, if debug info was dumped
+    pub fn line(&self) -> Option<i64> {
+        match self {
+            BInstruction::ABC { line, .. } => *line,
+            BInstruction::ABx { line, .. } => *line,
+            BInstruction::AsBx { line, .. } => *line,
+        }
+    }
+}
+/// Instructions without a line, for protos built by hand
+#[cfg(test)]
+impl BInstruction {
+    pub(crate) fn abc(opcode: u8, a: u8, b: u16, c: u16) -> Self {
+        Self::ABC {
+            line: None,
+            opcode,
+            a,
+            b,
+            c,
+        }
+    }
+
+    pub(crate) fn abx(opcode: u8, a: u8, b: u32) -> Self {
+        Self::ABx {
+            line: None,
+            opcode,
+            a,
+            b,
+        }
+    }
+
+    pub(crate) fn asbx(opcode: u8, a: u8, b: i32) -> Self {
+        Self::AsBx {
+            line: None,
+            opcode,
+            a,
+            b,
+        }
+    }
+}
 impl fmt::Debug for BInstruction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
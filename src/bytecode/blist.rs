@@ -1,3 +1,5 @@
+use tracing::trace;
+
 use super::breader::{BReadable, BReader};
 
 /// Instructions, constants, protos, locals, upvalues
@@ -12,7 +14,7 @@ where
 {
     pub fn read(reader: &mut BReader) -> Self {
         let size = reader.get_c_int();
-        println!(" - blist read size: {}", size);
+        trace!(size, "reading list");
         let mut list = Vec::with_capacity(size as usize);
 
         for _ in 0..size {
@@ -18,6 +18,7 @@ pub(crate) const OPNAMES: [&str; 47] = [
     "TFORCALL", "TFORLOOP", "SETLIST", "CLOSURE", "VARARG", "EXTRAARG",
 ];
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone)]
 pub enum Opmode {
     ABC,
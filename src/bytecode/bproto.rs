@@ -1,3 +1,5 @@
+use tracing::{debug_span, trace};
+
 use crate::{bytecode::BReader, lprimative::LPrimitive};
 
 use super::{
@@ -36,20 +38,26 @@ impl BReadable for BProto {
         let num_params = reader.get_byte();
         let vararg_flag = reader.get_byte();
         let max_stack = reader.get_byte();
+        let _proto = debug_span!("proto", line_defined, last_line_defined).entered();
 
-        println!("Reading instructions");
+        trace!("reading instructions");
         let mut instructions = BList::read(reader);
 
-        println!("Reading constants");
+        trace!("reading constants");
         let constants = BList::read(reader);
 
-        println!("Reading upvalues");
+        trace!("reading upvalues");
         let upvalues = BList::read(reader);
 
-        println!("Reading protos");
-        let protos = BList::read(reader);
+        trace!("reading protos");
+        let mut protos = BList::<BProto>::read(reader);
+
+        //Nested protos only dump their source when it differs from their parent's
+        for proto in protos.list.iter_mut() {
+            proto.inherit_source_name(&source_name);
+        }
 
-        println!("Reading debug lines info");
+        trace!("reading debug lines info");
         let debug_line_info = BList::<BDebugLineInfo>::read(reader);
 
         for (instruction, line_info) in instructions
@@ -64,9 +72,9 @@ impl BReadable for BProto {
             }
         }
 
-        println!("Reading debug local names");
+        trace!("reading debug local names");
         let debug_local_vars = BList::read(reader);
-        println!("Reading debug upvalue names");
+        trace!("reading debug upvalue names");
         let debug_upvalues = BList::read(reader);
 
         Self {
@@ -87,3 +95,80 @@ impl BReadable for BProto {
         }
     }
 }
+#[cfg(test)]
+impl BProto {
+    /// A main chunk built by hand, without nested protos, upvalues or debug
+    /// info
+    pub(crate) fn from_parts(instructions: Vec<BInstruction>, constants: Vec<LPrimitive>) -> Self {
+        Self {
+            source_name: None,
+            line_defined: 0,
+            last_line_defined: 0,
+            num_params: 0,
+            vararg_flag: 1,
+            max_stack: 2,
+
+            instructions: BList { list: instructions },
+            constants: BList { list: constants },
+            upvalues: BList { list: Vec::new() },
+            protos: BList { list: Vec::new() },
+
+            debug_local_vars: BList { list: Vec::new() },
+            debug_upvalues: BList { list: Vec::new() },
+        }
+    }
+}
+impl BProto {
+    fn inherit_source_name(&mut self, parent: &Option<String>) {
+        if self.source_name.is_none() {
+            self.source_name = parent.clone();
+        }
+        for proto in self.protos.list.iter_mut() {
+            proto.inherit_source_name(&self.source_name);
+        }
+    }
+
+    /// Source line of the instruction at `pc`, if debug info was dumped
+    pub fn line_at(&self, pc: usize) -> Option<i64> {
+        self.instructions.list.get(pc)?.line()
+    }
+
+    /// Name of the `n`th local variable (counting from 1) in scope at `pc`,
+    /// like luaF_getlocalname. Locals are stored in the order they come
+    /// into scope, so the nth local in scope lives in register n - 1
+    pub fn local_name(&self, n: usize, pc: usize) -> Option<&str> {
+        let pc = pc as i64;
+        self.debug_local_vars
+            .list
+            .iter()
+            .take_while(|local| local.scope_start <= pc)
+            .filter(|local| pc < local.scope_end)
+            .nth(n.checked_sub(1)?)
+            .map(|local| local.local.as_str())
+    }
+
+    /// Name of the upvalue at `index`, if debug info was dumped
+    pub fn upvalue_name(&self, index: usize) -> Option<&str> {
+        self.debug_upvalues
+            .list
+            .get(index)
+            .map(|upvalue| upvalue.upvalue.as_str())
+    }
+
+    /// Printable source name, following luaO_chunkid: "@file" becomes
+    /// "file", "=name" becomes "name" and anything else is shown as a string
+    pub fn short_src(&self) -> String {
+        let source = match self.source_name.as_deref() {
+            Some(source) => source,
+            None => return "?".to_owned(),
+        };
+        if let Some(name) = source.strip_prefix(['@', '=']) {
+            return name.to_owned();
+        }
+        match source.lines().next() {
+            Some(line) if line.len() == source.len() => format!("[string \"{}\"]", line),
+            Some(line) => format!("[string \"{}...\"]", line),
+            None => "[string \"\"]".to_owned(),
+        }
+    }
+}
@@ -1,22 +1,26 @@
 use std::io::Cursor;
 
 use bytes::Buf;
+use tracing::debug;
+
+use crate::lstring::StringTable;
 
 ///Bytecode reader which reads Integer, String bytecode primatives from headers
-pub struct BReader {
+pub struct BReader<'s> {
     pub inner: Cursor<Vec<u8>>,
+    /// String constants are interned into the table of the state loading the chunk
+    pub strings: &'s mut StringTable,
 
-    pub endianness: u8,    // 0 for high, 1 for low
-    pub integral_flag: u8, // 0 for floating point, 1 for integers
+    pub endianness: u8, // 0 for high, 1 for low
 
     pub c_int_size: u8,
     pub c_size_t: u8,
     pub lua_int_size: u8,
     pub lua_num_size: u8,
 }
-impl BReader {
+impl<'s> BReader<'s> {
     /// https://www.lua.org/source/5.3/ldump.c.html
-    pub fn from_headers(mut inner: Cursor<Vec<u8>>) -> Self {
+    pub fn from_headers(mut inner: Cursor<Vec<u8>>, strings: &'s mut StringTable) -> Self {
         //Read signature, version & format version
         let signature = inner.get_u32(); //4 bytes
         let version = inner.get_u8(); //byte
@@ -57,19 +61,18 @@ impl BReader {
         let lua_int_size = inner.get_u8(); //byte   in C: sizeof(lua_Integer), int64 on windows
         let lua_num_size = inner.get_u8(); //byte   in C: sizeof(lua_Number),  seems to be a double
 
-        println!("c_int_size {}", c_int_size);
-        println!("c_size_t {}", c_size_t);
-        println!("instruction_size {}", instruction_size);
-        println!("lua_int_size {}", lua_int_size);
-        println!("lua_num_size {}", lua_num_size);
+        debug!(
+            c_int_size,
+            c_size_t, instruction_size, lua_int_size, lua_num_size, "read headers"
+        );
 
         assert_eq!(instruction_size, 4);
 
         let mut reader = Self {
             inner,
+            strings,
 
             endianness: 1, //Not sure how this is properly determine these
-            integral_flag: 0,
 
             c_int_size,
             c_size_t,
@@ -168,10 +171,6 @@ impl BReader {
         self.inner.get_u8()
     }
 
-    pub fn remaining(&self) -> usize {
-        self.inner.remaining()
-    }
-
     pub fn get_c_int(&mut self) -> i64 {
         match self.c_int_size {
             4 => self.get_i32() as i64,
@@ -189,56 +188,30 @@ impl BReader {
     }
 
     pub fn get_lua_integer(&mut self) -> i64 {
-        // match self.integral_flag {
-        //     0 => {
-        //         //Floating point
-        //         match self.lua_num_size {
-        //             4 => self.get_f32() as f64,
-        //             8 => self.get_f64(),
-        //             n => panic!("invalid chunk l_num_size {}", n),
-        //         }
-        //     }
-        //     1 => {
-        //Integer
-        match self.lua_num_size {
+        match self.lua_int_size {
             4 => self.get_i32() as i64,
-            8 => self.get_i64() as i64,
-            n => panic!("invalid chunk l_num_size {}", n),
+            8 => self.get_i64(),
+            n => panic!("invalid chunk l_int_size {}", n),
         }
-        //     }
-        //     n => panic!("Invalid chunk integral flag {}", n),
-        // }
     }
 
     pub fn get_lua_float(&mut self) -> f64 {
-        // match self.integral_flag {
-        //     0 => {
-        //Floating point
         match self.lua_num_size {
             4 => self.get_f32() as f64,
             8 => self.get_f64(),
             n => panic!("invalid chunk l_num_size {}", n),
         }
-        // }
-        //     1 => {
-        //         //Integer
-        //         match self.lua_num_size {
-        //             4 => self.get_i32() as f64, //not ideal to cast to float
-        //             8 => self.get_i64() as f64, //not ideal to cast to float
-        //             n => panic!("invalid chunk l_num_size {}", n),
-        //         }
-        //     }
-        //     n => panic!("Invalid chunk integral flag {}", n),
-        // }
     }
 
     pub fn get_string(&mut self) -> Option<String> {
-        let len = self.get_byte() as isize - 1;
-        if len == -1 {
-            return None;
-        }
+        //Sizes include the nul terminator, long strings store theirs as a size_t after 0xFF
+        let len = match self.get_byte() {
+            0 => return None,
+            0xFF => self.get_c_size_t() as usize - 1,
+            size => size as usize - 1,
+        };
 
-        let mut ret = String::with_capacity(len as usize);
+        let mut ret = String::with_capacity(len);
 
         for _ in 0..len {
             ret.push(self.get_byte() as char);
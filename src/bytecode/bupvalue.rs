@@ -2,8 +2,8 @@ use super::breader::BReadable;
 
 #[derive(Debug)]
 pub struct BUpvalue {
-    stack_flag: u8,
-    index: u8,
+    pub(crate) stack_flag: u8, //Whether the upvalue is a register of the enclosing function or one of its upvalues
+    pub(crate) index: u8,
 }
 impl BReadable for BUpvalue {
     fn read(reader: &mut super::breader::BReader) -> Self {
@@ -0,0 +1,492 @@
+use std::io::{self, Write};
+
+use super::{binstruction::BInstruction, bopcode::OPNAMES, bproto::BProto};
+
+/// Instructions which execute one after the other, only entered at the
+/// first and only left after the last
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    /// Pc of the first instruction
+    pub start: usize,
+    /// Pc after the last instruction
+    pub end: usize,
+    pub successors: Vec<usize>,
+    pub predecessors: Vec<usize>,
+}
+
+/// Control-flow graph of a proto. Blocks are in the order of their
+/// instructions, so the entry is block 0
+pub struct Cfg<'p> {
+    proto: &'p BProto,
+    pub blocks: Vec<BasicBlock>,
+    /// Block of each instruction
+    block_of: Vec<usize>,
+    /// Immediate dominator of each block, None for the entry and for
+    /// blocks which can't be reached
+    idom: Vec<Option<usize>>,
+}
+impl<'p> Cfg<'p> {
+    /// Splits the instructions at jump targets, and after jumps, tests,
+    /// comparisons, loops and returns
+    pub fn new(proto: &'p BProto) -> Self {
+        let list = &proto.instructions.list;
+
+        let mut starts = vec![false; list.len() + 1];
+        starts[0] = true;
+        for (pc, instruction) in list.iter().enumerate() {
+            if let Some(target) = jump_target(pc, instruction) {
+                starts[target] = true;
+            }
+            if ends_block(instruction) {
+                starts[pc + 1] = true;
+            }
+            if skips_next(instruction) {
+                starts[(pc + 2).min(list.len())] = true;
+            }
+        }
+
+        let mut blocks = Vec::new();
+        let mut block_of = Vec::with_capacity(list.len());
+        for (pc, &start) in starts[..list.len()].iter().enumerate() {
+            if start {
+                blocks.push(BasicBlock {
+                    start: pc,
+                    end: pc,
+                    successors: Vec::new(),
+                    predecessors: Vec::new(),
+                });
+            }
+            let block = blocks.len() - 1;
+            blocks[block].end = pc + 1;
+            block_of.push(block);
+        }
+
+        for block in 0..blocks.len() {
+            let last = blocks[block].end - 1;
+            for next in successors(last, &list[last]) {
+                //luac ends every proto with a RETURN, so only broken chunks
+                //fall off the end
+                let Some(&next) = block_of.get(next) else {
+                    continue;
+                };
+                if !blocks[block].successors.contains(&next) {
+                    blocks[block].successors.push(next);
+                    blocks[next].predecessors.push(block);
+                }
+            }
+        }
+
+        let idom = dominators(&blocks);
+        Self {
+            proto,
+            blocks,
+            block_of,
+            idom,
+        }
+    }
+
+    /// Block which the instruction at `pc` is in
+    pub fn block_at(&self, pc: usize) -> usize {
+        self.block_of[pc]
+    }
+
+    /// The closest block every path from the entry to `block` goes
+    /// through, None for the entry and for blocks which can't be reached
+    pub fn immediate_dominator(&self, block: usize) -> Option<usize> {
+        self.idom[block]
+    }
+
+    pub fn is_reachable(&self, block: usize) -> bool {
+        block == 0 || self.idom[block].is_some()
+    }
+
+    /// Whether every path from the entry to `block` goes through
+    /// `dominator`. Blocks dominate themselves
+    pub fn dominates(&self, dominator: usize, block: usize) -> bool {
+        if !self.is_reachable(block) {
+            return false;
+        }
+        let mut block = Some(block);
+        while let Some(current) = block {
+            if current == dominator {
+                return true;
+            }
+            block = self.idom[current];
+        }
+        false
+    }
+
+    /// An edge to a block which dominates the block it leaves, which
+    /// closes a loop whose header is `to`
+    pub fn is_back_edge(&self, from: usize, to: usize) -> bool {
+        self.dominates(to, from)
+    }
+
+    /// The blocks of the proto as a cluster of a dot graph, with node ids
+    /// prefixed by `id`. Blocks are labelled with their immediate dominator,
+    /// loops are drawn with dashed back edges and blocks which can't be
+    /// reached are grey
+    fn write_cluster(&self, id: &str, output: &mut impl Write) -> io::Result<()> {
+        let name = match self.proto.line_defined {
+            0 => format!("main chunk <{}>", self.proto.short_src()),
+            line => format!("function <{}:{}>", self.proto.short_src(), line),
+        };
+        writeln!(output, "  subgraph cluster_{} {{", id)?;
+        writeln!(output, "    label=\"{}\";", escape(&name))?;
+
+        for (index, block) in self.blocks.iter().enumerate() {
+            let mut label = match self.immediate_dominator(index) {
+                Some(dominator) => format!("block {}, dominated by {}\\l", index, dominator),
+                None => format!("block {}\\l", index),
+            };
+            for pc in block.start..block.end {
+                label.push_str(&escape(&instruction_text(self.proto, pc)));
+                label.push_str("\\l");
+            }
+            let style = if self.is_reachable(index) {
+                ""
+            } else {
+                " color=grey fontcolor=grey"
+            };
+            writeln!(
+                output,
+                "    {}b{} [label=\"{}\"{}];",
+                id, index, label, style
+            )?;
+        }
+
+        for (index, block) in self.blocks.iter().enumerate() {
+            for &successor in &block.successors {
+                let style = if self.is_back_edge(index, successor) {
+                    " [style=dashed label=\"loop\"]"
+                } else {
+                    ""
+                };
+                writeln!(
+                    output,
+                    "    {}b{} -> {}b{}{};",
+                    id, index, id, successor, style
+                )?;
+            }
+        }
+        writeln!(output, "  }}")
+    }
+}
+
+/// A dot graph of the blocks of `top` and every proto nested in it, each
+/// in a cluster of its own
+pub fn write_dot(top: &BProto, output: &mut impl Write) -> io::Result<()> {
+    writeln!(output, "digraph cfg {{")?;
+    writeln!(output, "  node [shape=box fontname=monospace];")?;
+    let mut protos = vec![top];
+    let mut index = 0;
+    while let Some(proto) = protos.pop() {
+        Cfg::new(proto).write_cluster(&format!("p{}", index), output)?;
+        protos.extend(proto.protos.list.iter().rev());
+        index += 1;
+    }
+    writeln!(output, "}}")
+}
+
+/// Pc, line, opcode and operands, with the destination of jumps
+fn instruction_text(proto: &BProto, pc: usize) -> String {
+    let instruction = &proto.instructions.list[pc];
+    let line = match instruction.line() {
+        Some(line) => format!("[{}]", line),
+        None => "[-]".to_owned(),
+    };
+    let operands = match *instruction {
+        BInstruction::ABC { a, b, c, .. } => format!("{} {} {}", a, b, c),
+        BInstruction::ABx { a, b, .. } => format!("{} {}", a, b),
+        BInstruction::AsBx { a, b, .. } => match jump_target(pc, instruction) {
+            Some(target) => format!("{} {}\t; to {}", a, b, target),
+            None => format!("{} {}", a, b),
+        },
+    };
+    format!(
+        "{:>4} {:<6} {:<9} {}",
+        pc,
+        line,
+        OPNAMES[instruction.opcode() as usize],
+        operands
+    )
+}
+
+/// Immediate dominators by the iterative algorithm of Cooper, Harvey and
+/// Kennedy, visiting blocks in reverse postorder until nothing changes
+fn dominators(blocks: &[BasicBlock]) -> Vec<Option<usize>> {
+    let mut idom = vec![None; blocks.len()];
+    if blocks.is_empty() {
+        return idom;
+    }
+
+    let order = reverse_postorder(blocks);
+    let mut rank = vec![usize::MAX; blocks.len()];
+    for (position, &block) in order.iter().enumerate() {
+        rank[block] = position;
+    }
+
+    let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+        while a != b {
+            while rank[a] > rank[b] {
+                a = idom[a].expect("processed blocks have a dominator");
+            }
+            while rank[b] > rank[a] {
+                b = idom[b].expect("processed blocks have a dominator");
+            }
+        }
+        a
+    };
+
+    idom[0] = Some(0);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in &order[1..] {
+            let mut dominator = None;
+            for &predecessor in &blocks[block].predecessors {
+                if idom[predecessor].is_none() {
+                    continue;
+                }
+                dominator = Some(match dominator {
+                    None => predecessor,
+                    Some(other) => intersect(&idom, predecessor, other),
+                });
+            }
+            if idom[block] != dominator {
+                idom[block] = dominator;
+                changed = true;
+            }
+        }
+    }
+
+    idom[0] = None;
+    idom
+}
+
+/// Blocks reachable from the entry, each before the blocks it leads to
+/// except along back edges
+fn reverse_postorder(blocks: &[BasicBlock]) -> Vec<usize> {
+    let mut visited = vec![false; blocks.len()];
+    let mut postorder = Vec::with_capacity(blocks.len());
+    //Blocks being visited and the index of the successor to visit next
+    let mut stack = vec![(0, 0)];
+    visited[0] = true;
+    while let Some((block, next)) = stack.last_mut() {
+        match blocks[*block].successors.get(*next) {
+            Some(&successor) => {
+                *next += 1;
+                if !visited[successor] {
+                    visited[successor] = true;
+                    stack.push((successor, 0));
+                }
+            }
+            None => {
+                postorder.push(*block);
+                stack.pop();
+            }
+        }
+    }
+    postorder.reverse();
+    postorder
+}
+
+/// Target of JMP, FORLOOP, FORPREP and TFORLOOP, which jump by sBx
+pub(crate) fn jump_target(pc: usize, instruction: &BInstruction) -> Option<usize> {
+    match *instruction {
+        BInstruction::AsBx {
+            opcode: 30 | 39 | 40 | 42,
+            b,
+            ..
+        } => Some((pc as i64 + 1 + b as i64) as usize),
+        _ => None,
+    }
+}
+
+/// Where execution may go after `instruction`
+pub(crate) fn successors(pc: usize, instruction: &BInstruction) -> Vec<usize> {
+    match *instruction {
+        //JMP and FORPREP
+        BInstruction::AsBx {
+            opcode: 30 | 40, ..
+        } => vec![jump_target(pc, instruction).expect("jumps have a target")],
+        //RETURN
+        BInstruction::ABC { opcode: 38, .. } => vec![],
+        //FORLOOP and TFORLOOP
+        BInstruction::AsBx {
+            opcode: 39 | 42, ..
+        } => vec![
+            pc + 1,
+            jump_target(pc, instruction).expect("loops have a target"),
+        ],
+        //EQ, LT, LE, TEST and TESTSET go on to the jump after them or skip it
+        BInstruction::ABC {
+            opcode: 31..=35, ..
+        } => vec![pc + 1, pc + 2],
+        //LOADBOOL with C set
+        BInstruction::ABC { opcode: 3, c, .. } if c != 0 => vec![pc + 2],
+        _ => vec![pc + 1],
+    }
+}
+
+/// EQ, LT, LE, TEST, TESTSET and LOADBOOL with C set, which may skip the
+/// instruction after them
+pub(crate) fn skips_next(instruction: &BInstruction) -> bool {
+    match *instruction {
+        BInstruction::ABC {
+            opcode: 31..=35, ..
+        } => true,
+        BInstruction::ABC { opcode: 3, c, .. } => c != 0,
+        _ => false,
+    }
+}
+
+/// JMP, EQ, LT, LE, TEST, TESTSET, RETURN, FORLOOP, FORPREP, TFORLOOP and
+/// LOADBOOL with C set
+fn ends_block(instruction: &BInstruction) -> bool {
+    skips_next(instruction) || matches!(instruction.opcode(), 30 | 38 | 39 | 40 | 42)
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\t', "    ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lprimative::LPrimitive;
+
+    const K: u16 = 256;
+
+    fn chunk(instructions: Vec<BInstruction>) -> BProto {
+        let constants = [0, 1, 5, 10].map(LPrimitive::INT).to_vec();
+        BProto::from_parts(instructions, constants)
+    }
+
+    fn jmp(offset: i32) -> BInstruction {
+        BInstruction::asbx(30, 0, offset)
+    }
+
+    fn ret() -> BInstruction {
+        BInstruction::abc(38, 0, 1, 0)
+    }
+
+    /// Instructions of each block, as the pc of the first and the pc after
+    /// the last
+    fn bounds(cfg: &Cfg) -> Vec<(usize, usize)> {
+        cfg.blocks
+            .iter()
+            .map(|block| (block.start, block.end))
+            .collect()
+    }
+
+    fn idoms(cfg: &Cfg) -> Vec<Option<usize>> {
+        (0..cfg.blocks.len())
+            .map(|block| cfg.immediate_dominator(block))
+            .collect()
+    }
+
+    /// Every edge, with whether it's a back edge
+    fn edges(cfg: &Cfg) -> Vec<(usize, usize, bool)> {
+        let mut edges = Vec::new();
+        for (from, block) in cfg.blocks.iter().enumerate() {
+            for &to in &block.successors {
+                edges.push((from, to, cfg.is_back_edge(from, to)));
+            }
+        }
+        edges.sort();
+        edges
+    }
+
+    #[test]
+    fn if_else_diamond() {
+        //if r0 then r1 = 1 else r1 = 5 end
+        let test = BInstruction::abc(34, 0, 0, 0);
+        let proto = chunk(vec![
+            test,
+            jmp(2),
+            BInstruction::abx(1, 1, 1),
+            jmp(1),
+            BInstruction::abx(1, 1, 2),
+            ret(),
+        ]);
+        assert!(skips_next(&test) && ends_block(&test));
+        assert!(!skips_next(&jmp(2)) && ends_block(&jmp(2)));
+        assert!(!ends_block(&BInstruction::abx(1, 1, 1)));
+
+        let cfg = Cfg::new(&proto);
+        assert_eq!(bounds(&cfg), [(0, 1), (1, 2), (2, 4), (4, 5), (5, 6)]);
+        //Both branches are dominated by the test, and so is where they join
+        assert_eq!(idoms(&cfg), [None, Some(0), Some(0), Some(1), Some(0)]);
+        assert_eq!(
+            edges(&cfg),
+            [
+                (0, 1, false),
+                (0, 2, false),
+                (1, 3, false),
+                (2, 4, false),
+                (3, 4, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn while_loop() {
+        //r0 = 0 while r0 < 10 do r0 = r0 + 1 end
+        let proto = chunk(vec![
+            BInstruction::abx(1, 0, 0),
+            BInstruction::abc(32, 0, 0, K | 3),
+            jmp(2),
+            BInstruction::abc(13, 0, 0, K | 1),
+            jmp(-4),
+            ret(),
+        ]);
+        let cfg = Cfg::new(&proto);
+        //The jump back starts a block at the condition
+        assert_eq!(bounds(&cfg), [(0, 1), (1, 2), (2, 3), (3, 5), (5, 6)]);
+        assert_eq!(idoms(&cfg), [None, Some(0), Some(1), Some(1), Some(2)]);
+        assert_eq!(
+            edges(&cfg),
+            [
+                (0, 1, false),
+                (1, 2, false),
+                (1, 3, false),
+                (2, 4, false),
+                (3, 1, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeat_with_break() {
+        //repeat r0 = r0 + 1 if 5 < r0 then break end until 10 < r0
+        let proto = chunk(vec![
+            BInstruction::abc(13, 0, 0, K | 1),
+            BInstruction::abc(32, 0, K | 2, 0),
+            jmp(2),
+            BInstruction::abc(32, 0, K | 3, 0),
+            jmp(-5),
+            ret(),
+        ]);
+        let cfg = Cfg::new(&proto);
+        assert_eq!(bounds(&cfg), [(0, 2), (2, 3), (3, 4), (4, 5), (5, 6)]);
+        //The break and the end of the loop both leave from the body
+        assert_eq!(idoms(&cfg), [None, Some(0), Some(0), Some(2), Some(0)]);
+        //Only the jump back to the entry closes the loop
+        assert_eq!(
+            edges(&cfg),
+            [
+                (0, 1, false),
+                (0, 2, false),
+                (1, 4, false),
+                (2, 3, false),
+                (2, 4, false),
+                (3, 0, true),
+            ]
+        );
+        assert!(cfg.dominates(0, 3) && !cfg.dominates(3, 0));
+    }
+}
@@ -1,8 +1,10 @@
 use crate::bytecode::bproto::BProto;
 use crate::bytecode::breader::BReadable;
-use std::{env, fs, io::Cursor, process::Command};
+use std::{env, fs, io::Cursor, path::Path, process::Command};
+use tracing::{debug_span, trace};
 
 use self::breader::BReader;
+use crate::lstring::StringTable;
 
 pub(crate) mod bconstant;
 pub(crate) mod bdebug;
@@ -12,8 +14,10 @@ pub(crate) mod bopcode;
 pub(crate) mod bproto;
 pub(crate) mod breader;
 pub(crate) mod bupvalue;
+pub(crate) mod cfg;
+pub(crate) mod optimize;
 
-fn dump_bytecode() -> Result<Vec<u8>, std::io::Error> {
+pub fn dump_bytecode() -> Result<Vec<u8>, std::io::Error> {
     let mut io_dir = env::current_dir().expect("failed to get current_dir");
     io_dir.push("io");
 
@@ -26,15 +30,38 @@ fn dump_bytecode() -> Result<Vec<u8>, std::io::Error> {
     fs::read("io/output.lua")
 }
 
-pub fn decode_bytecode() -> Result<Box<BProto>, std::io::Error> {
+pub fn decode_bytecode(strings: &mut StringTable) -> Result<Box<BProto>, std::io::Error> {
     //Dump bytecode
     let bytecode = dump_bytecode()?;
 
-    let mut reader = BReader::from_headers(Cursor::new(bytecode));
-    println!("read headers");
+    Ok(decode(bytecode, strings))
+}
+
+/// Reads a chunk precompiled by luac from `path` rather than dumping the
+/// input script
+pub fn load_chunk(path: &Path, strings: &mut StringTable) -> Result<Box<BProto>, std::io::Error> {
+    let bytecode = fs::read(path)?;
+
+    Ok(decode(bytecode, strings))
+}
+
+/// Loads a chunk checked in under fixtures, compiled from the .lua source
+/// of the same name by luac 5.3
+#[cfg(test)]
+pub(crate) fn load_fixture(name: &str, strings: &mut StringTable) -> Box<BProto> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures")
+        .join(name)
+        .with_extension("luac");
+    load_chunk(&path, strings).expect("failed to read fixture")
+}
+
+fn decode(bytecode: Vec<u8>, strings: &mut StringTable) -> Box<BProto> {
+    let _decode = debug_span!("decode", bytes = bytecode.len()).entered();
+    let mut reader = BReader::from_headers(Cursor::new(bytecode), strings);
     let proto = Box::new(BProto::read(&mut reader));
 
-    println!("read bytecode {:#?}", proto);
+    trace!(?proto, "read bytecode");
 
-    Ok(proto)
+    proto
 }
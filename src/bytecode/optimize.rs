@@ -0,0 +1,621 @@
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    ops::AddAssign,
+    rc::Rc,
+    thread,
+    time::Duration,
+};
+
+use tracing::{debug, debug_span};
+
+use crate::{
+    interpreter::{
+        arith::{arith, ArithOp},
+        genv::BudgetMode,
+        lclosure::BITRK,
+        Interpreter,
+    },
+    lprimative::LPrimitive,
+    lstring::StringTable,
+};
+
+use super::{
+    binstruction::BInstruction,
+    bproto::BProto,
+    cfg::{jump_target, skips_next, Cfg},
+};
+
+/// Largest constant index LOADK can hold in Bx
+const MAXARG_BX: usize = (1 << 18) - 1;
+
+/// What `optimize` took out of a proto and the protos nested in it
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Removed {
+    pub instructions: usize,
+    pub constants: usize,
+}
+impl AddAssign for Removed {
+    fn add_assign(&mut self, other: Self) {
+        self.instructions += other.instructions;
+        self.constants += other.constants;
+    }
+}
+
+/// Rewrites `proto` and the protos nested in it to do the same with fewer
+/// instructions and constants. Arithmetic on two constants is folded,
+/// jumps to jumps go straight to where the chain ends, and instructions
+/// which can't run or don't change anything are removed, after which the
+/// constants no instruction uses are dropped
+///
+/// Instructions keep their lines, and locals are in scope over the same
+/// instructions as before, so positions in errors and the debugger don't
+/// change
+pub fn optimize(proto: &mut BProto) -> Removed {
+    let _proto = debug_span!("optimize", line_defined = proto.line_defined).entered();
+    let instructions = proto.instructions.list.len();
+    let constants = proto.constants.list.len();
+
+    let folded = fold_constants(proto);
+    let threaded = thread_jumps(proto);
+
+    let (removed, dead) = {
+        let cfg = Cfg::new(proto);
+        let list = &proto.instructions.list;
+        let live = live_instructions(&cfg, list);
+        let dead = live.iter().filter(|&&live| !live).count();
+        let removed: Vec<bool> = (0..list.len())
+            .map(|pc| !live[pc] || is_redundant(&cfg, list, pc))
+            .collect();
+        (removed, dead)
+    };
+    remove_instructions(proto, &removed);
+    compact_constants(proto);
+
+    let mut total = Removed {
+        instructions: instructions - proto.instructions.list.len(),
+        constants: constants.saturating_sub(proto.constants.list.len()),
+    };
+    debug!(
+        folded,
+        threaded,
+        dead,
+        instructions = total.instructions,
+        constants = total.constants,
+        "optimized"
+    );
+
+    for child in proto.protos.list.iter_mut() {
+        total += optimize(child);
+    }
+    total
+}
+
+/// Replaces arithmetic on two numeric constants with a LOADK of the result.
+/// Operations which would raise an error are left to raise it when run
+fn fold_constants(proto: &mut BProto) -> usize {
+    let mut folded = 0;
+    for instruction in proto.instructions.list.iter_mut() {
+        //ADD..=SHR
+        let BInstruction::ABC {
+            line,
+            opcode: opcode @ 13..=24,
+            a,
+            b,
+            c,
+        } = *instruction
+        else {
+            continue;
+        };
+        let (b, c) = (b as usize, c as usize);
+        if b & BITRK == 0 || c & BITRK == 0 {
+            continue;
+        }
+
+        let constants = &proto.constants.list;
+        let (lhs, rhs) = (&constants[b & !BITRK], &constants[c & !BITRK]);
+        let numbers =
+            |value: &LPrimitive| matches!(value, LPrimitive::INT(_) | LPrimitive::FLOAT(_));
+        if !numbers(lhs) || !numbers(rhs) {
+            continue;
+        }
+        let Ok(value) = arith(ArithOp::from_opcode(opcode), lhs, rhs) else {
+            continue;
+        };
+
+        let index = match constants.iter().position(|k| same_constant(k, &value)) {
+            Some(index) => index,
+            None => {
+                proto.constants.list.push(value);
+                proto.constants.list.len() - 1
+            }
+        };
+        if index > MAXARG_BX {
+            continue;
+        }
+        *instruction = BInstruction::ABx {
+            line,
+            opcode: 1,
+            a,
+            b: index as u32,
+        };
+        folded += 1;
+    }
+    folded
+}
+
+/// Points jumps whose target is another jump at the end of the chain.
+/// Jumps which close upvalues are only jumped over when they're the first
+fn thread_jumps(proto: &mut BProto) -> usize {
+    let list = &mut proto.instructions.list;
+    let mut threaded = 0;
+    for pc in 0..list.len() {
+        if list[pc].opcode() != 30 {
+            continue;
+        }
+        let start = jump_target(pc, &list[pc]).expect("JMP has a target");
+        let mut target = start;
+        //Bounded so a cycle of jumps, an infinite loop, ends
+        for _ in 0..list.len() {
+            match list[target] {
+                BInstruction::AsBx {
+                    opcode: 30, a: 0, ..
+                } => {
+                    let next = jump_target(target, &list[target]).expect("JMP has a target");
+                    if next == target {
+                        break;
+                    }
+                    target = next;
+                }
+                _ => break,
+            }
+        }
+        if target != start {
+            set_jump_target(pc, &mut list[pc], target);
+            threaded += 1;
+        }
+    }
+    threaded
+}
+
+/// Instructions in the blocks execution can get to. The instruction after
+/// one which may skip it is kept even when nothing gets to it, so that the
+/// same one is skipped, and so is the RETURN luac ends every proto with, so
+/// that the line of the proto's `end` stays active for hooks and
+/// `debug.getinfo`
+fn live_instructions(cfg: &Cfg, list: &[BInstruction]) -> Vec<bool> {
+    let mut live: Vec<bool> = (0..list.len())
+        .map(|pc| cfg.is_reachable(cfg.block_at(pc)))
+        .collect();
+    for pc in 1..list.len() {
+        if live[pc - 1] && skips_next(&list[pc - 1]) {
+            live[pc] = true;
+        }
+    }
+    if let Some(last) = live.last_mut() {
+        *last = true;
+    }
+    live
+}
+
+/// Instructions which don't change anything: a MOVE of a register to
+/// itself, a MOVE between two registers the MOVE before it in the block
+/// made equal, and a JMP to the next instruction which closes nothing. The
+/// jump after a comparison is kept since the comparison skips it or not
+fn is_redundant(cfg: &Cfg, list: &[BInstruction], pc: usize) -> bool {
+    match list[pc] {
+        BInstruction::ABC {
+            opcode: 0, a, b, ..
+        } => {
+            let (a, b) = (a as usize, b as usize);
+            let starts_block = cfg.blocks[cfg.block_at(pc)].start == pc;
+            a == b
+                || (!starts_block
+                    && matches!(
+                        list[pc - 1],
+                        BInstruction::ABC { opcode: 0, a: prev_a, b: prev_b, .. }
+                            if (prev_a as usize, prev_b as usize) == (a, b)
+                                || (prev_a as usize, prev_b as usize) == (b, a)
+                    ))
+        }
+        BInstruction::AsBx {
+            opcode: 30,
+            a: 0,
+            b: 0,
+            ..
+        } => pc == 0 || !skips_next(&list[pc - 1]),
+        _ => false,
+    }
+}
+
+/// Takes out the instructions marked removed. Jumps and the scopes of
+/// locals which started or ended at a removed instruction move to the
+/// next one which is kept
+fn remove_instructions(proto: &mut BProto, removed: &[bool]) {
+    let list = &mut proto.instructions.list;
+    //Where each instruction ends up, or the next one kept for removed ones
+    let mut new_pc = Vec::with_capacity(list.len() + 1);
+    let mut kept = 0;
+    for &removed in removed {
+        new_pc.push(kept);
+        if !removed {
+            kept += 1;
+        }
+    }
+    new_pc.push(kept);
+
+    for (pc, instruction) in list.iter_mut().enumerate() {
+        if let Some(target) = jump_target(pc, instruction) {
+            set_jump_target(new_pc[pc], instruction, new_pc[target]);
+        }
+    }
+    let mut removed = removed.iter();
+    list.retain(|_| !removed.next().expect("an entry per instruction"));
+
+    let last = new_pc.len() as i64 - 1;
+    for local in proto.debug_local_vars.list.iter_mut() {
+        local.scope_start = new_pc[local.scope_start.clamp(0, last) as usize] as i64;
+        local.scope_end = new_pc[local.scope_end.clamp(0, last) as usize] as i64;
+    }
+}
+
+/// Drops the constants no instruction uses and merges duplicates, keeping
+/// the order so that constants only ever move to a lower index and still
+/// fit the operands referring to them. Protos using LOADKX are left alone
+fn compact_constants(proto: &mut BProto) {
+    let list = &mut proto.instructions.list;
+    if list.iter().any(|instruction| instruction.opcode() == 2) {
+        return;
+    }
+
+    let mut used = vec![false; proto.constants.list.len()];
+    for instruction in list.iter_mut() {
+        map_constants(instruction, |k| {
+            used[k] = true;
+            k
+        });
+    }
+
+    let mut constants: Vec<LPrimitive> = Vec::new();
+    let mut new_index = vec![0; used.len()];
+    for (k, value) in proto.constants.list.drain(..).enumerate() {
+        if !used[k] {
+            continue;
+        }
+        new_index[k] = match constants
+            .iter()
+            .position(|kept| same_constant(kept, &value))
+        {
+            Some(index) => index,
+            None => {
+                constants.push(value);
+                constants.len() - 1
+            }
+        };
+    }
+    proto.constants.list = constants;
+
+    for instruction in list.iter_mut() {
+        map_constants(instruction, |k| new_index[k]);
+    }
+}
+
+/// Replaces each constant index an instruction uses with `f` of it
+fn map_constants(instruction: &mut BInstruction, mut f: impl FnMut(usize) -> usize) {
+    let mut rk = |operand: &mut u16| {
+        if *operand as usize & BITRK != 0 {
+            *operand = (BITRK | f(*operand as usize & !BITRK)) as u16;
+        }
+    };
+    match instruction {
+        //LOADK
+        BInstruction::ABx { opcode: 1, b, .. } => *b = f(*b as usize) as u32,
+        //GETTABUP, GETTABLE and SELF
+        BInstruction::ABC {
+            opcode: 6 | 7 | 12,
+            c,
+            ..
+        } => rk(c),
+        //SETTABUP, SETTABLE, ADD..=SHR, EQ, LT and LE
+        BInstruction::ABC {
+            opcode: 8 | 10 | 13..=24 | 31..=33,
+            b,
+            c,
+            ..
+        } => {
+            rk(b);
+            rk(c);
+        }
+        _ => {}
+    }
+}
+
+/// Constants are only merged when they're the same value of the same
+/// type, so 1 and 1.0 and the two zeros stay apart
+fn same_constant(lhs: &LPrimitive, rhs: &LPrimitive) -> bool {
+    match (lhs, rhs) {
+        (LPrimitive::NIL, LPrimitive::NIL) => true,
+        (LPrimitive::BOOL(x), LPrimitive::BOOL(y)) => x == y,
+        (LPrimitive::INT(x), LPrimitive::INT(y)) => x == y,
+        (LPrimitive::FLOAT(x), LPrimitive::FLOAT(y)) => x.to_bits() == y.to_bits(),
+        (LPrimitive::STRING(x), LPrimitive::STRING(y)) => x == y,
+        _ => false,
+    }
+}
+
+fn set_jump_target(pc: usize, instruction: &mut BInstruction, target: usize) {
+    if let BInstruction::AsBx { b, .. } = instruction {
+        *b = (target as i64 - pc as i64 - 1) as i32;
+    }
+}
+
+/// Collects what a run prints
+pub(crate) struct Capture(pub(crate) Rc<RefCell<Vec<u8>>>);
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Limits each run of `differential` is held to, as a normal run would be
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub instructions: Option<u64>,
+    /// In bytes
+    pub memory: Option<usize>,
+    pub timeout: Option<Duration>,
+}
+
+/// Runs the chunk as compiled and once optimized, each in a state of its
+/// own, and compares what they print and the errors they end with. The
+/// output of the run as compiled goes to stdout, and where the two differ
+/// to stderr. True if they're the same
+pub fn differential(bytecode: &[u8], limits: Limits) -> io::Result<bool> {
+    let mut strings = StringTable::default();
+    let plain = super::decode(bytecode.to_vec(), &mut strings);
+    let expected = run_captured(&plain, strings, limits);
+
+    let mut strings = StringTable::default();
+    let mut optimized = super::decode(bytecode.to_vec(), &mut strings);
+    let removed = optimize(&mut optimized);
+    let actual = run_captured(&optimized, strings, limits);
+
+    io::stdout().write_all(expected.as_bytes())?;
+
+    let expected_lines: Vec<&str> = expected.lines().collect();
+    let actual_lines: Vec<&str> = actual.lines().collect();
+    let mut stderr = io::stderr();
+    writeln!(
+        stderr,
+        "optimizing removed {} instructions and {} constants",
+        removed.instructions, removed.constants
+    )?;
+    for line in 0..expected_lines.len().max(actual_lines.len()) {
+        let (expected, actual) = (expected_lines.get(line), actual_lines.get(line));
+        if expected.map(|line| mask_addresses(line)) != actual.map(|line| mask_addresses(line)) {
+            writeln!(stderr, "optimized run differs at line {}", line + 1)?;
+            writeln!(
+                stderr,
+                "  compiled:  {}",
+                expected.unwrap_or(&"<end of output>")
+            )?;
+            writeln!(
+                stderr,
+                "  optimized: {}",
+                actual.unwrap_or(&"<end of output>")
+            )?;
+            return Ok(false);
+        }
+    }
+    writeln!(stderr, "optimized run matches")?;
+    Ok(true)
+}
+
+/// Output of a run followed by its error, formatted as the binary prints
+/// it. Errors are printed after the output rather than interleaved
+pub(crate) fn run_captured(top: &BProto, strings: StringTable, limits: Limits) -> String {
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let mut interpreter = Interpreter::new(top, strings);
+    interpreter.set_output(Box::new(Capture(output.clone())));
+    interpreter.set_memory_limit(limits.memory);
+    if limits.instructions.is_some() {
+        interpreter.set_instruction_budget(limits.instructions, BudgetMode::Error);
+    }
+    if let Some(timeout) = limits.timeout {
+        let interrupt = interpreter.interrupt_handle();
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            interrupt.interrupt();
+        });
+    }
+
+    let result = interpreter.interpret();
+    interpreter.close();
+
+    let mut output = String::from_utf8_lossy(&output.borrow()).into_owned();
+    if let Err(e) = result {
+        output.push_str(&format!("lua: {}\n", e));
+        if !e.traceback().is_empty() {
+            output.push_str(&format!("{}\n", e.traceback()));
+        }
+    }
+    output
+}
+
+/// Tables and functions print as their address, which differs between
+/// states. Every hex number is replaced so that only the rest is compared
+fn mask_addresses(line: &str) -> String {
+    let mut masked = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find("0x") {
+        masked.push_str(&rest[..start]);
+        masked.push_str("0x?");
+        rest = rest[start + 2..].trim_start_matches(|c: char| c.is_ascii_hexdigit());
+    }
+    masked.push_str(rest);
+    masked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::bdebug::BDebugLocal;
+
+    const JMP: u8 = 30;
+    const RETURN: u8 = 38;
+
+    fn jmp(offset: i32) -> BInstruction {
+        BInstruction::asbx(JMP, 0, offset)
+    }
+
+    fn loadk(a: u8, k: u32) -> BInstruction {
+        BInstruction::abx(1, a, k)
+    }
+
+    fn ret() -> BInstruction {
+        BInstruction::abc(RETURN, 0, 1, 0)
+    }
+
+    fn chunk(instructions: Vec<BInstruction>) -> BProto {
+        BProto::from_parts(instructions, vec![LPrimitive::INT(1)])
+    }
+
+    #[test]
+    fn jumps_are_retargeted_across_removed_instructions() {
+        //A jump forward and one back over the instruction removed
+        let mut proto = chunk(vec![jmp(2), loadk(0, 0), loadk(1, 0), jmp(-4), ret()]);
+        remove_instructions(&mut proto, &[false, true, false, false, false]);
+        assert_eq!(
+            proto.instructions.list,
+            [jmp(1), loadk(1, 0), jmp(-3), ret()]
+        );
+    }
+
+    #[test]
+    fn jumps_into_a_removed_range_go_to_the_next_kept_instruction() {
+        let mut proto = chunk(vec![
+            jmp(1),
+            loadk(0, 0),
+            BInstruction::abc(0, 1, 1, 0),
+            BInstruction::abc(0, 0, 0, 0),
+            ret(),
+        ]);
+        proto.debug_local_vars.list.push(BDebugLocal {
+            local: "x".to_owned(),
+            scope_start: 2,
+            scope_end: 4,
+        });
+        remove_instructions(&mut proto, &[false, false, true, true, false]);
+        assert_eq!(proto.instructions.list, [jmp(1), loadk(0, 0), ret()]);
+        //So do the scopes of locals
+        let local = &proto.debug_local_vars.list[0];
+        assert_eq!((local.scope_start, local.scope_end), (2, 2));
+    }
+
+    #[test]
+    fn the_jump_a_test_skips_is_kept() {
+        let tests = [
+            BInstruction::abc(31, 0, 0, 256),
+            BInstruction::abc(32, 1, 0, 256),
+            BInstruction::abc(33, 1, 256, 0),
+            BInstruction::abc(34, 0, 0, 1),
+            BInstruction::abc(35, 1, 0, 0),
+        ];
+        for test in tests {
+            //Only the jump to the next instruction which nothing skips goes
+            let mut proto = chunk(vec![loadk(0, 0), jmp(0), test, jmp(0), ret()]);
+            optimize(&mut proto);
+            assert_eq!(
+                proto.instructions.list,
+                [loadk(0, 0), test, jmp(0), ret()],
+                "{:?}",
+                test
+            );
+        }
+    }
+
+    #[test]
+    fn jump_chains_are_threaded_to_where_they_end() {
+        let mut proto = chunk(vec![
+            jmp(1),
+            loadk(0, 0),
+            jmp(1),
+            loadk(1, 0),
+            jmp(0),
+            ret(),
+        ]);
+        assert_eq!(thread_jumps(&mut proto), 2);
+        assert_eq!(
+            proto.instructions.list,
+            [jmp(4), loadk(0, 0), jmp(2), loadk(1, 0), jmp(0), ret()]
+        );
+
+        //A jump which closes upvalues is where the chain ends
+        let close = BInstruction::asbx(JMP, 1, 0);
+        let mut proto = chunk(vec![jmp(0), close, ret()]);
+        assert_eq!(thread_jumps(&mut proto), 0);
+
+        //and so is a jump which was already seen, in a loop of jumps
+        let mut proto = chunk(vec![jmp(0), jmp(-2), ret()]);
+        thread_jumps(&mut proto);
+        assert_eq!(proto.instructions.list.len(), 3);
+    }
+
+    #[test]
+    fn constants_are_compacted_and_operands_remapped() {
+        let x = StringTable::default().intern("x");
+        let mut proto = BProto::from_parts(
+            vec![
+                loadk(0, 1),
+                BInstruction::abc(13, 0, BITRK as u16 | 2, BITRK as u16 | 5),
+                BInstruction::abc(8, 0, BITRK as u16 | 4, 1),
+                BInstruction::abc(6, 1, 0, BITRK as u16 | 6),
+                BInstruction::abc(7, 1, 0, 3),
+                ret(),
+            ],
+            vec![
+                LPrimitive::BOOL(true),
+                LPrimitive::STRING(x.clone()),
+                LPrimitive::INT(1),
+                LPrimitive::NIL,
+                LPrimitive::STRING(x.clone()),
+                LPrimitive::FLOAT(1.0),
+                LPrimitive::INT(1),
+            ],
+        );
+        compact_constants(&mut proto);
+
+        //Unused ones are dropped and duplicates merged, but 1 and 1.0 stay apart
+        let constants: Vec<String> = proto
+            .constants
+            .list
+            .iter()
+            .map(|k| format!("{:?}", k))
+            .collect();
+        assert_eq!(
+            constants,
+            [
+                format!("{:?}", LPrimitive::STRING(x.clone())),
+                format!("{:?}", LPrimitive::INT(1)),
+                format!("{:?}", LPrimitive::FLOAT(1.0)),
+            ]
+        );
+        //Only operands with BITRK set are constants
+        assert_eq!(
+            proto.instructions.list,
+            [
+                loadk(0, 0),
+                BInstruction::abc(13, 0, BITRK as u16 | 1, BITRK as u16 | 2),
+                BInstruction::abc(8, 0, BITRK as u16, 1),
+                BInstruction::abc(6, 1, 0, BITRK as u16 | 1),
+                BInstruction::abc(7, 1, 0, 3),
+                ret(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::{
+    bytecode::bproto::BProto,
+    interpreter::{
+        callinfo::CallStack,
+        hook::{HookEvent, MASK_COUNT, MASK_LINE},
+    },
+};
+
+/// Line events count executions of lines, count events come before every
+/// instruction to see which way conditional instructions went
+pub const COVERAGE_MASK: u8 = MASK_LINE | MASK_COUNT;
+
+/// Branch taken when the jump after a conditional instruction executes,
+/// and when it's skipped
+const BRANCH_JUMP: usize = 0;
+const BRANCH_SKIP: usize = 1;
+
+/// A conditional instruction, by the line it's on and its number among the
+/// conditionals of that line in the proto tree, which is the same in every
+/// run so reports can be merged
+#[derive(Debug, Clone, Copy)]
+struct Conditional {
+    line: i64,
+    block: usize,
+}
+
+/// Counts of a source file, as in a record of an lcov file
+#[derive(Debug, Default)]
+struct FileCoverage {
+    lines: BTreeMap<i64, u64>,
+    /// Times each branch of each block was taken, by line, block and
+    /// branch. None if the block never executed
+    branches: BTreeMap<(i64, usize, usize), Option<u64>>,
+}
+
+/// Line and branch coverage recorded from the hook of the interpreter.
+/// Sources are keyed by the source name of their protos
+#[derive(Default)]
+pub struct Coverage {
+    files: BTreeMap<String, FileCoverage>,
+    /// Source of each proto, by address
+    sources: HashMap<usize, String>,
+    /// Conditional instructions, by proto address and pc
+    conditionals: HashMap<(usize, usize), Conditional>,
+    /// Conditional instruction each frame executed last, by depth, until
+    /// the frame executes its next instruction
+    pending: Vec<Option<(usize, usize)>>,
+}
+impl Coverage {
+    /// Every line with code in the protos of `top` starts out not executed
+    pub fn new(top: &BProto) -> Self {
+        let mut coverage = Self::default();
+        let mut blocks = HashMap::new();
+        coverage.add_proto(top, &mut blocks);
+        coverage
+    }
+
+    fn add_proto(&mut self, proto: &BProto, blocks: &mut HashMap<(String, i64), usize>) {
+        let source = source_key(proto);
+        let address = proto as *const BProto as usize;
+        self.sources.insert(address, source.clone());
+
+        let file = self.files.entry(source.clone()).or_default();
+        for (pc, instruction) in proto.instructions.list.iter().enumerate() {
+            let Some(line) = instruction.line() else {
+                continue;
+            };
+            file.lines.entry(line).or_insert(0);
+
+            //EQ, LT, LE and TEST
+            if matches!(instruction.opcode(), 31..=34) {
+                let next = blocks.entry((source.clone(), line)).or_insert(0);
+                let block = *next;
+                *next += 1;
+                self.conditionals
+                    .insert((address, pc), Conditional { line, block });
+                for branch in [BRANCH_JUMP, BRANCH_SKIP] {
+                    file.branches.insert((line, block, branch), None);
+                }
+            }
+        }
+
+        for child in &proto.protos.list {
+            self.add_proto(child, blocks);
+        }
+    }
+
+    /// Called by the hook with the innermost frame being the one executing
+    pub fn event(&mut self, event: HookEvent, call_stack: &CallStack) {
+        let Some(frame) = call_stack.last() else {
+            return;
+        };
+        let Some(proto) = frame.proto else {
+            return;
+        };
+        let address = proto as *const BProto as usize;
+
+        match event {
+            HookEvent::Line(line) => {
+                if let Some(file) = self.files.get_mut(&self.sources[&address]) {
+                    *file.lines.entry(line).or_insert(0) += 1;
+                }
+            }
+            HookEvent::Count => {
+                let depth = call_stack.len();
+                if self.pending.len() < depth {
+                    self.pending.resize(depth, None);
+                }
+
+                //The instruction after a conditional is a jump, which it skips
+                //or not. Frames start without one, errors may leave them behind
+                let pending = self.pending[depth - 1].take();
+                if let Some((conditional, pc)) = pending.filter(|&(at, _)| at == address) {
+                    let branch = match frame.pc {
+                        next if next == pc + 1 => Some(BRANCH_JUMP),
+                        next if next == pc + 2 => Some(BRANCH_SKIP),
+                        _ => None,
+                    };
+                    if let Some(branch) = branch {
+                        self.take_branch(conditional, pc, branch);
+                    }
+                }
+
+                if self.conditionals.contains_key(&(address, frame.pc)) {
+                    self.pending[depth - 1] = Some((address, frame.pc));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn take_branch(&mut self, address: usize, pc: usize, branch: usize) {
+        let Conditional { line, block } = self.conditionals[&(address, pc)];
+        let Some(file) = self.files.get_mut(&self.sources[&address]) else {
+            return;
+        };
+        //Both branches of a block which executed have a count
+        for other in [BRANCH_JUMP, BRANCH_SKIP] {
+            file.branches
+                .entry((line, block, other))
+                .or_default()
+                .get_or_insert(0);
+        }
+        if let Some(Some(taken)) = file.branches.get_mut(&(line, block, branch)) {
+            *taken += 1;
+        }
+    }
+
+    /// Adds the counts of an lcov file written by an earlier run, so that
+    /// coverage accumulates across runs
+    pub fn merge_file(&mut self, path: &Path) -> Result<(), anyhow::Error> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut file = None;
+        for line in text.lines() {
+            let (tag, value) = line.split_once(':').unwrap_or((line, ""));
+            let fields: Vec<&str> = value.split(',').collect();
+            match (tag, fields.as_slice()) {
+                ("SF", _) => file = Some(self.files.entry(value.to_owned()).or_default()),
+                ("DA", [line, count, ..]) => {
+                    let file = file.as_mut().ok_or_else(|| malformed(path))?;
+                    *file.lines.entry(line.parse()?).or_insert(0) += count.parse::<u64>()?;
+                }
+                ("BRDA", [line, block, branch, taken]) => {
+                    let file = file.as_mut().ok_or_else(|| malformed(path))?;
+                    let entry = file
+                        .branches
+                        .entry((line.parse()?, block.parse()?, branch.parse()?))
+                        .or_default();
+                    if *taken != "-" {
+                        *entry.get_or_insert(0) += taken.parse::<u64>()?;
+                    }
+                }
+                ("end_of_record", _) => file = None,
+                //Totals are recomputed when writing
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes an lcov tracefile with a record per source
+    pub fn write_lcov(&self, output: &mut impl Write) -> io::Result<()> {
+        writeln!(output, "TN:")?;
+        for (source, file) in &self.files {
+            writeln!(output, "SF:{}", source)?;
+
+            for (&(line, block, branch), taken) in &file.branches {
+                match taken {
+                    Some(taken) => {
+                        writeln!(output, "BRDA:{},{},{},{}", line, block, branch, taken)?
+                    }
+                    None => writeln!(output, "BRDA:{},{},{},-", line, block, branch)?,
+                }
+            }
+            let hit = file
+                .branches
+                .values()
+                .filter(|taken| taken.is_some_and(|taken| taken > 0))
+                .count();
+            writeln!(output, "BRF:{}", file.branches.len())?;
+            writeln!(output, "BRH:{}", hit)?;
+
+            for (line, count) in &file.lines {
+                writeln!(output, "DA:{},{}", line, count)?;
+            }
+            let hit = file.lines.values().filter(|&&count| count > 0).count();
+            writeln!(output, "LF:{}", file.lines.len())?;
+            writeln!(output, "LH:{}", hit)?;
+            writeln!(output, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+/// Path of the source for files, the source name as it is otherwise
+fn source_key(proto: &BProto) -> String {
+    match proto.source_name.as_deref() {
+        Some(source) => source.strip_prefix('@').unwrap_or(source).to_owned(),
+        None => "?".to_owned(),
+    }
+}
+
+fn malformed(path: &Path) -> anyhow::Error {
+    anyhow::anyhow!("malformed lcov file '{}'", path.display())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, env, process, rc::Rc};
+
+    use super::*;
+    use crate::{
+        bytecode::load_fixture,
+        interpreter::{Interpreter, Status},
+        lstring::StringTable,
+    };
+
+    /// Runs the script again under a recorder of its own
+    fn record(interpreter: &mut Interpreter, top: &BProto) -> Coverage {
+        let recorder = Rc::new(RefCell::new(Coverage::new(top)));
+        let hook = recorder.clone();
+        interpreter.set_hook(
+            Some(Box::new(move |event, _, call_stack| {
+                hook.borrow_mut().event(event, call_stack);
+                Ok(())
+            })),
+            COVERAGE_MASK,
+            1,
+        );
+        assert!(matches!(interpreter.interpret(), Ok(Status::Finished)));
+        interpreter.set_hook(None, 0, 0);
+        Rc::try_unwrap(recorder).ok().unwrap().into_inner()
+    }
+
+    #[test]
+    fn merging_an_earlier_run_adds_its_counts() {
+        let mut strings = StringTable::default();
+        let top = load_fixture("coverage", &mut strings);
+        let mut interpreter = Interpreter::new(&top, strings);
+        let path = env::temp_dir().join(format!("coverage-{}.info", process::id()));
+
+        let first = record(&mut interpreter, &top);
+        first
+            .write_lcov(&mut fs::File::create(&path).unwrap())
+            .unwrap();
+        let earlier = fs::read_to_string(&path).unwrap();
+        let mut second = record(&mut interpreter, &top);
+        second.merge_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        interpreter.close();
+
+        //The first run only took one branch of each conditional
+        assert!(earlier.contains("BRDA:3,0,0,0\nBRDA:3,0,1,1\n"));
+        assert!(earlier.contains("BRH:2\n"));
+        let mut lcov = Vec::new();
+        second.write_lcov(&mut lcov).unwrap();
+        let lcov = String::from_utf8(lcov).unwrap();
+        assert_eq!(
+            lcov.lines().collect::<Vec<_>>(),
+            [
+                "TN:",
+                "SF:fixtures/coverage.lua",
+                "BRDA:3,0,0,1",
+                "BRDA:3,0,1,1",
+                "BRDA:9,0,0,1",
+                "BRDA:9,0,1,1",
+                "BRF:4",
+                "BRH:4",
+                "DA:3,2",
+                "DA:4,1",
+                "DA:6,1",
+                "DA:7,2",
+                "DA:9,2",
+                "DA:10,1",
+                "DA:11,1",
+                "DA:13,1",
+                "DA:14,1",
+                "DA:15,2",
+                "LF:10",
+                "LH:10",
+                "end_of_record",
+            ]
+        );
+    }
+}
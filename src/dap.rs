@@ -0,0 +1,1015 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    mem,
+    path::{Path, PathBuf},
+    ptr,
+    rc::Rc,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    bytecode::{bproto::BProto, decode_bytecode, load_chunk},
+    debugger::{self, collect_protos, describe, function_description, DEBUGGER_MASK},
+    interpreter::{
+        callinfo::CallStack, error::LuaError, hook::HookEvent, interrupt::InterruptHandle, ldebug,
+        ltable::LTable, Interpreter, Stack,
+    },
+    lprimative::{LPrimitive, LValue},
+    lstring::StringTable,
+};
+
+/// The VM has no coroutines, so the main thread is the only one
+const THREAD_ID: i64 = 1;
+
+/// Debug Adapter Protocol server over stdio. It waits for a launch
+/// request, lets the client set breakpoints until configurationDone, then
+/// runs the chunk with a hook which stops it as the client asks
+pub fn run() -> Result<(), anyhow::Error> {
+    serve(Rc::new(Transport::stdio()))
+}
+
+fn serve(transport: Rc<Transport>) -> Result<(), anyhow::Error> {
+    let mut strings = StringTable::default();
+
+    //There's nothing to debug until there's a chunk
+    let (top, stop_on_entry) = loop {
+        let Ok(request) = transport.requests.recv() else {
+            return Ok(());
+        };
+        match request["command"].as_str() {
+            Some("initialize") => transport.respond(
+                &request,
+                json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsEvaluateForHovers": true,
+                }),
+            ),
+            Some("launch") => {
+                //Without a chunk the input script is dumped as usual
+                let arguments = &request["arguments"];
+                let top = match arguments["chunk"].as_str() {
+                    Some(chunk) => load_chunk(Path::new(chunk), &mut strings),
+                    None => decode_bytecode(&mut strings),
+                };
+                match top {
+                    Ok(top) => {
+                        transport.respond(&request, json!({}));
+                        transport.event("initialized", json!({}));
+                        break (top, arguments["stopOnEntry"].as_bool().unwrap_or(false));
+                    }
+                    Err(e) => transport.fail(&request, &format!("cannot load the chunk: {}", e)),
+                }
+            }
+            Some("disconnect" | "terminate") => {
+                transport.respond(&request, json!({}));
+                return Ok(());
+            }
+            _ => transport.fail(&request, "the script hasn't been launched"),
+        }
+    };
+
+    let mut interpreter = Interpreter::new(&top, strings);
+    let session = Rc::new(RefCell::new(Session::new(
+        transport.clone(),
+        &top,
+        stop_on_entry,
+        interpreter.interrupt_handle(),
+    )));
+    if !session.borrow_mut().configure() {
+        return Ok(());
+    }
+
+    interpreter.set_output(Box::new(Output {
+        transport: transport.clone(),
+        line: Vec::new(),
+    }));
+    let hook_session = session.clone();
+    interpreter.set_hook(
+        Some(Box::new(move |event, stack, call_stack| {
+            hook_session.borrow_mut().event(event, stack, call_stack)
+        })),
+        DEBUGGER_MASK,
+        1,
+    );
+
+    let result = interpreter.interpret();
+    if session.borrow().disconnected {
+        interpreter.close();
+        return Ok(());
+    }
+    if let Err(e) = &result {
+        let output = match e.traceback() {
+            "" => format!("lua: {}\n", e),
+            traceback => format!("lua: {}\n{}\n", e, traceback),
+        };
+        transport.event("output", json!({"category": "stderr", "output": output}));
+    }
+    interpreter.close();
+
+    transport.event("exited", json!({"exitCode": i32::from(result.is_err())}));
+    transport.event("terminated", json!({}));
+    session.borrow_mut().finish();
+    Ok(())
+}
+
+/// Messages framed by a Content-Length header. Requests are read on a
+/// thread of their own so they can be looked at while the script runs
+struct Transport {
+    requests: Receiver<Value>,
+    output: RefCell<Box<dyn Write>>,
+    seq: Cell<i64>,
+}
+impl Transport {
+    fn new(mut input: impl BufRead + Send + 'static, output: impl Write + 'static) -> Self {
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            while let Some(message) = read_message(&mut input) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            requests,
+            output: RefCell::new(Box::new(output)),
+            seq: Cell::new(1),
+        }
+    }
+
+    fn stdio() -> Self {
+        Self::new(BufReader::new(io::stdin()), io::stdout())
+    }
+
+    fn send(&self, mut message: Value) {
+        message["seq"] = self.seq.replace(self.seq.get() + 1).into();
+        let body = message.to_string();
+        let mut output = self.output.borrow_mut();
+        let _ = write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = output.flush();
+    }
+
+    fn respond(&self, request: &Value, body: Value) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": true,
+            "command": request["command"],
+            "body": body,
+        }));
+    }
+
+    fn fail(&self, request: &Value, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": false,
+            "command": request["command"],
+            "message": message,
+        }));
+    }
+
+    fn event(&self, event: &str, body: Value) {
+        self.send(json!({"type": "event", "event": event, "body": body}));
+    }
+}
+
+/// Reads a message, None at the end of the input or if it's malformed
+fn read_message(input: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        match line.trim_end().split_once(':') {
+            Some((name, value)) if name.eq_ignore_ascii_case("Content-Length") => {
+                length = value.trim().parse().ok();
+            }
+            //Blank lines before the headers are skipped
+            None if line.trim_end().is_empty() && length.is_some() => break,
+            _ => {}
+        }
+    }
+
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+/// Sends what `print` writes to the client as output events, a line at a
+/// time
+struct Output {
+    transport: Rc<Transport>,
+    line: Vec<u8>,
+}
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        if let Some(end) = self.line.iter().rposition(|&b| b == b'\n') {
+            let text: Vec<u8> = self.line.drain(..=end).collect();
+            self.transport.event(
+                "output",
+                json!({"category": "stdout", "output": String::from_utf8_lossy(&text)}),
+            );
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.line.is_empty() {
+            let text = mem::take(&mut self.line);
+            self.transport.event(
+                "output",
+                json!({"category": "stdout", "output": String::from_utf8_lossy(&text)}),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// What to run until before stopping again
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    /// Continuing or stepping, as in the command line debugger
+    Run(debugger::Mode),
+    /// Stops at the first line, for stopOnEntry
+    Entry,
+    /// Stops before the next instruction
+    Pause,
+}
+
+/// What to do once a request has been handled
+enum Flow {
+    Stay,
+    Resume,
+    Disconnect,
+}
+
+struct Breakpoint<'i> {
+    id: i64,
+    proto: &'i BProto,
+    pc: usize,
+}
+
+/// What a variablesReference handed to the client refers to
+#[derive(Clone)]
+enum Reference<'i> {
+    /// Index of the frame in the call stack
+    Locals(usize),
+    Upvalues(usize),
+    Globals,
+    Table(Rc<RefCell<LTable<'i>>>),
+}
+
+struct Session<'i> {
+    transport: Rc<Transport>,
+    top: &'i BProto,
+    /// As set by the last setBreakpoints for each source
+    breakpoints: HashMap<PathBuf, Vec<Breakpoint<'i>>>,
+    next_id: i64,
+    mode: Mode,
+    /// Set when a breakpoint stopped at an instruction, so the line event
+    /// of the same instruction doesn't stop there again
+    stopped: bool,
+    /// Requests which can only be handled while stopped, and every request
+    /// after them so they're handled in order
+    pending: VecDeque<Value>,
+    /// Only valid until execution resumes, reference n is at n - 1
+    references: Vec<Reference<'i>>,
+    interrupt: InterruptHandle,
+    /// Set once the client disconnected while the script runs, which stops it
+    disconnected: bool,
+}
+impl<'i> Session<'i> {
+    fn new(
+        transport: Rc<Transport>,
+        top: &'i BProto,
+        stop_on_entry: bool,
+        interrupt: InterruptHandle,
+    ) -> Self {
+        Self {
+            transport,
+            top,
+            breakpoints: HashMap::new(),
+            next_id: 1,
+            mode: match stop_on_entry {
+                true => Mode::Entry,
+                false => Mode::Run(debugger::Mode::Continue),
+            },
+            stopped: false,
+            pending: VecDeque::new(),
+            references: Vec::new(),
+            interrupt,
+            disconnected: false,
+        }
+    }
+
+    /// Handles requests until configurationDone. False if the client
+    /// disconnected instead
+    fn configure(&mut self) -> bool {
+        while let Ok(request) = self.transport.requests.recv() {
+            if request["command"] == "configurationDone" {
+                self.transport.respond(&request, json!({}));
+                return true;
+            }
+            if let Flow::Disconnect = self.handle(request, None) {
+                return false;
+            }
+        }
+        false
+    }
+
+    /// Handles requests once the script has finished, until the client
+    /// disconnects
+    fn finish(&mut self) {
+        let mut pending = mem::take(&mut self.pending);
+        while let Some(request) = pending
+            .pop_front()
+            .or_else(|| self.transport.requests.recv().ok())
+        {
+            if let Flow::Disconnect = self.handle(request, None) {
+                return;
+            }
+        }
+    }
+
+    /// Called by the hook with the innermost frame being the one executing
+    fn event(
+        &mut self,
+        event: HookEvent,
+        stack: &Stack<'i>,
+        call_stack: &CallStack<'i>,
+    ) -> Result<(), LuaError> {
+        if self.disconnected {
+            return Ok(());
+        }
+        let depth = call_stack.len();
+        let stop = match event {
+            HookEvent::Count => {
+                self.stopped = false;
+                self.poll();
+                let hit = self.hit(call_stack);
+                match self.mode {
+                    Mode::Pause => Some(("pause", hit)),
+                    _ if !hit.is_empty() => Some(("breakpoint", hit)),
+                    _ => None,
+                }
+            }
+            HookEvent::Line(_) if mem::take(&mut self.stopped) => None,
+            HookEvent::Line(_) => match self.mode {
+                Mode::Entry => Some(("entry", Vec::new())),
+                Mode::Run(mode) if mode.stops(depth) => Some(("step", Vec::new())),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        if let Some((reason, hit)) = stop {
+            self.stopped = event == HookEvent::Count;
+            self.stop(reason, hit, stack, call_stack);
+        }
+        Ok(())
+    }
+
+    /// Handles the requests which arrived while the script runs, unless
+    /// they have to wait for it to stop
+    fn poll(&mut self) {
+        while self.pending.is_empty() {
+            let Ok(request) = self.transport.requests.try_recv() else {
+                return;
+            };
+            match request["command"].as_str() {
+                Some("threads" | "setBreakpoints" | "pause" | "disconnect" | "terminate") => {
+                    if let Flow::Disconnect = self.handle(request, None) {
+                        self.disconnect();
+                        return;
+                    }
+                }
+                _ => self.pending.push_back(request),
+            }
+        }
+    }
+
+    /// Ids of the breakpoints at the instruction about to be executed
+    fn hit(&self, call_stack: &CallStack<'i>) -> Vec<i64> {
+        let Some((frame, proto)) = call_stack
+            .last()
+            .and_then(|frame| Some((frame, frame.proto?)))
+        else {
+            return Vec::new();
+        };
+        self.breakpoints
+            .values()
+            .flatten()
+            .filter(|breakpoint| ptr::eq(breakpoint.proto, proto) && breakpoint.pc == frame.pc)
+            .map(|breakpoint| breakpoint.id)
+            .collect()
+    }
+
+    /// Tells the client execution stopped and handles its requests until
+    /// one resumes it
+    fn stop(&mut self, reason: &str, hit: Vec<i64>, stack: &Stack<'i>, call_stack: &CallStack<'i>) {
+        self.transport.event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+                "hitBreakpointIds": hit,
+            }),
+        );
+        self.mode = Mode::Run(debugger::Mode::Continue);
+
+        loop {
+            let request = match self.pending.pop_front() {
+                Some(request) => request,
+                None => match self.transport.requests.recv() {
+                    Ok(request) => request,
+                    //Running to the end once the client is gone
+                    Err(_) => {
+                        self.breakpoints.clear();
+                        return;
+                    }
+                },
+            };
+            match self.handle(request, Some((stack, call_stack))) {
+                Flow::Stay => {}
+                Flow::Resume => break,
+                Flow::Disconnect => {
+                    self.disconnect();
+                    break;
+                }
+            }
+        }
+        self.references.clear();
+    }
+
+    /// Interrupts the script, without stopping at anything else, once the
+    /// client is gone
+    fn disconnect(&mut self) {
+        self.disconnected = true;
+        self.breakpoints.clear();
+        self.mode = Mode::Run(debugger::Mode::Continue);
+        self.interrupt.interrupt();
+    }
+
+    /// Handles a request, with the frames of the script if it's stopped
+    fn handle(&mut self, request: Value, frames: Option<(&Stack<'i>, &CallStack<'i>)>) -> Flow {
+        let arguments = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or_default();
+        let body = match (command, frames) {
+            ("threads", _) => Ok(json!({"threads": [{"id": THREAD_ID, "name": "main"}]})),
+            ("setBreakpoints", _) => Ok(self.set_breakpoints(arguments)),
+            ("configurationDone", _) => Ok(json!({})),
+            ("pause", _) => {
+                self.mode = Mode::Pause;
+                Ok(json!({}))
+            }
+            ("disconnect" | "terminate", _) => {
+                self.transport.respond(&request, json!({}));
+                return Flow::Disconnect;
+            }
+            ("stackTrace", Some((_, call_stack))) => Ok(stack_trace(arguments, call_stack)),
+            ("scopes", Some((_, call_stack))) => self.scopes(arguments, call_stack),
+            ("variables", Some((stack, call_stack))) => {
+                self.variables(arguments, stack, call_stack)
+            }
+            ("evaluate", Some((stack, call_stack))) => self.evaluate(arguments, stack, call_stack),
+            ("continue" | "next" | "stepIn" | "stepOut", Some((_, call_stack))) => {
+                let depth = call_stack.len();
+                self.mode = Mode::Run(match command {
+                    "next" => debugger::Mode::Next(depth),
+                    "stepIn" => debugger::Mode::Step,
+                    "stepOut" => debugger::Mode::Finish(depth),
+                    _ => debugger::Mode::Continue,
+                });
+                self.transport
+                    .respond(&request, json!({"allThreadsContinued": true}));
+                return Flow::Resume;
+            }
+            (
+                "stackTrace" | "scopes" | "variables" | "evaluate" | "continue" | "next" | "stepIn"
+                | "stepOut",
+                None,
+            ) => Err("the script isn't stopped".to_owned()),
+            (command, _) => Err(format!("unsupported request '{}'", command)),
+        };
+
+        match body {
+            Ok(body) => self.transport.respond(&request, body),
+            Err(message) => self.transport.fail(&request, &message),
+        }
+        Flow::Stay
+    }
+
+    /// Maps each line to the first instruction of the first line with code
+    /// at or after it, in every proto of the source with code on that line
+    fn set_breakpoints(&mut self, arguments: &Value) -> Value {
+        let path = resolve_path(arguments["source"]["path"].as_str().unwrap_or_default());
+        let mut protos = Vec::new();
+        collect_protos(self.top, &mut protos);
+        protos.retain(|proto| source_path(proto).as_ref() == Some(&path));
+
+        let mut breakpoints = Vec::new();
+        let mut verified = Vec::new();
+        let requested = arguments["breakpoints"].as_array().into_iter().flatten();
+        for line in requested.filter_map(|breakpoint| breakpoint["line"].as_i64()) {
+            let code = protos
+                .iter()
+                .flat_map(|proto| proto.instructions.list.iter())
+                .filter_map(|instruction| instruction.line())
+                .filter(|&code| code >= line)
+                .min();
+            let Some(code) = code else {
+                verified.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "no code at or after this line",
+                }));
+                continue;
+            };
+
+            let id = self.next_id;
+            self.next_id += 1;
+            for &proto in &protos {
+                let first = proto
+                    .instructions
+                    .list
+                    .iter()
+                    .position(|instruction| instruction.line() == Some(code));
+                if let Some(pc) = first {
+                    breakpoints.push(Breakpoint { id, proto, pc });
+                }
+            }
+            verified.push(json!({"id": id, "verified": true, "line": code}));
+        }
+
+        self.breakpoints.insert(path, breakpoints);
+        json!({"breakpoints": verified})
+    }
+
+    fn scopes(&mut self, arguments: &Value, call_stack: &CallStack<'i>) -> Result<Value, String> {
+        let i = frame_index(arguments, call_stack)?;
+        let locals = self.reference(Reference::Locals(i));
+        let upvalues = self.reference(Reference::Upvalues(i));
+        let globals = self.reference(Reference::Globals);
+        Ok(json!({"scopes": [
+            {"name": "Locals", "presentationHint": "locals", "variablesReference": locals, "expensive": false},
+            {"name": "Upvalues", "variablesReference": upvalues, "expensive": false},
+            {"name": "Globals", "variablesReference": globals, "expensive": true},
+        ]}))
+    }
+
+    fn variables(
+        &mut self,
+        arguments: &Value,
+        stack: &Stack<'i>,
+        call_stack: &CallStack<'i>,
+    ) -> Result<Value, String> {
+        let reference = arguments["variablesReference"]
+            .as_u64()
+            .and_then(|n| {
+                self.references
+                    .get(usize::try_from(n).ok()?.checked_sub(1)?)
+            })
+            .cloned()
+            .ok_or("invalid variablesReference")?;
+
+        let values = match reference {
+            Reference::Locals(i) => locals(stack, call_stack, i),
+            Reference::Upvalues(i) => upvalues(stack, call_stack, i),
+            Reference::Globals => match globals(stack, call_stack) {
+                Some(globals) => fields(&globals.borrow()),
+                None => Vec::new(),
+            },
+            Reference::Table(table) => fields(&table.borrow()),
+        };
+        let variables: Vec<Value> = values
+            .into_iter()
+            .map(|(name, value)| {
+                json!({
+                    "name": name,
+                    "value": describe(&value),
+                    "type": value.type_name(),
+                    "variablesReference": self.value_reference(&value),
+                })
+            })
+            .collect();
+        Ok(json!({"variables": variables}))
+    }
+
+    /// Evaluates a variable followed by fields and indices, "t.x[1]", as
+    /// seen from a frame. Tables are indexed without metamethods so that
+    /// evaluating can't run code
+    fn evaluate(
+        &mut self,
+        arguments: &Value,
+        stack: &Stack<'i>,
+        call_stack: &CallStack<'i>,
+    ) -> Result<Value, String> {
+        let expression = arguments["expression"].as_str().unwrap_or_default().trim();
+        let i = match arguments["frameId"].is_null() {
+            true => call_stack.len().checked_sub(1).ok_or("no frames")?,
+            false => frame_index(arguments, call_stack)?,
+        };
+        let value = evaluate_path(expression, stack, call_stack, i)
+            .ok_or_else(|| format!("cannot evaluate '{}'", expression))?;
+        Ok(json!({
+            "result": describe(&value),
+            "type": value.type_name(),
+            "variablesReference": self.value_reference(&value),
+        }))
+    }
+
+    fn reference(&mut self, reference: Reference<'i>) -> usize {
+        self.references.push(reference);
+        self.references.len()
+    }
+
+    /// Tables can be expanded, other values have no reference
+    fn value_reference(&mut self, value: &LValue<'i>) -> usize {
+        match value {
+            LValue::Table(table) => self.reference(Reference::Table(table.clone())),
+            _ => 0,
+        }
+    }
+}
+
+/// Frame ids are call stack indices plus one
+fn frame_index(arguments: &Value, call_stack: &CallStack) -> Result<usize, String> {
+    arguments["frameId"]
+        .as_u64()
+        .and_then(|id| usize::try_from(id).ok()?.checked_sub(1))
+        .filter(|&i| i < call_stack.len())
+        .ok_or_else(|| "invalid frameId".to_owned())
+}
+
+fn stack_trace(arguments: &Value, call_stack: &CallStack) -> Value {
+    let start = arguments["startFrame"].as_u64().unwrap_or(0) as usize;
+    let levels = match arguments["levels"].as_u64().unwrap_or(0) as usize {
+        0 => call_stack.len(),
+        levels => levels,
+    };
+
+    let frames: Vec<Value> = (0..call_stack.len())
+        .rev()
+        .skip(start)
+        .take(levels)
+        .map(|i| {
+            let frame = &call_stack[i];
+            let mut value = json!({
+                "id": i + 1,
+                "name": function_description(call_stack, i),
+                "line": frame.current_line().unwrap_or(0),
+                "column": 0,
+            });
+            if let Some(proto) = frame.proto {
+                value["column"] = 1.into();
+                value["source"] = match source_path(proto) {
+                    Some(path) => json!({"name": proto.short_src(), "path": path}),
+                    None => json!({"name": proto.short_src()}),
+                };
+            }
+            value
+        })
+        .collect();
+    json!({"stackFrames": frames, "totalFrames": call_stack.len()})
+}
+
+/// Path of the file a proto was loaded from, None for string chunks
+fn source_path(proto: &BProto) -> Option<PathBuf> {
+    let path = proto.source_name.as_deref()?.strip_prefix('@')?;
+    Some(resolve_path(path))
+}
+
+/// Chunks are dumped from the io directory, so relative names may be
+/// relative to it rather than to the current directory
+fn resolve_path(path: &str) -> PathBuf {
+    [Path::new(path).to_owned(), Path::new("io").join(path)]
+        .into_iter()
+        .find_map(|path| fs::canonicalize(path).ok())
+        .unwrap_or_else(|| PathBuf::from(path))
+}
+
+/// Named locals of frame `i` in scope, then its varargs
+fn locals<'i>(
+    stack: &Stack<'i>,
+    call_stack: &CallStack<'i>,
+    i: usize,
+) -> Vec<(String, LValue<'i>)> {
+    let named = (1..)
+        .map_while(|n| ldebug::find_local(stack, call_stack, i, n))
+        .filter(|(name, _)| !name.starts_with('('));
+    let varargs = (1..).map_while(|n| ldebug::find_local(stack, call_stack, i, -n));
+    named
+        .chain(varargs)
+        .map(|(name, index)| (name.to_owned(), stack[index].clone()))
+        .collect()
+}
+
+fn upvalues<'i>(
+    stack: &Stack<'i>,
+    call_stack: &CallStack<'i>,
+    i: usize,
+) -> Vec<(String, LValue<'i>)> {
+    let frame = &call_stack[i];
+    let (Some(proto), LValue::LClosure(closure)) = (frame.proto, &stack[frame.func]) else {
+        return Vec::new();
+    };
+    closure
+        .upvalues
+        .iter()
+        .enumerate()
+        .map(|(n, upvalue)| {
+            let name = proto.upvalue_name(n).unwrap_or("?").to_owned();
+            (name, upvalue.borrow().get(stack))
+        })
+        .collect()
+}
+
+/// The main chunk's only upvalue is _ENV, which starts out as the globals
+fn globals<'i>(stack: &Stack<'i>, call_stack: &CallStack<'i>) -> Option<Rc<RefCell<LTable<'i>>>> {
+    let LValue::LClosure(main) = stack.get(call_stack.first()?.func)? else {
+        return None;
+    };
+    match main.upvalues.first()?.borrow().get(stack) {
+        LValue::Table(table) => Some(table),
+        _ => None,
+    }
+}
+
+/// Fields of a table, named like they'd be indexed in Lua
+fn fields<'i>(table: &LTable<'i>) -> Vec<(String, LValue<'i>)> {
+    let array = table
+        .array_values()
+        .enumerate()
+        .map(|(n, value)| (format!("[{}]", n + 1), value.clone()));
+    let hash = table.hash_entries().map(|(key, value)| {
+        let name = match key {
+            LValue::LPrimitive(LPrimitive::STRING(key)) => key.to_string(),
+            key => format!("[{}]", describe(key)),
+        };
+        (name, value.clone())
+    });
+    array.chain(hash).collect()
+}
+
+/// Resolves the name at the start of the path like the compiler would,
+/// as a local, then an upvalue, then a global, and follows the rest of it
+fn evaluate_path<'i>(
+    path: &str,
+    stack: &Stack<'i>,
+    call_stack: &CallStack<'i>,
+    i: usize,
+) -> Option<LValue<'i>> {
+    let end = path.find(['.', '[']).unwrap_or(path.len());
+    let (name, mut rest) = path.split_at(end);
+    let name = name.trim();
+
+    //Later locals shadow earlier ones with the same name
+    let local = locals(stack, call_stack, i)
+        .into_iter()
+        .rev()
+        .find(|(local, _)| local == name);
+    let mut value = match local {
+        Some((_, value)) => value,
+        None => match upvalues(stack, call_stack, i)
+            .into_iter()
+            .find(|(upvalue, _)| upvalue == name)
+        {
+            Some((_, value)) => value,
+            None => field(&globals(stack, call_stack)?.borrow(), name),
+        },
+    };
+
+    while !rest.is_empty() {
+        let LValue::Table(table) = value else {
+            return None;
+        };
+        let table = table.borrow();
+        value = if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            rest = &after[end..];
+            field(&table, after[..end].trim())
+        } else {
+            let (key, after) = rest.strip_prefix('[')?.split_once(']')?;
+            rest = after;
+            let key = key.trim();
+            match key.parse::<i64>() {
+                Ok(n) => table.get_int(n),
+                Err(_) => {
+                    let quoted = key
+                        .strip_prefix('"')
+                        .and_then(|key| key.strip_suffix('"'))
+                        .or_else(|| {
+                            key.strip_prefix('\'')
+                                .and_then(|key| key.strip_suffix('\''))
+                        });
+                    field(&table, quoted?)
+                }
+            }
+        };
+    }
+    Some(value)
+}
+
+/// Raw gets a string field. Short strings are only equal to their interned
+/// copy, so the keys are compared by contents rather than interning it
+fn field<'i>(table: &LTable<'i>, name: &str) -> LValue<'i> {
+    table
+        .hash_entries()
+        .find(|(key, _)| matches!(key, LValue::LPrimitive(LPrimitive::STRING(key)) if &***key == name))
+        .map(|(_, value)| value.clone())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::bytecode::optimize::Capture;
+
+    const SOURCE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/dap.lua");
+    const CHUNK: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/dap.luac");
+
+    /// Messages the server sends for a session of `requests`, which are
+    /// framed and numbered as a client would
+    fn session(requests: &[(&str, Value)]) -> Vec<Value> {
+        let mut input = Vec::new();
+        for (seq, (command, arguments)) in requests.iter().enumerate() {
+            let body = json!({
+                "seq": seq + 1,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            })
+            .to_string();
+            write!(input, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        }
+
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let transport = Transport::new(Cursor::new(input), Capture(output.clone()));
+        serve(Rc::new(transport)).unwrap();
+
+        let mut output = Cursor::new(output.take());
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut output) {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn response(messages: &[Value], seq: u64) -> &Value {
+        let response = messages
+            .iter()
+            .find(|message| message["type"] == "response" && message["request_seq"] == seq)
+            .unwrap_or_else(|| panic!("no response to request {}", seq));
+        assert_eq!(response["success"], true, "{}", response);
+        &response["body"]
+    }
+
+    fn events(messages: &[Value]) -> Vec<&Value> {
+        messages
+            .iter()
+            .filter(|message| message["type"] == "event")
+            .collect()
+    }
+
+    /// Id, line and name of each frame
+    fn frames(body: &Value) -> Vec<(u64, u64, &str)> {
+        body["stackFrames"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|frame| {
+                (
+                    frame["id"].as_u64().unwrap(),
+                    frame["line"].as_u64().unwrap(),
+                    frame["name"].as_str().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scripted_session() {
+        let messages = session(&[
+            ("initialize", json!({"adapterID": "lua"})),
+            ("launch", json!({"chunk": CHUNK})),
+            (
+                "setBreakpoints",
+                json!({"source": {"path": SOURCE}, "breakpoints": [{"line": 2}]}),
+            ),
+            ("configurationDone", json!({})),
+            ("stackTrace", json!({"threadId": THREAD_ID})),
+            ("scopes", json!({"frameId": 3})),
+            ("variables", json!({"variablesReference": 1})),
+            ("next", json!({"threadId": THREAD_ID})),
+            ("stackTrace", json!({"threadId": THREAD_ID, "levels": 1})),
+            ("evaluate", json!({"expression": "b", "frameId": 3})),
+            ("stepOut", json!({"threadId": THREAD_ID})),
+            ("stackTrace", json!({"threadId": THREAD_ID, "levels": 1})),
+            ("continue", json!({"threadId": THREAD_ID})),
+        ]);
+
+        assert_eq!(
+            response(&messages, 1)["supportsConfigurationDoneRequest"],
+            true
+        );
+        response(&messages, 2);
+        assert_eq!(
+            response(&messages, 3)["breakpoints"],
+            json!([{"id": 1, "line": 2, "verified": true}])
+        );
+        response(&messages, 4);
+
+        //Frame ids count from 1 at the bottom of the stack
+        assert_eq!(
+            frames(response(&messages, 5)),
+            [
+                (3, 2, "upvalue 'inner'"),
+                (2, 7, "local 'outer'"),
+                (1, 11, "main chunk"),
+            ]
+        );
+        let scopes = &response(&messages, 6)["scopes"];
+        assert_eq!(scopes[0]["name"], "Locals");
+        assert_eq!(scopes[0]["variablesReference"], 1);
+        assert_eq!(
+            response(&messages, 7)["variables"],
+            json!([{"name": "a", "type": "number", "value": "5", "variablesReference": 0}])
+        );
+
+        response(&messages, 8);
+        assert_eq!(frames(response(&messages, 9)), [(3, 3, "upvalue 'inner'")]);
+        assert_eq!(response(&messages, 10)["result"], "10");
+        response(&messages, 11);
+        assert_eq!(frames(response(&messages, 12)), [(2, 8, "local 'outer'")]);
+        response(&messages, 13);
+
+        let events = events(&messages);
+        let names: Vec<&str> = events
+            .iter()
+            .map(|event| event["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "initialized",
+                "stopped",
+                "stopped",
+                "stopped",
+                "output",
+                "exited",
+                "terminated",
+            ]
+        );
+        assert_eq!(events[1]["body"]["reason"], "breakpoint");
+        assert_eq!(events[1]["body"]["hitBreakpointIds"], json!([1]));
+        assert_eq!(events[2]["body"]["reason"], "step");
+        assert_eq!(events[3]["body"]["reason"], "step");
+        assert_eq!(events[4]["body"]["output"], " >> PRINT >> 10 \n");
+        assert_eq!(events[5]["body"]["exitCode"], 0);
+
+        //Messages are numbered in the order they're sent
+        let seqs: Vec<u64> = messages
+            .iter()
+            .map(|message| message["seq"].as_u64().unwrap())
+            .collect();
+        assert_eq!(seqs, (1..=messages.len() as u64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn disconnecting_while_stopped_ends_the_session() {
+        let messages = session(&[
+            ("initialize", json!({})),
+            ("launch", json!({"chunk": CHUNK})),
+            (
+                "setBreakpoints",
+                json!({"source": {"path": SOURCE}, "breakpoints": [{"line": 2}]}),
+            ),
+            ("configurationDone", json!({})),
+            //Waits for the script to stop, so the disconnect comes after
+            ("stackTrace", json!({"threadId": THREAD_ID})),
+            ("disconnect", json!({})),
+        ]);
+
+        assert_eq!(frames(response(&messages, 5)).len(), 3);
+        response(&messages, 6);
+        //The script is stopped without printing or reporting its exit
+        let events = events(&messages);
+        let names: Vec<&str> = events
+            .iter()
+            .map(|event| event["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["initialized", "stopped"]);
+        assert_eq!(messages.last().unwrap()["command"], "disconnect");
+    }
+}
@@ -0,0 +1,471 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, Write},
+    path::Path,
+    ptr,
+};
+
+use crate::{
+    bytecode::{binstruction::BInstruction, bopcode::OPNAMES, bproto::BProto},
+    interpreter::{
+        callinfo::CallStack,
+        error::LuaError,
+        hook::{HookEvent, MASK_COUNT, MASK_LINE},
+        ldebug, Stack,
+    },
+    lprimative::{LPrimitive, LValue},
+};
+
+/// Events the debugger hooks. Count events come before every instruction
+/// to check breakpoints, line events drive stepping
+pub const DEBUGGER_MASK: u8 = MASK_LINE | MASK_COUNT;
+
+const HELP: &str = "\
+break file:line    stop at the first instruction of a line
+break function     stop when a function with this name is called
+delete n           remove breakpoint n
+step               run until the next line, entering calls
+next               run until the next line of this function
+finish             run until this function returns
+continue           run until a breakpoint
+bt                 show the call stack
+locals             show the locals of this function
+upvalues           show the upvalues of this function
+print register     show the value of a register
+disasm             show the instructions of this function
+quit               exit without finishing the script";
+
+enum Breakpoint<'i> {
+    /// Mapped to the first instruction of the line in each proto with code
+    /// on it
+    Instruction {
+        proto: &'i BProto,
+        pc: usize,
+        location: String,
+    },
+    /// Matched against the name the function was called by
+    Function(String),
+}
+
+/// What to run until before stopping again, shared with the DAP server
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Mode {
+    Continue,
+    Step,
+    /// Stops at a line of a frame at most this deep
+    Next(usize),
+    /// Stops at a line of a frame less deep than this
+    Finish(usize),
+}
+impl Mode {
+    /// Whether a line reached `depth` frames deep is where to stop
+    pub(crate) fn stops(self, depth: usize) -> bool {
+        match self {
+            Mode::Continue => false,
+            Mode::Step => true,
+            Mode::Next(next) => depth <= next,
+            Mode::Finish(finish) => depth < finish,
+        }
+    }
+}
+
+/// Interactive debugger run from the hook of the interpreter. It reads
+/// commands from stdin whenever execution stops, which it does before the
+/// main chunk's first instruction
+pub struct Debugger<'i> {
+    top: &'i BProto,
+    breakpoints: Vec<Option<Breakpoint<'i>>>,
+    mode: Mode,
+    /// Set when a breakpoint stopped at an instruction, so the line event
+    /// of the same instruction doesn't stop there again
+    stopped: bool,
+    /// Lines of the source files, None for those which can't be read
+    sources: HashMap<String, Option<Vec<String>>>,
+}
+impl<'i> Debugger<'i> {
+    pub fn new(top: &'i BProto) -> Self {
+        println!("debugging, type help for the commands");
+        Self {
+            top,
+            breakpoints: Vec::new(),
+            mode: Mode::Step,
+            stopped: false,
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Called by the hook with the innermost frame being the one executing
+    pub fn event(
+        &mut self,
+        event: HookEvent,
+        stack: &Stack<'i>,
+        call_stack: &CallStack<'i>,
+    ) -> Result<(), LuaError> {
+        let depth = call_stack.len();
+        let stop = match event {
+            HookEvent::Count => {
+                self.stopped = false;
+                self.breakpoint(call_stack)
+                    .map(|n| format!("breakpoint {}", n))
+            }
+            HookEvent::Line(_) if std::mem::take(&mut self.stopped) => None,
+            HookEvent::Line(_) if self.mode.stops(depth) => match self.mode {
+                Mode::Next(_) => Some("next".to_owned()),
+                Mode::Finish(_) => Some("finish".to_owned()),
+                _ => Some("step".to_owned()),
+            },
+            _ => None,
+        };
+
+        if let Some(reason) = stop {
+            self.stopped = event == HookEvent::Count;
+            self.show_stop(&reason, call_stack);
+            self.prompt(stack, call_stack);
+        }
+        Ok(())
+    }
+
+    /// Number of the breakpoint at the instruction about to be executed
+    fn breakpoint(&self, call_stack: &CallStack<'i>) -> Option<usize> {
+        let frame = call_stack.last()?;
+        let proto = frame.proto?;
+        let called = match frame.pc {
+            0 => ldebug::func_name(call_stack, call_stack.len() - 1).map(|(_, name)| name),
+            _ => None,
+        };
+
+        self.breakpoints
+            .iter()
+            .enumerate()
+            .find_map(|(n, breakpoint)| {
+                let hit = match breakpoint.as_ref()? {
+                    Breakpoint::Instruction {
+                        proto: at,
+                        pc: at_pc,
+                        ..
+                    } => ptr::eq(*at, proto) && *at_pc == frame.pc,
+                    Breakpoint::Function(name) => called.as_ref() == Some(name),
+                };
+                hit.then_some(n + 1)
+            })
+    }
+
+    /// Reads and runs commands until one resumes execution
+    fn prompt(&mut self, stack: &Stack<'i>, call_stack: &CallStack<'i>) {
+        let stdin = io::stdin();
+        loop {
+            print!("(ldb) ");
+            let _ = io::stdout().flush();
+
+            let mut line = String::new();
+            //Running to the end once there's nothing left to read
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                println!();
+                self.breakpoints.clear();
+                self.mode = Mode::Continue;
+                return;
+            }
+
+            let mut words = line.split_whitespace();
+            let depth = call_stack.len();
+            match (words.next(), words.next()) {
+                (None, _) => {}
+                (Some("break" | "b"), Some(target)) => self.add_breakpoint(target),
+                (Some("delete" | "d"), Some(n)) => self.delete_breakpoint(n),
+                (Some("step" | "s"), None) => {
+                    self.mode = Mode::Step;
+                    return;
+                }
+                (Some("next" | "n"), None) => {
+                    self.mode = Mode::Next(depth);
+                    return;
+                }
+                (Some("finish" | "f"), None) => {
+                    self.mode = Mode::Finish(depth);
+                    return;
+                }
+                (Some("continue" | "c"), None) => {
+                    self.mode = Mode::Continue;
+                    return;
+                }
+                (Some("bt"), None) => backtrace(call_stack),
+                (Some("locals"), None) => locals(stack, call_stack),
+                (Some("upvalues"), None) => upvalues(stack, call_stack),
+                (Some("print" | "p"), Some(register)) => {
+                    print_register(register, stack, call_stack)
+                }
+                (Some("disasm"), None) => match call_stack.last() {
+                    Some(frame) => match frame.proto {
+                        Some(proto) => disassemble(proto, frame.pc),
+                        None => println!("not in a Lua function"),
+                    },
+                    None => disassemble(self.top, 0),
+                },
+                (Some("help" | "h"), None) => println!("{}", HELP),
+                (Some("quit" | "q"), None) => std::process::exit(0),
+                (Some(command), _) => println!("unknown command '{}', try help", command.trim()),
+            }
+        }
+    }
+
+    fn add_breakpoint(&mut self, target: &str) {
+        let n = self.breakpoints.len() + 1;
+        let Some((file, line)) = target
+            .rsplit_once(':')
+            .and_then(|(file, line)| Some((file, line.parse::<i64>().ok()?)))
+        else {
+            println!("breakpoint {} at function '{}'", n, target);
+            self.breakpoints
+                .push(Some(Breakpoint::Function(target.to_owned())));
+            return;
+        };
+
+        let mut protos = Vec::new();
+        collect_protos(self.top, &mut protos);
+        protos.retain(|proto| {
+            let src = proto.short_src();
+            src == file || src.ends_with(&format!("/{}", file))
+        });
+
+        //Lines without code stop at the next line which has some
+        let Some(line) = protos
+            .iter()
+            .flat_map(|proto| proto.instructions.list.iter())
+            .filter_map(|instruction| instruction.line())
+            .filter(|&code| code >= line)
+            .min()
+        else {
+            println!("no code at {}:{} or after it", file, line);
+            return;
+        };
+
+        for proto in protos {
+            let Some(pc) = proto
+                .instructions
+                .list
+                .iter()
+                .position(|instruction| instruction.line() == Some(line))
+            else {
+                continue;
+            };
+            let n = self.breakpoints.len() + 1;
+            let location = format!("{}:{}", proto.short_src(), line);
+            println!("breakpoint {} at {} (pc {})", n, location, pc);
+            self.breakpoints.push(Some(Breakpoint::Instruction {
+                proto,
+                pc,
+                location,
+            }));
+        }
+    }
+
+    fn delete_breakpoint(&mut self, n: &str) {
+        let breakpoint = n
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| self.breakpoints.get_mut(n.checked_sub(1)?))
+            .and_then(Option::take);
+        match breakpoint {
+            Some(Breakpoint::Instruction { location, .. }) => {
+                println!("deleted breakpoint {} at {}", n, location)
+            }
+            Some(Breakpoint::Function(name)) => {
+                println!("deleted breakpoint {} at function '{}'", n, name)
+            }
+            None => println!("no breakpoint {}", n),
+        }
+    }
+
+    /// Shows where execution stopped and the source line, if it's readable
+    fn show_stop(&mut self, reason: &str, call_stack: &CallStack<'i>) {
+        let Some((frame, proto)) = call_stack
+            .last()
+            .and_then(|frame| Some((frame, frame.proto?)))
+        else {
+            return;
+        };
+        let line = frame.current_line();
+        println!(
+            "{}: {}:{} (pc {}) in {}",
+            reason,
+            proto.short_src(),
+            line.map_or("?".to_owned(), |line| line.to_string()),
+            frame.pc,
+            function_description(call_stack, call_stack.len() - 1),
+        );
+
+        let src = proto.short_src();
+        let source = self
+            .sources
+            .entry(src.clone())
+            .or_insert_with(|| read_source(&src));
+        let text = source
+            .as_ref()
+            .zip(line)
+            .and_then(|(lines, line)| lines.get(usize::try_from(line).ok()?.checked_sub(1)?));
+        match text {
+            Some(text) => println!("{:>5}  {}", line.unwrap_or_default(), text),
+            None => println!("{:>5}  {}", frame.pc, instruction_text(proto, frame.pc)),
+        }
+    }
+}
+
+/// Lines of a source file. Chunks are dumped from the io directory so
+/// their names are relative to it
+fn read_source(src: &str) -> Option<Vec<String>> {
+    let text = fs::read_to_string(src)
+        .or_else(|_| fs::read_to_string(Path::new("io").join(src)))
+        .ok()?;
+    Some(text.lines().map(str::to_owned).collect())
+}
+
+pub(crate) fn collect_protos<'i>(proto: &'i BProto, protos: &mut Vec<&'i BProto>) {
+    protos.push(proto);
+    for child in &proto.protos.list {
+        collect_protos(child, protos);
+    }
+}
+
+/// "function 'name'", "main chunk" or where the function was defined
+pub(crate) fn function_description(call_stack: &CallStack, i: usize) -> String {
+    let frame = &call_stack[i];
+    match (ldebug::func_name(call_stack, i), frame.proto) {
+        (Some((what, name)), _) => format!("{} '{}'", what, name),
+        (None, Some(proto)) if proto.line_defined == 0 => "main chunk".to_owned(),
+        (None, Some(proto)) => format!("function <{}:{}>", proto.short_src(), proto.line_defined),
+        (None, None) => "?".to_owned(),
+    }
+}
+
+fn backtrace(call_stack: &CallStack) {
+    for (n, i) in (0..call_stack.len()).rev().enumerate() {
+        let frame = &call_stack[i];
+        let position = match (frame.proto, frame.current_line()) {
+            (Some(proto), Some(line)) => format!("{}:{}", proto.short_src(), line),
+            (Some(proto), None) => format!("{}:?", proto.short_src()),
+            (None, _) => "[C]".to_owned(),
+        };
+        println!(
+            "#{} {} in {}",
+            n,
+            position,
+            function_description(call_stack, i)
+        );
+        if frame.tail_call {
+            println!("   (...tail calls...)");
+        }
+    }
+}
+
+/// Named locals in scope, resolved from the BDebugLocal scopes, then the
+/// varargs
+fn locals<'i>(stack: &Stack<'i>, call_stack: &CallStack<'i>) {
+    let Some(i) = call_stack.len().checked_sub(1) else {
+        return;
+    };
+    let named = (1..)
+        .map_while(|n| ldebug::find_local(stack, call_stack, i, n))
+        .filter(|(name, _)| !name.starts_with('('));
+    let varargs = (1..).map_while(|n| ldebug::find_local(stack, call_stack, i, -n));
+    for (name, index) in named.chain(varargs) {
+        println!("{} = {}", name, describe(&stack[index]));
+    }
+}
+
+fn upvalues(stack: &Stack, call_stack: &CallStack) {
+    let Some(frame) = call_stack.last() else {
+        return;
+    };
+    let (Some(proto), LValue::LClosure(closure)) = (frame.proto, &stack[frame.func]) else {
+        println!("not in a Lua function");
+        return;
+    };
+    for (n, upvalue) in closure.upvalues.iter().enumerate() {
+        let name = proto.upvalue_name(n).unwrap_or("?");
+        println!("{} = {}", name, describe(&upvalue.borrow().get(stack)));
+    }
+}
+
+/// Shows a register of the innermost frame, counting from its base
+fn print_register(register: &str, stack: &Stack, call_stack: &CallStack) {
+    let Some(frame) = call_stack.last() else {
+        return;
+    };
+    let Ok(register) = register.trim_start_matches(['r', 'R']).parse::<usize>() else {
+        println!("'{}' isn't a register number", register);
+        return;
+    };
+    match stack.get(frame.base + register) {
+        Some(value) if frame.base + register < frame.top => {
+            println!("R{} = {}", register, describe(value))
+        }
+        _ => println!("R{} is outside of the frame", register),
+    }
+}
+
+/// Quotes strings so they can't be mistaken for other values
+pub(crate) fn describe(value: &LValue) -> String {
+    match value {
+        LValue::LPrimitive(LPrimitive::STRING(s)) => format!("{:?}", s.to_string()),
+        value => value.to_string(),
+    }
+}
+
+/// Lists every instruction of a proto, marking the one at `current`
+fn disassemble(proto: &BProto, current: usize) {
+    println!(
+        "function <{}:{}> ({} instructions)",
+        proto.short_src(),
+        proto.line_defined,
+        proto.instructions.list.len()
+    );
+    for (pc, instruction) in proto.instructions.list.iter().enumerate() {
+        let marker = if pc == current { "=>" } else { "  " };
+        let line = instruction
+            .line()
+            .map_or("?".to_owned(), |line| line.to_string());
+        println!(
+            "{} {:>4} [{:>4}] {}",
+            marker,
+            pc,
+            line,
+            instruction_text(proto, pc)
+        );
+    }
+}
+
+/// The opcode and operands of an instruction, with the constant it loads
+/// or the destination of a jump
+fn instruction_text(proto: &BProto, pc: usize) -> String {
+    match proto.instructions.list[pc] {
+        BInstruction::ABC {
+            opcode, a, b, c, ..
+        } => format!("{:<9} {} {} {}", OPNAMES[opcode as usize], a, b, c),
+        //LOADK
+        BInstruction::ABx {
+            opcode: opcode @ 1,
+            a,
+            b,
+            ..
+        } => format!(
+            "{:<9} {} {}\t; {}",
+            OPNAMES[opcode as usize],
+            a,
+            b,
+            describe(&LValue::LPrimitive(
+                proto.constants.list[b as usize].clone()
+            ))
+        ),
+        BInstruction::ABx { opcode, a, b, .. } => {
+            format!("{:<9} {} {}", OPNAMES[opcode as usize], a, b)
+        }
+        BInstruction::AsBx { opcode, a, b, .. } => format!(
+            "{:<9} {} {}\t; to {}",
+            OPNAMES[opcode as usize],
+            a,
+            b,
+            pc as i64 + 1 + b as i64
+        ),
+    }
+}
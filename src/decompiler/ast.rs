@@ -0,0 +1,699 @@
+use std::collections::HashMap;
+
+/// A variable of the function being decompiled. Locals are indices into
+/// its debug locals, temporaries stand for registers which have no name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Var {
+    Local(usize),
+    Temp(usize),
+    Upvalue(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    And,
+    Or,
+}
+impl BinOp {
+    /// Operators of ADD to SHR, in opcode order
+    pub const ARITH: [BinOp; 12] = [
+        BinOp::Add,
+        BinOp::Sub,
+        BinOp::Mul,
+        BinOp::Mod,
+        BinOp::Pow,
+        BinOp::Div,
+        BinOp::IDiv,
+        BinOp::BAnd,
+        BinOp::BOr,
+        BinOp::BXor,
+        BinOp::Shl,
+        BinOp::Shr,
+    ];
+
+    fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Mod => "%",
+            BinOp::Pow => "^",
+            BinOp::Div => "/",
+            BinOp::IDiv => "//",
+            BinOp::BAnd => "&",
+            BinOp::BOr => "|",
+            BinOp::BXor => "~",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+            BinOp::Concat => "..",
+            BinOp::Eq => "==",
+            BinOp::Ne => "~=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+        }
+    }
+
+    /// Left and right priorities, as in lparser.c
+    fn priority(self) -> (u8, u8) {
+        match self {
+            BinOp::Add | BinOp::Sub => (10, 10),
+            BinOp::Mul | BinOp::Mod | BinOp::Div | BinOp::IDiv => (11, 11),
+            BinOp::Pow => (14, 13),
+            BinOp::BAnd => (6, 6),
+            BinOp::BOr => (4, 4),
+            BinOp::BXor => (5, 5),
+            BinOp::Shl | BinOp::Shr => (7, 7),
+            BinOp::Concat => (9, 8),
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le => (3, 3),
+            BinOp::And => (2, 2),
+            BinOp::Or => (1, 1),
+        }
+    }
+}
+
+/// Priority of the operand of unary operators
+const UNARY_PRIORITY: u8 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+    BNot,
+    Not,
+    Len,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Vararg,
+    Var(Var),
+    Global(String),
+    Index(Box<Expr>, Box<Expr>),
+    Call(Box<Call>),
+    /// Closure of the nested proto at the index, with the variables its
+    /// upvalues capture
+    Function(usize, Vec<Var>),
+    Table(Vec<Field>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+    /// Every result of a call or of `...`, rather than the first one
+    Multi(Box<Expr>),
+    /// A value the test before the assignment it's in already evaluated,
+    /// which is only printed once an and/or expression absorbs it
+    Tested(Box<Expr>),
+}
+impl Expr {
+    pub fn binary(op: BinOp, left: Expr, right: Expr) -> Self {
+        Expr::Binary(op, Box::new(left), Box::new(right))
+    }
+
+    pub fn unary(op: UnOp, operand: Expr) -> Self {
+        Expr::Unary(op, Box::new(operand))
+    }
+
+    /// The expression without the Multi or Tested around it
+    pub fn inner(&self) -> &Expr {
+        match self {
+            Expr::Multi(inner) | Expr::Tested(inner) => inner.inner(),
+            _ => self,
+        }
+    }
+
+    /// Whether the expression always evaluates to true or false
+    pub fn is_boolean(&self) -> bool {
+        match self {
+            Expr::Bool(_) => true,
+            Expr::Unary(UnOp::Not, _) => true,
+            Expr::Binary(BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le, ..) => true,
+            Expr::Binary(BinOp::And | BinOp::Or, left, right) => {
+                left.is_boolean() && right.is_boolean()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Negation of a condition, which only needs to have the opposite
+/// truthiness
+pub fn negate(condition: Expr) -> Expr {
+    match condition {
+        Expr::Unary(UnOp::Not, operand) => *operand,
+        Expr::Binary(BinOp::Eq, left, right) => Expr::Binary(BinOp::Ne, left, right),
+        Expr::Binary(BinOp::Ne, left, right) => Expr::Binary(BinOp::Eq, left, right),
+        Expr::Binary(BinOp::And, left, right) => {
+            Expr::binary(BinOp::Or, negate(*left), negate(*right))
+        }
+        Expr::Binary(BinOp::Or, left, right) => {
+            Expr::binary(BinOp::And, negate(*left), negate(*right))
+        }
+        Expr::Bool(value) => Expr::Bool(!value),
+        condition => Expr::unary(UnOp::Not, condition),
+    }
+}
+
+/// `not value`, as a boolean
+pub fn not(value: Expr) -> Expr {
+    match value {
+        Expr::Unary(UnOp::Not, operand) if operand.is_boolean() => *operand,
+        Expr::Binary(BinOp::Eq, left, right) => Expr::Binary(BinOp::Ne, left, right),
+        Expr::Binary(BinOp::Ne, left, right) => Expr::Binary(BinOp::Eq, left, right),
+        Expr::Bool(value) => Expr::Bool(!value),
+        value => Expr::unary(UnOp::Not, value),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    /// The object for method calls
+    pub func: Expr,
+    pub method: Option<String>,
+    pub args: Vec<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    Item(Expr),
+    Pair(Expr, Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stat {
+    Local(Vec<Var>, Vec<Expr>),
+    Assign(Vec<Expr>, Vec<Expr>),
+    Call(Call),
+    If(Expr, Vec<Stat>, Vec<Stat>),
+    While(Expr, Vec<Stat>),
+    Repeat(Vec<Stat>, Expr),
+    NumericFor(Var, Expr, Expr, Expr, Vec<Stat>),
+    GenericFor(Vec<Var>, Vec<Expr>, Vec<Stat>),
+    Do(Vec<Stat>),
+    Return(Vec<Expr>),
+    Break,
+    Goto(String),
+    Label(String),
+}
+impl Stat {
+    /// Blocks nested directly in the statement
+    pub fn blocks(&self) -> Vec<&Vec<Stat>> {
+        match self {
+            Stat::If(_, then, otherwise) => vec![then, otherwise],
+            Stat::While(_, body)
+            | Stat::Repeat(body, _)
+            | Stat::NumericFor(.., body)
+            | Stat::GenericFor(.., body)
+            | Stat::Do(body) => vec![body],
+            _ => vec![],
+        }
+    }
+
+    pub fn blocks_mut(&mut self) -> Vec<&mut Vec<Stat>> {
+        match self {
+            Stat::If(_, then, otherwise) => vec![then, otherwise],
+            Stat::While(_, body)
+            | Stat::Repeat(body, _)
+            | Stat::NumericFor(.., body)
+            | Stat::GenericFor(.., body)
+            | Stat::Do(body) => vec![body],
+            _ => vec![],
+        }
+    }
+}
+
+/// Names of the variables of a function
+#[derive(Debug, Default)]
+pub struct Names {
+    pub locals: Vec<String>,
+    pub temps: HashMap<usize, String>,
+    pub upvalues: Vec<String>,
+}
+impl Names {
+    pub fn of(&self, var: Var) -> &str {
+        match var {
+            Var::Local(index) => &self.locals[index],
+            Var::Temp(id) => &self.temps[&id],
+            Var::Upvalue(index) => &self.upvalues[index],
+        }
+    }
+}
+
+/// A decompiled function, with the functions nested in it by proto index
+#[derive(Debug)]
+pub struct Function {
+    pub params: Vec<Var>,
+    pub vararg: bool,
+    pub body: Vec<Stat>,
+    pub names: Names,
+    pub children: HashMap<usize, Function>,
+}
+
+pub fn is_name(name: &str) -> bool {
+    const KEYWORDS: [&str; 22] = [
+        "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if",
+        "in", "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+    ];
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&name)
+}
+
+/// How an expression binds to the operators around it
+#[derive(Clone, Copy)]
+enum Binding {
+    Atom,
+    Prefix,
+    Unary,
+    Binary(u8, u8),
+}
+
+/// Whether the last expression of a list is cut down to one value, which
+/// calls and `...` need parentheses for
+#[derive(Clone, Copy, PartialEq)]
+enum Adjust {
+    One,
+    All,
+}
+
+/// Prints decompiled functions as Lua source, indented by two spaces
+pub struct Printer {
+    out: String,
+    indent: usize,
+}
+impl Printer {
+    /// Source of the main chunk, whose body isn't wrapped in a function
+    pub fn chunk(function: &Function) -> String {
+        let mut printer = Printer {
+            out: String::new(),
+            indent: 0,
+        };
+        printer.block(function, &function.body);
+        printer.out
+    }
+
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.indent {
+            self.out.push_str("  ");
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    fn block(&mut self, function: &Function, body: &[Stat]) {
+        for (index, stat) in body.iter().enumerate() {
+            self.stat(function, stat, index + 1 == body.len());
+        }
+    }
+
+    fn nested(&mut self, function: &Function, body: &[Stat]) {
+        self.indent += 1;
+        self.block(function, body);
+        self.indent -= 1;
+    }
+
+    fn stat(&mut self, f: &Function, stat: &Stat, last: bool) {
+        let text = match stat {
+            Stat::Local(vars, values) => {
+                if let ([var], [Expr::Function(index, captures)]) = (&vars[..], &values[..]) {
+                    if captures.contains(var) {
+                        let header = format!("local function {}", f.names.of(*var));
+                        return self.function_stat(f, &header, *index, false);
+                    }
+                }
+                let names: Vec<&str> = vars.iter().map(|&var| f.names.of(var)).collect();
+                if values.is_empty() {
+                    format!("local {}", names.join(", "))
+                } else {
+                    let adjust = adjust(vars.len(), values.len());
+                    format!(
+                        "local {} = {}",
+                        names.join(", "),
+                        self.list(f, values, adjust)
+                    )
+                }
+            }
+            Stat::Assign(targets, values) => {
+                if let ([target], [Expr::Function(index, _)]) = (&targets[..], &values[..]) {
+                    if let Some(name) = self.function_name(f, target) {
+                        let child = &f.children[index];
+                        let method = match child.params.first() {
+                            Some(&self_param) => {
+                                child.names.of(self_param) == "self"
+                                    && matches!(target, Expr::Index(..))
+                            }
+                            None => false,
+                        };
+                        let header = match (method, name.rsplit_once('.')) {
+                            (true, Some((object, method))) => {
+                                format!("function {}:{}", object, method)
+                            }
+                            _ => format!("function {}", name),
+                        };
+                        return self.function_stat(f, &header, *index, method);
+                    }
+                }
+                let targets: Vec<String> = targets.iter().map(|t| self.expr(f, t)).collect();
+                let adjust = adjust(targets.len(), values.len());
+                format!("{} = {}", targets.join(", "), self.list(f, values, adjust))
+            }
+            Stat::Call(call) => self.call(f, call),
+            Stat::If(condition, then, otherwise) => {
+                if then.is_empty() && !otherwise.is_empty() {
+                    let condition = negate(condition.clone());
+                    return self.stat(f, &Stat::If(condition, otherwise.clone(), vec![]), last);
+                }
+                let text = format!("if {} then", self.expr(f, condition));
+                self.line(&text);
+                self.nested(f, then);
+                let mut otherwise = otherwise;
+                loop {
+                    match &otherwise[..] {
+                        [] => break,
+                        [Stat::If(condition, then, rest)] if !then.is_empty() => {
+                            let text = format!("elseif {} then", self.expr(f, condition));
+                            self.line(&text);
+                            self.nested(f, then);
+                            otherwise = rest;
+                        }
+                        _ => {
+                            self.line("else");
+                            self.nested(f, otherwise);
+                            break;
+                        }
+                    }
+                }
+                self.line("end");
+                return;
+            }
+            Stat::While(condition, body) => {
+                let text = format!("while {} do", self.expr(f, condition));
+                self.line(&text);
+                self.nested(f, body);
+                self.line("end");
+                return;
+            }
+            Stat::Repeat(body, condition) => {
+                self.line("repeat");
+                self.nested(f, body);
+                let text = format!("until {}", self.expr(f, condition));
+                self.line(&text);
+                return;
+            }
+            Stat::NumericFor(var, start, limit, step, body) => {
+                let mut text = format!(
+                    "for {} = {}, {}",
+                    f.names.of(*var),
+                    self.expr(f, start),
+                    self.expr(f, limit)
+                );
+                if *step != Expr::Int(1) {
+                    text.push_str(", ");
+                    text.push_str(&self.expr(f, step));
+                }
+                text.push_str(" do");
+                self.line(&text);
+                self.nested(f, body);
+                self.line("end");
+                return;
+            }
+            Stat::GenericFor(vars, values, body) => {
+                let names: Vec<&str> = vars.iter().map(|&var| f.names.of(var)).collect();
+                let text = format!(
+                    "for {} in {} do",
+                    names.join(", "),
+                    self.list(f, values, adjust(3, values.len()))
+                );
+                self.line(&text);
+                self.nested(f, body);
+                self.line("end");
+                return;
+            }
+            Stat::Do(body) => {
+                self.line("do");
+                self.nested(f, body);
+                self.line("end");
+                return;
+            }
+            Stat::Return(values) => {
+                let text = match values.len() {
+                    0 => "return".to_owned(),
+                    _ => format!("return {}", self.list(f, values, Adjust::All)),
+                };
+                //Only the last statement of a block may be a return
+                match last {
+                    true => text,
+                    false => format!("do {} end", text),
+                }
+            }
+            Stat::Break => "break".to_owned(),
+            Stat::Goto(label) => format!("goto {}", label),
+            Stat::Label(label) => format!("::{}::", label),
+        };
+        //A statement starting with a parenthesis would continue the one before
+        match text.starts_with('(') {
+            true => self.line(&format!(";{}", text)),
+            false => self.line(&text),
+        }
+    }
+
+    /// `a.b.c` for targets a function statement can name
+    fn function_name(&self, f: &Function, target: &Expr) -> Option<String> {
+        match target {
+            Expr::Var(var) => Some(f.names.of(*var).to_owned()),
+            Expr::Global(name) if is_name(name) => Some(name.clone()),
+            Expr::Index(object, key) => match &**key {
+                Expr::Str(key) if is_name(key) => {
+                    let object = match &**object {
+                        Expr::Var(var) => f.names.of(*var).to_owned(),
+                        Expr::Global(name) if is_name(name) => name.clone(),
+                        object @ Expr::Index(..) => self.function_name(f, object)?,
+                        _ => return None,
+                    };
+                    Some(format!("{}.{}", object, key))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn function_stat(&mut self, f: &Function, header: &str, index: usize, method: bool) {
+        let child = &f.children[&index];
+        let params = params(child, method);
+        self.line(&format!("{}({})", header, params));
+        self.nested(child, &child.body);
+        self.line("end");
+    }
+
+    fn list(&self, f: &Function, values: &[Expr], adjust: Adjust) -> String {
+        let mut texts = Vec::with_capacity(values.len());
+        for (index, value) in values.iter().enumerate() {
+            let last = index + 1 == values.len();
+            let text = match value.inner() {
+                Expr::Call(_) | Expr::Vararg
+                    if last && adjust == Adjust::All && !matches!(value, Expr::Multi(_)) =>
+                {
+                    format!("({})", self.expr(f, value))
+                }
+                _ => self.expr(f, value),
+            };
+            texts.push(text);
+        }
+        texts.join(", ")
+    }
+
+    fn call(&self, f: &Function, call: &Call) -> String {
+        let func = self.prefix(f, &call.func);
+        let args = self.list(f, &call.args, Adjust::All);
+        match &call.method {
+            Some(method) => format!("{}:{}({})", func, method, args),
+            None => format!("{}({})", func, args),
+        }
+    }
+
+    /// Expression which calls and indexing can apply to
+    fn prefix(&self, f: &Function, expr: &Expr) -> String {
+        match binding(expr) {
+            Binding::Prefix => self.expr(f, expr),
+            _ => format!("({})", self.expr(f, expr)),
+        }
+    }
+
+    pub fn expr(&self, f: &Function, expr: &Expr) -> String {
+        match expr {
+            Expr::Nil => "nil".to_owned(),
+            Expr::Bool(value) => value.to_string(),
+            Expr::Int(i64::MIN) => "(-9223372036854775807 - 1)".to_owned(),
+            Expr::Int(value) => value.to_string(),
+            Expr::Float(value) if value.is_nan() => "0/0".to_owned(),
+            Expr::Float(value) if value.is_infinite() => match *value > 0.0 {
+                true => "1/0".to_owned(),
+                false => "-1/0".to_owned(),
+            },
+            Expr::Float(value) => format!("{:?}", value),
+            Expr::Str(value) => quote(value),
+            Expr::Vararg => "...".to_owned(),
+            Expr::Var(var) => f.names.of(*var).to_owned(),
+            Expr::Global(name) if is_name(name) => name.clone(),
+            Expr::Global(name) => format!("_ENV[{}]", quote(name)),
+            Expr::Index(object, key) => match &**key {
+                Expr::Str(key) if is_name(key) => format!("{}.{}", self.prefix(f, object), key),
+                key => format!("{}[{}]", self.prefix(f, object), self.expr(f, key)),
+            },
+            Expr::Call(call) => self.call(f, call),
+            Expr::Function(index, _) => {
+                let child = &f.children[index];
+                let mut printer = Printer {
+                    out: String::new(),
+                    indent: self.indent + 1,
+                };
+                printer.block(child, &child.body);
+                let mut indent = String::new();
+                for _ in 0..self.indent {
+                    indent.push_str("  ");
+                }
+                format!(
+                    "function({})\n{}{}end",
+                    params(child, false),
+                    printer.out,
+                    indent
+                )
+            }
+            Expr::Table(fields) => {
+                let mut texts = Vec::with_capacity(fields.len());
+                for (index, field) in fields.iter().enumerate() {
+                    texts.push(match field {
+                        Field::Item(value) if index + 1 == fields.len() => {
+                            self.list(f, std::slice::from_ref(value), Adjust::All)
+                        }
+                        Field::Item(value) => self.expr(f, value),
+                        Field::Pair(Expr::Str(key), value) if is_name(key) => {
+                            format!("{} = {}", key, self.expr(f, value))
+                        }
+                        Field::Pair(key, value) => {
+                            format!("[{}] = {}", self.expr(f, key), self.expr(f, value))
+                        }
+                    });
+                }
+                format!("{{{}}}", texts.join(", "))
+            }
+            Expr::Binary(op, left, right) => {
+                let (left_priority, right_priority) = op.priority();
+                let left_text = self.expr(f, left);
+                let left_text = match binding(left) {
+                    Binding::Binary(_, priority) if priority < left_priority => {
+                        format!("({})", left_text)
+                    }
+                    Binding::Unary if left_priority > UNARY_PRIORITY => format!("({})", left_text),
+                    _ => left_text,
+                };
+                let right_text = self.expr(f, right);
+                let right_text = match binding(right) {
+                    Binding::Binary(priority, _) if priority <= right_priority => {
+                        format!("({})", right_text)
+                    }
+                    _ => right_text,
+                };
+                format!("{} {} {}", left_text, op.symbol(), right_text)
+            }
+            Expr::Unary(op, operand) => {
+                let text = self.expr(f, operand);
+                let text = match binding(operand) {
+                    Binding::Binary(priority, _) if priority <= UNARY_PRIORITY => {
+                        format!("({})", text)
+                    }
+                    _ => text,
+                };
+                match op {
+                    //Two minuses in a row would start a comment
+                    UnOp::Neg if text.starts_with('-') => format!("- {}", text),
+                    UnOp::Neg => format!("-{}", text),
+                    UnOp::BNot => format!("~{}", text),
+                    UnOp::Not => format!("not {}", text),
+                    UnOp::Len => format!("#{}", text),
+                }
+            }
+            Expr::Multi(inner) | Expr::Tested(inner) => self.expr(f, inner),
+        }
+    }
+}
+
+fn adjust(targets: usize, values: usize) -> Adjust {
+    match values < targets {
+        true => Adjust::All,
+        false => Adjust::One,
+    }
+}
+
+fn binding(expr: &Expr) -> Binding {
+    match expr {
+        Expr::Var(_) | Expr::Global(_) | Expr::Index(..) | Expr::Call(_) => Binding::Prefix,
+        Expr::Int(value) if *value < 0 && *value != i64::MIN => Binding::Unary,
+        Expr::Float(value) if value.is_nan() || value.is_infinite() => BinOp::Div.priority().into(),
+        Expr::Float(value) if value.is_sign_negative() => Binding::Unary,
+        Expr::Unary(..) => Binding::Unary,
+        Expr::Binary(op, ..) => op.priority().into(),
+        Expr::Multi(inner) | Expr::Tested(inner) => binding(inner),
+        _ => Binding::Atom,
+    }
+}
+impl From<(u8, u8)> for Binding {
+    fn from((left, right): (u8, u8)) -> Self {
+        Binding::Binary(left, right)
+    }
+}
+
+fn params(function: &Function, method: bool) -> String {
+    let mut params: Vec<&str> = function
+        .params
+        .iter()
+        .skip(method as usize)
+        .map(|&param| function.names.of(param))
+        .collect();
+    if function.vararg {
+        params.push("...");
+    }
+    params.join(", ")
+}
+
+/// A double quoted string literal, with control characters as decimal
+/// escapes
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_ascii_control() => quoted.push_str(&format!("\\{:03}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,213 @@
+//! Turns compiled chunks back into Lua source, for chunks whose source is
+//! lost. Control flow is rebuilt as if, while, repeat and for statements,
+//! with goto only where the jumps can't be nested, and the temporaries of
+//! expressions are folded back into them. Locals get their names from the
+//! debug info when the chunk has it. Stripped chunks get made up names,
+//! and their temporaries are declared once per function, so closures
+//! created in a loop share them rather than getting a fresh one each time
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::{
+    bytecode::{bproto::BProto, cfg::Cfg},
+    lprimative::LPrimitive,
+};
+
+use self::{
+    ast::{is_name, Expr, Function, Names, Printer, Stat, Var},
+    structure::Structurer,
+    translate::{Liveness, Scopes, Source, Translator},
+};
+
+mod ast;
+mod rewrite;
+mod structure;
+mod translate;
+
+/// Lua source for `top` and the functions nested in it
+pub fn decompile(top: &BProto) -> String {
+    let mut namer = Namer::new(top);
+    let function = function(top, vec!["_ENV".to_owned()], &mut namer);
+    Printer::chunk(&function)
+}
+
+/// Makes up names which no local, global or field of the chunk uses
+struct Namer {
+    used: HashSet<String>,
+    counters: HashMap<&'static str, usize>,
+}
+impl Namer {
+    fn new(top: &BProto) -> Self {
+        let mut used = HashSet::new();
+        let mut protos = vec![top];
+        while let Some(proto) = protos.pop() {
+            for local in &proto.debug_local_vars.list {
+                used.insert(local.local.clone());
+            }
+            for constant in &proto.constants.list {
+                if let LPrimitive::STRING(string) = constant {
+                    used.insert(string.to_string());
+                }
+            }
+            protos.extend(proto.protos.list.iter());
+        }
+        Self {
+            used,
+            counters: HashMap::new(),
+        }
+    }
+
+    fn fresh(&mut self, prefix: &'static str) -> String {
+        let counter = self.counters.entry(prefix).or_insert(0);
+        loop {
+            *counter += 1;
+            let name = format!("{}{}", prefix, counter);
+            if self.used.insert(name.clone()) {
+                return name;
+            }
+        }
+    }
+}
+
+fn function(proto: &BProto, upvalues: Vec<String>, namer: &mut Namer) -> Function {
+    let locals = translate::locals(proto);
+    let cfg = Cfg::new(proto);
+    let scopes = Scopes::new(&locals, proto.instructions.list.len());
+    let liveness = Liveness::new(proto, &cfg, &locals, &scopes);
+    let env = upvalues.iter().position(|name| name == "_ENV");
+
+    //Values TESTSET tests are put back into the and or or they come from,
+    //unless one can't be, and then they're evaluated into a temporary first
+    let mut translated = None;
+    for tested in [true, false] {
+        let mut translator = Translator::new(proto, &cfg, &locals, &scopes, &liveness, env, tested);
+        let nodes = translator.translate();
+        let carried = carried(&cfg, &liveness, &mut translator);
+        let body = Structurer::new(proto, &cfg, &locals, &mut translator, nodes).structure();
+        if let Some(body) = rewrite::rewrite(body, &mut translator.temps, &carried) {
+            translated = Some(body);
+            break;
+        }
+    }
+    let mut body = translated.expect("translating without tested values always succeeds");
+    //luac ends every function with a return
+    if let Some(Stat::Return(values)) = body.last() {
+        if values.is_empty() {
+            body.pop();
+        }
+    }
+
+    let mut names = Names {
+        locals: locals
+            .iter()
+            .map(|local| match local.internal || is_name(&local.name) {
+                true => local.name.clone(),
+                false => namer.fresh("a"),
+            })
+            .collect(),
+        temps: HashMap::new(),
+        upvalues,
+    };
+    let mut closures = BTreeMap::new();
+    rewrite::each_in_block(&mut body, &mut |expr| match expr {
+        Expr::Var(Var::Temp(id)) if !names.temps.contains_key(id) => {
+            names.temps.insert(*id, namer.fresh("t"));
+        }
+        Expr::Function(index, captures) => {
+            closures.insert(*index, captures.clone());
+        }
+        _ => {}
+    });
+    //Captured temporaries are named by now, as captures are visited first.
+    //Nested functions are named in order, so that the names are the same
+    //every time
+    let children = closures
+        .into_iter()
+        .map(|(index, captures)| {
+            let upvalues = captures
+                .iter()
+                .map(|&var| names.of(var).to_owned())
+                .collect();
+            (index, function(&proto.protos.list[index], upvalues, namer))
+        })
+        .collect();
+
+    Function {
+        params: (0..proto.num_params as usize).map(Var::Local).collect(),
+        vararg: proto.vararg_flag != 0,
+        body,
+        names,
+        children,
+    }
+}
+
+/// Temporaries whose value is carried from one iteration of a loop to the
+/// next, which are live where a back edge enters the header
+fn carried(cfg: &Cfg, liveness: &Liveness, translator: &mut Translator) -> HashSet<usize> {
+    let mut carried = HashSet::new();
+    for (block, basic) in cfg.blocks.iter().enumerate() {
+        let header = (basic.predecessors.iter()).any(|&from| cfg.is_back_edge(from, block));
+        if header {
+            for register in liveness.live_in[block].iter() {
+                carried.insert(translator.temps.id(Source::Entry(block, register)));
+            }
+        }
+    }
+    carried
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::decompile;
+    use crate::{
+        bytecode::{
+            load_fixture,
+            optimize::{run_captured, Limits},
+        },
+        lstring::StringTable,
+    };
+
+    /// Decompiles the fixture, checks the source against the one checked in
+    /// as `expected`, whose .luac was compiled from it by luac 5.3, and
+    /// checks both chunks print the same
+    fn round_trip(name: &str, expected: &str) -> String {
+        let mut strings = StringTable::default();
+        let top = load_fixture(name, &mut strings);
+        let source = decompile(&top);
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(expected)
+            .with_extension("lua");
+        let checked_in = fs::read_to_string(path).expect("failed to read fixture");
+        assert_eq!(
+            source, checked_in,
+            "recompile {} after changing it",
+            expected
+        );
+
+        let original = run_captured(&top, strings, Limits::default());
+        let mut strings = StringTable::default();
+        let recompiled = load_fixture(expected, &mut strings);
+        assert_eq!(
+            run_captured(&recompiled, strings, Limits::default()),
+            original
+        );
+        source
+    }
+
+    #[test]
+    fn round_trips_with_debug_info() {
+        let source = round_trip("decompile", "decompiled");
+        assert!(source.contains("local x = a and b or c"));
+        assert!(source.contains("return setmetatable({a and 1 or 2, b = a == nil}, mt)"));
+        assert!(source.contains("return setmetatable({f(1), x = 1, f(2), y = f(3)}, mt)"));
+    }
+
+    #[test]
+    fn round_trips_stripped() {
+        let source = round_trip("decompile_stripped", "decompiled_stripped");
+        assert!(source.contains("return a3 and a4 or a5"));
+        assert!(source.contains("return setmetatable({a6 and 1 or 2, b = a6 == nil}, t1)"));
+    }
+}
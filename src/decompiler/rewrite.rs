@@ -0,0 +1,800 @@
+use std::collections::{HashMap, HashSet};
+
+use super::{
+    ast::{negate, not, BinOp, Call, Expr, Field, Stat, UnOp, Var},
+    translate::{pure, Temps},
+};
+
+/// Expressions directly in `stat`, in the order they're evaluated, with
+/// the targets of assignments last
+pub fn exprs_mut(stat: &mut Stat) -> Vec<&mut Expr> {
+    match stat {
+        Stat::Local(_, values) | Stat::Return(values) | Stat::GenericFor(_, values, _) => {
+            values.iter_mut().collect()
+        }
+        Stat::Assign(targets, values) => {
+            let mut exprs: Vec<&mut Expr> = values.iter_mut().collect();
+            exprs.extend(targets.iter_mut());
+            exprs
+        }
+        Stat::Call(call) => vec![&mut call.func],
+        Stat::If(condition, ..) | Stat::While(condition, _) | Stat::Repeat(_, condition) => {
+            vec![condition]
+        }
+        Stat::NumericFor(_, init, limit, step, _) => vec![init, limit, step],
+        Stat::Do(_) | Stat::Break | Stat::Goto(_) | Stat::Label(_) => vec![],
+    }
+}
+
+/// Calls `f` on `expr` and everything in it, innermost first. Functions
+/// are entered through their captures
+fn each_mut(expr: &mut Expr, f: &mut dyn FnMut(&mut Expr)) {
+    match expr {
+        Expr::Index(object, key) => {
+            each_mut(object, f);
+            each_mut(key, f);
+        }
+        Expr::Call(call) => each_call_mut(call, f),
+        Expr::Function(_, captures) => {
+            for capture in captures {
+                let mut var = Expr::Var(*capture);
+                f(&mut var);
+                if let Expr::Var(var) = var {
+                    *capture = var;
+                }
+            }
+        }
+        Expr::Table(fields) => {
+            for field in fields {
+                match field {
+                    Field::Item(value) => each_mut(value, f),
+                    Field::Pair(key, value) => {
+                        each_mut(key, f);
+                        each_mut(value, f);
+                    }
+                }
+            }
+        }
+        Expr::Binary(_, left, right) => {
+            each_mut(left, f);
+            each_mut(right, f);
+        }
+        Expr::Unary(_, operand) | Expr::Multi(operand) | Expr::Tested(operand) => {
+            each_mut(operand, f)
+        }
+        _ => {}
+    }
+    f(expr);
+}
+
+fn each_call_mut(call: &mut Call, f: &mut dyn FnMut(&mut Expr)) {
+    each_mut(&mut call.func, f);
+    for arg in &mut call.args {
+        each_mut(arg, f);
+    }
+}
+
+/// Calls `f` on every expression in `stats` and the blocks in them, and
+/// on the variables statements declare
+pub fn each_in_block(stats: &mut [Stat], f: &mut dyn FnMut(&mut Expr)) {
+    for stat in stats {
+        if let Stat::Call(call) = stat {
+            each_call_mut(call, f);
+        } else {
+            for expr in exprs_mut(stat) {
+                each_mut(expr, f);
+            }
+        }
+        let declared = match stat {
+            Stat::Local(vars, _) | Stat::GenericFor(vars, ..) => vars.iter_mut().collect(),
+            Stat::NumericFor(var, ..) => vec![var],
+            _ => vec![],
+        };
+        for var in declared {
+            let mut expr = Expr::Var(*var);
+            f(&mut expr);
+            if let Expr::Var(new) = expr {
+                *var = new;
+            }
+        }
+        for block in stat.blocks_mut() {
+            each_in_block(block, f);
+        }
+    }
+}
+
+/// Simplifies the statements of a function. Returns None if a value a
+/// TESTSET tested couldn't be put back into an `and` or `or`
+pub fn rewrite(
+    mut stats: Vec<Stat>,
+    temps: &mut Temps,
+    carried: &HashSet<usize>,
+) -> Option<Vec<Stat>> {
+    each_in_block(&mut stats, &mut |expr| {
+        if let Expr::Var(Var::Temp(id)) = expr {
+            *id = temps.find(*id);
+        }
+    });
+    let carried: HashSet<usize> = carried.iter().map(|&id| temps.find(id)).collect();
+
+    logical(&mut stats);
+    let mut tested = false;
+    each_in_block(&mut stats, &mut |expr| {
+        if let Expr::Tested(inner) = expr {
+            tested |= !pure(inner);
+            *expr = std::mem::replace(&mut **inner, Expr::Nil);
+        }
+    });
+    if tested {
+        return None;
+    }
+
+    constructors(&mut stats);
+    let mut counts = Counts::new(&mut stats);
+    fold(&mut stats, &mut counts);
+    unused(&mut stats, &counts);
+    localize(&mut stats, &carried);
+    Some(stats)
+}
+
+/// Turns the ifs which assign one of two values into `and` and `or`
+fn logical(stats: &mut Vec<Stat>) {
+    for stat in stats.iter_mut() {
+        for block in stat.blocks_mut() {
+            logical(block);
+        }
+    }
+    let mut index = 0;
+    while index < stats.len() {
+        if let Some(assign) = select(&stats[index]) {
+            stats[index] = assign;
+            continue;
+        }
+        //The value is kept when the test fails and replaced when it passes
+        if index + 1 < stats.len() {
+            if let (Stat::Assign(targets, values), Stat::If(condition, then, otherwise)) =
+                (&stats[index], &stats[index + 1])
+            {
+                if let ([target @ Expr::Var(_)], [value], [Stat::Assign(inner, replaced)], []) =
+                    (&targets[..], &values[..], &then[..], &otherwise[..])
+                {
+                    if let ([inner], [replaced]) = (&inner[..], &replaced[..]) {
+                        let op = if condition == target {
+                            Some(BinOp::And)
+                        } else if *condition == negate(target.clone()) {
+                            Some(BinOp::Or)
+                        } else {
+                            None
+                        };
+                        if let (Some(op), true) = (op, inner == target) {
+                            let value = Expr::binary(op, value.clone(), replaced.clone());
+                            stats[index] = Stat::Assign(vec![target.clone()], vec![value]);
+                            stats.remove(index + 1);
+                            continue;
+                        }
+                    }
+                }
+            }
+        }
+        index += 1;
+    }
+}
+
+/// `if c then x = a else x = b end` as a single assignment, when `c` is
+/// how `a` or `b` was tested
+fn select(stat: &Stat) -> Option<Stat> {
+    let Stat::If(condition, then, otherwise) = stat else {
+        return None;
+    };
+    let ([Stat::Assign(t1, v1)], [Stat::Assign(t2, v2)]) = (&then[..], &otherwise[..]) else {
+        return None;
+    };
+    let ([target @ Expr::Var(_)], [a], [other], [b]) = (&t1[..], &v1[..], &t2[..], &v2[..]) else {
+        return None;
+    };
+    if target != other {
+        return None;
+    }
+    let value = a.inner();
+    let selected = if condition == value {
+        Expr::binary(BinOp::Or, value.clone(), b.clone())
+    } else if *condition == negate(value.clone()) {
+        Expr::binary(BinOp::And, value.clone(), b.clone())
+    } else if matches!(condition, Expr::Binary(BinOp::And, _, right) if **right == *value) {
+        Expr::binary(BinOp::Or, condition.clone(), b.clone())
+    } else if let (Expr::Bool(a), Expr::Bool(b)) = (a, b) {
+        if a == b {
+            return None;
+        }
+        let condition = match condition.is_boolean() {
+            true => condition.clone(),
+            false => Expr::unary(UnOp::Not, Expr::unary(UnOp::Not, condition.clone())),
+        };
+        match a {
+            true => condition,
+            false => not(condition),
+        }
+    } else {
+        return None;
+    };
+    Some(Stat::Assign(vec![target.clone()], vec![selected]))
+}
+
+/// Moves the fields set on a new table after its constructor, like those
+/// SETLIST stores once the items between were computed by control flow,
+/// into the constructor, which takes the place of the last of them. The
+/// temporaries assigned between can then be folded into it
+fn constructors(stats: &mut Vec<Stat>) {
+    for stat in stats.iter_mut() {
+        for block in stat.blocks_mut() {
+            constructors(block);
+        }
+    }
+    let mut index = 0;
+    while index < stats.len() {
+        if let Some(last) = constructor(stats, index) {
+            index = last;
+        }
+        index += 1;
+    }
+}
+
+/// Merges the fields set after the constructor at `index`, returning the
+/// index it was moved to
+fn constructor(stats: &mut Vec<Stat>, index: usize) -> Option<usize> {
+    let Stat::Assign(targets, values) = &stats[index] else {
+        return None;
+    };
+    let ([Expr::Var(Var::Temp(id))], [Expr::Table(fields)]) = (&targets[..], &values[..]) else {
+        return None;
+    };
+    let (id, mut fields) = (*id, fields.clone());
+    if fields
+        .iter()
+        .any(|field| matches!(field, Field::Item(Expr::Multi(_))))
+    {
+        return None;
+    }
+    let reads = |expr: &Expr| mentioned(&mut [Stat::Return(vec![expr.clone()])]).contains(&id);
+    if fields.iter().any(|field| match field {
+        Field::Item(value) => reads(value),
+        Field::Pair(key, value) => reads(key) || reads(value),
+    }) {
+        return None;
+    }
+    let mut items = fields
+        .iter()
+        .filter(|field| matches!(field, Field::Item(_)))
+        .count() as i64;
+    //Everything merged so far is evaluated later, past the statements skipped
+    let mut moved = Hazards::of(&Expr::Table(fields.clone()));
+    let mut moved_reads = mentioned(&mut [Stat::Return(vec![Expr::Table(fields.clone())])]);
+    let mut orders: Vec<usize> = vec![index; fields.len()];
+    let mut assigned: HashMap<usize, usize> = HashMap::new();
+    let mut merged = Vec::new();
+    for (at, stat) in stats.iter().enumerate().skip(index + 1) {
+        let Stat::Assign(targets, values) = stat else {
+            break;
+        };
+        if let ([Expr::Var(Var::Temp(temp))], [value]) = (&targets[..], &values[..]) {
+            let hazards = Hazards::of(value);
+            let swaps = !(hazards.effects && (moved.effects || moved.volatile)
+                || moved.effects && hazards.volatile);
+            if *temp == id || moved_reads.contains(temp) || reads(value) || !swaps {
+                break;
+            }
+            assigned.insert(*temp, at);
+            continue;
+        }
+        if targets.len() != values.len()
+            || values
+                .iter()
+                .any(|value| matches!(value, Expr::Multi(_)) || reads(value))
+        {
+            break;
+        }
+        let mut set = Vec::with_capacity(targets.len());
+        for (target, value) in targets.iter().zip(values) {
+            let Expr::Index(table, key) = target else {
+                break;
+            };
+            if **table != Expr::Var(Var::Temp(id)) {
+                break;
+            }
+            match &**key {
+                //Items are stored after the fields, which mustn't overlap them
+                Expr::Int(n) if *n == items + 1 => {
+                    items += 1;
+                    //An item is ordered by where its value was computed
+                    let order = match value {
+                        Expr::Var(Var::Temp(temp)) => assigned.get(temp).copied().unwrap_or(at),
+                        _ => at,
+                    };
+                    set.push((order, Field::Item(value.clone())));
+                }
+                key @ (Expr::Str(_) | Expr::Bool(_)) => {
+                    set.push((at, Field::Pair(key.clone(), value.clone())))
+                }
+                _ => break,
+            }
+        }
+        if set.len() != targets.len() {
+            break;
+        }
+        for value in values {
+            let hazards = Hazards::of(value);
+            moved.effects |= hazards.effects;
+            moved.volatile |= hazards.volatile;
+        }
+        moved_reads.extend(mentioned(&mut [Stat::Return(values.clone())]));
+        for (order, field) in set {
+            //Items stay in the order of their keys
+            let order = match field {
+                Field::Item(_) => (orders.iter().zip(&fields))
+                    .filter(|(_, field)| matches!(field, Field::Item(_)))
+                    .map(|(&order, _)| order)
+                    .fold(order, usize::max),
+                Field::Pair(..) => order,
+            };
+            orders.push(order);
+            fields.push(field);
+        }
+        merged.push(at);
+    }
+    let &last = merged.last()?;
+    let mut fields: Vec<(usize, Field)> = orders.into_iter().zip(fields).collect();
+    fields.sort_by_key(|&(order, _)| order);
+    let table = Expr::Table(fields.into_iter().map(|(_, field)| field).collect());
+    stats[last] = Stat::Assign(vec![Expr::Var(Var::Temp(id))], vec![table]);
+    for &at in merged.iter().rev().skip(1) {
+        stats.remove(at);
+    }
+    stats.remove(index);
+    Some(last - merged.len())
+}
+
+/// How often each temporary is assigned and read
+#[derive(Default)]
+struct Counts {
+    defs: HashMap<usize, u32>,
+    uses: HashMap<usize, u32>,
+}
+impl Counts {
+    fn new(stats: &mut [Stat]) -> Self {
+        let mut counts = Self::default();
+        let mut mentions: HashMap<usize, u32> = HashMap::new();
+        each_in_block(stats, &mut |expr| match expr {
+            Expr::Var(Var::Temp(id)) => *mentions.entry(*id).or_insert(0) += 1,
+            //A captured temporary has to stay a variable
+            Expr::Function(_, captures) => {
+                for capture in captures {
+                    if let Var::Temp(id) = capture {
+                        *mentions.entry(*id).or_insert(0) += 2;
+                    }
+                }
+            }
+            _ => {}
+        });
+        counts.count_defs(stats);
+        for (id, mentions) in mentions {
+            let defs = counts.defs.get(&id).copied().unwrap_or(0);
+            counts.uses.insert(id, mentions - defs);
+        }
+        counts
+    }
+
+    fn count_defs(&mut self, stats: &[Stat]) {
+        for stat in stats {
+            for var in defined(stat) {
+                if let Var::Temp(id) = var {
+                    *self.defs.entry(id).or_insert(0) += 1;
+                }
+            }
+            for block in stat.blocks() {
+                self.count_defs(block);
+            }
+        }
+    }
+
+    fn uses(&self, id: usize) -> u32 {
+        self.uses.get(&id).copied().unwrap_or(0)
+    }
+
+    fn single(&self, id: usize) -> bool {
+        self.defs.get(&id) == Some(&1) && self.uses(id) == 1
+    }
+}
+
+/// Variables `stat` assigns or declares
+fn defined(stat: &Stat) -> Vec<Var> {
+    match stat {
+        Stat::Local(vars, _) | Stat::GenericFor(vars, ..) => vars.clone(),
+        Stat::NumericFor(var, ..) => vec![*var],
+        Stat::Assign(targets, _) => targets
+            .iter()
+            .filter_map(|target| match target {
+                Expr::Var(var) => Some(*var),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// What evaluating the expressions before a use could interfere with
+#[derive(Default)]
+struct Hazards {
+    /// Something which may run arbitrary code, like a call or a metamethod
+    effects: bool,
+    /// Reads of globals and upvalues, which such code could change
+    volatile: bool,
+}
+impl Hazards {
+    fn of(expr: &Expr) -> Self {
+        let mut hazards = Self::default();
+        hazards.search(expr, usize::MAX, false);
+        hazards
+    }
+
+    /// Looks for the read of temporary `id` in `expr`, noting what's
+    /// evaluated before it. Returns whether the read is only evaluated
+    /// conditionally, None if `expr` doesn't read it
+    fn search(&mut self, expr: &Expr, id: usize, conditional: bool) -> Option<bool> {
+        match expr {
+            Expr::Var(Var::Temp(temp)) if *temp == id => return Some(conditional),
+            Expr::Var(Var::Upvalue(_)) | Expr::Global(_) => self.volatile = true,
+            Expr::Index(object, key) => {
+                let found = self
+                    .search(object, id, conditional)
+                    .or_else(|| self.search(key, id, conditional));
+                if found.is_some() {
+                    return found;
+                }
+                self.effects = true;
+            }
+            Expr::Call(call) => {
+                let found = self.search(&call.func, id, conditional);
+                if found.is_some() {
+                    return found;
+                }
+                for arg in &call.args {
+                    let found = self.search(arg, id, conditional);
+                    if found.is_some() {
+                        return found;
+                    }
+                }
+                self.effects = true;
+            }
+            Expr::Table(fields) => {
+                for field in fields {
+                    let found = match field {
+                        Field::Item(value) => self.search(value, id, conditional),
+                        Field::Pair(key, value) => self
+                            .search(key, id, conditional)
+                            .or_else(|| self.search(value, id, conditional)),
+                    };
+                    if found.is_some() {
+                        return found;
+                    }
+                }
+            }
+            Expr::Binary(op @ (BinOp::And | BinOp::Or), left, right) => {
+                let _ = op;
+                let found = self
+                    .search(left, id, conditional)
+                    .or_else(|| self.search(right, id, true));
+                if found.is_some() {
+                    return found;
+                }
+            }
+            Expr::Binary(_, left, right) => {
+                let found = self
+                    .search(left, id, conditional)
+                    .or_else(|| self.search(right, id, conditional));
+                if found.is_some() {
+                    return found;
+                }
+                self.effects = true;
+            }
+            Expr::Unary(op, operand) => {
+                let found = self.search(operand, id, conditional);
+                if found.is_some() {
+                    return found;
+                }
+                self.effects |= *op != UnOp::Not;
+            }
+            Expr::Multi(inner) | Expr::Tested(inner) => return self.search(inner, id, conditional),
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Whether `value` can be evaluated where temporary `id` is read in
+/// `stat` instead of in a statement right before it
+fn can_fold(stat: &Stat, id: usize, value: &Expr) -> bool {
+    let mut stat = stat.clone();
+    let exprs: Vec<&Expr> = match &mut stat {
+        Stat::Assign(targets, values) => {
+            //Tables and keys of the targets are evaluated before the values
+            let mut exprs = Vec::new();
+            for target in targets.iter() {
+                if let Expr::Index(object, key) = target {
+                    exprs.push(&**object);
+                    exprs.push(&**key);
+                }
+            }
+            exprs.extend(values.iter());
+            exprs
+        }
+        Stat::Call(call) => vec![&call.func]
+            .into_iter()
+            .chain(call.args.iter())
+            .collect(),
+        Stat::While(..) | Stat::Repeat(..) => return false,
+        stat => exprs_mut(stat).into_iter().map(|expr| &*expr).collect(),
+    };
+    let mut before = Hazards::default();
+    let mut found = None;
+    for expr in exprs {
+        found = before.search(expr, id, false);
+        if found.is_some() {
+            break;
+        }
+    }
+    let Some(conditional) = found else {
+        return false;
+    };
+    let hazards = Hazards::of(value);
+    if conditional && (hazards.effects || hazards.volatile || !pure(value)) {
+        return false;
+    }
+    if hazards.effects {
+        !before.effects && !before.volatile
+    } else if hazards.volatile {
+        !before.effects
+    } else {
+        true
+    }
+}
+
+/// Replaces the read of temporary `id` in `stat` by `value`
+fn substitute(stat: &mut Stat, id: usize, value: Expr) {
+    let mut value = Some(value);
+    let mut replace = |expr: &mut Expr| {
+        if *expr == Expr::Var(Var::Temp(id)) {
+            if let Some(value) = value.take() {
+                *expr = value;
+            }
+        }
+    };
+    match stat {
+        Stat::Call(call) => each_call_mut(call, &mut replace),
+        Stat::Assign(targets, values) => {
+            for target in targets {
+                if let Expr::Index(object, key) = target {
+                    each_mut(object, &mut replace);
+                    each_mut(key, &mut replace);
+                }
+            }
+            for value in values {
+                each_mut(value, &mut replace);
+            }
+        }
+        stat => {
+            for expr in exprs_mut(stat) {
+                each_mut(expr, &mut replace);
+            }
+        }
+    }
+}
+
+/// Moves the values of temporaries assigned once and read once by the
+/// statement right after into that statement
+fn fold(stats: &mut Vec<Stat>, counts: &mut Counts) {
+    for stat in stats.iter_mut() {
+        for block in stat.blocks_mut() {
+            fold(block, counts);
+        }
+    }
+    let mut index = stats.len();
+    while index > 1 {
+        index -= 1;
+        let (previous, next) = stats.split_at_mut(index);
+        let (previous, next) = (&previous[index - 1], &mut next[0]);
+        let folded = match previous {
+            Stat::Assign(targets, values) => match (&targets[..], &values[..]) {
+                ([Expr::Var(Var::Temp(id))], [value])
+                    if counts.single(*id) && can_fold(next, *id, value) =>
+                {
+                    substitute(next, *id, value.clone());
+                    true
+                }
+                (targets, [value @ Expr::Multi(_)]) => fold_results(targets, value, next, counts),
+                _ => false,
+            },
+            _ => false,
+        };
+        if folded {
+            stats.remove(index - 1);
+            index = index.min(stats.len());
+        }
+    }
+}
+
+/// Moves all the results of a call assigned to temporaries into a list
+/// which takes as many values
+fn fold_results(targets: &[Expr], value: &Expr, next: &mut Stat, counts: &Counts) -> bool {
+    let Some(ids) = targets
+        .iter()
+        .map(|target| match target {
+            Expr::Var(Var::Temp(id)) if counts.single(*id) => Some(*id),
+            _ => None,
+        })
+        .collect::<Option<Vec<usize>>>()
+    else {
+        return false;
+    };
+    let reads: Vec<Expr> = ids.iter().map(|&id| Expr::Var(Var::Temp(id))).collect();
+    let values = match next {
+        Stat::Local(vars, values) if vars.len() == values.len() => values,
+        Stat::Assign(targets, values) if targets.len() == values.len() => values,
+        Stat::GenericFor(_, values, _) if values.len() == 3 => values,
+        _ => return false,
+    };
+    if !values.ends_with(&reads) {
+        return false;
+    }
+    let before = values.len() - reads.len();
+    let mut hazards = Hazards::default();
+    for value in &values[..before] {
+        hazards.search(value, usize::MAX, false);
+    }
+    if hazards.effects || hazards.volatile {
+        return false;
+    }
+    values.truncate(before);
+    values.push(value.clone());
+    true
+}
+
+/// Assignments to temporaries which are never read are left out, or
+/// become calls, unless evaluating the value could have other effects
+fn unused(stats: &mut Vec<Stat>, counts: &Counts) {
+    let mut index = 0;
+    while index < stats.len() {
+        for block in stats[index].blocks_mut() {
+            unused(block, counts);
+        }
+        let Stat::Assign(targets, values) = &stats[index] else {
+            index += 1;
+            continue;
+        };
+        let ids: Option<Vec<usize>> = targets
+            .iter()
+            .map(|target| match target {
+                Expr::Var(Var::Temp(id)) if counts.uses(*id) == 0 => Some(*id),
+                _ => None,
+            })
+            .collect();
+        let (Some(_), [value]) = (ids, &values[..]) else {
+            index += 1;
+            continue;
+        };
+        match value.inner() {
+            Expr::Call(call) => stats[index] = Stat::Call((**call).clone()),
+            value
+                if pure(value)
+                    || matches!(value, Expr::Table(_)) && !Hazards::of(value).effects =>
+            {
+                stats.remove(index);
+                continue;
+            }
+            //Kept for its side effects, like the error of indexing nil
+            _ => {}
+        }
+        index += 1;
+    }
+}
+
+/// Temporaries in `stats` and everything nested in them
+fn mentioned(stats: &mut [Stat]) -> HashSet<usize> {
+    let mut temps = HashSet::new();
+    each_in_block(stats, &mut |expr| {
+        if let Expr::Var(Var::Temp(id)) = expr {
+            temps.insert(*id);
+        }
+    });
+    temps
+}
+
+/// Declares the temporaries with a local statement in the innermost
+/// block around all their uses. Those carried from one iteration of a
+/// loop to the next are declared for the whole function
+fn localize(stats: &mut Vec<Stat>, carried: &HashSet<usize>) {
+    let mut declared = HashSet::new();
+    declarations(stats, &mut declared);
+    let temps = mentioned(stats)
+        .into_iter()
+        .filter(|id| !declared.contains(id))
+        .collect();
+    declare(stats, temps, carried, true);
+}
+
+fn declarations(stats: &[Stat], declared: &mut HashSet<usize>) {
+    for stat in stats {
+        if !matches!(stat, Stat::Assign(..)) {
+            declared.extend(defined(stat).into_iter().filter_map(|var| match var {
+                Var::Temp(id) => Some(id),
+                _ => None,
+            }));
+        }
+        for block in stat.blocks() {
+            declarations(block, declared);
+        }
+    }
+}
+
+fn declare(stats: &mut Vec<Stat>, temps: Vec<usize>, carried: &HashSet<usize>, top: bool) {
+    let mentions: Vec<HashSet<usize>> = stats
+        .iter_mut()
+        .map(|stat| mentioned(std::slice::from_mut(stat)))
+        .collect();
+    let labelled = stats.iter().any(|stat| matches!(stat, Stat::Label(_)));
+    let mut nested: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+    let mut here: Vec<(usize, usize)> = Vec::new();
+    for id in temps {
+        let users: Vec<usize> = (0..stats.len())
+            .filter(|&index| mentions[index].contains(&id))
+            .collect();
+        let Some(&first) = users.first() else {
+            continue;
+        };
+        if users.len() == 1 && !(top && carried.contains(&id)) {
+            let stat = &mut stats[first];
+            let mut direct = defined(stat).contains(&Var::Temp(id));
+            if !direct {
+                for expr in exprs_mut(stat) {
+                    direct |=
+                        mentioned(&mut [Stat::Local(vec![], vec![expr.clone()])]).contains(&id);
+                }
+            }
+            let blocks: Vec<usize> = (stat.blocks_mut().into_iter().enumerate())
+                .filter_map(|(index, block)| mentioned(block).contains(&id).then_some(index))
+                .collect();
+            if let (false, [block]) = (direct, &blocks[..]) {
+                nested.entry((first, *block)).or_default().push(id);
+                continue;
+            }
+        }
+        here.push((if labelled { 0 } else { first }, id));
+    }
+
+    for ((index, block), temps) in nested {
+        let mut blocks = stats[index].blocks_mut();
+        declare(blocks.swap_remove(block), temps, carried, false);
+    }
+
+    here.sort();
+    let mut declarations: Vec<(usize, Vec<Var>)> = Vec::new();
+    for (index, id) in here {
+        //The first assignment can declare it
+        if let Stat::Assign(targets, values) = &stats[index] {
+            if let ([Expr::Var(Var::Temp(target))], [value]) = (&targets[..], &values[..]) {
+                if *target == id
+                    && !mentioned(&mut [Stat::Return(vec![value.clone()])]).contains(&id)
+                {
+                    stats[index] = Stat::Local(vec![Var::Temp(id)], values.clone());
+                    continue;
+                }
+            }
+        }
+        match declarations.last_mut() {
+            Some((last, vars)) if *last == index => vars.push(Var::Temp(id)),
+            _ => declarations.push((index, vec![Var::Temp(id)])),
+        }
+    }
+    for (index, vars) in declarations.into_iter().rev() {
+        stats.insert(index, Stat::Local(vars, vec![]));
+    }
+}
@@ -0,0 +1,686 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::bytecode::{binstruction::BInstruction, bproto::BProto, cfg::Cfg};
+
+use super::{
+    ast::{negate, BinOp, Expr, Stat, Var},
+    translate::{Exit, Local, Node, Translator},
+};
+
+/// Label of `continue` statements, at the end of loop bodies
+pub const CONTINUE: &str = "continue";
+
+/// Where control goes from the range being structured
+#[derive(Clone, Copy, Default)]
+struct Context {
+    /// Pc reached by falling off the end of the range
+    follow: Option<usize>,
+    /// Pc after the innermost loop, which break jumps to
+    exit: Option<usize>,
+    /// Pc which `goto continue` jumps to in the innermost loop
+    next: Option<usize>,
+    /// Loop header already being structured
+    header: Option<usize>,
+    /// Node whose condition ends a repeat loop
+    until: Option<usize>,
+}
+
+/// Turns the blocks of a proto back into nested statements
+pub struct Structurer<'a, 't> {
+    proto: &'a BProto,
+    cfg: &'a Cfg<'a>,
+    locals: &'a [Local],
+    translator: &'t mut Translator<'a>,
+    /// Nodes which are still part of the graph, by their first pc
+    nodes: BTreeMap<usize, Node>,
+    /// Pc after the last latch of each loop, by the pc of its header
+    loops: HashMap<usize, usize>,
+    /// Labels some goto jumps to
+    targets: HashSet<String>,
+}
+impl<'a, 't> Structurer<'a, 't> {
+    pub fn new(
+        proto: &'a BProto,
+        cfg: &'a Cfg<'a>,
+        locals: &'a [Local],
+        translator: &'t mut Translator<'a>,
+        nodes: Vec<Node>,
+    ) -> Self {
+        let nodes = nodes
+            .into_iter()
+            .enumerate()
+            .filter(|&(block, _)| cfg.is_reachable(block))
+            .map(|(_, node)| (node.start, node))
+            .collect();
+        let mut structurer = Self {
+            proto,
+            cfg,
+            locals,
+            translator,
+            nodes,
+            loops: HashMap::new(),
+            targets: HashSet::new(),
+        };
+        structurer.absorb_jumps();
+        while structurer.merge_conditions() {}
+        structurer.find_loops();
+        structurer
+    }
+
+    /// The statements of the whole proto
+    pub fn structure(mut self) -> Vec<Stat> {
+        let end = self.proto.instructions.list.len();
+        let (stats, _) = self.walk(0, end, Context::default());
+        let mut stats = self.scope(stats, end);
+        remove_labels(&mut stats, &self.targets);
+        stats
+    }
+
+    fn targets_of(exit: &Exit) -> Vec<usize> {
+        match *exit {
+            Exit::Jump(target) | Exit::ForPrep(target, _) | Exit::ForIn(target, _) => {
+                vec![target]
+            }
+            Exit::Cond(_, t, f) => vec![t, f],
+            Exit::ForLoop(target) | Exit::ForCall(target) => vec![target],
+            Exit::Next | Exit::Return => vec![],
+        }
+    }
+
+    fn successors(&self, node: &Node) -> Vec<usize> {
+        let mut successors = Self::targets_of(&node.exit);
+        match node.exit {
+            Exit::Next | Exit::ForLoop(_) | Exit::ForCall(_) => successors.push(node.end),
+            _ => {}
+        }
+        successors
+    }
+
+    fn predecessors(&self) -> HashMap<usize, usize> {
+        let mut predecessors = HashMap::new();
+        for node in self.nodes.values() {
+            for successor in self.successors(node) {
+                *predecessors.entry(successor).or_insert(0) += 1;
+            }
+        }
+        predecessors
+    }
+
+    /// Tests take the jump after them when their condition holds, which
+    /// becomes where the condition leads
+    fn absorb_jumps(&mut self) {
+        let predecessors = self.predecessors();
+        let starts: Vec<usize> = self.nodes.keys().copied().collect();
+        for start in starts {
+            let Some(&Node {
+                exit: Exit::Cond(_, t, _),
+                ..
+            }) = self.nodes.get(&start)
+            else {
+                continue;
+            };
+            let absorbed = match self.nodes.get(&t) {
+                Some(jump) => {
+                    t == self.nodes[&start].end
+                        && jump.stats.is_empty()
+                        && jump.end == t + 1
+                        && matches!(jump.exit, Exit::Jump(_))
+                        && predecessors.get(&t) == Some(&1)
+                }
+                None => false,
+            };
+            if absorbed {
+                let jump = self.nodes.remove(&t).expect("absorbed node exists");
+                let Exit::Jump(target) = jump.exit else {
+                    unreachable!("only jumps are absorbed")
+                };
+                let node = self.nodes.get_mut(&start).expect("node exists");
+                let Exit::Cond(condition, _, f) = std::mem::replace(&mut node.exit, Exit::Next)
+                else {
+                    unreachable!("only conditions absorb jumps")
+                };
+                node.exit = Exit::Cond(condition, target, f);
+                node.end = jump.end;
+            }
+        }
+    }
+
+    /// Merges a condition tested only after another, with nothing in
+    /// between, into an `and` or `or` of both
+    fn merge_conditions(&mut self) -> bool {
+        let predecessors = self.predecessors();
+        let starts: Vec<usize> = self.nodes.keys().copied().collect();
+        for start in starts {
+            let Some(Node {
+                exit: Exit::Cond(_, tx, fx),
+                ..
+            }) = self.nodes.get(&start)
+            else {
+                continue;
+            };
+            let (tx, fx) = (*tx, *fx);
+            for inner in [tx, fx] {
+                let mergeable = match self.nodes.get(&inner) {
+                    Some(node) => {
+                        inner > start
+                            && node.stats.is_empty()
+                            && matches!(node.exit, Exit::Cond(..))
+                            && predecessors.get(&inner) == Some(&1)
+                    }
+                    None => false,
+                };
+                if !mergeable {
+                    continue;
+                }
+                let Exit::Cond(cy, ty, fy) = &self.nodes[&inner].exit else {
+                    unreachable!("checked above")
+                };
+                let (cy, ty, fy) = (cy.clone(), *ty, *fy);
+                let inner_end = self.nodes[&inner].end;
+                let node = self.nodes.get_mut(&start).expect("node exists");
+                let Exit::Cond(cx, ..) = &node.exit else {
+                    unreachable!("checked above")
+                };
+                let cx = cx.clone();
+                let merged = if inner == tx && fy == fx {
+                    Exit::Cond(Expr::binary(BinOp::And, cx, cy), ty, fx)
+                } else if inner == tx && ty == fx {
+                    Exit::Cond(Expr::binary(BinOp::And, cx, negate(cy)), fy, fx)
+                } else if inner == fx && ty == tx {
+                    Exit::Cond(Expr::binary(BinOp::Or, cx, cy), tx, fy)
+                } else if inner == fx && fy == tx {
+                    Exit::Cond(Expr::binary(BinOp::Or, cx, negate(cy)), tx, ty)
+                } else {
+                    continue;
+                };
+                node.exit = merged;
+                //So the branch after both conditions comes right after the node
+                if node.end == inner {
+                    node.end = inner_end;
+                }
+                self.nodes.remove(&inner);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Headers of loops are jumped back to by a node they dominate. Each
+    /// loop ends after the last node which can get back to its header.
+    /// The jumps of for loops are left to the for statement
+    fn find_loops(&mut self) {
+        let mut predecessors: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut back_edges = Vec::new();
+        for node in self.nodes.values() {
+            for target in self.successors(node) {
+                if !self.nodes.contains_key(&target) {
+                    continue;
+                }
+                predecessors.entry(target).or_default().push(node.start);
+                let from = self.cfg.block_at(node.start);
+                let back = target <= node.start
+                    && !matches!(node.exit, Exit::ForLoop(_) | Exit::ForCall(_))
+                    && self.cfg.is_back_edge(from, self.cfg.block_at(target));
+                if back {
+                    back_edges.push((node.start, target));
+                }
+            }
+        }
+        for (latch, header) in back_edges {
+            let header_block = self.cfg.block_at(header);
+            let mut end = self.nodes[&latch].end;
+            let mut seen = HashSet::from([header, latch]);
+            let mut stack = vec![latch];
+            while let Some(node) = stack.pop() {
+                for &predecessor in predecessors.get(&node).into_iter().flatten() {
+                    let inside = self
+                        .cfg
+                        .dominates(header_block, self.cfg.block_at(predecessor));
+                    if inside && seen.insert(predecessor) {
+                        end = end.max(self.nodes[&predecessor].end);
+                        stack.push(predecessor);
+                    }
+                }
+            }
+            let loop_end = self.loops.entry(header).or_insert(end);
+            *loop_end = (*loop_end).max(end);
+        }
+    }
+
+    /// Follows jumps which do nothing else to where they end up
+    fn resolve(&self, pc: usize) -> usize {
+        self.resolve_from(pc, None)
+    }
+
+    /// Follows jumps like resolve, but not the one of the node at `from`,
+    /// which is where control comes from
+    fn resolve_from(&self, mut pc: usize, from: Option<usize>) -> usize {
+        for _ in 0..self.nodes.len() {
+            //Code which can't be reached is left out
+            if let Some(next) = self.next_node(pc) {
+                pc = next;
+            }
+            if from == Some(pc) {
+                break;
+            }
+            match self.nodes.get(&pc) {
+                Some(Node {
+                    stats,
+                    exit: Exit::Jump(target),
+                    ..
+                }) if stats.is_empty() => pc = *target,
+                _ => break,
+            }
+        }
+        pc
+    }
+
+    fn same(&self, a: usize, b: Option<usize>) -> bool {
+        self.same_from(a, b, None)
+    }
+
+    fn same_from(&self, a: usize, b: Option<usize>, from: Option<usize>) -> bool {
+        b.is_some_and(|b| self.resolve_from(a, from) == self.resolve_from(b, from))
+    }
+
+    /// First node at or after `pc`
+    fn next_node(&self, pc: usize) -> Option<usize> {
+        self.nodes.range(pc..).next().map(|(&start, _)| start)
+    }
+
+    /// Statement for going to `target` at the end of the node from `start`
+    /// to `end`, None if control gets there anyway
+    fn jump(
+        &mut self,
+        start: usize,
+        target: usize,
+        end: usize,
+        to: usize,
+        context: Context,
+    ) -> Option<Stat> {
+        let from = Some(start);
+        match self.next_node(end).filter(|&next| next < to) {
+            Some(next) if self.same_from(target, Some(next), from) => None,
+            None if self.same_from(target, context.follow, from) => None,
+            _ => Some(self.leave(target, context, from)),
+        }
+    }
+
+    /// Statements of the nodes from `from` up to `to`. Also returns the
+    /// condition which ends a repeat loop when it's reached
+    fn walk(
+        &mut self,
+        from: usize,
+        to: usize,
+        context: Context,
+    ) -> (Vec<(usize, Stat)>, Option<Expr>) {
+        let mut stats = Vec::new();
+        let mut pc = from;
+        //Whether control can get from the last node to `pc`
+        let mut falls = true;
+        while let Some(start) = self.next_node(pc).filter(|&start| start < to) {
+            falls = true;
+            if self.loops.contains_key(&start) && context.header != Some(start) {
+                pc = self.structure_loop(start, &mut stats);
+                continue;
+            }
+            stats.push((start, Stat::Label(format!("L{}", start))));
+            //Nodes keep their statements, which tell empty jumps apart
+            let node = &self.nodes[&start];
+            stats.extend(node.stats.iter().cloned());
+            let (end, exit) = (node.end, node.exit.clone());
+            if context.until == Some(start) {
+                let Exit::Cond(condition, t, _) = exit else {
+                    unreachable!("repeat loops end with a condition")
+                };
+                //Leaving the loop is what ends it
+                let condition = match self.same(t, context.exit) {
+                    true => condition,
+                    false => negate(condition),
+                };
+                return (stats, Some(condition));
+            }
+            pc = end;
+            match exit {
+                Exit::Next | Exit::ForLoop(_) | Exit::ForCall(_) => {}
+                Exit::Return => falls = false,
+                Exit::Jump(target) => {
+                    falls = false;
+                    stats.extend(
+                        self.jump(start, target, end, to, context)
+                            .map(|stat| (start, stat)),
+                    )
+                }
+                Exit::Cond(condition, t, f) => {
+                    match self.structure_if(start, end, to, condition, t, f, &mut stats, context) {
+                        Some(next) => pc = next,
+                        None => {
+                            pc = to;
+                            falls = false;
+                        }
+                    }
+                }
+                Exit::ForPrep(target, values) => {
+                    pc = self.structure_numeric_for(start, end, target, values, &mut stats)
+                }
+                Exit::ForIn(target, values) => {
+                    pc = self.structure_generic_for(start, end, target, values, &mut stats)
+                }
+            }
+        }
+        //Where the last node leads isn't where the range leads
+        if falls && pc >= to && !self.same(pc, context.follow) && context.follow.is_some() {
+            let leave = self.leave(pc, context, None);
+            stats.push((pc, leave));
+        }
+        (stats, None)
+    }
+
+    /// Break, continue or goto `target`, from the node at `from` if it's
+    /// an empty jump
+    fn leave(&mut self, target: usize, context: Context, from: Option<usize>) -> Stat {
+        if self.same_from(target, context.exit, from) {
+            return Stat::Break;
+        }
+        if self.same_from(target, context.next, from) {
+            self.targets.insert(CONTINUE.to_owned());
+            return Stat::Goto(CONTINUE.to_owned());
+        }
+        let label = format!("L{}", self.resolve_from(target, from));
+        self.targets.insert(label.clone());
+        Stat::Goto(label)
+    }
+
+    /// If statement of the node from `start` to `end`, which goes to `t`
+    /// when `condition` holds and to `f` otherwise. The branch right
+    /// after the node is the then branch. Returns the pc to go on from,
+    /// None if the if goes on to where the range does
+    #[allow(clippy::too_many_arguments)]
+    fn structure_if(
+        &mut self,
+        start: usize,
+        end: usize,
+        to: usize,
+        condition: Expr,
+        t: usize,
+        f: usize,
+        stats: &mut Vec<(usize, Stat)>,
+        context: Context,
+    ) -> Option<usize> {
+        if self.resolve(t) == self.resolve(f) {
+            stats.extend(
+                self.jump(start, t, end, to, context)
+                    .map(|stat| (start, stat)),
+            );
+            return Some(end);
+        }
+        let (condition, then, other) = if f == end {
+            (negate(condition), f, t)
+        } else if t == end {
+            (condition, t, f)
+        } else {
+            let taken = self.leave(t, context, None);
+            stats.push((start, Stat::If(condition, vec![taken], vec![])));
+            stats.extend(
+                self.jump(start, f, end, to, context)
+                    .map(|stat| (start, stat)),
+            );
+            return Some(end);
+        };
+
+        if other > start && (other < to || self.same(other, context.follow)) {
+            //A then branch which jumps over the code after it has an else
+            let last = self.nodes.range(then..other).next_back();
+            let otherwise = match last {
+                Some((
+                    _,
+                    Node {
+                        exit: Exit::Jump(e),
+                        ..
+                    },
+                )) if *e > other && *e <= to => Some((*e, *e, Some(*e))),
+                Some((
+                    _,
+                    Node {
+                        exit: Exit::Jump(e),
+                        ..
+                    },
+                )) if other < to && self.same(*e, context.follow) && !(then..to).contains(e) => {
+                    Some((to, *e, None))
+                }
+                _ => None,
+            };
+            return match otherwise {
+                Some((else_end, follow, next)) => {
+                    let branch = Context {
+                        follow: Some(follow),
+                        ..context
+                    };
+                    let (then_stats, _) = self.walk(then, other, branch);
+                    let (else_stats, _) = self.walk(other, else_end, branch);
+                    let then_stats = self.scope(then_stats, other);
+                    let else_stats = self.scope(else_stats, else_end);
+                    stats.push((start, Stat::If(condition, then_stats, else_stats)));
+                    next
+                }
+                None => {
+                    let branch = Context {
+                        follow: Some(other),
+                        ..context
+                    };
+                    let (then_stats, _) = self.walk(then, other, branch);
+                    let then_stats = self.scope(then_stats, other);
+                    stats.push((start, Stat::If(condition, then_stats, vec![])));
+                    Some(other)
+                }
+            };
+        }
+        if self.same(other, context.follow) && context.until.is_none() {
+            let (then_stats, _) = self.walk(then, to, context);
+            let then_stats = self.scope(then_stats, to);
+            stats.push((start, Stat::If(condition, then_stats, vec![])));
+            return None;
+        }
+        let leave = self.leave(other, context, None);
+        stats.push((start, Stat::If(negate(condition), vec![leave], vec![])));
+        Some(then)
+    }
+
+    /// Walks the body of a loop, and ends it with the label `continue`
+    /// jumps to if any does
+    fn loop_body(&mut self, from: usize, to: usize, context: Context) -> (Vec<Stat>, Option<Expr>) {
+        let continued = self.targets.remove(CONTINUE);
+        let (stats, until) = self.walk(from, to, context);
+        let mut stats = self.scope(stats, to);
+        if self.targets.contains(CONTINUE) {
+            stats.push(Stat::Label(CONTINUE.to_owned()));
+        }
+        if continued {
+            self.targets.insert(CONTINUE.to_owned());
+        }
+        (stats, until)
+    }
+
+    /// Loop with its header at `header`, returns the pc after it
+    fn structure_loop(&mut self, header: usize, stats: &mut Vec<(usize, Stat)>) -> usize {
+        let end = self.loops[&header];
+        //Locals coming into scope as the loop starts are declared before it
+        let node = self.nodes.get_mut(&header).expect("headers are nodes");
+        let hoisted = node
+            .stats
+            .iter()
+            .take_while(|(pc, stat)| *pc == header && matches!(stat, Stat::Local(..)))
+            .count();
+        stats.extend(node.stats.drain(..hoisted));
+
+        let latch = self
+            .nodes
+            .range(header..end)
+            .next_back()
+            .map(|(&start, _)| start);
+        let body = Context {
+            follow: Some(header),
+            exit: Some(end),
+            next: Some(header),
+            header: Some(header),
+            until: None,
+        };
+        let node = &self.nodes[&header];
+        let repeat = match latch.map(|latch| &self.nodes[&latch].exit) {
+            Some(Exit::Cond(_, t, f)) => {
+                (self.resolve(*t) == header && self.same(*f, Some(end)))
+                    || (self.resolve(*f) == header && self.same(*t, Some(end)))
+            }
+            _ => false,
+        };
+        let stat = if repeat {
+            let context = Context {
+                follow: None,
+                next: None,
+                until: latch,
+                ..body
+            };
+            let (body, until) = self.loop_body(header, end, context);
+            Stat::Repeat(body, until.expect("repeat loops end with a condition"))
+        } else if let (true, Exit::Cond(condition, t, f)) = (node.stats.is_empty(), &node.exit) {
+            let (condition, t, f, next) = (condition.clone(), *t, *f, node.end);
+            if t == next && self.same(f, Some(end)) {
+                let (body, _) = self.loop_body(next, end, body);
+                Stat::While(condition, body)
+            } else if f == next && self.same(t, Some(end)) {
+                let (body, _) = self.loop_body(next, end, body);
+                Stat::While(negate(condition), body)
+            } else {
+                let (body, _) = self.loop_body(header, end, body);
+                Stat::While(Expr::Bool(true), body)
+            }
+        } else {
+            let (body, _) = self.loop_body(header, end, body);
+            Stat::While(Expr::Bool(true), body)
+        };
+        stats.push((header, stat));
+        end
+    }
+
+    /// The local a for loop assigns `register` to in its body from `pc`
+    fn loop_variable(&mut self, pc: usize, register: usize) -> Var {
+        let local = self
+            .locals
+            .iter()
+            .position(|local| local.start == pc && local.register == register && !local.internal);
+        match local {
+            Some(local) => Var::Local(local),
+            None => self.translator.entry(pc, register),
+        }
+    }
+
+    /// Numeric for loop from the FORPREP ending at `end` to its FORLOOP
+    /// at `target`
+    fn structure_numeric_for(
+        &mut self,
+        start: usize,
+        end: usize,
+        target: usize,
+        values: Vec<Expr>,
+        stats: &mut Vec<(usize, Stat)>,
+    ) -> usize {
+        let a = self.proto.instructions.list[end - 1].a();
+        let var = self.loop_variable(end, a + 3);
+        let context = Context {
+            follow: Some(target),
+            exit: Some(target + 1),
+            next: Some(target),
+            header: None,
+            until: None,
+        };
+        let (body, _) = self.loop_body(end, target, context);
+        let [init, limit, step]: [Expr; 3] =
+            values.try_into().expect("for loops have three values");
+        stats.push((start, Stat::NumericFor(var, init, limit, step, body)));
+        target + 1
+    }
+
+    /// Generic for loop from the jump ending at `end` to its TFORCALL at
+    /// `target`
+    fn structure_generic_for(
+        &mut self,
+        start: usize,
+        end: usize,
+        target: usize,
+        values: Vec<Expr>,
+        stats: &mut Vec<(usize, Stat)>,
+    ) -> usize {
+        let BInstruction::ABC { a, c, .. } = self.proto.instructions.list[target] else {
+            unreachable!("TFORCALL is ABC")
+        };
+        let (a, c) = (a as usize, c as usize);
+        let vars = (a + 3..a + 3 + c)
+            .map(|register| self.loop_variable(end, register))
+            .collect();
+        let context = Context {
+            follow: Some(target),
+            exit: Some(target + 2),
+            next: Some(target),
+            header: None,
+            until: None,
+        };
+        let (body, _) = self.loop_body(end, target, context);
+        stats.push((start, Stat::GenericFor(vars, values, body)));
+        target + 2
+    }
+
+    /// Drops the pcs of the statements of a block ending at `end`. Locals
+    /// whose scope ends before the statements after them are wrapped in a
+    /// do block with the statements in their scope
+    fn scope(&self, stats: Vec<(usize, Stat)>, end: usize) -> Vec<Stat> {
+        let mut scoped = Vec::with_capacity(stats.len());
+        let mut stats = stats.into_iter().peekable();
+        while let Some((pc, stat)) = stats.next() {
+            let scope_end = match &stat {
+                Stat::Local(vars, _) => vars
+                    .iter()
+                    .filter_map(|var| match var {
+                        Var::Local(local) => Some(self.locals[*local].end),
+                        _ => None,
+                    })
+                    .max(),
+                _ => None,
+            };
+            let Some(scope_end) = scope_end.filter(|&scope_end| scope_end < end) else {
+                scoped.push(stat);
+                continue;
+            };
+            let mut inner = vec![(pc, stat)];
+            let mut rest = Vec::new();
+            for (pc, stat) in stats.by_ref() {
+                if !rest.is_empty() || (pc >= scope_end && !matches!(stat, Stat::Label(_))) {
+                    rest.push((pc, stat));
+                } else {
+                    inner.push((pc, stat));
+                }
+            }
+            if rest.is_empty() {
+                let (_, stat) = inner.remove(0);
+                scoped.push(stat);
+                scoped.extend(self.scope(inner, end));
+                break;
+            }
+            let (_, local) = inner.remove(0);
+            let mut block = vec![local];
+            block.extend(self.scope(inner, scope_end));
+            scoped.push(Stat::Do(block));
+            stats = rest.into_iter().peekable();
+        }
+        scoped
+    }
+}
+
+/// Removes the labels no goto jumps to
+pub fn remove_labels(stats: &mut Vec<Stat>, targets: &HashSet<String>) {
+    stats.retain(|stat| !matches!(stat, Stat::Label(label) if !targets.contains(label)));
+    for stat in stats {
+        for block in stat.blocks_mut() {
+            remove_labels(block, targets);
+        }
+    }
+}
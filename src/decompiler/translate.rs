@@ -0,0 +1,1375 @@
+use std::collections::HashMap;
+
+use crate::{
+    bytecode::{
+        binstruction::BInstruction,
+        bproto::BProto,
+        cfg::{jump_target, Cfg},
+    },
+    lprimative::LPrimitive,
+};
+
+use super::ast::{negate, BinOp, Call, Expr, Field, Stat, UnOp, Var};
+
+/// sBx is stored as an unsigned Bx offset by MAXARG_sBx, EXTRAARG's Ax
+/// spans both
+const MAXARG_SBX: i64 = ((1 << 18) - 1) >> 1;
+/// Items SETLIST stores per batch
+const FIELDS_PER_FLUSH: i64 = 50;
+
+/// A local variable of the proto, from its debug info
+#[derive(Debug)]
+pub struct Local {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+    pub register: usize,
+    /// The hidden locals of for loops, whose registers are treated like
+    /// temporaries
+    pub internal: bool,
+    /// Parameters and loop variables, which no local statement declares
+    pub implicit: bool,
+}
+impl Local {
+    /// Whether a local statement brings the local into scope
+    pub fn declared(&self) -> bool {
+        !self.internal && !self.implicit && self.start < self.end
+    }
+}
+
+/// The locals of `proto`. Stripped protos only get their parameters, with
+/// empty names
+pub fn locals(proto: &BProto) -> Vec<Local> {
+    let list = &proto.instructions.list;
+    let debug = &proto.debug_local_vars.list;
+    if debug.is_empty() {
+        return (0..proto.num_params as usize)
+            .map(|register| Local {
+                name: String::new(),
+                start: 0,
+                end: list.len(),
+                register,
+                internal: false,
+                implicit: true,
+            })
+            .collect();
+    }
+
+    let mut locals: Vec<Local> = Vec::with_capacity(debug.len());
+    for (index, local) in debug.iter().enumerate() {
+        let start = local.scope_start.max(0) as usize;
+        let end = (local.scope_end.max(0) as usize).min(list.len());
+        //The nth local in scope lives in register n - 1
+        let register = locals
+            .iter()
+            .filter(|other| other.start <= start && start < other.end)
+            .count();
+        let loop_variable = start > 0
+            && match list.get(start - 1) {
+                //FORPREP sets up the loop, whose variable is after its three
+                //hidden locals
+                Some(&BInstruction::AsBx { opcode: 40, a, .. }) => register == a as usize + 3,
+                //The jump of generic for loops to their TFORCALL
+                Some(&BInstruction::AsBx { opcode: 30, b, .. }) => {
+                    let target = (start as i64 + b as i64) as usize;
+                    match list.get(target) {
+                        Some(&BInstruction::ABC {
+                            opcode: 41, a, c, ..
+                        }) => (a as usize + 3..a as usize + 3 + c as usize).contains(&register),
+                        _ => false,
+                    }
+                }
+                _ => false,
+            };
+        locals.push(Local {
+            name: local.local.clone(),
+            start,
+            end,
+            register,
+            internal: local.local.starts_with('('),
+            implicit: index < proto.num_params as usize || loop_variable,
+        });
+    }
+    locals
+}
+
+/// Which local lives in each register at each pc
+pub struct Scopes {
+    registers: Vec<Vec<Option<usize>>>,
+}
+impl Scopes {
+    pub fn new(locals: &[Local], len: usize) -> Self {
+        let mut registers = vec![Vec::new(); len + 1];
+        for (index, local) in locals.iter().enumerate() {
+            for in_scope in &mut registers[local.start..local.end] {
+                if in_scope.len() <= local.register {
+                    in_scope.resize(local.register + 1, None);
+                }
+                in_scope[local.register] = (!local.internal).then_some(index);
+            }
+        }
+        Self { registers }
+    }
+
+    /// The named local in `register` at `pc`, if any
+    pub fn local(&self, pc: usize, register: usize) -> Option<usize> {
+        *self.registers.get(pc)?.get(register)?
+    }
+}
+
+/// Set of registers
+#[derive(Clone, Default, PartialEq)]
+pub struct Registers([u64; 4]);
+impl Registers {
+    pub fn insert(&mut self, register: usize) {
+        self.0[register / 64] |= 1 << (register % 64);
+    }
+
+    pub fn contains(&self, register: usize) -> bool {
+        self.0[register / 64] & (1 << (register % 64)) != 0
+    }
+
+    fn union(&self, other: &Registers) -> Registers {
+        let mut union = self.clone();
+        for (word, other) in union.0.iter_mut().zip(other.0) {
+            *word |= other;
+        }
+        union
+    }
+
+    fn minus(&self, other: &Registers) -> Registers {
+        let mut difference = self.clone();
+        for (word, other) in difference.0.iter_mut().zip(other.0) {
+            *word &= !other;
+        }
+        difference
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..256).filter(|&register| self.contains(register))
+    }
+}
+
+fn rk(operand: u16) -> Option<usize> {
+    match operand & 0x100 {
+        0 => Some(operand as usize),
+        _ => None,
+    }
+}
+
+/// Registers the instruction at `pc` reads and writes. `open` is the
+/// first register of the results of the last call or `...` which left
+/// them all on the stack
+fn operands(proto: &BProto, pc: usize, open: &mut Option<usize>) -> (Vec<usize>, Vec<usize>) {
+    let list = &proto.instructions.list;
+    let (a, b, c) = match list[pc] {
+        BInstruction::ABC { a, b, c, .. } => (a as usize, b as usize, c as usize),
+        BInstruction::ABx { a, b, .. } => (a as usize, b as usize, 0),
+        BInstruction::AsBx { a, .. } => (a as usize, 0, 0),
+    };
+    //Reads up to the open results, from `from`
+    let mut up_to_open = |from: usize, count: usize| match count {
+        0 => (from..=open.take().unwrap_or(from)).collect(),
+        count => (from..from + count - 1).collect::<Vec<_>>(),
+    };
+    match list[pc].opcode() {
+        0 => (vec![b], vec![a]),
+        1..=3 | 5 | 11 => (vec![], vec![a]),
+        4 => (vec![], (a..=a + b).collect()),
+        6 => (rk(c as u16).into_iter().collect(), vec![a]),
+        7 => (
+            [Some(b), rk(c as u16)].into_iter().flatten().collect(),
+            vec![a],
+        ),
+        8 => (
+            [rk(b as u16), rk(c as u16)].into_iter().flatten().collect(),
+            vec![],
+        ),
+        9 | 34 => (vec![a], vec![]),
+        10 => (
+            [Some(a), rk(b as u16), rk(c as u16)]
+                .into_iter()
+                .flatten()
+                .collect(),
+            vec![],
+        ),
+        12 => (
+            [Some(b), rk(c as u16)].into_iter().flatten().collect(),
+            vec![a, a + 1],
+        ),
+        13..=24 => (
+            [rk(b as u16), rk(c as u16)].into_iter().flatten().collect(),
+            vec![a],
+        ),
+        25..=28 => (vec![b], vec![a]),
+        29 => ((b..=c).collect(), vec![a]),
+        30 => {
+            let target = jump_target(pc, &list[pc]).expect("jumps have a target");
+            match (pc.checked_sub(1).map(|pc| list[pc]), list.get(target)) {
+                //TESTSET assigns its register when it takes the jump after it
+                (Some(BInstruction::ABC { opcode: 35, a, .. }), _) => (vec![], vec![a as usize]),
+                //Generic for loops start with the explist in three registers
+                (_, Some(&BInstruction::ABC { opcode: 41, a, .. })) => {
+                    let a = a as usize;
+                    (vec![a, a + 1, a + 2], vec![])
+                }
+                _ => (vec![], vec![]),
+            }
+        }
+        31..=33 => (
+            [rk(b as u16), rk(c as u16)].into_iter().flatten().collect(),
+            vec![],
+        ),
+        35 => (vec![b], vec![]),
+        36 | 37 => {
+            let mut reads = vec![a];
+            reads.extend(up_to_open(a + 1, b));
+            let writes = match (list[pc].opcode(), c) {
+                (37, _) | (_, 0) => {
+                    *open = Some(a);
+                    vec![a]
+                }
+                _ => (a..a + c - 1).collect(),
+            };
+            (reads, writes)
+        }
+        38 => (up_to_open(a, b), vec![]),
+        //The loops themselves keep their state in the hidden registers, which
+        //the for statement takes care of
+        39 => (vec![], vec![a, a + 3]),
+        40 => (vec![a, a + 1, a + 2], vec![a]),
+        41 => (vec![], (a + 3..a + 3 + c).collect()),
+        42 => (vec![], vec![a]),
+        43 => {
+            let mut reads = vec![a];
+            reads.extend(up_to_open(a + 1, b + 1));
+            (reads, vec![])
+        }
+        44 => {
+            //A captured register is a variable, however often it's read
+            let mut reads = Vec::new();
+            for upvalue in &proto.protos.list[b].upvalues.list {
+                if upvalue.stack_flag != 0 && upvalue.index as usize != a {
+                    reads.extend([upvalue.index as usize; 2]);
+                }
+            }
+            (reads, vec![a])
+        }
+        45 => match b {
+            0 => {
+                *open = Some(a);
+                (vec![], vec![a])
+            }
+            b => (vec![], (a..a + b - 1).collect()),
+        },
+        _ => (vec![], vec![]),
+    }
+}
+
+/// How often the value written to a temporary register is read in its
+/// block, and whether it's still needed when the block ends
+#[derive(Clone, Copy)]
+struct Uses {
+    reads: u32,
+    live: bool,
+}
+
+/// Liveness of the temporary registers, and the uses of every value
+/// written to one
+pub struct Liveness {
+    uses: HashMap<(usize, usize), Uses>,
+    pub live_in: Vec<Registers>,
+}
+impl Liveness {
+    pub fn new(proto: &BProto, cfg: &Cfg, locals: &[Local], scopes: &Scopes) -> Self {
+        let list = &proto.instructions.list;
+        let mut uses = HashMap::new();
+        let mut generated = Vec::with_capacity(cfg.blocks.len());
+        let mut killed = Vec::with_capacity(cfg.blocks.len());
+        let mut last_writes = Vec::with_capacity(cfg.blocks.len());
+
+        let mut declared: HashMap<usize, Vec<usize>> = HashMap::new();
+        for local in locals.iter().filter(|local| local.declared()) {
+            declared
+                .entry(local.start)
+                .or_default()
+                .push(local.register);
+        }
+
+        for block in &cfg.blocks {
+            let mut gen = Registers::default();
+            let mut kill = Registers::default();
+            let mut last_write: HashMap<usize, usize> = HashMap::new();
+            let mut constructing = Registers::default();
+            let mut open = None;
+            for (pc, instruction) in (block.start..).zip(&list[block.start..block.end]) {
+                let (mut reads, writes) = operands(proto, pc, &mut open);
+                let opcode = instruction.opcode();
+                //Filling in a table constructor isn't a use of the table
+                if matches!(opcode, 10 | 43) && constructing.contains(reads[0]) {
+                    reads.remove(0);
+                }
+                //A local statement reads the temporaries the locals are
+                //initialized from, instructions read locals themselves
+                reads.retain(|&register| scopes.local(pc, register).is_none());
+                let declarations = declared.get(&pc).into_iter().flatten();
+                for &register in declarations.chain(&reads) {
+                    match last_write.get(&register) {
+                        Some(&write) => {
+                            let uses: &mut Uses = uses.get_mut(&(write, register)).unwrap();
+                            uses.reads += 1;
+                        }
+                        None => gen.insert(register),
+                    }
+                    constructing.0[register / 64] &= !(1 << (register % 64));
+                }
+                for &register in &writes {
+                    if scopes.local(pc, register).is_some() {
+                        continue;
+                    }
+                    kill.insert(register);
+                    last_write.insert(register, pc);
+                    uses.insert(
+                        (pc, register),
+                        Uses {
+                            reads: 0,
+                            live: false,
+                        },
+                    );
+                    match opcode {
+                        11 => constructing.insert(register),
+                        _ => constructing.0[register / 64] &= !(1 << (register % 64)),
+                    }
+                }
+            }
+            generated.push(gen);
+            killed.push(kill);
+            last_writes.push(last_write);
+        }
+
+        let mut live_in = generated.clone();
+        let mut live_out = vec![Registers::default(); cfg.blocks.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for index in (0..cfg.blocks.len()).rev() {
+                let mut out = Registers::default();
+                for &successor in &cfg.blocks[index].successors {
+                    out = out.union(&live_in[successor]);
+                }
+                let input = generated[index].union(&out.minus(&killed[index]));
+                if input != live_in[index] || out != live_out[index] {
+                    live_in[index] = input;
+                    live_out[index] = out;
+                    changed = true;
+                }
+            }
+        }
+
+        for (index, last_write) in last_writes.iter().enumerate() {
+            for (&register, &pc) in last_write {
+                if live_out[index].contains(register) {
+                    uses.get_mut(&(pc, register)).unwrap().live = true;
+                }
+            }
+        }
+        Self { uses, live_in }
+    }
+}
+
+/// Where a temporary value comes from: written by the instruction at a pc,
+/// or live on entry to a block
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Source {
+    Write(usize, usize),
+    Entry(usize, usize),
+}
+
+/// Temporaries, which are merged into webs when a value may come from
+/// either of them
+#[derive(Default)]
+pub struct Temps {
+    ids: HashMap<Source, usize>,
+    parent: Vec<usize>,
+}
+impl Temps {
+    pub fn id(&mut self, source: Source) -> usize {
+        let next = self.parent.len();
+        let id = *self.ids.entry(source).or_insert(next);
+        if id == next {
+            self.parent.push(id);
+        }
+        id
+    }
+
+    pub fn find(&mut self, mut id: usize) -> usize {
+        while self.parent[id] != id {
+            self.parent[id] = self.parent[self.parent[id]];
+            id = self.parent[id];
+        }
+        id
+    }
+
+    pub fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parent[a.max(b)] = a.min(b);
+    }
+}
+
+/// How a block ends
+#[derive(Debug, Clone)]
+pub enum Exit {
+    Next,
+    Jump(usize),
+    /// Goes to the first pc if the condition holds and to the second if not
+    Cond(Expr, usize, usize),
+    Return,
+    /// Jump of a numeric for loop to its FORLOOP, with its start, limit and
+    /// step
+    ForPrep(usize, Vec<Expr>),
+    /// Jump of a generic for loop to its TFORCALL, with the values of its
+    /// explist
+    ForIn(usize, Vec<Expr>),
+    /// FORLOOP back to the body
+    ForLoop(usize),
+    /// TFORCALL and TFORLOOP back to the body
+    ForCall(usize),
+}
+
+/// The statements of a basic block, each with the pc it comes from
+#[derive(Debug)]
+pub struct Node {
+    pub start: usize,
+    pub end: usize,
+    pub stats: Vec<(usize, Stat)>,
+    pub exit: Exit,
+}
+
+/// A value not yet assigned anywhere, which the instruction reading it
+/// will use as part of its expression
+enum Value {
+    Expr(Expr),
+    /// A table constructor and its fields by the pc which set them
+    Table(Vec<(usize, Field)>),
+    /// The function and object SELF looks up
+    Method(Expr, String),
+    SelfArg(Expr),
+    /// One of the results of a call or `...` with more than one
+    Part(usize),
+}
+
+struct Pending {
+    value: Value,
+    /// Pc of the write, which orders the pending values
+    pc: usize,
+    /// A table constructor read more than once, which is assigned to its
+    /// temporary as soon as it's read
+    shared: bool,
+}
+
+struct Group {
+    value: Expr,
+    registers: Vec<usize>,
+}
+
+pub struct Translator<'a> {
+    proto: &'a BProto,
+    cfg: &'a Cfg<'a>,
+    locals: &'a [Local],
+    scopes: &'a Scopes,
+    liveness: &'a Liveness,
+    pub temps: Temps,
+    /// Upvalue which holds the globals
+    env: Option<usize>,
+    /// Whether TESTSET may assign the value its test evaluated
+    tested: bool,
+    /// Value the last TESTSET assigns when it jumps
+    tested_value: Option<Expr>,
+
+    block: usize,
+    pending: HashMap<usize, Pending>,
+    current: HashMap<usize, usize>,
+    open: Option<(usize, Expr)>,
+    groups: Vec<Group>,
+    stats: Vec<(usize, Stat)>,
+}
+impl<'a> Translator<'a> {
+    pub fn new(
+        proto: &'a BProto,
+        cfg: &'a Cfg<'a>,
+        locals: &'a [Local],
+        scopes: &'a Scopes,
+        liveness: &'a Liveness,
+        env: Option<usize>,
+        tested: bool,
+    ) -> Self {
+        Self {
+            proto,
+            cfg,
+            locals,
+            scopes,
+            liveness,
+            temps: Temps::default(),
+            env,
+            tested,
+            tested_value: None,
+            block: 0,
+            pending: HashMap::new(),
+            current: HashMap::new(),
+            open: None,
+            groups: Vec::new(),
+            stats: Vec::new(),
+        }
+    }
+
+    /// Translates every block, then merges the temporaries a value flows
+    /// between across blocks
+    pub fn translate(&mut self) -> Vec<Node> {
+        let mut nodes = Vec::with_capacity(self.cfg.blocks.len());
+        let mut outgoing = Vec::with_capacity(self.cfg.blocks.len());
+        for block in 0..self.cfg.blocks.len() {
+            nodes.push(self.translate_block(block));
+            outgoing.push(std::mem::take(&mut self.current));
+        }
+        for (block, outgoing) in outgoing.iter().enumerate() {
+            for &successor in &self.cfg.blocks[block].successors {
+                for register in self.liveness.live_in[successor].iter() {
+                    let from = match outgoing.get(&register) {
+                        Some(&id) => id,
+                        None => self.temps.id(Source::Entry(block, register)),
+                    };
+                    let to = self.temps.id(Source::Entry(successor, register));
+                    self.temps.union(from, to);
+                }
+            }
+        }
+        nodes
+    }
+
+    /// Temporary of the value `register` holds on entry to the block at
+    /// `pc`, like the variable of a for loop without debug info
+    pub fn entry(&mut self, pc: usize, register: usize) -> Var {
+        let block = self.cfg.block_at(pc);
+        Var::Temp(self.temps.id(Source::Entry(block, register)))
+    }
+
+    fn translate_block(&mut self, block: usize) -> Node {
+        self.block = block;
+        self.pending.clear();
+        self.current.clear();
+        self.open = None;
+        self.groups.clear();
+        let (start, end) = (self.cfg.blocks[block].start, self.cfg.blocks[block].end);
+        for pc in start..end - 1 {
+            self.declare(pc);
+            self.instruction(pc);
+        }
+        self.declare(end - 1);
+        let exit = self.exit(end - 1);
+        self.flush(end - 1);
+        Node {
+            start,
+            end,
+            stats: std::mem::take(&mut self.stats),
+            exit,
+        }
+    }
+
+    /// Local statement for the locals coming into scope at `pc`
+    fn declare(&mut self, pc: usize) {
+        let mut declared: Vec<(usize, usize)> = (self.locals.iter().enumerate())
+            .filter(|(_, local)| local.declared() && local.start == pc)
+            .map(|(index, local)| (local.register, index))
+            .collect();
+        if declared.is_empty() {
+            return;
+        }
+        declared.sort();
+        let registers: Vec<usize> = declared.iter().map(|&(register, _)| register).collect();
+        let mut values = self.read_list(pc, &registers, true);
+        //Locals without a value start as nil, unless a call or `...` before
+        //them would give them its other results
+        while let [.., before, Expr::Nil] | [before @ Expr::Nil] = &values[..] {
+            if matches!(before, Expr::Call(_) | Expr::Vararg) && values.len() > 1 {
+                break;
+            }
+            values.pop();
+        }
+        let vars = declared
+            .into_iter()
+            .map(|(_, index)| Var::Local(index))
+            .collect();
+        self.emit(pc, Stat::Local(vars, values));
+    }
+
+    fn constant(&self, index: usize) -> Expr {
+        match &self.proto.constants.list[index] {
+            LPrimitive::NIL => Expr::Nil,
+            LPrimitive::BOOL(value) => Expr::Bool(*value),
+            LPrimitive::INT(value) => Expr::Int(*value),
+            LPrimitive::FLOAT(value) => Expr::Float(*value),
+            LPrimitive::STRING(value) => Expr::Str(value.to_string()),
+        }
+    }
+
+    fn rk(&mut self, pc: usize, operand: u16) -> Expr {
+        match rk(operand) {
+            Some(register) => self.read(pc, register),
+            None => self.constant((operand & 0xFF) as usize),
+        }
+    }
+
+    /// Ax of the EXTRAARG after `pc`
+    fn extra_arg(&self, pc: usize) -> usize {
+        match self.proto.instructions.list.get(pc + 1) {
+            Some(&BInstruction::AsBx {
+                opcode: 46, a, b, ..
+            }) => (((b as i64 + MAXARG_SBX) << 8) | a as i64) as usize,
+            _ => 0,
+        }
+    }
+
+    fn read(&mut self, pc: usize, register: usize) -> Expr {
+        match self.scopes.local(pc, register) {
+            Some(local) => Expr::Var(Var::Local(local)),
+            None => self.read_temp(pc, register),
+        }
+    }
+
+    fn read_temp(&mut self, pc: usize, register: usize) -> Expr {
+        if self
+            .pending
+            .get(&register)
+            .is_some_and(|pending| pending.shared)
+        {
+            self.flush_register(pc, register);
+        }
+        if let Some(pending) = self.pending.remove(&register) {
+            return match pending.value {
+                Value::Expr(value) => value,
+                Value::Table(fields) => table(fields),
+                Value::Method(object, method) => {
+                    Expr::Index(Box::new(object), Box::new(Expr::Str(method)))
+                }
+                Value::SelfArg(object) => object,
+                Value::Part(group) => {
+                    self.pending.insert(register, pending);
+                    self.flush_group(pc, group);
+                    self.read_temp(pc, register)
+                }
+            };
+        }
+        Expr::Var(self.variable(register))
+    }
+
+    /// Temporary holding `register`, whose value isn't pending
+    fn variable(&mut self, register: usize) -> Var {
+        match self.current.get(&register) {
+            Some(&id) => Var::Temp(id),
+            None => Var::Temp(self.temps.id(Source::Entry(self.block, register))),
+        }
+    }
+
+    /// Values of `registers`, followed by the open results if the list
+    /// reads them. All the results of a call in the last registers become
+    /// the call itself
+    fn read_list(&mut self, pc: usize, registers: &[usize], temps: bool) -> Vec<Expr> {
+        let mut values = Vec::with_capacity(registers.len());
+        let mut index = 0;
+        while index < registers.len() {
+            let register = registers[index];
+            if let Some(Pending {
+                value: Value::Part(group),
+                ..
+            }) = self.pending.get(&register)
+            {
+                let group = *group;
+                let tail = &registers[index..];
+                let parts = &self.groups[group].registers;
+                if tail == &parts[..]
+                    && parts.iter().all(|register| {
+                        matches!(self.pending.get(register), Some(Pending { value: Value::Part(g), .. }) if *g == group)
+                    })
+                {
+                    for register in tail {
+                        self.pending.remove(register);
+                    }
+                    values.push(Expr::Multi(Box::new(self.groups[group].value.clone())));
+                    break;
+                }
+            }
+            values.push(match temps {
+                true => self.read_temp(pc, register),
+                false => self.read(pc, register),
+            });
+            index += 1;
+        }
+        values
+    }
+
+    /// Registers from `from` and the open results, or `count - 1` registers
+    fn read_open(&mut self, pc: usize, from: usize, count: usize) -> Vec<Expr> {
+        match count {
+            0 => {
+                let (start, value) = self.open.take().unwrap_or((from, Expr::Nil));
+                let registers: Vec<usize> = (from..start).collect();
+                let mut values = self.read_list(pc, &registers, false);
+                values.push(value);
+                values
+            }
+            count => {
+                let registers: Vec<usize> = (from..from + count - 1).collect();
+                self.read_list(pc, &registers, false)
+            }
+        }
+    }
+
+    fn write(&mut self, pc: usize, register: usize, value: Expr) {
+        self.write_value(pc, register, Value::Expr(value))
+    }
+
+    fn write_value(&mut self, pc: usize, register: usize, value: Value) {
+        if let Some(local) = self.scopes.local(pc, register) {
+            let value = match value {
+                Value::Expr(value) => value,
+                Value::Table(fields) => table(fields),
+                _ => unreachable!("only SELF writes methods, to temporaries"),
+            };
+            self.emit(
+                pc,
+                Stat::Assign(vec![Expr::Var(Var::Local(local))], vec![value]),
+            );
+            return;
+        }
+        //Whatever was pending in the register is overwritten without being read
+        if self.pending.contains_key(&register) {
+            self.flush_register(pc, register);
+        }
+        let id = self.temps.id(Source::Write(pc, register));
+        self.current.insert(register, id);
+        let uses = self.liveness.uses.get(&(pc, register)).copied();
+        let uses = uses.unwrap_or(Uses {
+            reads: 2,
+            live: true,
+        });
+        match (uses.reads, uses.live, &value) {
+            (1, false, _) => {
+                let shared = false;
+                self.pending.insert(register, Pending { value, pc, shared });
+            }
+            //The fields are set before the table is used
+            (_, _, Value::Table(_)) => {
+                let shared = true;
+                self.pending.insert(register, Pending { value, pc, shared });
+            }
+            (0, false, Value::Expr(value)) if pure(value) => {}
+            _ => {
+                let value = match value {
+                    Value::Expr(value) => value,
+                    Value::Table(fields) => table(fields),
+                    Value::Method(object, method) => {
+                        Expr::Index(Box::new(object), Box::new(Expr::Str(method)))
+                    }
+                    Value::SelfArg(object) => object,
+                    Value::Part(_) => unreachable!("parts are written together"),
+                };
+                self.emit(
+                    pc,
+                    Stat::Assign(vec![Expr::Var(Var::Temp(id))], vec![value]),
+                );
+            }
+        }
+    }
+
+    /// Writes the results of a call or `...` to `count` registers from
+    /// `from`
+    fn write_results(&mut self, pc: usize, from: usize, count: usize, value: Expr) {
+        let registers: Vec<usize> = (from..from + count).collect();
+        let pending = registers.iter().all(|&register| {
+            self.scopes.local(pc, register).is_none()
+                && matches!(
+                    self.liveness.uses.get(&(pc, register)),
+                    Some(Uses {
+                        reads: 1,
+                        live: false
+                    })
+                )
+        });
+        let mut targets = Vec::with_capacity(count);
+        for &register in &registers {
+            if self.pending.contains_key(&register) {
+                self.flush_register(pc, register);
+            }
+            targets.push(match self.scopes.local(pc, register) {
+                Some(local) => Expr::Var(Var::Local(local)),
+                None => {
+                    let id = self.temps.id(Source::Write(pc, register));
+                    self.current.insert(register, id);
+                    Expr::Var(Var::Temp(id))
+                }
+            });
+        }
+        if pending {
+            self.groups.push(Group { value, registers });
+            let group = self.groups.len() - 1;
+            for register in from..from + count {
+                self.pending.insert(
+                    register,
+                    Pending {
+                        value: Value::Part(group),
+                        pc,
+                        shared: false,
+                    },
+                );
+            }
+        } else {
+            self.emit(
+                pc,
+                Stat::Assign(targets, vec![Expr::Multi(Box::new(value))]),
+            );
+        }
+    }
+
+    /// Assigns every pending value which a statement could change or be
+    /// changed by to its temporary, in the order they were written, then
+    /// adds the statement
+    fn emit(&mut self, pc: usize, stat: Stat) {
+        self.flush(pc);
+        self.stats.push((pc, stat));
+    }
+
+    fn flush(&mut self, pc: usize) {
+        let mut registers: Vec<(usize, usize)> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| match &pending.value {
+                Value::Expr(value) => !pure(value) || matches!(value, Expr::Var(_)),
+                Value::Table(_) | Value::Part(_) => true,
+                Value::Method(..) | Value::SelfArg(_) => false,
+            })
+            .map(|(&register, pending)| (pending.pc, register))
+            .collect();
+        registers.sort();
+        for (_, register) in registers {
+            if self.pending.contains_key(&register) {
+                self.flush_register(pc, register);
+            }
+        }
+    }
+
+    fn flush_register(&mut self, pc: usize, register: usize) {
+        let Some(pending) = self.pending.remove(&register) else {
+            return;
+        };
+        let value = match pending.value {
+            Value::Expr(value) => value,
+            Value::Table(fields) => table(fields),
+            Value::Method(object, method) => {
+                Expr::Index(Box::new(object), Box::new(Expr::Str(method)))
+            }
+            Value::SelfArg(object) => object,
+            Value::Part(group) => {
+                self.pending.insert(register, pending);
+                self.flush_group(pc, group);
+                return;
+            }
+        };
+        let var = self.variable(register);
+        self.stats
+            .push((pc, Stat::Assign(vec![Expr::Var(var)], vec![value])));
+    }
+
+    fn flush_group(&mut self, pc: usize, group: usize) {
+        let registers = self.groups[group].registers.clone();
+        let mut targets = Vec::with_capacity(registers.len());
+        for register in registers {
+            self.pending.remove(&register);
+            targets.push(Expr::Var(self.variable(register)));
+        }
+        let value = self.groups[group].value.clone();
+        self.stats.push((
+            pc,
+            Stat::Assign(targets, vec![Expr::Multi(Box::new(value))]),
+        ));
+    }
+
+    fn instruction(&mut self, pc: usize) {
+        let instruction = self.proto.instructions.list[pc];
+        let (a, b, c) = match instruction {
+            BInstruction::ABC { a, b, c, .. } => (a as usize, b as usize, c as usize),
+            BInstruction::ABx { a, b, .. } => (a as usize, b as usize, 0),
+            BInstruction::AsBx { a, .. } => (a as usize, 0, 0),
+        };
+        match instruction.opcode() {
+            //MOVE
+            0 => {
+                let value = self.read(pc, b);
+                self.write(pc, a, value);
+            }
+            //LOADK and LOADKX
+            1 => self.write(pc, a, self.constant(b)),
+            2 => self.write(pc, a, self.constant(self.extra_arg(pc))),
+            //LOADBOOL
+            3 => self.write(pc, a, Expr::Bool(b != 0)),
+            //LOADNIL
+            4 => {
+                for register in a..=a + b {
+                    self.write(pc, register, Expr::Nil);
+                }
+            }
+            //GETUPVAL
+            5 => self.write(pc, a, Expr::Var(Var::Upvalue(b))),
+            //GETTABUP
+            6 => {
+                let key = self.rk(pc, c as u16);
+                let value = self.upvalue_index(b, key);
+                self.write(pc, a, value);
+            }
+            //GETTABLE
+            7 => {
+                let object = self.read(pc, b);
+                let key = self.rk(pc, c as u16);
+                self.write(pc, a, Expr::Index(Box::new(object), Box::new(key)));
+            }
+            //SETTABUP
+            8 => {
+                let key = self.rk(pc, b as u16);
+                let value = self.rk(pc, c as u16);
+                let target = self.upvalue_index(a, key);
+                self.emit(pc, Stat::Assign(vec![target], vec![value]));
+            }
+            //SETUPVAL
+            9 => {
+                let value = self.read(pc, a);
+                let target = Expr::Var(Var::Upvalue(b));
+                self.emit(pc, Stat::Assign(vec![target], vec![value]));
+            }
+            //SETTABLE
+            10 => {
+                let key = self.rk(pc, b as u16);
+                let value = self.rk(pc, c as u16);
+                if let Some(Pending {
+                    value: Value::Table(fields),
+                    ..
+                }) = self.pending.get_mut(&a)
+                {
+                    fields.push((pc, Field::Pair(key, value)));
+                    return;
+                }
+                let object = self.read(pc, a);
+                let target = Expr::Index(Box::new(object), Box::new(key));
+                self.emit(pc, Stat::Assign(vec![target], vec![value]));
+            }
+            //NEWTABLE
+            11 => self.write_value(pc, a, Value::Table(Vec::new())),
+            //SELF
+            12 => {
+                let object = self.read(pc, b);
+                let key = self.rk(pc, c as u16);
+                match key {
+                    Expr::Str(method) if super::ast::is_name(&method) => {
+                        self.pending.insert(
+                            a + 1,
+                            Pending {
+                                value: Value::SelfArg(object.clone()),
+                                pc,
+                                shared: false,
+                            },
+                        );
+                        self.pending.insert(
+                            a,
+                            Pending {
+                                value: Value::Method(object, method),
+                                pc,
+                                shared: false,
+                            },
+                        );
+                    }
+                    key => {
+                        //The object is used twice, so it needs a name
+                        let object = match object {
+                            Expr::Var(var) => Expr::Var(var),
+                            object => {
+                                let id = self.temps.id(Source::Write(pc, a + 1));
+                                self.current.insert(a + 1, id);
+                                let var = Expr::Var(Var::Temp(id));
+                                self.emit(pc, Stat::Assign(vec![var.clone()], vec![object]));
+                                var
+                            }
+                        };
+                        self.write(pc, a + 1, object.clone());
+                        self.write(pc, a, Expr::Index(Box::new(object), Box::new(key)));
+                    }
+                }
+            }
+            //ADD to SHR
+            opcode @ 13..=24 => {
+                let left = self.rk(pc, b as u16);
+                let right = self.rk(pc, c as u16);
+                let op = BinOp::ARITH[opcode as usize - 13];
+                self.write(pc, a, Expr::binary(op, left, right));
+            }
+            //UNM, BNOT, NOT and LEN
+            opcode @ 25..=28 => {
+                let operand = self.read(pc, b);
+                let op = [UnOp::Neg, UnOp::BNot, UnOp::Not, UnOp::Len][opcode as usize - 25];
+                self.write(pc, a, Expr::unary(op, operand));
+            }
+            //CONCAT, which is right associative
+            29 => {
+                let registers: Vec<usize> = (b..=c).collect();
+                let mut values = self.read_list(pc, &registers, false);
+                let mut value = values.pop().expect("CONCAT has operands");
+                while let Some(left) = values.pop() {
+                    value = Expr::binary(BinOp::Concat, left, value);
+                }
+                self.write(pc, a, value);
+            }
+            //CALL and TAILCALL
+            opcode @ (36 | 37) => {
+                let call = self.call(pc, a, b);
+                match (opcode, c) {
+                    (37, _) | (_, 0) => {
+                        self.open = Some((a, Expr::Multi(Box::new(call))));
+                    }
+                    (_, 1) => match call {
+                        Expr::Call(call) => self.emit(pc, Stat::Call(*call)),
+                        _ => unreachable!("calls are Call expressions"),
+                    },
+                    (_, 2) => self.write(pc, a, call),
+                    (_, c) => self.write_results(pc, a, c - 1, call),
+                }
+            }
+            //RETURN
+            38 => {
+                let values = self.read_open(pc, a, b);
+                self.emit(pc, Stat::Return(values));
+            }
+            //SETLIST
+            43 => self.set_list(pc, a, b, c),
+            //CLOSURE
+            44 => {
+                let mut captures = Vec::new();
+                for upvalue in &self.proto.protos.list[b].upvalues.list {
+                    let index = upvalue.index as usize;
+                    captures.push(match upvalue.stack_flag {
+                        0 => Var::Upvalue(index),
+                        _ if index == a => self.own_local(pc, a),
+                        _ => match self.scopes.local(pc, index) {
+                            Some(local) => Var::Local(local),
+                            None => {
+                                self.flush_register(pc, index);
+                                self.variable(index)
+                            }
+                        },
+                    });
+                }
+                let id = self.temps.id(Source::Write(pc, a));
+                let own = captures.contains(&Var::Temp(id));
+                let function = Expr::Function(b, captures);
+                match own {
+                    //A closure referring to itself has to be assigned first
+                    true => {
+                        if self.pending.contains_key(&a) {
+                            self.flush_register(pc, a);
+                        }
+                        self.current.insert(a, id);
+                        self.emit(
+                            pc,
+                            Stat::Assign(vec![Expr::Var(Var::Temp(id))], vec![function]),
+                        );
+                    }
+                    false => self.write(pc, a, function),
+                }
+            }
+            //VARARG
+            45 => match b {
+                0 => self.open = Some((a, Expr::Multi(Box::new(Expr::Vararg)))),
+                2 => self.write(pc, a, Expr::Vararg),
+                b => self.write_results(pc, a, b - 1, Expr::Vararg),
+            },
+            //JMP, tests, loops and EXTRAARG are handled by exit
+            _ => {}
+        }
+    }
+
+    /// The variable a closure in `register` at `pc` captures when it
+    /// refers to itself, the local it's about to be assigned to
+    fn own_local(&mut self, pc: usize, register: usize) -> Var {
+        let local = self.locals.iter().position(|local| {
+            local.declared() && local.start == pc + 1 && local.register == register
+        });
+        match local {
+            Some(local) => Var::Local(local),
+            None => Var::Temp(self.temps.id(Source::Write(pc, register))),
+        }
+    }
+
+    fn upvalue_index(&self, upvalue: usize, key: Expr) -> Expr {
+        match key {
+            Expr::Str(name) if self.env == Some(upvalue) => Expr::Global(name),
+            key => Expr::Index(Box::new(Expr::Var(Var::Upvalue(upvalue))), Box::new(key)),
+        }
+    }
+
+    fn call(&mut self, pc: usize, a: usize, b: usize) -> Expr {
+        let (func, method) = match self.pending.remove(&a) {
+            Some(Pending {
+                value: Value::Method(object, method),
+                ..
+            }) => {
+                self.pending.remove(&(a + 1));
+                (object, Some(method))
+            }
+            Some(pending) => {
+                self.pending.insert(a, pending);
+                (self.read(pc, a), None)
+            }
+            None => (self.read(pc, a), None),
+        };
+        let from = a + 1 + method.is_some() as usize;
+        let count = match b {
+            0 => 0,
+            b => b - method.is_some() as usize,
+        };
+        let args = self.read_open(pc, from, count);
+        Expr::Call(Box::new(Call { func, method, args }))
+    }
+
+    fn set_list(&mut self, pc: usize, a: usize, b: usize, c: usize) {
+        let batch = match c {
+            0 => self.extra_arg(pc),
+            c => c,
+        } as i64;
+        //Where the items were computed, before reading them takes them
+        let count = match b {
+            0 => self.open.as_ref().map_or(1, |&(start, _)| start - a),
+            b => b,
+        };
+        let written: Vec<Option<usize>> = (a + 1..a + 1 + count)
+            .map(|register| self.pending.get(&register).map(|pending| pending.pc))
+            .collect();
+        let values = match b {
+            0 => self.read_open(pc, a + 1, 0),
+            b => self.read_open(pc, a + 1, b + 1),
+        };
+        let first = (batch - 1) * FIELDS_PER_FLUSH + 1;
+        if let Some(Pending {
+            value: Value::Table(fields),
+            ..
+        }) = self.pending.get_mut(&a)
+        {
+            //Items are stored last, but were evaluated before the fields after
+            //them. Each item is ordered by the instruction which computed it,
+            //or else by the instruction after the ones which computed it
+            let orders = (pc.saturating_sub(values.len())..)
+                .zip(written.into_iter().chain(std::iter::repeat(None)))
+                .map(|(after, written)| written.unwrap_or(after));
+            fields.extend(
+                orders
+                    .zip(values)
+                    .map(|(order, value)| (order, Field::Item(value))),
+            );
+            fields.sort_by_key(|&(pc, _)| pc);
+            return;
+        }
+        let table = self.read(pc, a);
+        let open = matches!(values.last(), Some(Expr::Multi(_)));
+        if !open {
+            let targets = (0..values.len() as i64)
+                .map(|index| {
+                    Expr::Index(Box::new(table.clone()), Box::new(Expr::Int(first + index)))
+                })
+                .collect();
+            self.emit(pc, Stat::Assign(targets, values));
+            return;
+        }
+        //Moves any number of values into the table
+        let pack = Expr::Call(Box::new(Call {
+            func: Expr::Index(
+                Box::new(Expr::Global("table".to_owned())),
+                Box::new(Expr::Str("pack".to_owned())),
+            ),
+            method: None,
+            args: values,
+        }));
+        let id = self.temps.id(Source::Write(pc, a + 1));
+        let packed = Expr::Var(Var::Temp(id));
+        self.emit(pc, Stat::Local(vec![Var::Temp(id)], vec![pack]));
+        let count = Expr::Index(
+            Box::new(packed.clone()),
+            Box::new(Expr::Str("n".to_owned())),
+        );
+        self.emit(
+            pc,
+            Stat::Call(Call {
+                func: Expr::Index(
+                    Box::new(Expr::Global("table".to_owned())),
+                    Box::new(Expr::Str("move".to_owned())),
+                ),
+                method: None,
+                args: vec![packed, Expr::Int(1), count, Expr::Int(first), table],
+            }),
+        );
+    }
+
+    /// How the block ending at `pc` ends. Tests jump when the instruction
+    /// after them is taken
+    /// The constant the instruction before the TEST at `pc` loaded into
+    /// `register`, when the register isn't read again unless TEST jumps
+    fn loaded_constant(&self, pc: usize, register: usize) -> Option<Expr> {
+        let id = *self.current.get(&register)?;
+        let (at, Stat::Assign(targets, values)) = self.stats.last()? else {
+            return None;
+        };
+        let constant = match (&targets[..], &values[..]) {
+            ([Expr::Var(Var::Temp(target))], [value]) if *target == id => value,
+            _ => return None,
+        };
+        let skipped = self.cfg.block_at(pc + 2);
+        let constant_load = *at == pc - 1
+            && matches!(
+                constant,
+                Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Float(_) | Expr::Str(_)
+            );
+        (constant_load && !self.liveness.live_in[skipped].contains(register))
+            .then(|| constant.clone())
+    }
+
+    fn exit(&mut self, pc: usize) -> Exit {
+        let instruction = self.proto.instructions.list[pc];
+        let (a, b, c) = match instruction {
+            BInstruction::ABC { a, b, c, .. } => (a as usize, b as usize, c as usize),
+            BInstruction::ABx { a, b, .. } => (a as usize, b as usize, 0),
+            BInstruction::AsBx { a, .. } => (a as usize, 0, 0),
+        };
+        let target = jump_target(pc, &instruction);
+        match instruction.opcode() {
+            30 => {
+                let target = target.expect("jumps have a target");
+                if let Some(&BInstruction::ABC { opcode: 41, a, .. }) =
+                    self.proto.instructions.list.get(target)
+                {
+                    let a = a as usize;
+                    let mut values = self.read_list(pc, &[a, a + 1, a + 2], false);
+                    //The explist is adjusted to three values anyway
+                    while values.len() > 1 && values.last() == Some(&Expr::Nil) {
+                        values.pop();
+                    }
+                    return Exit::ForIn(target, values);
+                }
+                //TESTSET assigns its register when it jumps
+                match pc.checked_sub(1).map(|pc| self.proto.instructions.list[pc]) {
+                    Some(BInstruction::ABC {
+                        opcode: 35, a, b, ..
+                    }) => {
+                        if let Some(value) = self.tested_value.take() {
+                            self.write(pc, a as usize, value);
+                        } else {
+                            let value = self.read(pc - 1, b as usize);
+                            self.write(pc, a as usize, value);
+                        }
+                    }
+                    //So does a TEST of a constant, which was only loaded
+                    //for this jump
+                    Some(BInstruction::ABC { opcode: 34, a, .. }) => {
+                        if let Some(value) = self.tested_value.take() {
+                            self.write(pc, a as usize, value);
+                        }
+                    }
+                    _ => {}
+                }
+                Exit::Jump(target)
+            }
+            opcode @ 31..=33 => {
+                let left = self.rk(pc, b as u16);
+                let right = self.rk(pc, c as u16);
+                let op = [BinOp::Eq, BinOp::Lt, BinOp::Le][opcode as usize - 31];
+                let comparison = Expr::binary(op, left, right);
+                let condition = match a {
+                    0 => negate(comparison),
+                    _ => comparison,
+                };
+                Exit::Cond(condition, pc + 1, pc + 2)
+            }
+            34 => {
+                let value = match self.loaded_constant(pc, a) {
+                    //Tested like a TESTSET of the constant, which luac can't
+                    //emit, so the and or or can be put back together
+                    Some(constant) => {
+                        self.stats.pop();
+                        self.tested_value = Some(constant.clone());
+                        constant
+                    }
+                    None => self.read(pc, a),
+                };
+                Exit::Cond(truthy(value, c), pc + 1, pc + 2)
+            }
+            35 => {
+                let mut value = self.read(pc, b);
+                if !pure(&value) && !matches!(value, Expr::Var(_)) {
+                    if self.tested {
+                        self.tested_value = Some(Expr::Tested(Box::new(value.clone())));
+                    } else {
+                        let id = self.temps.id(Source::Write(pc, b));
+                        let var = Expr::Var(Var::Temp(id));
+                        self.emit(pc, Stat::Assign(vec![var.clone()], vec![value]));
+                        value = var;
+                        self.tested_value = Some(value.clone());
+                    }
+                } else {
+                    self.tested_value = Some(value.clone());
+                }
+                Exit::Cond(truthy(value, c), pc + 1, pc + 2)
+            }
+            3 if c != 0 => {
+                self.instruction(pc);
+                Exit::Jump(pc + 2)
+            }
+            38 => {
+                self.instruction(pc);
+                Exit::Return
+            }
+            39 => Exit::ForLoop(target.expect("loops have a target")),
+            40 => {
+                let values = self.read_list(pc, &[a, a + 1, a + 2], false);
+                Exit::ForPrep(target.expect("loops have a target"), values)
+            }
+            42 => Exit::ForCall(target.expect("loops have a target")),
+            _ => {
+                self.instruction(pc);
+                Exit::Next
+            }
+        }
+    }
+}
+
+/// A condition which holds when the truthiness of `value` is `c`
+fn truthy(value: Expr, c: usize) -> Expr {
+    match c {
+        0 => negate(value),
+        _ => value,
+    }
+}
+
+fn table(mut fields: Vec<(usize, Field)>) -> Expr {
+    fields.sort_by_key(|&(pc, _)| pc);
+    Expr::Table(fields.into_iter().map(|(_, field)| field).collect())
+}
+
+/// Evaluating the expression has no effect and gives the same value
+/// wherever it's moved to, as long as no variable is assigned in between
+pub fn pure(expr: &Expr) -> bool {
+    match expr {
+        Expr::Nil
+        | Expr::Bool(_)
+        | Expr::Int(_)
+        | Expr::Float(_)
+        | Expr::Str(_)
+        | Expr::Vararg
+        | Expr::Var(_)
+        | Expr::Function(..) => true,
+        Expr::Multi(inner) | Expr::Tested(inner) => pure(inner),
+        _ => false,
+    }
+}
@@ -0,0 +1,291 @@
+use std::{cmp::Ordering, rc::Rc};
+
+use crate::lprimative::{float_to_integer, LPrimitive, LValue};
+
+/// Arithmetic and bitwise operators, in the same order as their opcodes
+/// ADD..=BNOT
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    Unm,
+    BNot,
+}
+impl ArithOp {
+    pub fn from_opcode(opcode: u8) -> Self {
+        use ArithOp::*;
+        match opcode {
+            13 => Add,
+            14 => Sub,
+            15 => Mul,
+            16 => Mod,
+            17 => Pow,
+            18 => Div,
+            19 => IDiv,
+            20 => BAnd,
+            21 => BOr,
+            22 => BXor,
+            23 => Shl,
+            24 => Shr,
+            25 => Unm,
+            26 => BNot,
+            n => panic!("Opcode {} is not an arithmetic operation", n),
+        }
+    }
+
+    fn is_bitwise(self) -> bool {
+        use ArithOp::*;
+        matches!(self, BAnd | BOr | BXor | Shl | Shr | BNot)
+    }
+}
+
+/// Performs a primitive arithmetic or bitwise operation with Lua 5.3
+/// semantics. Unary operators ignore `rhs`. On failure the error message
+/// is returned without position information
+pub fn arith(op: ArithOp, lhs: &LPrimitive, rhs: &LPrimitive) -> Result<LPrimitive, String> {
+    use ArithOp::*;
+
+    if op.is_bitwise() {
+        let (x, y) = match (lhs.to_integer(), rhs.to_integer()) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return Err(bitwise_error(lhs, rhs)),
+        };
+        return Ok(LPrimitive::INT(match op {
+            BAnd => x & y,
+            BOr => x | y,
+            BXor => x ^ y,
+            Shl => shift_left(x, y),
+            Shr => shift_left(x, y.wrapping_neg()),
+            BNot => !x,
+            _ => unreachable!("op is bitwise"),
+        }));
+    }
+
+    let (x, y) = match (lhs.to_number(), rhs.to_number()) {
+        (Some(x), Some(y)) => (x, y),
+        (None, _) => return Err(arith_error(lhs)),
+        (_, None) => return Err(arith_error(rhs)),
+    };
+
+    //Integer operations stay integers, except for the two divisions. Strings
+    //coerced to numbers always take the float path
+    if let (LPrimitive::INT(x), LPrimitive::INT(y)) = (lhs, rhs) {
+        let (x, y) = (*x, *y);
+        match op {
+            Add => return Ok(LPrimitive::INT(x.wrapping_add(y))),
+            Sub => return Ok(LPrimitive::INT(x.wrapping_sub(y))),
+            Mul => return Ok(LPrimitive::INT(x.wrapping_mul(y))),
+            Unm => return Ok(LPrimitive::INT(x.wrapping_neg())),
+            Mod => {
+                if y == 0 {
+                    return Err("attempt to perform 'n%0'".to_owned());
+                }
+                return Ok(LPrimitive::INT(int_mod(x, y)));
+            }
+            IDiv => {
+                if y == 0 {
+                    return Err("attempt to divide by zero".to_owned());
+                }
+                return Ok(LPrimitive::INT(int_floor_div(x, y)));
+            }
+            _ => {}
+        }
+    }
+
+    let (x, y) = (
+        x.to_float().expect("x is a number"),
+        y.to_float().expect("y is a number"),
+    );
+    Ok(LPrimitive::FLOAT(match op {
+        Add => x + y,
+        Sub => x - y,
+        Mul => x * y,
+        Div => x / y,
+        Pow => x.powf(y),
+        IDiv => (x / y).floor(),
+        Mod => float_mod(x, y),
+        Unm => -x,
+        _ => unreachable!("bitwise operators handled above"),
+    }))
+}
+
+fn arith_error(culprit: &LPrimitive) -> String {
+    format!(
+        "attempt to perform arithmetic on a {} value",
+        culprit.type_name()
+    )
+}
+
+fn bitwise_error(lhs: &LPrimitive, rhs: &LPrimitive) -> String {
+    if lhs.to_number().is_some() && rhs.to_number().is_some() {
+        return "number has no integer representation".to_owned();
+    }
+    let culprit = if lhs.to_number().is_none() { lhs } else { rhs };
+    format!(
+        "attempt to perform bitwise operation on a {} value",
+        culprit.type_name()
+    )
+}
+
+fn shift_left(x: i64, y: i64) -> i64 {
+    if y <= -64 || y >= 64 {
+        0
+    } else if y >= 0 {
+        ((x as u64) << y) as i64
+    } else {
+        ((x as u64) >> -y) as i64
+    }
+}
+
+fn int_mod(x: i64, y: i64) -> i64 {
+    if y == -1 {
+        return 0; //Avoids overflow with i64::MIN % -1
+    }
+    let m = x % y;
+    if m != 0 && (m ^ y) < 0 {
+        m + y
+    } else {
+        m
+    }
+}
+
+fn int_floor_div(x: i64, y: i64) -> i64 {
+    if y == -1 {
+        return x.wrapping_neg(); //Avoids overflow with i64::MIN / -1
+    }
+    let q = x / y;
+    if (x % y != 0) && ((x ^ y) < 0) {
+        q - 1
+    } else {
+        q
+    }
+}
+
+fn float_mod(x: f64, y: f64) -> f64 {
+    let m = x % y;
+    if m * y < 0.0 {
+        m + y
+    } else {
+        m
+    }
+}
+
+/// Primitive equality as performed by EQ without metamethods. Integers
+/// and floats compare by mathematical value
+pub fn raw_equals(lhs: &LPrimitive, rhs: &LPrimitive) -> bool {
+    match (lhs, rhs) {
+        (LPrimitive::NIL, LPrimitive::NIL) => true,
+        (LPrimitive::BOOL(x), LPrimitive::BOOL(y)) => x == y,
+        (LPrimitive::STRING(x), LPrimitive::STRING(y)) => x == y,
+        (LPrimitive::INT(_) | LPrimitive::FLOAT(_), LPrimitive::INT(_) | LPrimitive::FLOAT(_)) => {
+            compare_numbers(lhs, rhs) == Some(Ordering::Equal)
+        }
+        _ => false,
+    }
+}
+
+/// Ordering used by LT and LE. Only numbers with numbers and strings with
+/// strings are comparable
+pub fn compare(lhs: &LPrimitive, rhs: &LPrimitive) -> Result<Option<Ordering>, String> {
+    match (lhs, rhs) {
+        (LPrimitive::STRING(x), LPrimitive::STRING(y)) => Ok(Some(x.as_bytes().cmp(y.as_bytes()))),
+        (LPrimitive::INT(_) | LPrimitive::FLOAT(_), LPrimitive::INT(_) | LPrimitive::FLOAT(_)) => {
+            Ok(compare_numbers(lhs, rhs))
+        }
+        _ => Err(compare_error(lhs.type_name(), rhs.type_name())),
+    }
+}
+
+/// Compares two numbers exactly, without losing precision when mixing
+/// integers and floats. None when either is NaN
+fn compare_numbers(lhs: &LPrimitive, rhs: &LPrimitive) -> Option<Ordering> {
+    match (lhs, rhs) {
+        (LPrimitive::INT(x), LPrimitive::INT(y)) => Some(x.cmp(y)),
+        (LPrimitive::FLOAT(x), LPrimitive::FLOAT(y)) => x.partial_cmp(y),
+        (LPrimitive::INT(x), LPrimitive::FLOAT(y)) => compare_int_float(*x, *y),
+        (LPrimitive::FLOAT(x), LPrimitive::INT(y)) => {
+            compare_int_float(*y, *x).map(Ordering::reverse)
+        }
+        _ => unreachable!("compare_numbers called with non-numbers"),
+    }
+}
+
+fn compare_int_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        return None;
+    }
+    match float_to_integer(f.floor()) {
+        //The float's floor fits an integer so compare in the integer domain
+        Some(floor) => Some(match i.cmp(&floor) {
+            Ordering::Equal if f.floor() != f => Ordering::Less,
+            ordering => ordering,
+        }),
+        //Out of integer range so every integer lies on one side
+        None if f > 0.0 => Some(Ordering::Less),
+        None => Some(Ordering::Greater),
+    }
+}
+
+/// Arithmetic on any two values. Only primitives support arithmetic
+pub fn arith_values<'i>(
+    op: ArithOp,
+    lhs: &LValue<'i>,
+    rhs: &LValue<'i>,
+) -> Result<LValue<'i>, String> {
+    match (lhs, rhs) {
+        (LValue::LPrimitive(x), LValue::LPrimitive(y)) => arith(op, x, y).map(LValue::LPrimitive),
+        _ => {
+            //Blame the first operand which isn't a number
+            let culprit = match lhs {
+                LValue::LPrimitive(p) if p.to_number().is_some() => rhs,
+                _ => lhs,
+            };
+            Err(format!(
+                "attempt to perform {} on a {} value",
+                if op.is_bitwise() {
+                    "bitwise operation"
+                } else {
+                    "arithmetic"
+                },
+                culprit.type_name()
+            ))
+        }
+    }
+}
+
+/// Equality of any two values. Functions and tables are compared by identity
+pub fn equals_values<'i>(lhs: &LValue<'i>, rhs: &LValue<'i>) -> bool {
+    match (lhs, rhs) {
+        (LValue::LPrimitive(x), LValue::LPrimitive(y)) => raw_equals(x, y),
+        (LValue::LClosure(x), LValue::LClosure(y)) => Rc::ptr_eq(x, y),
+        (LValue::CClosure(x), LValue::CClosure(y)) => std::ptr::fn_addr_eq(**x, **y),
+        (LValue::Table(x), LValue::Table(y)) => Rc::ptr_eq(x, y),
+        _ => false,
+    }
+}
+
+/// Ordering of any two values as used by LT and LE
+pub fn compare_values(lhs: &LValue, rhs: &LValue) -> Result<Option<Ordering>, String> {
+    match (lhs, rhs) {
+        (LValue::LPrimitive(x), LValue::LPrimitive(y)) => compare(x, y),
+        _ => Err(compare_error(lhs.type_name(), rhs.type_name())),
+    }
+}
+
+fn compare_error(t1: &str, t2: &str) -> String {
+    if t1 == t2 {
+        format!("attempt to compare two {} values", t1)
+    } else {
+        format!("attempt to compare {} with {}", t1, t2)
+    }
+}
@@ -0,0 +1,97 @@
+use crate::bytecode::bproto::BProto;
+
+use super::{cfunction::Continuation, lupvalue::UpvalueRef};
+
+/// Calls a frame makes without an instruction making them, which debug
+/// info names after what they are, like CIST_HOOKED and CIST_FIN
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Callback {
+    Hook,
+    Finalizer,
+}
+
+/// Bookkeeping for an active function call. The interpreter keeps one
+/// per call so that it can describe where execution is (tracebacks)
+/// without relying on the Rust call stack
+///
+/// 'i lifetime lives as long as the interpreter
+#[derive(Debug, Clone)]
+pub struct CallInfo<'i> {
+    /// Proto being executed, None for host functions
+    pub(crate) proto: Option<&'i BProto>,
+    /// Stack index of the function being called
+    pub(crate) func: usize,
+    /// Stack index of the first fixed argument. The varargs of a Lua call
+    /// are between func and base
+    pub(crate) base: usize,
+    /// Index of the instruction currently being executed
+    pub(crate) pc: usize,
+    /// End of the values produced by an instruction with a variable number
+    /// of results, saved while the frame is suspended
+    pub(crate) saved_top: usize,
+    /// Set when this frame was reused by a tail call, meaning the frames
+    /// of the functions which tail called are gone
+    pub(crate) tail_call: bool,
+    /// Upvalues capturing registers of this frame, closed when it returns
+    pub(crate) open_upvalues: Vec<UpvalueRef<'i>>,
+    /// One past the last stack slot the frame uses, like ci->top. Slots
+    /// above the innermost frame's top are garbage
+    pub(crate) top: usize,
+    /// Set by a host function calling back into Lua if it can finish
+    /// once the call is resumed after a suspension, like lua_callk
+    pub(crate) continuation: Option<Continuation<'i>>,
+    /// Set while the frame runs a hook or a finalizer
+    pub(crate) callback: Option<Callback>,
+}
+impl<'i> CallInfo<'i> {
+    pub fn lua(proto: &'i BProto, func: usize, base: usize) -> Self {
+        Self {
+            proto: Some(proto),
+            func,
+            base,
+            pc: 0,
+            saved_top: base,
+            tail_call: false,
+            open_upvalues: Vec::new(),
+            top: base + proto.max_stack as usize,
+            continuation: None,
+            callback: None,
+        }
+    }
+
+    pub fn host(func: usize, num_args: usize) -> Self {
+        Self {
+            proto: None,
+            func,
+            base: func + 1,
+            pc: 0,
+            saved_top: func + 1 + num_args,
+            tail_call: false,
+            open_upvalues: Vec::new(),
+            top: func + 1 + num_args,
+            continuation: None,
+            callback: None,
+        }
+    }
+
+    /// Whether the frame can be continued after a suspension
+    pub fn is_resumable(&self) -> bool {
+        self.proto.is_some() || self.continuation.is_some()
+    }
+
+    /// Line currently being executed, if known
+    pub fn current_line(&self) -> Option<i64> {
+        self.proto?.line_at(self.pc)
+    }
+
+    /// "source:line:" prefix used for error messages raised in this frame
+    pub fn position(&self) -> Option<String> {
+        let proto = self.proto?;
+        Some(match self.current_line() {
+            Some(line) => format!("{}:{}:", proto.short_src(), line),
+            None => format!("{}:?:", proto.short_src()),
+        })
+    }
+}
+
+pub type CallStack<'i> = Vec<CallInfo<'i>>;
@@ -1,17 +1,31 @@
-use super::{genv::GlobalEnv, Stack, StackItem};
+use crate::lprimative::LValue;
+
+use super::{callinfo::CallStack, error::LuaError, genv::GlobalEnv, Stack};
 
 /// A function not written in lua made available to Lua
-/// via the global environment.
+/// via the global environment. It receives the state, the
+/// stack and call stack, so that it can call back into Lua,
+/// and a copy of its arguments.
 ///
 /// 'i lifetime lives as long as the interpreter
-pub type CClosure<'i> = fn(&mut GlobalEnv<'i>, &[StackItem<'i>]) -> Stack<'i>;
+pub type CClosure<'i> = fn(
+    &mut GlobalEnv<'i>,
+    &mut Stack<'i>,
+    &mut CallStack<'i>,
+    Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError>;
 
-/// Describes features of a CClosure such as its parameters
-/// and returns
-#[derive(Debug, Clone)]
-pub struct CProto {
-    pub(crate) num_params: u8,
-    pub(crate) vararg_flag: u8,
-}
+/// Finishes a host function after the Lua function it called returned,
+/// given the number of results left after the called function or the
+/// error it raised. It's called in place of the rest of the host function
+/// when a suspended call is resumed, so host functions which call Lua
+/// usually finish by calling it themselves
+pub type Continuation<'i> = fn(
+    &mut GlobalEnv<'i>,
+    &mut Stack<'i>,
+    &mut CallStack<'i>,
+    Result<usize, LuaError>,
+) -> Result<Vec<LValue<'i>>, LuaError>;
 
-pub type CFunction<'i> = (CProto, CClosure<'i>);
+/// A host function as a value, shared by the LValues holding it
+pub type CFunction<'i> = CClosure<'i>;
@@ -0,0 +1,156 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use serde_json::{json, Map, Value};
+
+use crate::{
+    bytecode::{bopcode::OPNAMES, bproto::BProto},
+    lprimative::LValue,
+};
+
+use super::{cfunction::CFunction, genv::GlobalEnv, ldebug};
+
+/// What the interpreter executed since the counters were last reset. The
+/// counts of tables, strings and collections are kept by the collector and
+/// the string table, along with the memory they account for
+pub(crate) struct Counters<'i> {
+    /// Instructions executed, by opcode
+    pub(crate) opcodes: [u64; OPNAMES.len()],
+    /// Calls of each proto, by address
+    pub(crate) protos: HashMap<usize, (&'i BProto, u64)>,
+    /// Calls of each host function, by address
+    pub(crate) host_functions: HashMap<usize, (Rc<CFunction<'i>>, u64)>,
+}
+impl<'i> Default for Counters<'i> {
+    fn default() -> Self {
+        Self {
+            opcodes: [0; OPNAMES.len()],
+            protos: HashMap::new(),
+            host_functions: HashMap::new(),
+        }
+    }
+}
+impl<'i> Counters<'i> {
+    pub(crate) fn count_call(&mut self, proto: &'i BProto) {
+        let address = proto as *const BProto as usize;
+        self.protos.entry(address).or_insert((proto, 0)).1 += 1;
+    }
+
+    pub(crate) fn count_host_call(&mut self, function: &Rc<CFunction<'i>>) {
+        let address = **function as usize;
+        self.host_functions
+            .entry(address)
+            .or_insert_with(|| (function.clone(), 0))
+            .1 += 1;
+    }
+}
+
+/// The counters of an interpreter, with functions named. Functions are
+/// listed most called first
+#[derive(Debug, Clone)]
+pub struct CounterReport {
+    /// Every opcode, in opcode order
+    pub opcodes: Vec<(&'static str, u64)>,
+    /// Lua functions, "function <src:line>" or "main chunk <src>"
+    pub protos: Vec<(String, u64)>,
+    /// Host functions, by the name they're loaded as like "debug.getinfo"
+    pub host_functions: Vec<(String, u64)>,
+    pub tables: u64,
+    pub strings: u64,
+    pub gc_cycles: u64,
+    /// Bytes in use when the report was made, which isn't reset
+    pub memory: usize,
+}
+impl CounterReport {
+    pub(crate) fn new(genv: &GlobalEnv, memory: usize) -> Self {
+        let counters = &genv.counters;
+
+        let mut protos: Vec<_> = counters
+            .protos
+            .values()
+            .map(|&(proto, calls)| {
+                let name = match proto.line_defined {
+                    0 => format!("main chunk <{}>", proto.short_src()),
+                    line => format!("function <{}:{}>", proto.short_src(), line),
+                };
+                (name, calls)
+            })
+            .collect();
+        protos.sort_by_key(|(name, calls)| (Reverse(*calls), name.clone()));
+
+        let mut host_functions: Vec<_> = counters
+            .host_functions
+            .values()
+            .map(|(function, calls)| {
+                let function = LValue::CClosure(function.clone());
+                let name = ldebug::global_func_name(genv, &function).unwrap_or("?".to_owned());
+                (name, *calls)
+            })
+            .collect();
+        host_functions.sort_by_key(|(name, calls)| (Reverse(*calls), name.clone()));
+
+        Self {
+            opcodes: OPNAMES.iter().copied().zip(counters.opcodes).collect(),
+            protos,
+            host_functions,
+            tables: genv.gc.table_allocations,
+            strings: genv.strings.allocations,
+            gc_cycles: genv.gc.cycles,
+            memory,
+        }
+    }
+
+    /// Total instructions executed
+    pub fn instructions(&self) -> u64 {
+        self.opcodes.iter().map(|(_, count)| count).sum()
+    }
+
+    /// Tables of the counts, leaving out opcodes which never ran
+    pub fn write(&self, output: &mut impl Write) -> io::Result<()> {
+        writeln!(output, "{:<40} {:>12}", "opcode", "executed")?;
+        for (name, count) in self.opcodes.iter().filter(|(_, count)| *count > 0) {
+            writeln!(output, "{:<40} {:>12}", name, count)?;
+        }
+        writeln!(output, "{:<40} {:>12}", "total", self.instructions())?;
+
+        writeln!(output)?;
+        writeln!(output, "{:<40} {:>12}", "function", "calls")?;
+        for (name, calls) in self.protos.iter().chain(&self.host_functions) {
+            writeln!(output, "{:<40} {:>12}", name, calls)?;
+        }
+
+        writeln!(output)?;
+        writeln!(output, "{:<40} {:>12}", "tables allocated", self.tables)?;
+        writeln!(output, "{:<40} {:>12}", "strings allocated", self.strings)?;
+        writeln!(output, "{:<40} {:>12}", "collection cycles", self.gc_cycles)?;
+        writeln!(output, "{:<40} {:>12}", "bytes in use", self.memory)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let counts = |counts: &[(String, u64)]| {
+            counts
+                .iter()
+                .map(|(name, calls)| json!({ "name": name, "calls": calls }))
+                .collect::<Vec<_>>()
+        };
+        let opcodes: Map<String, Value> = self
+            .opcodes
+            .iter()
+            .map(|&(name, count)| (name.to_owned(), json!(count)))
+            .collect();
+        json!({
+            "opcodes": opcodes,
+            "instructions": self.instructions(),
+            "functions": counts(&self.protos),
+            "host_functions": counts(&self.host_functions),
+            "tables": self.tables,
+            "strings": self.strings,
+            "gc_cycles": self.gc_cycles,
+            "memory": self.memory,
+        })
+    }
+}
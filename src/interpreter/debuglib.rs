@@ -0,0 +1,547 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::lprimative::{LPrimitive, LValue};
+
+use super::{
+    callinfo::CallStack,
+    error::LuaError,
+    genv::{arg_error, arg_type_name, GlobalEnv},
+    hook::{HookMask, HOOK_KEY, MASK_CALL, MASK_COUNT, MASK_LINE, MASK_RET},
+    ldebug::{self, find_local, frame_index, func_name},
+    ltable::LTable,
+    lupvalue::UpvalueRef,
+    Stack,
+};
+
+/// Registers the `debug` library, like luaopen_debug
+pub fn open(genv: &mut GlobalEnv) {
+    genv.register_library(
+        "debug",
+        &[
+            ("getinfo", c_getinfo),
+            ("getlocal", c_getlocal),
+            ("setlocal", c_setlocal),
+            ("getupvalue", c_getupvalue),
+            ("setupvalue", c_setupvalue),
+            ("upvalueid", c_upvalueid),
+            ("getmetatable", c_getmetatable),
+            ("setmetatable", c_setmetatable),
+            ("sethook", c_sethook),
+            ("gethook", c_gethook),
+            ("traceback", c_traceback),
+        ],
+    );
+}
+
+/// Integer argument `n`, like luaL_checkinteger
+fn check_integer(
+    call_stack: &CallStack,
+    args: &[LValue],
+    n: usize,
+    name: &str,
+) -> Result<i64, LuaError> {
+    match args.get(n - 1) {
+        Some(LValue::LPrimitive(p)) if p.to_number().is_some() => p
+            .to_integer()
+            .ok_or_else(|| arg_error(call_stack, n, name, "number has no integer representation")),
+        _ => Err(arg_error(
+            call_stack,
+            n,
+            name,
+            format!("number expected, got {}", arg_type_name(args, n)),
+        )),
+    }
+}
+
+/// Argument `n`, which must be given even if it's nil, like luaL_checkany
+fn check_any<'a, 'i>(
+    call_stack: &CallStack,
+    args: &'a [LValue<'i>],
+    n: usize,
+    name: &str,
+) -> Result<&'a LValue<'i>, LuaError> {
+    args.get(n - 1)
+        .ok_or_else(|| arg_error(call_stack, n, name, "value expected"))
+}
+
+/// Function argument `n`, like luaL_checktype with LUA_TFUNCTION
+fn check_function<'a, 'i>(
+    call_stack: &CallStack,
+    args: &'a [LValue<'i>],
+    n: usize,
+    name: &str,
+) -> Result<&'a LValue<'i>, LuaError> {
+    match args.get(n - 1) {
+        Some(f @ (LValue::LClosure(_) | LValue::CClosure(_))) => Ok(f),
+        _ => Err(arg_error(
+            call_stack,
+            n,
+            name,
+            format!("function expected, got {}", arg_type_name(args, n)),
+        )),
+    }
+}
+
+fn string<'i>(genv: &mut GlobalEnv<'i>, s: &str) -> LValue<'i> {
+    LValue::LPrimitive(LPrimitive::STRING(genv.strings.intern(s)))
+}
+
+/// Returns a table describing a function or the function running at a
+/// level of the call stack, `debug.getinfo(f [, what])`
+///
+/// `what` picks the fields to fill: 'S' for the source, 'l' for the
+/// current line, 'u' for upvalues and parameters, 'n' for the name the
+/// function was called by, 't' for tail calls, 'L' for the lines with
+/// code and 'f' for the function itself
+pub fn c_getinfo<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    args: Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    let what = match args.get(1) {
+        None | Some(LValue::LPrimitive(LPrimitive::NIL)) => "flnStu".to_owned(),
+        Some(LValue::LPrimitive(
+            p @ (LPrimitive::STRING(_) | LPrimitive::INT(_) | LPrimitive::FLOAT(_)),
+        )) => p.to_string(),
+        Some(_) => {
+            return Err(arg_error(
+                call_stack,
+                2,
+                "debug.getinfo",
+                format!("string expected, got {}", arg_type_name(&args, 2)),
+            ))
+        }
+    };
+
+    //A function isn't running, so it has no current line or name
+    let (function, frame) = match args.first() {
+        Some(f @ (LValue::LClosure(_) | LValue::CClosure(_))) => (f.clone(), None),
+        _ => {
+            let level = check_integer(call_stack, &args, 1, "debug.getinfo")?;
+            match frame_index(call_stack, level) {
+                Some(i) => (stack[call_stack[i].func].clone(), Some(i)),
+                None => return Ok(vec![LValue::default()]),
+            }
+        }
+    };
+    if !what.chars().all(|c| "SlutnLf".contains(c)) {
+        return Err(arg_error(call_stack, 2, "debug.getinfo", "invalid option"));
+    }
+
+    let proto = match &function {
+        LValue::LClosure(closure) => Some(closure.proto),
+        _ => None,
+    };
+    let int = |n: i64| LValue::LPrimitive(LPrimitive::INT(n));
+    let bool = |b: bool| LValue::LPrimitive(LPrimitive::BOOL(b));
+
+    let info = Rc::new(RefCell::new(LTable::default()));
+    genv.gc.track_table(&info);
+    let mut fields = Vec::new();
+    if what.contains('S') {
+        let (source, short_src, line_defined, last_line_defined, kind) = match proto {
+            Some(proto) => (
+                proto.source_name.clone().unwrap_or_else(|| "=?".to_owned()),
+                proto.short_src(),
+                proto.line_defined,
+                proto.last_line_defined,
+                match proto.line_defined {
+                    0 => "main",
+                    _ => "Lua",
+                },
+            ),
+            None => ("=[C]".to_owned(), "[C]".to_owned(), -1, -1, "C"),
+        };
+        fields.push(("source", string(genv, &source)));
+        fields.push(("short_src", string(genv, &short_src)));
+        fields.push(("linedefined", int(line_defined)));
+        fields.push(("lastlinedefined", int(last_line_defined)));
+        fields.push(("what", string(genv, kind)));
+    }
+    if what.contains('l') {
+        let line = frame.and_then(|i| call_stack[i].current_line());
+        fields.push(("currentline", int(line.unwrap_or(-1))));
+    }
+    if what.contains('u') {
+        let (upvalues, params, vararg) = match &function {
+            LValue::LClosure(closure) => (
+                closure.upvalues.len(),
+                closure.proto.num_params,
+                closure.proto.vararg_flag != 0,
+            ),
+            _ => (0, 0, true),
+        };
+        fields.push(("nups", int(upvalues as i64)));
+        fields.push(("nparams", int(params as i64)));
+        fields.push(("isvararg", bool(vararg)));
+    }
+    if what.contains('n') {
+        let (namewhat, name) = match frame.and_then(|i| func_name(call_stack, i)) {
+            Some((namewhat, name)) => (namewhat, string(genv, &name)),
+            None => ("", LValue::default()),
+        };
+        fields.push(("name", name));
+        fields.push(("namewhat", string(genv, namewhat)));
+    }
+    if what.contains('t') {
+        let tail_call = frame.is_some_and(|i| call_stack[i].tail_call);
+        fields.push(("istailcall", bool(tail_call)));
+    }
+    if what.contains('L') {
+        //Like collectvalidlines
+        let lines = match proto {
+            Some(proto) => {
+                let mut lines = LTable::default();
+                for line in proto.instructions.list.iter().filter_map(|i| i.line()) {
+                    lines.set_int(line, bool(true));
+                }
+                let lines = Rc::new(RefCell::new(lines));
+                genv.gc.track_table(&lines);
+                LValue::Table(lines)
+            }
+            None => LValue::default(),
+        };
+        fields.push(("activelines", lines));
+    }
+    if what.contains('f') {
+        fields.push(("func", function.clone()));
+    }
+    for (name, value) in fields {
+        genv.set_field(&info, name, value);
+    }
+
+    Ok(vec![LValue::Table(info)])
+}
+
+/// Returns the name and value of local `n` of the function running at
+/// `level`, or the name of parameter `n` of a function,
+/// `debug.getlocal(level | f, n)`
+///
+/// Negative numbers give the varargs of the function
+pub fn c_getlocal<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    args: Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    let n = check_integer(call_stack, &args, 2, "debug.getlocal")?;
+
+    //Only parameters are in scope at the start of a function
+    if let Some(f @ (LValue::LClosure(_) | LValue::CClosure(_))) = args.first() {
+        let name = match f {
+            LValue::LClosure(closure) => usize::try_from(n)
+                .ok()
+                .and_then(|n| closure.proto.local_name(n, 0)),
+            _ => None,
+        };
+        return Ok(vec![match name {
+            Some(name) => string(genv, name),
+            None => LValue::default(),
+        }]);
+    }
+
+    let level = check_integer(call_stack, &args, 1, "debug.getlocal")?;
+    let i = frame_index(call_stack, level)
+        .ok_or_else(|| arg_error(call_stack, 1, "debug.getlocal", "level out of range"))?;
+    Ok(match find_local(stack, call_stack, i, n) {
+        Some((name, index)) => vec![string(genv, name), stack[index].clone()],
+        None => vec![LValue::default()],
+    })
+}
+
+/// Assigns local `n` of the function running at `level`, returning its
+/// name, `debug.setlocal(level, n, value)`
+pub fn c_setlocal<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    args: Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    let level = check_integer(call_stack, &args, 1, "debug.setlocal")?;
+    let i = frame_index(call_stack, level)
+        .ok_or_else(|| arg_error(call_stack, 1, "debug.setlocal", "level out of range"))?;
+    let n = check_integer(call_stack, &args, 2, "debug.setlocal")?;
+    let value = check_any(call_stack, &args, 3, "debug.setlocal")?;
+
+    Ok(vec![match find_local(stack, call_stack, i, n) {
+        Some((name, index)) => {
+            stack[index] = value.clone();
+            string(genv, name)
+        }
+        None => LValue::default(),
+    }])
+}
+
+/// Upvalue `n` of a function, counting from 1, like aux_upvalue
+fn upvalue<'i>(function: &LValue<'i>, n: i64) -> Option<(&'i str, UpvalueRef<'i>)> {
+    match function {
+        LValue::LClosure(closure) => {
+            let index = usize::try_from(n).ok()?.checked_sub(1)?;
+            let upvalue = closure.upvalues.get(index)?.clone();
+            let name = closure.proto.upvalue_name(index).unwrap_or("(*no name)");
+            Some((name, upvalue))
+        }
+        //Host functions don't have upvalues
+        _ => None,
+    }
+}
+
+/// Returns the name and value of upvalue `n` of a function, or nothing if
+/// it doesn't have one, `debug.getupvalue(f, n)`
+pub fn c_getupvalue<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    args: Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    let n = check_integer(call_stack, &args, 2, "debug.getupvalue")?;
+    let function = check_function(call_stack, &args, 1, "debug.getupvalue")?;
+
+    Ok(match upvalue(function, n) {
+        Some((name, upvalue)) => vec![string(genv, name), upvalue.borrow().get(stack)],
+        None => Vec::new(),
+    })
+}
+
+/// Assigns upvalue `n` of a function, returning its name or nothing if it
+/// doesn't have one, `debug.setupvalue(f, n, value)`
+pub fn c_setupvalue<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    args: Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    let value = check_any(call_stack, &args, 3, "debug.setupvalue")?;
+    let n = check_integer(call_stack, &args, 2, "debug.setupvalue")?;
+    let function = check_function(call_stack, &args, 1, "debug.setupvalue")?;
+
+    Ok(match upvalue(function, n) {
+        Some((name, upvalue)) => {
+            genv.gc.barrier_upvalue(&upvalue);
+            upvalue.borrow_mut().set(stack, value.clone());
+            vec![string(genv, name)]
+        }
+        None => Vec::new(),
+    })
+}
+
+/// Returns a number identifying upvalue `n` of a function, which is the
+/// same for closures sharing the upvalue, `debug.upvalueid(f, n)`
+///
+/// There's no light userdata, so the identifier is the upvalue's address
+pub fn c_upvalueid<'i>(
+    _genv: &mut GlobalEnv<'i>,
+    _stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    args: Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    let n = check_integer(call_stack, &args, 2, "debug.upvalueid")?;
+    let function = check_function(call_stack, &args, 1, "debug.upvalueid")?;
+
+    match upvalue(function, n) {
+        Some((_, upvalue)) => Ok(vec![LValue::LPrimitive(LPrimitive::INT(
+            Rc::as_ptr(&upvalue) as usize as i64,
+        ))]),
+        None => Err(arg_error(
+            call_stack,
+            2,
+            "debug.upvalueid",
+            "invalid upvalue index",
+        )),
+    }
+}
+
+/// Metatable of a value, ignoring `__metatable`, `debug.getmetatable(value)`
+pub fn c_getmetatable<'i>(
+    _genv: &mut GlobalEnv<'i>,
+    _stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    args: Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    let metatable = match check_any(call_stack, &args, 1, "debug.getmetatable")? {
+        LValue::Table(t) => t.borrow().metatable.clone(),
+        _ => None,
+    };
+
+    Ok(vec![metatable.map_or_else(LValue::default, LValue::Table)])
+}
+
+/// Sets or removes the metatable of a table, even a protected one,
+/// `debug.setmetatable(table, metatable)`
+///
+/// Only tables have metatables, there are no per type metatables
+pub fn c_setmetatable<'i>(
+    genv: &mut GlobalEnv<'i>,
+    _stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    args: Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    let metatable = match args.get(1) {
+        Some(LValue::Table(t)) => Some(t.clone()),
+        Some(LValue::LPrimitive(LPrimitive::NIL)) => None,
+        _ => {
+            return Err(arg_error(
+                call_stack,
+                2,
+                "debug.setmetatable",
+                "nil or table expected",
+            ))
+        }
+    };
+    let table = match &args[0] {
+        LValue::Table(t) => t.clone(),
+        _ => {
+            return Err(arg_error(
+                call_stack,
+                1,
+                "debug.setmetatable",
+                format!("table expected, got {}", arg_type_name(&args, 1)),
+            ))
+        }
+    };
+
+    genv.gc.barrier_table(&table);
+    table.borrow_mut().metatable = metatable;
+    genv.gc.check_finalizer(&table);
+
+    Ok(vec![LValue::Table(table)])
+}
+
+/// Returns a traceback of the call stack from `level`, 1 by default which
+/// is the function calling traceback, `debug.traceback([message [, level]])`
+///
+/// Messages which aren't strings or numbers are returned untouched
+pub fn c_traceback<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    args: Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    let message = match args.first() {
+        None | Some(LValue::LPrimitive(LPrimitive::NIL)) => None,
+        Some(LValue::LPrimitive(
+            p @ (LPrimitive::STRING(_) | LPrimitive::INT(_) | LPrimitive::FLOAT(_)),
+        )) => Some(p.to_string()),
+        Some(message) => return Ok(vec![message.clone()]),
+    };
+    let level = match args.get(1) {
+        None | Some(LValue::LPrimitive(LPrimitive::NIL)) => 1,
+        Some(_) => check_integer(call_stack, &args, 2, "debug.traceback")?,
+    };
+
+    let traceback = ldebug::traceback(genv, stack, call_stack, level);
+    let traceback = match message {
+        Some(message) => format!("{}\n{}", message, traceback),
+        None => traceback,
+    };
+    Ok(vec![string(genv, &traceback)])
+}
+
+/// Sets the function called on the events of `mask`, or turns hooks off
+/// without arguments, `debug.sethook([hook, mask [, count]])`
+///
+/// The mask has 'c' for calls, 'r' for returns and 'l' for lines, and a
+/// count greater than 0 adds a count event every `count` instructions
+pub fn c_sethook<'i>(
+    genv: &mut GlobalEnv<'i>,
+    _stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    args: Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    let (hook, mask) = match args.first() {
+        None | Some(LValue::LPrimitive(LPrimitive::NIL)) => {
+            (LValue::default(), HookMask::default())
+        }
+        Some(hook) => {
+            let mask = match args.get(1) {
+                Some(LValue::LPrimitive(
+                    p @ (LPrimitive::STRING(_) | LPrimitive::INT(_) | LPrimitive::FLOAT(_)),
+                )) => p.to_string(),
+                _ => {
+                    return Err(arg_error(
+                        call_stack,
+                        2,
+                        "debug.sethook",
+                        format!("string expected, got {}", arg_type_name(&args, 2)),
+                    ))
+                }
+            };
+            if !matches!(hook, LValue::LClosure(_) | LValue::CClosure(_)) {
+                return Err(arg_error(
+                    call_stack,
+                    1,
+                    "debug.sethook",
+                    format!("function expected, got {}", arg_type_name(&args, 1)),
+                ));
+            }
+            let count = match args.get(2) {
+                None | Some(LValue::LPrimitive(LPrimitive::NIL)) => 0,
+                Some(LValue::LPrimitive(p)) if p.to_number().is_some() => {
+                    p.to_integer().ok_or_else(|| {
+                        arg_error(
+                            call_stack,
+                            3,
+                            "debug.sethook",
+                            "number has no integer representation",
+                        )
+                    })?
+                }
+                Some(_) => {
+                    return Err(arg_error(
+                        call_stack,
+                        3,
+                        "debug.sethook",
+                        format!("number expected, got {}", arg_type_name(&args, 3)),
+                    ))
+                }
+            };
+
+            //Like makemask
+            let mut bits = if count > 0 { MASK_COUNT } else { 0 };
+            for (c, bit) in [('c', MASK_CALL), ('r', MASK_RET), ('l', MASK_LINE)] {
+                if mask.contains(c) {
+                    bits |= bit;
+                }
+            }
+            (hook.clone(), HookMask::new(bits, count.max(0) as u64))
+        }
+    };
+
+    let key = LValue::LPrimitive(LPrimitive::STRING(genv.strings.intern(HOOK_KEY)));
+    genv.gc.barrier_table(&genv.registry);
+    genv.registry
+        .borrow_mut()
+        .set(key, hook)
+        .expect("string keys are valid");
+    genv.hooks.set_lua(mask);
+
+    Ok(Vec::new())
+}
+
+/// Returns the hook function, mask and count set by debug.sethook,
+/// `debug.gethook()`
+pub fn c_gethook<'i>(
+    genv: &mut GlobalEnv<'i>,
+    _stack: &mut Stack<'i>,
+    _call_stack: &mut CallStack<'i>,
+    _args: Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    let key = LValue::LPrimitive(LPrimitive::STRING(genv.strings.intern(HOOK_KEY)));
+    let hook = genv.registry.borrow().get(key);
+
+    //Like unmakemask
+    let mask = genv.hooks.lua.mask;
+    let mut string = String::new();
+    for (c, bit) in [('c', MASK_CALL), ('r', MASK_RET), ('l', MASK_LINE)] {
+        if mask & bit != 0 {
+            string.push(c);
+        }
+    }
+
+    Ok(vec![
+        hook,
+        LValue::LPrimitive(LPrimitive::STRING(genv.strings.intern(&string))),
+        LValue::LPrimitive(LPrimitive::INT(genv.hooks.lua.count as i64)),
+    ])
+}
@@ -0,0 +1,115 @@
+use serde_json::Value;
+use thiserror::Error;
+
+use super::{callinfo::CallStack, genv::GlobalEnv, ldebug, Stack};
+
+/// Errors raised while running Lua code
+#[derive(Debug, Error)]
+pub enum LuaError {
+    /// The traceback is None until the error has been given one, see
+    /// `with_traceback`, and so is the crash report, which is only built
+    /// if the state asks for them
+    #[error("{message}")]
+    Runtime {
+        message: String,
+        traceback: Option<String>,
+        report: Option<Box<Value>>,
+    },
+    /// The state went over its memory limit, like LUA_ERRMEM. Raised
+    /// without a position or traceback since building them would allocate
+    #[error("not enough memory")]
+    Memory,
+    /// Execution ran out of instruction budget and was suspended, like
+    /// LUA_YIELD. It unwinds to the interpreter, leaving the frames in
+    /// place to be resumed, and never reaches the embedder
+    #[error("execution suspended")]
+    Suspended,
+}
+impl LuaError {
+    /// Raises a runtime error in the innermost frame of the call stack,
+    /// prefixing the message with the current position like luaG_runerror
+    pub fn runtime(call_stack: &CallStack, message: impl AsRef<str>) -> Self {
+        let message = match call_stack.last().and_then(|frame| frame.position()) {
+            Some(position) => format!("{} {}", position, message.as_ref()),
+            None => message.as_ref().to_owned(),
+        };
+
+        LuaError::Runtime {
+            message,
+            traceback: None,
+            report: None,
+        }
+    }
+
+    /// Raises an error from a host function, positioned at the function
+    /// which called it like luaL_error
+    pub fn host(call_stack: &CallStack, message: impl AsRef<str>) -> Self {
+        let caller = call_stack.len().checked_sub(2).map(|i| &call_stack[i]);
+        let message = match caller.and_then(|frame| frame.position()) {
+            Some(position) => format!("{} {}", position, message.as_ref()),
+            None => message.as_ref().to_owned(),
+        };
+
+        LuaError::Runtime {
+            message,
+            traceback: None,
+            report: None,
+        }
+    }
+
+    /// Wraps an error raised by a `__gc` metamethod, like GCTM. Finalizers
+    /// run without a message handler so the error has no traceback.
+    /// Memory errors are passed through unchanged
+    pub fn in_finalizer(self) -> Self {
+        match self {
+            LuaError::Runtime { message, .. } => LuaError::Runtime {
+                message: format!("error in __gc metamethod ({})", message),
+                traceback: Some(String::new()),
+                report: None,
+            },
+            LuaError::Memory => LuaError::Memory,
+            LuaError::Suspended => unreachable!("finalizers can't be suspended"),
+        }
+    }
+
+    /// Builds the traceback of a runtime error which doesn't have one yet,
+    /// and its crash report if the state keeps them. It's called by the
+    /// innermost frame the error unwinds, before it's popped, so the
+    /// traceback shows every frame the error was raised in
+    pub(crate) fn with_traceback<'i>(
+        mut self,
+        genv: &GlobalEnv<'i>,
+        stack: &Stack<'i>,
+        call_stack: &CallStack<'i>,
+    ) -> Self {
+        if let LuaError::Runtime {
+            message,
+            traceback: traceback @ None,
+            report,
+        } = &mut self
+        {
+            *traceback = Some(ldebug::traceback(genv, stack, call_stack, 0));
+            if genv.crash_reports {
+                *report = Some(Box::new(ldebug::crash_report(
+                    genv, stack, call_stack, message,
+                )));
+            }
+        }
+        self
+    }
+
+    pub fn traceback(&self) -> &str {
+        match self {
+            LuaError::Runtime { traceback, .. } => traceback.as_deref().unwrap_or(""),
+            LuaError::Memory | LuaError::Suspended => "",
+        }
+    }
+
+    /// Dump of the frames the error unwound, see `ldebug::crash_report`
+    pub fn crash_report(&self) -> Option<&Value> {
+        match self {
+            LuaError::Runtime { report, .. } => report.as_deref(),
+            LuaError::Memory | LuaError::Suspended => None,
+        }
+    }
+}
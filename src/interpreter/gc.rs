@@ -0,0 +1,748 @@
+use std::{
+    cell::RefCell,
+    collections::{HashSet, VecDeque},
+    mem::size_of,
+    rc::{Rc, Weak},
+};
+
+use crate::{
+    lprimative::{LPrimitive, LValue},
+    lstring::StringTable,
+};
+
+use super::{
+    callinfo::{CallInfo, CallStack, Callback},
+    error::LuaError,
+    genv::GlobalEnv,
+    lclosure::{self, LClosure},
+    ltable::LTable,
+    lupvalue::{LUpvalue, UpvalueRef},
+    Stack,
+};
+
+/// Amount of work done by a basic step, in estimated bytes, like GCSTEPSIZE
+pub const STEP_SIZE: usize = 2048;
+
+/// Work charged for sweeping an object
+const SWEEP_COST: usize = 64;
+
+/// Below this the heap is small enough not to bother collecting
+const MIN_THRESHOLD: usize = 16 * 1024;
+
+/// Objects which can take part in reference cycles. Objects are still
+/// reference counted so acyclic garbage is freed as soon as it becomes
+/// unreachable. The collector finds the unreachable objects Rc can't free
+/// and clears their contents, which breaks the cycles keeping them alive
+enum GcObject<'i> {
+    Table(Weak<RefCell<LTable<'i>>>),
+    Closure(Weak<LClosure<'i>>),
+    Upvalue(Weak<RefCell<LUpvalue<'i>>>),
+}
+
+/// A reachable object waiting to be traversed
+enum Gray<'i> {
+    Table(Rc<RefCell<LTable<'i>>>),
+    Closure(Rc<LClosure<'i>>),
+    Upvalue(UpvalueRef<'i>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Waiting for enough allocation to start a cycle
+    Pause,
+    /// Traversing reachable objects a few at a time
+    Propagate,
+    /// Freeing unreachable objects among those which existed when marking
+    /// finished, `next..end` of the object list
+    Sweep { next: usize, end: usize },
+}
+
+/// Incremental mark and sweep collector, like lgc.c. A cycle marks from
+/// the roots (the stack, the registry and the globals) in small steps
+/// interleaved with execution, finishes marking in one atomic step and
+/// then sweeps the object list in small steps
+///
+/// Objects are white (not in `marked`), gray (in `marked` and waiting in
+/// `gray`) or black (in `marked` and traversed). Storing into a black
+/// object while marking turns it gray again, so nothing reachable is
+/// missed by the atomic step
+///
+/// Tables with a `__mode` metafield hold their keys and/or values weakly.
+/// Weak keyed tables are ephemerons: a value is only reachable through
+/// the table if its key is reachable otherwise. Tables given a metatable
+/// with a `__gc` field are kept alive by the collector until it finds them
+/// unreachable, then resurrected to run their finalizer
+pub struct Collector<'i> {
+    /// Every table, closure and upvalue with its size when last measured
+    objects: Vec<(GcObject<'i>, usize)>,
+    marked: HashSet<*const ()>,
+    gray: Vec<Gray<'i>>,
+    /// Black objects that were stored into, traversed again by the atomic step
+    gray_again: Vec<Gray<'i>>,
+    phase: Phase,
+
+    /// Weak tables found while marking, cleared by the atomic step
+    weak_values: Vec<Rc<RefCell<LTable<'i>>>>,
+    ephemerons: Vec<Rc<RefCell<LTable<'i>>>>,
+    all_weak: Vec<Rc<RefCell<LTable<'i>>>>,
+
+    /// Tables whose metatable had a __gc field when it was set, oldest first
+    finobj: Vec<Rc<RefCell<LTable<'i>>>>,
+    finalizable: HashSet<*const ()>,
+    /// Unreachable tables whose finalizer is yet to run, in the order they run
+    tobefnz: VecDeque<Rc<RefCell<LTable<'i>>>>,
+    /// Set when the state closes, after which finalizers aren't registered
+    closing: bool,
+
+    mode_name: LValue<'i>,
+    gc_name: LValue<'i>,
+
+    /// Cleared by collectgarbage("stop")
+    pub(crate) running: bool,
+    /// Percentage the heap grows by between cycles, collectgarbage("setpause")
+    pub(crate) pause: usize,
+    /// Speed of the collector relative to allocation, collectgarbage("setstepmul")
+    pub(crate) step_mul: usize,
+
+    /// Estimated bytes in use by tracked objects
+    total: usize,
+    /// Bytes tables grew by since the sweep last measured them
+    grown: usize,
+    /// Bytes in use at which the next step runs
+    threshold: usize,
+    /// Bytes the whole state may use, see `memory_used`
+    pub(crate) memory_limit: Option<usize>,
+
+    /// Tables created and cycles finished, for the counters of the state
+    pub(crate) table_allocations: u64,
+    pub(crate) cycles: u64,
+}
+impl<'i> Collector<'i> {
+    pub fn new(strings: &mut StringTable) -> Self {
+        Self {
+            objects: Vec::new(),
+            marked: HashSet::new(),
+            gray: Vec::new(),
+            gray_again: Vec::new(),
+            phase: Phase::Pause,
+
+            weak_values: Vec::new(),
+            ephemerons: Vec::new(),
+            all_weak: Vec::new(),
+
+            finobj: Vec::new(),
+            finalizable: HashSet::new(),
+            tobefnz: VecDeque::new(),
+            closing: false,
+
+            mode_name: LValue::LPrimitive(LPrimitive::STRING(strings.intern("__mode"))),
+            gc_name: LValue::LPrimitive(LPrimitive::STRING(strings.intern("__gc"))),
+
+            running: true,
+            pause: 200,
+            step_mul: 200,
+
+            total: 0,
+            grown: 0,
+            threshold: MIN_THRESHOLD,
+            memory_limit: None,
+
+            table_allocations: 0,
+            cycles: 0,
+        }
+    }
+
+    /// Estimated bytes in use by tables, closures and upvalues
+    pub fn total(&self) -> usize {
+        self.total + self.grown
+    }
+
+    /// Charges the growth of a table's array or hash part. The table's
+    /// size is measured again when it's swept
+    pub fn charge_growth(&mut self, bytes: usize) {
+        self.grown += bytes;
+    }
+
+    pub fn track_table(&mut self, table: &Rc<RefCell<LTable<'i>>>) {
+        let size = table_size(&table.borrow());
+        self.track(GcObject::Table(Rc::downgrade(table)), size);
+        self.table_allocations += 1;
+    }
+
+    pub fn track_closure(&mut self, closure: &Rc<LClosure<'i>>) {
+        self.track(
+            GcObject::Closure(Rc::downgrade(closure)),
+            closure_size(closure),
+        );
+    }
+
+    pub fn track_upvalue(&mut self, upvalue: &UpvalueRef<'i>) {
+        self.track(GcObject::Upvalue(Rc::downgrade(upvalue)), UPVALUE_SIZE);
+    }
+
+    fn track(&mut self, object: GcObject<'i>, size: usize) {
+        //Objects created while sweeping are after the end of the sweep so they survive the cycle
+        self.objects.push((object, size));
+        self.total += size;
+    }
+
+    /// Must be called before storing a value into a table, like luaC_barrierback
+    pub fn barrier_table(&mut self, table: &Rc<RefCell<LTable<'i>>>) {
+        if self.phase == Phase::Propagate && self.marked.remove(&(Rc::as_ptr(table) as *const ())) {
+            self.gray_again.push(Gray::Table(table.clone()));
+        }
+    }
+
+    /// Must be called before storing a value into a closed upvalue, or
+    /// when closing an upvalue
+    pub fn barrier_upvalue(&mut self, upvalue: &UpvalueRef<'i>) {
+        if self.phase == Phase::Propagate && self.marked.remove(&(Rc::as_ptr(upvalue) as *const ()))
+        {
+            self.gray_again.push(Gray::Upvalue(upvalue.clone()));
+        }
+    }
+
+    /// Registers a table for finalization if its new metatable has a __gc
+    /// field, like luaC_checkfinalizer
+    pub fn check_finalizer(&mut self, table: &Rc<RefCell<LTable<'i>>>) {
+        let ptr = Rc::as_ptr(table) as *const ();
+        if self.closing || self.finalizable.contains(&ptr) {
+            return;
+        }
+
+        let has_finalizer = match &table.borrow().metatable {
+            Some(metatable) => !matches!(
+                metatable.borrow().get(self.gc_name.clone()),
+                LValue::LPrimitive(LPrimitive::NIL)
+            ),
+            None => false,
+        };
+        if has_finalizer {
+            self.finalizable.insert(ptr);
+            self.finobj.push(table.clone());
+        }
+    }
+
+    /// Marks a value, returning true if it wasn't marked already
+    fn mark_value(&mut self, value: &LValue<'i>) -> bool {
+        match value {
+            LValue::Table(t) => {
+                let unmarked = self.marked.insert(Rc::as_ptr(t) as *const ());
+                if unmarked {
+                    self.gray.push(Gray::Table(t.clone()));
+                }
+                unmarked
+            }
+            LValue::LClosure(c) => {
+                let unmarked = self.marked.insert(Rc::as_ptr(c) as *const ());
+                if unmarked {
+                    self.gray.push(Gray::Closure(c.clone()));
+                }
+                unmarked
+            }
+            //Strings are freed by reference counting and host functions hold nothing
+            LValue::LPrimitive(_) | LValue::CClosure(_) => false,
+        }
+    }
+
+    /// Whether a weak reference to the value would be cleared, meaning it's
+    /// an object which isn't marked. Strings are values rather than
+    /// objects for weak tables so they are never cleared
+    fn is_cleared(&self, value: &LValue<'i>) -> bool {
+        match value {
+            LValue::Table(t) => !self.marked.contains(&(Rc::as_ptr(t) as *const ())),
+            LValue::LClosure(c) => !self.marked.contains(&(Rc::as_ptr(c) as *const ())),
+            LValue::LPrimitive(_) | LValue::CClosure(_) => false,
+        }
+    }
+
+    /// Whether the keys and the values of a table are weak, from the
+    /// `__mode` field of its metatable
+    fn weakness(&self, table: &LTable<'i>) -> (bool, bool) {
+        let Some(metatable) = &table.metatable else {
+            return (false, false);
+        };
+        match metatable.borrow().get(self.mode_name.clone()) {
+            LValue::LPrimitive(LPrimitive::STRING(mode)) => {
+                (mode.contains('k'), mode.contains('v'))
+            }
+            _ => (false, false),
+        }
+    }
+
+    /// Marks the values of a weak keyed table whose key is marked,
+    /// returning true if anything new was marked
+    fn mark_ephemeron(&mut self, table: &LTable<'i>) -> bool {
+        let mut marked = false;
+        //Integer keys are never cleared
+        for value in table.array_values() {
+            marked |= self.mark_value(value);
+        }
+        for (key, value) in table.hash_entries() {
+            if !self.is_cleared(key) {
+                marked |= self.mark_value(value);
+            }
+        }
+        marked
+    }
+
+    fn mark_upvalue(&mut self, upvalue: &UpvalueRef<'i>) {
+        if self.marked.insert(Rc::as_ptr(upvalue) as *const ()) {
+            self.gray.push(Gray::Upvalue(upvalue.clone()));
+        }
+    }
+
+    fn mark_roots(&mut self, stack: &[LValue<'i>], registry: &Rc<RefCell<LTable<'i>>>) {
+        for value in stack {
+            self.mark_value(value);
+        }
+        self.mark_value(&LValue::Table(registry.clone()));
+
+        //Objects waiting for their finalizer are alive until it has run
+        for object in self.tobefnz.clone() {
+            self.mark_value(&LValue::Table(object));
+        }
+    }
+
+    /// Marks everything referenced by a gray object, returning the work done
+    fn traverse(&mut self, object: Gray<'i>) -> usize {
+        match object {
+            Gray::Table(t) => {
+                let table = t.borrow();
+                if let Some(metatable) = &table.metatable {
+                    self.mark_value(&LValue::Table(metatable.clone()));
+                }
+
+                match self.weakness(&table) {
+                    (false, false) => {
+                        for value in table.contents() {
+                            self.mark_value(value);
+                        }
+                    }
+                    (false, true) => {
+                        for (key, _) in table.hash_entries() {
+                            self.mark_value(key);
+                        }
+                        self.weak_values.push(t.clone());
+                    }
+                    (true, false) => {
+                        self.mark_ephemeron(&table);
+                        self.ephemerons.push(t.clone());
+                    }
+                    (true, true) => self.all_weak.push(t.clone()),
+                }
+                table_size(&table)
+            }
+            Gray::Closure(c) => {
+                for upvalue in &c.upvalues {
+                    self.mark_upvalue(upvalue);
+                }
+                closure_size(&c)
+            }
+            Gray::Upvalue(u) => {
+                //Open upvalues refer to the stack, which is a root
+                if let LUpvalue::Closed(value) = &*u.borrow() {
+                    self.mark_value(value);
+                }
+                UPVALUE_SIZE
+            }
+        }
+    }
+
+    /// Traverses every gray object, returning true if there were any
+    fn propagate_all(&mut self) -> bool {
+        let mut traversed = false;
+        while let Some(object) = self.gray.pop() {
+            self.traverse(object);
+            traversed = true;
+        }
+        traversed
+    }
+
+    /// Marks ephemeron values until no more keys become reachable
+    fn converge_ephemerons(&mut self) {
+        loop {
+            let mut changed = false;
+            for table in self.ephemerons.clone() {
+                changed |= self.mark_ephemeron(&table.borrow());
+            }
+            changed |= self.propagate_all();
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    fn clear_values(&self, tables: &[Rc<RefCell<LTable<'i>>>]) {
+        for table in tables {
+            table
+                .borrow_mut()
+                .clear_where(|_, value| self.is_cleared(value));
+        }
+    }
+
+    fn clear_keys(&self, tables: &[Rc<RefCell<LTable<'i>>>]) {
+        for table in tables {
+            table
+                .borrow_mut()
+                .clear_where(|key, _| self.is_cleared(key));
+        }
+    }
+
+    /// Moves the unreachable tables with finalizers, or all of them, to the
+    /// pending finalizers. Finalizers run in the reverse order of
+    /// registration so the newest tables go first
+    fn separate_finalizable(&mut self, all: bool) {
+        let (unreachable, reachable) = std::mem::take(&mut self.finobj)
+            .into_iter()
+            .partition(|table| all || self.is_cleared(&LValue::Table(table.clone())));
+        self.finobj = reachable;
+
+        for table in unreachable.into_iter().rev() {
+            self.finalizable.remove(&(Rc::as_ptr(&table) as *const ()));
+            self.tobefnz.push_back(table);
+        }
+    }
+
+    /// Finishes marking in one go, since the roots and the objects stored
+    /// into while marking may have changed since they were traversed
+    fn atomic(&mut self, stack: &mut Stack<'i>, top: usize, registry: &Rc<RefCell<LTable<'i>>>) {
+        self.mark_roots(&stack[..top], registry);
+        //Like traversethread, the dead part of the stack mustn't keep
+        //anything alive past this cycle
+        stack[top..].fill(LValue::default());
+        self.propagate_all();
+
+        for object in std::mem::take(&mut self.gray_again) {
+            let ptr = match &object {
+                Gray::Table(t) => Rc::as_ptr(t) as *const (),
+                Gray::Closure(c) => Rc::as_ptr(c) as *const (),
+                Gray::Upvalue(u) => Rc::as_ptr(u) as *const (),
+            };
+            self.marked.insert(ptr);
+            self.traverse(object);
+        }
+        self.propagate_all();
+        self.converge_ephemerons();
+
+        //Weak values referring to objects being finalized are cleared before
+        //the finalizers run, and weak keys only in the next cycle
+        let weak_values = self.weak_values.len();
+        let all_weak = self.all_weak.len();
+        self.clear_values(&self.weak_values);
+        self.clear_values(&self.all_weak);
+
+        //Resurrects the objects to finalize, with everything they refer to
+        self.separate_finalizable(false);
+        for object in self.tobefnz.clone() {
+            self.mark_value(&LValue::Table(object));
+        }
+        self.propagate_all();
+        self.converge_ephemerons();
+
+        self.clear_keys(&self.ephemerons);
+        self.clear_keys(&self.all_weak);
+        self.clear_values(&self.weak_values[weak_values..]);
+        self.clear_values(&self.all_weak[all_weak..]);
+
+        self.weak_values.clear();
+        self.ephemerons.clear();
+        self.all_weak.clear();
+
+        self.phase = Phase::Sweep {
+            next: 0,
+            end: self.objects.len(),
+        };
+    }
+
+    /// Sweeps objects from `next` until `budget` work is done, returning
+    /// where to continue or None when the sweep is done
+    fn sweep(&mut self, next: usize, mut end: usize, budget: usize) -> Option<(usize, usize)> {
+        let mut i = next;
+        let mut work = 0;
+        while i < end {
+            if work >= budget {
+                return Some((i, end));
+            }
+            work += SWEEP_COST;
+
+            let size = self.objects[i].1;
+            let survivor = match &self.objects[i].0 {
+                GcObject::Table(t) => t.upgrade().map(|t| {
+                    let reachable = self.marked.remove(&(Rc::as_ptr(&t) as *const ()));
+                    if reachable {
+                        (true, table_size(&t.borrow()))
+                    } else {
+                        //Dropping the contents outside the borrow
+                        let contents = std::mem::take(&mut *t.borrow_mut());
+                        drop(contents);
+                        (false, 0)
+                    }
+                }),
+                GcObject::Closure(c) => c.upgrade().map(|c| {
+                    //Closures hold nothing but upvalues, which are cleared themselves
+                    let reachable = self.marked.remove(&(Rc::as_ptr(&c) as *const ()));
+                    (reachable, size)
+                }),
+                GcObject::Upvalue(u) => u.upgrade().map(|u| {
+                    let reachable = self.marked.remove(&(Rc::as_ptr(&u) as *const ()));
+                    //Open upvalues are still in use by their frame
+                    let open = matches!(*u.borrow(), LUpvalue::Open(_));
+                    if !reachable && !open {
+                        let value = std::mem::replace(
+                            &mut *u.borrow_mut(),
+                            LUpvalue::Closed(LValue::default()),
+                        );
+                        drop(value);
+                    }
+                    (reachable || open, size)
+                }),
+            };
+
+            match survivor {
+                Some((true, new_size)) => {
+                    self.total = self.total - size + new_size;
+                    self.objects[i].1 = new_size;
+                    i += 1;
+                }
+                //Freed by Rc or cleared above
+                _ => {
+                    //Keeps objects created since the sweep started after its end
+                    self.total -= size;
+                    self.objects.swap(i, end - 1);
+                    self.objects.swap_remove(end - 1);
+                    end -= 1;
+                }
+            }
+        }
+        None
+    }
+
+    /// Performs up to `budget` work, returning true if a cycle finished
+    fn run(
+        &mut self,
+        mut budget: usize,
+        stack: &mut Stack<'i>,
+        top: usize,
+        registry: &Rc<RefCell<LTable<'i>>>,
+        strings: &mut StringTable,
+    ) -> bool {
+        loop {
+            match self.phase {
+                Phase::Pause => {
+                    self.weak_values.clear();
+                    self.ephemerons.clear();
+                    self.all_weak.clear();
+                    self.mark_roots(&stack[..top], registry);
+                    self.phase = Phase::Propagate;
+                }
+                Phase::Propagate => {
+                    while budget > 0 {
+                        match self.gray.pop() {
+                            Some(object) => budget = budget.saturating_sub(self.traverse(object)),
+                            None => break,
+                        }
+                    }
+                    if !self.gray.is_empty() {
+                        return false;
+                    }
+                    self.atomic(stack, top, registry);
+                }
+                Phase::Sweep { next, end } => match self.sweep(next, end, budget) {
+                    Some((next, end)) => {
+                        self.phase = Phase::Sweep { next, end };
+                        return false;
+                    }
+                    None => {
+                        strings.sweep();
+                        self.marked.clear();
+                        self.grown = 0;
+                        self.phase = Phase::Pause;
+                        self.threshold = (self.total / 100 * self.pause).max(MIN_THRESHOLD);
+                        self.cycles += 1;
+                        return true;
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// End of the live part of the stack, the top of the innermost frame
+fn live_top(stack: &Stack, call_stack: &CallStack) -> usize {
+    call_stack
+        .last()
+        .map_or(stack.len(), |frame| frame.top.min(stack.len()))
+}
+
+/// Estimated bytes in use by the state: its objects, its strings and the
+/// stacks
+pub fn memory_used(genv: &GlobalEnv, stack: &Stack, call_stack: &CallStack) -> usize {
+    genv.gc.total()
+        + genv.strings.total()
+        + stack.capacity() * size_of::<LValue>()
+        + call_stack.capacity() * size_of::<CallInfo>()
+}
+
+/// Runs a step of the collector if enough was allocated since the last
+/// one, like luaC_checkGC. Every live value must be reachable from the
+/// stack or the registry
+///
+/// Must also be called after anything which allocates, as it enforces the
+/// memory limit. Allocations are only charged once they're made, so the
+/// state goes over its limit by at most the allocation that crossed it
+/// before raising a memory error
+pub fn check<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+) -> Result<(), LuaError> {
+    if genv.gc.running && genv.gc.total() >= genv.gc.threshold {
+        step(genv, stack, call_stack)?;
+    }
+
+    if let Some(limit) = genv.gc.memory_limit {
+        if memory_used(genv, stack, call_stack) > limit {
+            //Like a failed allocation, try an emergency collection before giving up.
+            //Finalizers don't run during it as they could allocate
+            collect(genv, stack, call_stack);
+            if memory_used(genv, stack, call_stack) > limit {
+                return Err(LuaError::Memory);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Performs a basic step, returning true if it finished a cycle. The
+/// finalizers of the objects the cycle found unreachable run once it
+/// finishes
+pub fn step<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+) -> Result<bool, LuaError> {
+    let GlobalEnv {
+        gc,
+        registry,
+        strings,
+        ..
+    } = genv;
+
+    let budget = (STEP_SIZE / 100 * gc.step_mul).max(SWEEP_COST);
+    let finished = gc.run(
+        budget,
+        stack,
+        live_top(stack, call_stack),
+        registry,
+        strings,
+    );
+    if finished {
+        call_finalizers(genv, stack, call_stack, true)?;
+    } else {
+        gc.threshold = gc.total + STEP_SIZE;
+    }
+    Ok(finished)
+}
+
+/// Performs a full cycle, like luaC_fullgc
+pub fn full_collect<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+) -> Result<(), LuaError> {
+    collect(genv, stack, call_stack);
+    call_finalizers(genv, stack, call_stack, true)
+}
+
+/// Performs a full cycle, leaving the finalizers it finds pending
+fn collect<'i>(genv: &mut GlobalEnv<'i>, stack: &mut Stack<'i>, call_stack: &CallStack<'i>) {
+    let GlobalEnv {
+        gc,
+        registry,
+        strings,
+        ..
+    } = genv;
+
+    let top = live_top(stack, call_stack);
+    match gc.phase {
+        //Marks from a cycle in progress may be stale, start over
+        Phase::Propagate => {
+            gc.marked.clear();
+            gc.gray.clear();
+            gc.gray_again.clear();
+            gc.phase = Phase::Pause;
+        }
+        //Let the sweep finish so that nothing is left marked
+        Phase::Sweep { .. } => {
+            gc.run(usize::MAX, stack, top, registry, strings);
+        }
+        Phase::Pause => {}
+    }
+
+    gc.run(usize::MAX, stack, top, registry, strings);
+}
+
+/// Runs the finalizer of every object with one, reachable or not, as the
+/// state closes. Errors in finalizers are ignored
+pub fn close<'i>(genv: &mut GlobalEnv<'i>, stack: &mut Stack<'i>, call_stack: &mut CallStack<'i>) {
+    genv.gc.closing = true;
+    genv.gc.separate_finalizable(true);
+    let _ = call_finalizers(genv, stack, call_stack, false);
+}
+
+/// Calls the pending finalizers above the top of the stack, like GCTM.
+/// Only functions are called, other `__gc` values are ignored
+fn call_finalizers<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    propagate_errors: bool,
+) -> Result<(), LuaError> {
+    while let Some(object) = genv.gc.tobefnz.pop_front() {
+        let finalizer = match &object.borrow().metatable {
+            Some(metatable) => metatable.borrow().get(genv.gc.gc_name.clone()),
+            None => LValue::default(),
+        };
+        if !matches!(finalizer, LValue::LClosure(_) | LValue::CClosure(_)) {
+            continue;
+        }
+
+        let func = stack.len();
+        stack.push(finalizer);
+        stack.push(LValue::Table(object));
+
+        //The collector doesn't run during finalizers, and they can't be suspended
+        let running = std::mem::replace(&mut genv.gc.running, false);
+        genv.non_yieldable += 1;
+        let callback = call_stack
+            .last_mut()
+            .and_then(|frame| frame.callback.replace(Callback::Finalizer));
+        let result = lclosure::call(genv, stack, call_stack, func, 1);
+        if let Some(frame) = call_stack.last_mut() {
+            frame.callback = callback;
+        }
+        genv.non_yieldable -= 1;
+        genv.gc.running = running;
+        stack.truncate(func);
+
+        if let Err(e) = result {
+            if propagate_errors {
+                return Err(e.in_finalizer());
+            }
+        }
+    }
+    Ok(())
+}
+
+const UPVALUE_SIZE: usize = size_of::<RefCell<LUpvalue>>() + 2 * size_of::<usize>();
+
+pub(crate) fn table_size(table: &LTable) -> usize {
+    size_of::<RefCell<LTable>>() + 2 * size_of::<usize>() + table.allocated_bytes()
+}
+
+pub(crate) fn closure_size(closure: &LClosure) -> usize {
+    size_of::<LClosure>()
+        + 2 * size_of::<usize>()
+        + closure.upvalues.len() * size_of::<UpvalueRef>()
+}
@@ -1,41 +1,438 @@
-use crate::lprimative::LValue;
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use crate::{
+    lprimative::{LPrimitive, LValue},
+    lstring::StringTable,
+};
+use std::{
+    cell::RefCell,
+    fmt::Write as _,
+    io::{self, Write},
+    rc::Rc,
+};
 
-use super::cfunction::{CFunction, CProto};
+use super::{
+    callinfo::{CallInfo, CallStack},
+    cfunction::CClosure,
+    counters::Counters,
+    debuglib,
+    error::LuaError,
+    gc::{self, Collector},
+    hook::Hooks,
+    interrupt::Interrupts,
+    lclosure,
+    ltable::LTable,
+    Stack,
+};
+
+/// Index of the globals table in the registry, LUA_RIDX_GLOBALS
+const RIDX_GLOBALS: i64 = 2;
+
+/// Key of the table of loaded libraries in the registry, like LUA_LOADED_TABLE.
+/// Functions are named after the library they're found in by tracebacks
+const LOADED_KEY: &str = "_LOADED";
+
+/// What happens once a script has used up its instruction budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetMode {
+    /// Raise an "instruction budget exhausted" error
+    Error,
+    /// Suspend execution so that it can be resumed once given more budget
+    Suspend,
+}
 
 /// 'i lifetime lives as long as the interpretter
 pub struct GlobalEnv<'i> {
-    pub(crate) items: HashMap<String, CFunction<'i>>,
+    /// Interned strings, every string of the state is created through it
+    pub(crate) strings: StringTable,
+    pub(crate) gc: Collector<'i>,
+    /// Table only reachable from Rust, a root of the collector
+    pub(crate) registry: Rc<RefCell<LTable<'i>>>,
+    /// Table of global variables, the initial value of every chunk's _ENV upvalue
+    pub(crate) globals: Rc<RefCell<LTable<'i>>>,
+    /// Table of the loaded libraries by name, also kept in the registry
+    pub(crate) loaded: Rc<RefCell<LTable<'i>>>,
+
+    /// Instructions left to execute before the budget runs out. Counts down
+    /// from u64::MAX when there's no budget
+    pub(crate) budget: u64,
+    /// None when there's no budget
+    pub(crate) budget_mode: Option<BudgetMode>,
+    /// Number of calls into Lua which can't be suspended in progress, like nny
+    pub(crate) non_yieldable: usize,
+    pub(crate) interrupt: Interrupts,
+    pub(crate) hooks: Hooks<'i>,
+    /// Where `print` writes, stdout unless the embedder redirects it
+    pub(crate) output: Box<dyn Write + 'i>,
+    /// Set when errors are given a crash report along with their traceback
+    pub(crate) crash_reports: bool,
+    pub(crate) counters: Counters<'i>,
+}
+impl<'i> GlobalEnv<'i> {
+    pub fn new(mut strings: StringTable) -> GlobalEnv<'i> {
+        let mut genv = Self {
+            gc: Collector::new(&mut strings),
+            strings,
+            registry: Rc::new(RefCell::new(LTable::default())),
+            globals: Rc::new(RefCell::new(LTable::default())),
+            loaded: Rc::new(RefCell::new(LTable::default())),
+            budget: u64::MAX,
+            budget_mode: None,
+            non_yieldable: 0,
+            interrupt: Interrupts::default(),
+            hooks: Hooks::new(),
+            output: Box::new(io::stdout()),
+            crash_reports: false,
+            counters: Counters::default(),
+        };
+        genv.gc.track_table(&genv.registry);
+        genv.gc.track_table(&genv.globals);
+        genv.gc.track_table(&genv.loaded);
+
+        genv.registry
+            .borrow_mut()
+            .set_int(RIDX_GLOBALS, LValue::Table(genv.globals.clone()));
+        let (registry, loaded) = (genv.registry.clone(), genv.loaded.clone());
+        genv.set_field(&registry, LOADED_KEY, LValue::Table(loaded.clone()));
+        genv.set_field(&loaded, "_G", LValue::Table(genv.globals.clone()));
+
+        genv.register("print", c_print);
+        genv.register("collectgarbage", c_collectgarbage);
+        genv.register("getmetatable", c_getmetatable);
+        genv.register("setmetatable", c_setmetatable);
+        genv.register("pcall", c_pcall);
+        debuglib::open(&mut genv);
+
+        genv
+    }
+
+    /// Sets a global to a host function
+    fn register(&mut self, name: &str, closure: CClosure<'i>) {
+        let globals = self.globals.clone();
+        self.set_function(&globals, name, closure);
+    }
+
+    /// Sets a global to a table of host functions, like luaL_newlib
+    pub(crate) fn register_library(&mut self, name: &str, functions: &[(&str, CClosure<'i>)]) {
+        let library = Rc::new(RefCell::new(LTable::with_sizes(0, functions.len())));
+        self.gc.track_table(&library);
+        for &(name, closure) in functions {
+            self.set_function(&library, name, closure);
+        }
+
+        let globals = self.globals.clone();
+        self.set_field(&globals, name, LValue::Table(library.clone()));
+        let loaded = self.loaded.clone();
+        self.set_field(&loaded, name, LValue::Table(library));
+    }
+
+    fn set_function(&mut self, table: &Rc<RefCell<LTable<'i>>>, name: &str, closure: CClosure<'i>) {
+        let function = LValue::CClosure(Rc::new(closure));
+        self.set_field(table, name, function);
+    }
+
+    /// Raw sets a string field of a table
+    pub(crate) fn set_field(
+        &mut self,
+        table: &Rc<RefCell<LTable<'i>>>,
+        name: &str,
+        value: LValue<'i>,
+    ) {
+        let name = LValue::LPrimitive(LPrimitive::STRING(self.strings.intern(name)));
+        table
+            .borrow_mut()
+            .set(name, value)
+            .expect("string keys are valid");
+    }
+
+    /// Raises an error if the host asked for the script to be interrupted.
+    /// Checked at backward jumps and calls
+    pub(crate) fn check_interrupt(&self, call_stack: &CallStack) -> Result<(), LuaError> {
+        match self.interrupt.is_interrupted() {
+            true => Err(LuaError::runtime(call_stack, "interrupted")),
+            false => Ok(()),
+        }
+    }
+
+    /// Called by the dispatch loop when the budget reaches 0, before it
+    /// executes the next instruction. The budget stays used up, so once
+    /// it's exhausted every instruction fails until the host gives it more
+    ///
+    /// Execution can't be suspended inside finalizers or host functions
+    /// without a continuation, so it's suspended as soon as they return
+    pub(crate) fn exhaust_budget(&mut self, call_stack: &CallStack) -> Result<(), LuaError> {
+        match self.budget_mode {
+            None => {
+                self.budget = u64::MAX;
+                Ok(())
+            }
+            Some(BudgetMode::Suspend) => {
+                if self.non_yieldable == 0 && call_stack.iter().all(CallInfo::is_resumable) {
+                    Err(LuaError::Suspended)
+                } else {
+                    //Checked again by the next instruction
+                    self.budget = 1;
+                    Ok(())
+                }
+            }
+            Some(BudgetMode::Error) => Err(LuaError::runtime(
+                call_stack,
+                "instruction budget exhausted",
+            )),
+        }
+    }
 }
-impl<'i> Default for GlobalEnv<'i> {
-    fn default() -> GlobalEnv<'i> {
-        let mut items: HashMap<String, CFunction<'i>> = HashMap::new();
 
-        items.insert(
-            "print".to_owned(),
-            (
-                CProto {
-                    num_params: 1,
-                    vararg_flag: 0,
-                },
-                c_print,
-            ),
-        );
+/// Error for a bad argument of a host function, like luaL_argerror
+pub(crate) fn arg_error(
+    call_stack: &CallStack,
+    n: usize,
+    name: &str,
+    message: impl AsRef<str>,
+) -> LuaError {
+    LuaError::host(
+        call_stack,
+        format!("bad argument #{} to '{}' ({})", n, name, message.as_ref()),
+    )
+}
 
-        Self { items }
+/// Type of an argument as reported by argument errors
+pub(crate) fn arg_type_name(args: &[LValue], n: usize) -> &'static str {
+    match args.get(n - 1) {
+        Some(arg) => arg.type_name(),
+        None => "no value",
     }
 }
 
-/// Prints to the top of stack, so all varargs, fixed args, etc
+/// Prints all of its arguments
 pub fn c_print<'i>(
-    _genv: &mut GlobalEnv<'i>,
-    args: &[Rc<RefCell<LValue<'i>>>],
-) -> Vec<Rc<RefCell<LValue<'i>>>> {
-    print!(" >> PRINT >> ");
-    for a in &args[1..] {
-        print!("{} ", a.borrow());
+    genv: &mut GlobalEnv<'i>,
+    _stack: &mut Stack<'i>,
+    _call_stack: &mut CallStack<'i>,
+    args: Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    let mut line = String::from(" >> PRINT >> ");
+    for a in args {
+        let _ = write!(line, "{} ", a);
     }
-    print!("\n");
+    //Like lua_writestring, failing to write isn't an error of the script
+    let _ = writeln!(genv.output, "{}", line);
 
-    return Vec::new();
+    Ok(Vec::new())
+}
+
+/// Controls the collector, `collectgarbage([opt [, arg]])`
+pub fn c_collectgarbage<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    args: Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    let option = match args.first() {
+        None | Some(LValue::LPrimitive(LPrimitive::NIL)) => "collect".to_owned(),
+        Some(LValue::LPrimitive(
+            p @ (LPrimitive::STRING(_) | LPrimitive::INT(_) | LPrimitive::FLOAT(_)),
+        )) => p.to_string(),
+        Some(_) => {
+            return Err(arg_error(
+                call_stack,
+                1,
+                "collectgarbage",
+                format!("string expected, got {}", arg_type_name(&args, 1)),
+            ))
+        }
+    };
+    let arg = match args.get(1) {
+        None | Some(LValue::LPrimitive(LPrimitive::NIL)) => 0,
+        Some(LValue::LPrimitive(p)) if p.to_number().is_some() => {
+            p.to_integer().ok_or_else(|| {
+                arg_error(
+                    call_stack,
+                    2,
+                    "collectgarbage",
+                    "number has no integer representation",
+                )
+            })?
+        }
+        Some(_) => {
+            return Err(arg_error(
+                call_stack,
+                2,
+                "collectgarbage",
+                format!("number expected, got {}", arg_type_name(&args, 2)),
+            ))
+        }
+    };
+
+    let int = |n: usize| LValue::LPrimitive(LPrimitive::INT(n as i64));
+    let result = match option.as_str() {
+        "collect" => {
+            gc::full_collect(genv, stack, call_stack)?;
+            int(0)
+        }
+        "count" => LValue::LPrimitive(LPrimitive::FLOAT(
+            gc::memory_used(genv, stack, call_stack) as f64 / 1024.0,
+        )),
+        "step" => {
+            //Steps run even when the collector is stopped
+            let mut finished = gc::step(genv, stack, call_stack)?;
+            //A size in KB does as much work as allocating that much would
+            let mut remaining = arg.max(0) as usize * 1024;
+            while !finished && remaining > gc::STEP_SIZE {
+                remaining -= gc::STEP_SIZE;
+                finished = gc::step(genv, stack, call_stack)?;
+            }
+            LValue::LPrimitive(LPrimitive::BOOL(finished))
+        }
+        "stop" => {
+            genv.gc.running = false;
+            int(0)
+        }
+        "restart" => {
+            genv.gc.running = true;
+            int(0)
+        }
+        "isrunning" => LValue::LPrimitive(LPrimitive::BOOL(genv.gc.running)),
+        "setpause" => int(std::mem::replace(&mut genv.gc.pause, arg.max(0) as usize)),
+        "setstepmul" => int(std::mem::replace(
+            &mut genv.gc.step_mul,
+            arg.max(0) as usize,
+        )),
+        option => {
+            return Err(arg_error(
+                call_stack,
+                1,
+                "collectgarbage",
+                format!("invalid option '{}'", option),
+            ))
+        }
+    };
+
+    Ok(vec![result])
+}
+
+/// Metatable of a value, or the `__metatable` field of the metatable if it
+/// has one, `getmetatable(object)`
+pub fn c_getmetatable<'i>(
+    genv: &mut GlobalEnv<'i>,
+    _stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    args: Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    let metatable = match args.first() {
+        Some(LValue::Table(t)) => t.borrow().metatable.clone(),
+        Some(_) => None,
+        None => return Err(arg_error(call_stack, 1, "getmetatable", "value expected")),
+    };
+
+    let result = match metatable {
+        Some(metatable) => {
+            let name = LValue::LPrimitive(LPrimitive::STRING(genv.strings.intern("__metatable")));
+            match metatable.borrow().get(name) {
+                LValue::LPrimitive(LPrimitive::NIL) => LValue::Table(metatable.clone()),
+                protected => protected,
+            }
+        }
+        None => LValue::default(),
+    };
+
+    Ok(vec![result])
+}
+
+/// Sets or removes the metatable of a table, `setmetatable(table, metatable)`
+pub fn c_setmetatable<'i>(
+    genv: &mut GlobalEnv<'i>,
+    _stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    args: Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    let table = match args.first() {
+        Some(LValue::Table(t)) => t.clone(),
+        _ => {
+            return Err(arg_error(
+                call_stack,
+                1,
+                "setmetatable",
+                format!("table expected, got {}", arg_type_name(&args, 1)),
+            ))
+        }
+    };
+    let metatable = match args.get(1) {
+        Some(LValue::Table(t)) => Some(t.clone()),
+        Some(LValue::LPrimitive(LPrimitive::NIL)) => None,
+        _ => {
+            return Err(arg_error(
+                call_stack,
+                2,
+                "setmetatable",
+                "nil or table expected",
+            ))
+        }
+    };
+
+    let name = LValue::LPrimitive(LPrimitive::STRING(genv.strings.intern("__metatable")));
+    if let Some(current) = &table.borrow().metatable {
+        if !matches!(
+            current.borrow().get(name),
+            LValue::LPrimitive(LPrimitive::NIL)
+        ) {
+            return Err(LuaError::host(
+                call_stack,
+                "cannot change a protected metatable",
+            ));
+        }
+    }
+
+    genv.gc.barrier_table(&table);
+    table.borrow_mut().metatable = metatable;
+    genv.gc.check_finalizer(&table);
+
+    Ok(vec![LValue::Table(table)])
+}
+
+/// Calls a function in protected mode, returning false and the error
+/// message instead of propagating errors, `pcall(f, ...)`
+pub fn c_pcall<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    args: Vec<LValue<'i>>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    if args.is_empty() {
+        return Err(arg_error(call_stack, 1, "pcall", "value expected"));
+    }
+
+    //The function is called in place with the arguments following it
+    let frame = call_stack
+        .last_mut()
+        .expect("host functions have a CallInfo");
+    frame.continuation = Some(finish_pcall);
+    let func = frame.base;
+
+    let status = lclosure::call(genv, stack, call_stack, func, args.len() - 1);
+    finish_pcall(genv, stack, call_stack, status)
+}
+
+/// Continuation of pcall, returning the outcome of the call. The failed
+/// call's frames were popped as its error propagated
+fn finish_pcall<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    status: Result<usize, LuaError>,
+) -> Result<Vec<LValue<'i>>, LuaError> {
+    let func = call_stack
+        .last()
+        .expect("host functions have a CallInfo")
+        .base;
+
+    match status {
+        Ok(num_results) => Ok(std::iter::once(LValue::LPrimitive(LPrimitive::BOOL(true)))
+            .chain(stack[func..func + num_results].iter().cloned())
+            .collect()),
+        //Suspending isn't an error, it unwinds to the interpreter
+        Err(LuaError::Suspended) => Err(LuaError::Suspended),
+        Err(e) => Ok(vec![
+            LValue::LPrimitive(LPrimitive::BOOL(false)),
+            LValue::LPrimitive(LPrimitive::STRING(genv.strings.intern(&e.to_string()))),
+        ]),
+    }
 }
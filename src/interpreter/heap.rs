@@ -0,0 +1,462 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    fmt::Write as _,
+    io::{self, Write},
+    mem::size_of,
+    rc::Rc,
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    lprimative::{LPrimitive, LValue},
+    lstring::LString,
+};
+
+use super::{
+    callinfo::CallStack,
+    cfunction::CFunction,
+    gc::{closure_size, table_size},
+    genv::GlobalEnv,
+    ldebug,
+    ltable::LTable,
+    lupvalue::LUpvalue,
+    Stack,
+};
+
+/// Characters of a string shown in its name
+const STRING_PREVIEW: usize = 32;
+
+/// What an edge of the heap graph goes through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// The value of a table field, labelled by its key
+    Field,
+    /// A table or function used as a key of a table
+    Key,
+    Upvalue,
+    Metatable,
+    /// A register of a frame, labelled by the name of its local
+    Local,
+    /// From a root to the table it stands for
+    Root,
+}
+impl EdgeKind {
+    fn name(&self) -> &'static str {
+        match self {
+            EdgeKind::Field => "field",
+            EdgeKind::Key => "key",
+            EdgeKind::Upvalue => "upvalue",
+            EdgeKind::Metatable => "metatable",
+            EdgeKind::Local => "local",
+            EdgeKind::Root => "root",
+        }
+    }
+}
+
+/// A table, function or string, or a root the others are reached from:
+/// the globals, the registry or a frame
+#[derive(Debug, Clone)]
+pub struct HeapNode {
+    /// "table", "function", "string" or "root"
+    pub kind: &'static str,
+    pub address: usize,
+    /// Estimated bytes, as counted by the collector
+    pub size: usize,
+    /// The shortest path from a root, "_G.cache[3]", or the contents of
+    /// a string
+    pub name: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct HeapEdge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+    pub label: String,
+    /// Set when the table holds it weakly, so it doesn't retain it
+    pub weak: bool,
+}
+
+/// Graph of the objects reachable from the globals, the registry and the
+/// frames being executed. Nodes are numbered in the order they're reached
+/// breadth first, so each is named after the shortest path retaining it
+#[derive(Debug, Default)]
+pub struct HeapSnapshot {
+    pub nodes: Vec<HeapNode>,
+    pub edges: Vec<HeapEdge>,
+}
+impl HeapSnapshot {
+    pub fn new<'i>(genv: &GlobalEnv<'i>, stack: &Stack<'i>, call_stack: &CallStack<'i>) -> Self {
+        let mut builder = Builder {
+            snapshot: Self::default(),
+            index: HashMap::new(),
+            queue: VecDeque::new(),
+            weak: VecDeque::new(),
+            stack,
+        };
+
+        let globals = builder.root("_G");
+        builder.edge(
+            globals,
+            &LValue::Table(genv.globals.clone()),
+            EdgeKind::Root,
+            "_G",
+            false,
+        );
+        let registry = builder.root("registry");
+        builder.edge(
+            registry,
+            &LValue::Table(genv.registry.clone()),
+            EdgeKind::Root,
+            "registry",
+            false,
+        );
+        for (level, i) in (0..call_stack.len()).rev().enumerate() {
+            let frame = builder.root(&format!(
+                "frame {} ({})",
+                level,
+                frame_function(call_stack, i)
+            ));
+            builder.edge(
+                frame,
+                &stack[call_stack[i].func],
+                EdgeKind::Local,
+                "(function)",
+                false,
+            );
+            for n in (1..).map_while(|n| ldebug::find_local(stack, call_stack, i, -n)) {
+                builder.edge(frame, &stack[n.1], EdgeKind::Local, n.0, false);
+            }
+            for n in (1..).map_while(|n| ldebug::find_local(stack, call_stack, i, n)) {
+                builder.edge(frame, &stack[n.1], EdgeKind::Local, n.0, false);
+            }
+        }
+
+        builder.run();
+        builder.snapshot
+    }
+
+    /// Edges into a node, from the objects retaining it
+    pub fn retainers(&self, node: usize) -> impl Iterator<Item = &HeapEdge> {
+        self.edges.iter().filter(move |edge| edge.to == node)
+    }
+
+    /// Nodes whose name or address, "0x55d0c3a4f2b0", is `query`
+    pub fn find(&self, query: &str) -> impl Iterator<Item = usize> + '_ {
+        let query = query.to_owned();
+        self.nodes.iter().enumerate().filter_map(move |(n, node)| {
+            (node.name == query || format!("{:#x}", node.address) == query).then_some(n)
+        })
+    }
+
+    /// Describes what retains a node, one line per retainer with the path
+    /// from a root to it
+    pub fn write_retainers(&self, node: usize, output: &mut impl Write) -> io::Result<()> {
+        let target = &self.nodes[node];
+        writeln!(
+            output,
+            "{} {} ({:#x}, {} bytes) is retained by:",
+            target.kind, target.name, target.address, target.size
+        )?;
+        for edge in self.retainers(node) {
+            let from = &self.nodes[edge.from];
+            let mut line = String::from("  ");
+            if edge.weak {
+                line.push_str("weak ");
+            }
+            let _ = match edge.kind {
+                EdgeKind::Metatable => write!(line, "metatable of "),
+                kind => write!(line, "{} '{}' of ", kind.name(), edge.label),
+            };
+            let _ = match from.kind {
+                "root" => write!(line, "{}", from.name),
+                kind => write!(line, "{} {} ({:#x})", kind, from.name, from.address),
+            };
+            writeln!(output, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "nodes": self.nodes.iter().enumerate().map(|(id, node)| json!({
+                "id": id,
+                "type": node.kind,
+                "address": format!("{:#x}", node.address),
+                "size": node.size,
+                "name": node.name,
+            })).collect::<Vec<_>>(),
+            "edges": self.edges.iter().map(|edge| json!({
+                "from": edge.from,
+                "to": edge.to,
+                "kind": edge.kind.name(),
+                "label": edge.label,
+                "weak": edge.weak,
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    /// Graphviz digraph with a node per object, roots as boxes
+    pub fn write_dot(&self, output: &mut impl Write) -> io::Result<()> {
+        writeln!(output, "digraph heap {{")?;
+        writeln!(output, "  node [shape=ellipse];")?;
+        for (id, node) in self.nodes.iter().enumerate() {
+            let (label, shape) = match node.kind {
+                "root" => (escape(&node.name), " shape=box"),
+                kind => (
+                    format!("{}\\n{}\\n{} bytes", kind, escape(&node.name), node.size),
+                    "",
+                ),
+            };
+            writeln!(output, "  n{} [label=\"{}\"{}];", id, label, shape)?;
+        }
+        for edge in &self.edges {
+            let label = match edge.kind {
+                EdgeKind::Field | EdgeKind::Local | EdgeKind::Root => edge.label.clone(),
+                EdgeKind::Key | EdgeKind::Upvalue => {
+                    format!("{} {}", edge.kind.name(), edge.label)
+                }
+                EdgeKind::Metatable => "metatable".to_owned(),
+            };
+            let style = if edge.weak { " style=dashed" } else { "" };
+            writeln!(
+                output,
+                "  n{} -> n{} [label=\"{}\"{}];",
+                edge.from,
+                edge.to,
+                escape(&label),
+                style
+            )?;
+        }
+        writeln!(output, "}}")
+    }
+}
+
+struct Builder<'s, 'i> {
+    snapshot: HeapSnapshot,
+    /// Node of each object, by address
+    index: HashMap<usize, usize>,
+    /// Objects reached but not yet traversed
+    queue: VecDeque<(usize, LValue<'i>)>,
+    /// Weak edges to objects not reached yet, followed once everything
+    /// retained is, so that objects are named after a path retaining them
+    weak: VecDeque<(usize, LValue<'i>, EdgeKind, String)>,
+    /// For open upvalues
+    stack: &'s Stack<'i>,
+}
+impl<'s, 'i> Builder<'s, 'i> {
+    fn root(&mut self, name: &str) -> usize {
+        self.snapshot.nodes.push(HeapNode {
+            kind: "root",
+            address: 0,
+            size: 0,
+            name: name.to_owned(),
+        });
+        self.snapshot.nodes.len() - 1
+    }
+
+    /// Adds an edge to the node of `value`, reaching it if it's new, or
+    /// once everything retained is if the edge is weak. Values which aren't
+    /// objects have no node
+    fn edge(&mut self, from: usize, value: &LValue<'i>, kind: EdgeKind, label: &str, weak: bool) {
+        if weak && address(value).is_some_and(|address| !self.index.contains_key(&address)) {
+            self.weak
+                .push_back((from, value.clone(), kind, label.to_owned()));
+            return;
+        }
+        self.reach(from, value, kind, label, weak);
+    }
+
+    /// Adds an edge to the node of `value`, reaching it if it's new
+    fn reach(&mut self, from: usize, value: &LValue<'i>, kind: EdgeKind, label: &str, weak: bool) {
+        let Some(address) = address(value) else {
+            return;
+        };
+        let (kind_name, size) = match value {
+            LValue::Table(t) => ("table", table_size(&t.borrow())),
+            LValue::LClosure(c) => ("function", closure_size(c)),
+            LValue::CClosure(_) => ("function", size_of::<CFunction>()),
+            LValue::LPrimitive(LPrimitive::STRING(s)) => ("string", size_of::<LString>() + s.len()),
+            LValue::LPrimitive(_) => unreachable!("primitives have no address"),
+        };
+
+        let to = match self.index.get(&address) {
+            Some(&to) => to,
+            None => {
+                let name = match value {
+                    LValue::LPrimitive(LPrimitive::STRING(s)) => string_preview(s),
+                    _ => self.path(from, kind, label),
+                };
+                self.snapshot.nodes.push(HeapNode {
+                    kind: kind_name,
+                    address,
+                    size,
+                    name,
+                });
+                let to = self.snapshot.nodes.len() - 1;
+                self.index.insert(address, to);
+                if matches!(value, LValue::Table(_) | LValue::LClosure(_)) {
+                    self.queue.push_back((to, value.clone()));
+                }
+                to
+            }
+        };
+        self.snapshot.edges.push(HeapEdge {
+            from,
+            to,
+            kind,
+            label: label.to_owned(),
+            weak,
+        });
+    }
+
+    /// Name of an object reached from `from`: "t.name", "t[1]", "t:upvalue",
+    /// "getmetatable(t)" or "(key of t)"
+    fn path(&self, from: usize, kind: EdgeKind, label: &str) -> String {
+        let from = &self.snapshot.nodes[from];
+        let parent = &from.name;
+        match kind {
+            EdgeKind::Root => label.to_owned(),
+            EdgeKind::Local => format!("{}: {}", parent, label),
+            EdgeKind::Field if label.starts_with('[') => format!("{}{}", parent, label),
+            EdgeKind::Field if from.name == "_G" => label.to_owned(),
+            EdgeKind::Field => format!("{}.{}", parent, label),
+            EdgeKind::Upvalue => format!("{}:{}", parent, label),
+            EdgeKind::Metatable => format!("getmetatable({})", parent),
+            EdgeKind::Key => format!("(key of {})", parent),
+        }
+    }
+
+    fn run(&mut self) {
+        loop {
+            let Some((node, value)) = self.queue.pop_front() else {
+                //Objects only held weakly are reached last
+                match self.weak.pop_front() {
+                    Some((from, value, kind, label)) => {
+                        self.reach(from, &value, kind, &label, true);
+                        continue;
+                    }
+                    None => break,
+                }
+            };
+            match value {
+                LValue::Table(table) => self.table(node, &table),
+                LValue::LClosure(closure) => {
+                    for (n, upvalue) in closure.upvalues.iter().enumerate() {
+                        let value = match &*upvalue.borrow() {
+                            LUpvalue::Open(i) => self.stack[*i].clone(),
+                            LUpvalue::Closed(value) => value.clone(),
+                        };
+                        let name = closure.proto.upvalue_name(n).unwrap_or("?");
+                        self.edge(node, &value, EdgeKind::Upvalue, name, false);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn table(&mut self, node: usize, table: &Rc<RefCell<LTable<'i>>>) {
+        let table = table.borrow();
+        let (weak_keys, weak_values) = weakness(&table);
+        if let Some(metatable) = &table.metatable {
+            let metatable = LValue::Table(metatable.clone());
+            self.edge(node, &metatable, EdgeKind::Metatable, "metatable", false);
+        }
+        for (i, value) in table.array_values().enumerate() {
+            self.edge(
+                node,
+                value,
+                EdgeKind::Field,
+                &format!("[{}]", i + 1),
+                weak_values,
+            );
+        }
+        for (key, value) in table.hash_entries() {
+            let label = key_label(key);
+            //String keys are already in the label
+            if !matches!(key, LValue::LPrimitive(_)) {
+                self.edge(node, key, EdgeKind::Key, &label, weak_keys);
+            }
+            self.edge(node, value, EdgeKind::Field, &label, weak_values);
+        }
+    }
+}
+
+/// Address of the object a value refers to, which its node is found by.
+/// Values which aren't objects have none
+fn address(value: &LValue) -> Option<usize> {
+    match value {
+        LValue::Table(t) => Some(Rc::as_ptr(t) as *const u8 as usize),
+        LValue::LClosure(c) => Some(Rc::as_ptr(c) as *const u8 as usize),
+        LValue::CClosure(f) => Some(**f as usize),
+        LValue::LPrimitive(LPrimitive::STRING(s)) => Some(Rc::as_ptr(s) as *const u8 as usize),
+        LValue::LPrimitive(_) => None,
+    }
+}
+
+/// Whether a table holds its keys and its values weakly, from the `__mode`
+/// field of its metatable
+fn weakness(table: &LTable) -> (bool, bool) {
+    let Some(metatable) = &table.metatable else {
+        return (false, false);
+    };
+    for (key, value) in metatable.borrow().hash_entries() {
+        if let (
+            LValue::LPrimitive(LPrimitive::STRING(key)),
+            LValue::LPrimitive(LPrimitive::STRING(mode)),
+        ) = (key, value)
+        {
+            if &***key == "__mode" {
+                return (mode.contains('k'), mode.contains('v'));
+            }
+        }
+    }
+    (false, false)
+}
+
+/// A key as it's written in a path, "name" for identifiers and "[key]"
+/// otherwise
+fn key_label(key: &LValue) -> String {
+    match key {
+        LValue::LPrimitive(LPrimitive::STRING(s)) if is_identifier(s) => s.to_string(),
+        LValue::LPrimitive(LPrimitive::STRING(s)) => format!("[{:?}]", s.to_string()),
+        key => format!("[{}]", key),
+    }
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn string_preview(s: &str) -> String {
+    let mut preview = String::from("\"");
+    for c in s.chars().take(STRING_PREVIEW) {
+        let _ = write!(preview, "{}", c.escape_default());
+    }
+    if s.chars().count() > STRING_PREVIEW {
+        preview.push_str("...");
+    }
+    preview.push('"');
+    preview
+}
+
+/// The function running in frame `i` as tracebacks name it
+fn frame_function(call_stack: &CallStack, i: usize) -> String {
+    match (ldebug::func_name(call_stack, i), call_stack[i].proto) {
+        (Some((what, name)), _) => format!("{} '{}'", what, name),
+        (None, Some(proto)) if proto.line_defined == 0 => "main chunk".to_owned(),
+        (None, Some(proto)) => format!("function <{}:{}>", proto.short_src(), proto.line_defined),
+        (None, None) => "?".to_owned(),
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,255 @@
+use crate::lprimative::{LPrimitive, LValue};
+
+use super::{
+    callinfo::{CallStack, Callback},
+    error::LuaError,
+    genv::GlobalEnv,
+    lclosure, Stack,
+};
+
+/// Events a hook asks for, like the LUA_MASK* constants
+pub const MASK_CALL: u8 = 1 << 0;
+pub const MASK_RET: u8 = 1 << 1;
+pub const MASK_LINE: u8 = 1 << 2;
+pub const MASK_COUNT: u8 = 1 << 3;
+
+/// Key of the hook set by debug.sethook in the registry, like HOOKKEY
+pub(crate) const HOOK_KEY: &str = "_HKEY";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    /// A function was called, before it runs its first instruction
+    Call,
+    /// A Lua function was tail called, replacing the frame of the caller
+    TailCall,
+    /// A function is returning
+    Return,
+    /// The VM is about to execute an instruction of a new line, or to go
+    /// back to an earlier instruction
+    Line(i64),
+    /// The VM executed the number of instructions the hook asked for
+    Count,
+}
+impl HookEvent {
+    /// Name passed to Lua hooks, like hooknames
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookEvent::Call => "call",
+            HookEvent::TailCall => "tail call",
+            HookEvent::Return => "return",
+            HookEvent::Line(_) => "line",
+            HookEvent::Count => "count",
+        }
+    }
+
+    fn mask(&self) -> u8 {
+        match self {
+            HookEvent::Call | HookEvent::TailCall => MASK_CALL,
+            HookEvent::Return => MASK_RET,
+            HookEvent::Line(_) => MASK_LINE,
+            HookEvent::Count => MASK_COUNT,
+        }
+    }
+}
+
+/// Hook set from Rust. The innermost frame of the call stack is the one
+/// the event is about. Errors it returns are raised in the script
+pub type Hook<'i> =
+    Box<dyn FnMut(HookEvent, &Stack<'i>, &CallStack<'i>) -> Result<(), LuaError> + 'i>;
+
+/// Events a hook receives and how often it gets count events
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct HookMask {
+    pub(crate) mask: u8,
+    pub(crate) count: u64,
+    /// Instructions left until the next count event
+    remaining: u64,
+}
+impl HookMask {
+    /// Count events are only sent if `count` isn't 0
+    pub fn new(mask: u8, count: u64) -> Self {
+        Self {
+            mask: match count {
+                0 => mask & !MASK_COUNT,
+                _ => mask,
+            },
+            count,
+            remaining: count,
+        }
+    }
+
+    /// Counts an instruction, returning true when a count event is due
+    fn tick(&mut self) -> bool {
+        if self.mask & MASK_COUNT == 0 {
+            return false;
+        }
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.remaining = self.count;
+            return true;
+        }
+        false
+    }
+}
+
+/// The hooks of the state. The Lua hook set by debug.sethook and the Rust
+/// hook are independent, each receives the events it asked for
+#[derive(Default)]
+pub struct Hooks<'i> {
+    /// The function itself is kept in the registry so the collector sees it
+    pub(crate) lua: HookMask,
+    pub(crate) rust: Option<(Hook<'i>, HookMask)>,
+    /// Union of the masks, checked by the VM before anything else
+    pub(crate) mask: u8,
+    /// Cleared while a hook runs, like allowhook
+    allowed: bool,
+    /// Instruction of the innermost frame which last got a line event, or
+    /// the call it's in when the frame above returns, like oldpc
+    pub(crate) old_pc: usize,
+}
+impl<'i> Hooks<'i> {
+    pub fn new() -> Self {
+        Self {
+            allowed: true,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn set_lua(&mut self, mask: HookMask) {
+        self.lua = mask;
+        self.update_mask();
+    }
+
+    pub(crate) fn set_rust(&mut self, hook: Option<(Hook<'i>, HookMask)>) {
+        self.rust = hook;
+        self.update_mask();
+    }
+
+    fn update_mask(&mut self) {
+        self.mask = self.lua.mask | self.rust.as_ref().map_or(0, |(_, mask)| mask.mask);
+    }
+}
+
+/// Called by the VM before each instruction when there are line or count
+/// hooks, like luaG_traceexec. Instructions run by hooks are counted and
+/// tracked too, but don't send events
+pub(crate) fn trace_exec<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    pc: usize,
+) -> Result<(), LuaError> {
+    let hooks = &mut genv.hooks;
+    let lua_count = hooks.lua.tick();
+    let rust_count = hooks.rust.as_mut().is_some_and(|(_, mask)| mask.tick());
+    if (lua_count || rust_count) && hooks.allowed {
+        dispatch(
+            genv,
+            stack,
+            call_stack,
+            HookEvent::Count,
+            lua_count,
+            rust_count,
+        )?;
+    }
+
+    if genv.hooks.mask & MASK_LINE != 0 {
+        let proto = call_stack
+            .last()
+            .and_then(|frame| frame.proto)
+            .expect("only Lua frames execute instructions");
+        let old_pc = std::mem::replace(&mut genv.hooks.old_pc, pc);
+
+        //Entering a function, jumping backwards (even to the same line) or moving to a new line
+        if pc == 0 || pc <= old_pc || proto.line_at(pc) != proto.line_at(old_pc) {
+            if let Some(line) = proto.line_at(pc) {
+                call_hooks(genv, stack, call_stack, HookEvent::Line(line))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Calls the hooks which asked for a call, return or line event
+pub(crate) fn call_hooks<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    event: HookEvent,
+) -> Result<(), LuaError> {
+    let hooks = &genv.hooks;
+    if !hooks.allowed {
+        return Ok(());
+    }
+
+    let lua = hooks.lua.mask & event.mask() != 0;
+    let rust = hooks
+        .rust
+        .as_ref()
+        .is_some_and(|(_, mask)| mask.mask & event.mask() != 0);
+    if lua || rust {
+        dispatch(genv, stack, call_stack, event, lua, rust)?;
+    }
+    Ok(())
+}
+
+/// Runs the hooks for an event with further hooks disabled, like luaD_hook
+fn dispatch<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    event: HookEvent,
+    lua: bool,
+    rust: bool,
+) -> Result<(), LuaError> {
+    genv.hooks.allowed = false;
+    genv.non_yieldable += 1;
+    let callback = call_stack
+        .last_mut()
+        .and_then(|frame| frame.callback.replace(Callback::Hook));
+
+    let mut result = Ok(());
+    if rust {
+        if let Some((hook, _)) = &mut genv.hooks.rust {
+            result = hook(event, stack, call_stack);
+        }
+    }
+    if lua && result.is_ok() {
+        result = call_lua_hook(genv, stack, call_stack, event);
+    }
+
+    if let Some(frame) = call_stack.last_mut() {
+        frame.callback = callback;
+    }
+    genv.non_yieldable -= 1;
+    genv.hooks.allowed = true;
+    result
+}
+
+/// Calls the function set by debug.sethook with the event name and the
+/// line for line events, like hookf
+fn call_lua_hook<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    event: HookEvent,
+) -> Result<(), LuaError> {
+    let key = LValue::LPrimitive(LPrimitive::STRING(genv.strings.intern(HOOK_KEY)));
+    let hook = genv.registry.borrow().get(key);
+    if !matches!(hook, LValue::LClosure(_) | LValue::CClosure(_)) {
+        return Ok(());
+    }
+    let name = LValue::LPrimitive(LPrimitive::STRING(genv.strings.intern(event.name())));
+    let line = match event {
+        HookEvent::Line(line) => LValue::LPrimitive(LPrimitive::INT(line)),
+        _ => LValue::default(),
+    };
+
+    //Above everything the frame being hooked uses
+    let func = stack.len();
+    stack.extend([hook, name, line]);
+    let result = lclosure::call(genv, stack, call_stack, func, 2);
+    stack.truncate(func);
+
+    result.map(|_| ())
+}
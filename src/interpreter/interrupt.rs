@@ -0,0 +1,68 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// Cancels a run of an interpreter, from any thread. The VM raises an
+/// "interrupted" error at the next backward jump or call, so every loop
+/// and recursion notices it
+///
+/// The request stays set while the error unwinds the script, so `pcall`
+/// can't keep it running. A handle only applies to the run it was made
+/// for, so one used late, like a timer outliving its run, can't cancel a
+/// later run
+#[derive(Debug, Clone)]
+pub struct InterruptHandle {
+    requests: Arc<Requests>,
+    run: u64,
+}
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        //Handles of earlier runs can't take the request back from later ones
+        self.requests
+            .interrupted
+            .fetch_max(self.run, Ordering::Relaxed);
+    }
+}
+
+/// Interrupt requests of an interpreter, shared with the handles of its
+/// runs
+#[derive(Debug, Default)]
+pub(crate) struct Interrupts(Arc<Requests>);
+
+#[derive(Debug, Default)]
+struct Requests {
+    /// Number of the run in progress, or of the last one. Runs count from 1
+    run: AtomicU64,
+    /// Number of the latest run asked to stop, 0 if none was
+    interrupted: AtomicU64,
+}
+
+impl Interrupts {
+    /// Starts a new run, which requests for earlier runs don't apply to
+    pub(crate) fn start_run(&self) {
+        self.0.run.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Handle for the run in progress, or the next one to start if there
+    /// isn't one
+    pub(crate) fn handle(&self, running: bool) -> InterruptHandle {
+        let run = self.0.run.load(Ordering::Relaxed);
+        InterruptHandle {
+            requests: self.0.clone(),
+            run: match running {
+                true => run,
+                false => run + 1,
+            },
+        }
+    }
+
+    pub(crate) fn is_interrupted(&self) -> bool {
+        self.0.interrupted.load(Ordering::Relaxed) == self.0.run.load(Ordering::Relaxed)
+    }
+}
+
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<InterruptHandle>();
+};
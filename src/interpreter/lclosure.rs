@@ -1,11 +1,30 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, cmp::Ordering, rc::Rc};
+
+use tracing::{debug, debug_span, trace, Span};
 
 use crate::{
     bytecode::{binstruction::BInstruction, bproto::BProto},
     lprimative::{LPrimitive, LValue},
 };
 
-use super::{genv::GlobalEnv, Stack};
+use super::{
+    arith::{arith_values, compare_values, equals_values, ArithOp},
+    callinfo::{CallInfo, CallStack},
+    error::LuaError,
+    gc::{self, Collector},
+    genv::GlobalEnv,
+    hook::{self, HookEvent, MASK_CALL, MASK_COUNT, MASK_LINE},
+    ldebug::{var_info, Operand},
+    ltable::{fb2int, LTable},
+    lupvalue::{close_upvalues, find_upvalue, UpvalueRef},
+    Stack,
+};
+
+/// Set on a B or C operand when it indexes the constant table rather than a register
+pub(crate) const BITRK: usize = 1 << 8;
+
+/// Number of list items SETLIST flushes at a time, LFIELDS_PER_FLUSH
+const FIELDS_PER_FLUSH: usize = 50;
 
 macro_rules! Kst {
     ($proto:expr, $n:expr) => {
@@ -25,9 +44,22 @@ macro_rules! Proto {
             .expect("No proto exists at Proto!() lookup")
     };
 }
-macro_rules! genv {
-    ($genv:expr, $n:expr) => {
-        $genv.items.get($n).expect("Couldn't find genv!() lookup")
+macro_rules! Upvalue {
+    ($closure:expr, $n:expr) => {
+        $closure
+            .upvalues
+            .get($n as usize)
+            .expect("No upvalue exists at Upvalue!() lookup")
+    };
+}
+/// Value of a B or C operand, which is either a register or a constant
+macro_rules! RK {
+    ($stack:expr, $base:expr, $proto:expr, $n:expr) => {
+        if $n & BITRK != 0 {
+            LValue::LPrimitive(Kst!($proto, $n & !BITRK).clone())
+        } else {
+            $stack[$base + $n].clone()
+        }
     };
 }
 
@@ -38,53 +70,165 @@ macro_rules! genv {
 /// 'i lifetime lives as long as the Interpreter does
 #[derive(Debug)]
 pub struct LClosure<'i> {
-    proto: &'i BProto,
-    upvalues: Vec<Rc<RefCell<LValue<'i>>>>,
+    pub(crate) proto: &'i BProto,
+    pub(crate) upvalues: Vec<UpvalueRef<'i>>,
 }
 impl<'i> LClosure<'i> {
-    /// A closure is instantiated by CLOSURE instruction followed by
-    /// either MOVE or GETUPVAL instructions corresponding to each upvalue
-    /// the proto needs. In this manor upvalues are stored in the closure
-    /// rather than the closure having to maintain references to its parents
+    /// A closure is instantiated by the CLOSURE instruction, which captures
+    /// each upvalue the proto describes either from a register of the
+    /// enclosing function or from the enclosing function's own upvalues.
+    /// In this manor upvalues are stored in the closure rather than the
+    /// closure having to maintain references to its parents
     ///
-    /// The upvalue LValue's must be reference variants to LValue's owned by
-    /// stacks of parent closures
-    pub fn new(proto: &'i BProto, upvalues: Vec<Rc<RefCell<LValue<'i>>>>) -> LClosure<'i> {
+    /// Upvalues capturing the same variable are shared between closures
+    pub fn new(proto: &'i BProto, upvalues: Vec<UpvalueRef<'i>>) -> LClosure<'i> {
         Self { proto, upvalues }
     }
 
+    /// Lays out the arguments of a call the way `execute` expects them and
+    /// makes room on the stack for the proto's registers. Returns the base.
+    ///
+    /// The caller places the function at `func` followed by its `num_args`
+    /// arguments. Vararg protos have their fixed arguments moved after the
+    /// variable ones so that the varargs sit between func and base.
+    /// Missing fixed arguments are nil.
+    pub fn prepare_frame(
+        proto: &BProto,
+        stack: &mut Stack<'i>,
+        func: usize,
+        num_args: usize,
+    ) -> usize {
+        let num_params = proto.num_params as usize;
+
+        let base = if proto.vararg_flag != 0 && num_args > num_params {
+            stack[func + 1..func + 1 + num_args].rotate_left(num_params);
+            func + 1 + num_args - num_params
+        } else {
+            func + 1
+        };
+
+        //Ensure the stack is large enough
+        let top = base + proto.max_stack as usize;
+        if stack.len() < top {
+            stack.resize_with(top, LValue::default);
+        }
+
+        for i in num_args.min(num_params)..num_params {
+            stack[base + i] = LValue::default();
+        }
+
+        base
+    }
+
+    /// Finishes the CALL or TAILCALL the innermost frame was suspended in,
+    /// now that the callee left its results at R(A), then carries on
+    /// executing it
+    fn finish_call(
+        genv: &mut GlobalEnv<'i>,
+        stack: &mut Stack<'i>,
+        call_stack: &mut CallStack<'i>,
+        num_results: usize,
+    ) -> Result<usize, LuaError> {
+        let frame = call_stack
+            .last_mut()
+            .expect("finish_call called without a CallInfo");
+        let proto = frame.proto.expect("Lua frames have a proto");
+
+        match proto.instructions.list[frame.pc] {
+            BInstruction::ABC {
+                opcode: 36, a, c, ..
+            } => {
+                let ra = frame.base + a as usize;
+                match c as usize {
+                    0 => frame.saved_top = ra + num_results,
+                    c => {
+                        for i in num_results..c - 1 {
+                            stack[ra + i] = LValue::default();
+                        }
+                    }
+                }
+
+                frame.pc += 1;
+                Self::execute(genv, stack, call_stack)
+            }
+            BInstruction::ABC { opcode: 37, a, .. } => {
+                //Only host callees return to a TAILCALL, whose results are returned
+                let ra = frame.base + a as usize;
+                for i in 0..num_results {
+                    stack.swap(frame.func + i, ra + i);
+                }
+                Ok(num_results)
+            }
+            _ => unreachable!("frames are only suspended in calls"),
+        }
+    }
+
+    /// Runs the innermost frame of the call stack until it returns,
+    /// starting from the instruction and top saved in its CallInfo so that
+    /// suspended frames carry on where they stopped. Returns the number of
+    /// results, which are moved down to the frame's func
     pub fn execute(
-        &self,
         genv: &mut GlobalEnv<'i>,
         // Begins at and includes the Closure being called. Following
         // that come varargs then fixed args. The base is the offset
         // from the bottom of the stack to where the first fixed arg begins
         // The stack must not be a slice because this function needs to be able to extend the underlying Vector as it sees fit
         stack: &mut Stack<'i>,
+        // The caller pushes a CallInfo for this call before executing it
+        call_stack: &mut CallStack<'i>,
+    ) -> Result<usize, LuaError> {
+        let frame = call_stack
+            .last()
+            .expect("execute called without a CallInfo");
+        //Index of the current LClosure being executed on the stack. Between this and base are variable arguments
+        let func = frame.func;
+        //Base index of the stack for this function. The index of the first fixed argument
+        let mut base = frame.base;
 
-        top: usize,  //Top index of the stack for this function
-        base: usize, //Base index of the stack for this function. The index of the first fixed argument
-        func: usize, //Index of the current LClosure/CClosure being executed on the stack. Between this and base are variable arguments
-    ) {
         //Instruction execution
-        let mut pc = 0;
+        let mut pc = frame.pc;
+
+        //One past the last value produced by an instruction with a variable
+        //number of results (CALL with C=0 and VARARG with B=0), to be
+        //consumed by the next CALL with B=0, RETURN with B=0 or SETLIST with B=0
+        let mut top = frame.saved_top;
+
+        //A tail call swaps the closure being executed in place, reusing this frame
+        let mut closure = match &stack[func] {
+            LValue::LClosure(closure) => closure.clone(),
+            _ => unreachable!("Lua frames execute a Lua closure"),
+        };
 
         loop {
-            let instruction = self
-                .proto
+            let proto = closure.proto;
+            let instruction = proto
                 .instructions
                 .list
                 .get(pc)
-                .expect(format!("no instruction found at pc={}", pc).as_str());
+                .unwrap_or_else(|| panic!("no instruction found at pc={}", pc));
 
-            println!(
-                "executing func={} base={} top={} {:?}",
-                func, base, top, instruction
-            );
+            let frame = call_stack
+                .last_mut()
+                .expect("execute called without a CallInfo");
+            frame.pc = pc;
+
+            if genv.budget == 0 {
+                //Suspended frames carry on from this instruction
+                frame.saved_top = top;
+                genv.exhaust_budget(call_stack)?;
+            }
+            genv.budget -= 1;
+
+            if genv.hooks.mask & (MASK_LINE | MASK_COUNT) != 0 {
+                hook::trace_exec(genv, stack, call_stack, pc)?;
+            }
+
+            trace!(func, base, pc, ?instruction, "executing");
+            genv.counters.opcodes[instruction.opcode() as usize] += 1;
 
             match *instruction {
                 BInstruction::ABC {
-                    line,
+                    line: _,
                     opcode,
                     a,
                     b,
@@ -104,19 +248,9 @@ impl<'i> LClosure<'i> {
                             //  closures, always appearing after the CLOSURE instruction; see CLOSURE
                             //  for more information.
 
-                            let b = match &*stack[base + b].borrow() {
-                                //By value
-                                LValue::LPrimitive(p) => {
-                                    Rc::new(RefCell::new(LValue::LPrimitive(p.clone())))
-                                }
-                                //By reference
-                                LValue::LClosure(_) => stack[base + b].clone(),
-                                _ => todo!("MOVE by reference or unhandled"),
-                            };
-
-                            stack[base + a] = b;
+                            stack[base + a] = stack[base + b].clone();
                         }
-                        2 => {
+                        3 => {
                             // LOADBOOL
                             //  Loads a boolean value (true or false) into register R(A). true is usually
                             //  encoded as an integer 1, false is always 0. If C is non-zero, then the next
@@ -125,66 +259,219 @@ impl<'i> LClosure<'i> {
                             //
                             //  You can use any non-zero value for the boolean true in field B, but since
                             //  you cannot use booleans as numbers in Lua, it’s best to stick to 1 for true.
-                            // let b = match &*stack[base + b].borrow() {
-                            //     //By value
-                            //     LValue::LPrimitive(p) => {
-                            //         Rc::new(RefCell::new(LValue::LPrimitive(p.clone())))
-                            //     }
-                            //     //By reference
-                            //     _ => todo!("MOVE by reference or unhandled"),
-                            // };
-
-                            stack[base + a] =
-                                Rc::new(RefCell::new(LValue::LPrimitive(LPrimitive::BOOL(b != 0))));
+
+                            stack[base + a] = LValue::LPrimitive(LPrimitive::BOOL(b != 0));
 
                             if c != 0 {
                                 pc += 1;
                             }
                         }
-                        opcode @ 12..=17 => {
-                            // ADD, SUB, MUL, DIV, MOD, POW
-                            // let lhs: f64 = if b < 256 {
-                            //     match &*stack[base + b].borrow() {
-                            //         LValue::LPrimitive(LPrimitive::NUMBER(x)) => *x,
-                            //         _ => panic!(
-                            //             "ADD failed: Left hand side stack lvalue is not a number"
-                            //         ),
-                            //     }
-                            // } else {
-                            //     match Kst!(self.proto, (b - 256)) {
-                            //         LPrimitive::NUMBER(x) => *x,
-                            //         _ => panic!("ADD failed: Left hand side constant lprimitive is not a number"),
-                            //     }
-                            // };
-                            // let rhs: f64 = if c < 256 {
-                            //     match &*stack[base + c].borrow() {
-                            //         LValue::LPrimitive(LPrimitive::NUMBER(x)) => *x,
-                            //         _ => panic!(
-                            //             "ADD failed: Right hand side stack lvalue is not a number"
-                            //         ),
-                            //     }
-                            // } else {
-                            //     match Kst!(self.proto, (c - 256)) {
-                            //             LPrimitive::NUMBER(x) => *x,
-                            //             _ => panic!("ADD failed: Right hand side constant lprimitive is not a number"),
-                            //         }
-                            // };
-
-                            // stack[base + a] = Rc::new(RefCell::new(LValue::LPrimitive(
-                            //     LPrimitive::NUMBER(match opcode {
-                            //         12 => lhs + rhs,
-                            //         13 => lhs - rhs,
-                            //         14 => lhs * rhs,
-                            //         15 => lhs / rhs,
-                            //         16 => lhs % rhs,
-                            //         17 => lhs.powf(rhs),
-                            //         _ => {
-                            //             unreachable!("opcodes 12..=17 are numerical operations")
-                            //         }
-                            //     }),
-                            // )));
+                        4 => {
+                            // LOADNIL
+                            //  Sets a range of registers from R(A) to R(A+B) to nil. When two or more
+                            //  consecutive locals need to be assigned nil values, only a single LOADNIL
+                            //  is needed.
+
+                            for i in a..=a + b {
+                                stack[base + i] = LValue::default();
+                            }
+                        }
+                        5 => {
+                            // GETUPVAL
+                            //  R(A) := UpValue[B]
+
+                            stack[base + a] = Upvalue!(closure, b).borrow().get(stack);
+                        }
+                        6 => {
+                            // GETTABUP
+                            //  R(A) := UpValue[B][RK(C)]. Global lookups index the _ENV upvalue.
+
+                            let key = RK!(stack, base, proto, c);
+                            let table = Upvalue!(closure, b).borrow().get(stack);
+                            let value = index(&table, key)
+                                .map_err(|e| type_error(call_stack, e, Operand::Upvalue(b)))?;
+
+                            stack[base + a] = value;
+                        }
+                        7 => {
+                            // GETTABLE
+                            //  R(A) := R(B)[RK(C)]
+
+                            let key = RK!(stack, base, proto, c);
+                            let value = index(&stack[base + b], key)
+                                .map_err(|e| type_error(call_stack, e, Operand::Register(b)))?;
+
+                            stack[base + a] = value;
+                        }
+                        8 => {
+                            // SETTABUP
+                            //  UpValue[A][RK(B)] := RK(C). Global assignments index the _ENV upvalue.
+
+                            let key = RK!(stack, base, proto, b);
+                            let value = RK!(stack, base, proto, c);
+                            let table = Upvalue!(closure, a).borrow().get(stack);
+
+                            let grown = set_index(&mut genv.gc, &table, key, value).map_err(
+                                |e| match table {
+                                    LValue::Table(_) => LuaError::runtime(call_stack, e),
+                                    _ => type_error(call_stack, e, Operand::Upvalue(a)),
+                                },
+                            )?;
+                            if grown {
+                                gc::check(genv, stack, call_stack)?;
+                            }
+                        }
+                        9 => {
+                            // SETUPVAL
+                            //  UpValue[B] := R(A)
+
+                            let value = stack[base + a].clone();
+                            let upvalue = Upvalue!(closure, b);
+
+                            genv.gc.barrier_upvalue(upvalue);
+                            upvalue.borrow_mut().set(stack, value);
+                        }
+                        10 => {
+                            // SETTABLE
+                            //  R(A)[RK(B)] := RK(C)
+
+                            let key = RK!(stack, base, proto, b);
+                            let value = RK!(stack, base, proto, c);
+
+                            let grown = set_index(&mut genv.gc, &stack[base + a], key, value)
+                                .map_err(|e| match stack[base + a] {
+                                    LValue::Table(_) => LuaError::runtime(call_stack, e),
+                                    _ => type_error(call_stack, e, Operand::Register(a)),
+                                })?;
+                            if grown {
+                                gc::check(genv, stack, call_stack)?;
+                            }
+                        }
+                        11 => {
+                            // NEWTABLE
+                            //  R(A) := {} (size = B,C)
+                            //
+                            //  Creates a new empty table at register R(A). B and C are the encoded size
+                            //  hints of the array and hash parts of the table respectively.
+
+                            let table =
+                                Rc::new(RefCell::new(LTable::with_sizes(fb2int(b), fb2int(c))));
+                            genv.gc.track_table(&table);
+
+                            stack[base + a] = LValue::Table(table);
+                            gc::check(genv, stack, call_stack)?;
+                        }
+                        12 => {
+                            // SELF
+                            //  R(A+1) := R(B); R(A) := R(B)[RK(C)]
+                            //
+                            //  Prepares an object method call: the object is copied into R(A+1) to
+                            //  become the first argument and the method is looked up into R(A).
+
+                            let object = stack[base + b].clone();
+                            let key = RK!(stack, base, proto, c);
+                            let method = index(&object, key)
+                                .map_err(|e| type_error(call_stack, e, Operand::Register(b)))?;
+
+                            stack[base + a + 1] = object;
+                            stack[base + a] = method;
+                        }
+                        opcode @ 13..=26 => {
+                            // ADD, SUB, MUL, MOD, POW, DIV, IDIV, BAND, BOR, BXOR, SHL, SHR, UNM, BNOT
+                            //  R(A) := RK(B) op RK(C). The unary operators UNM and BNOT use R(B) and
+                            //  ignore C.
+
+                            let op = ArithOp::from_opcode(opcode);
+                            let lhs = RK!(stack, base, proto, b);
+                            let (rhs, c) = match op {
+                                ArithOp::Unm | ArithOp::BNot => (lhs.clone(), b),
+                                _ => (RK!(stack, base, proto, c), c),
+                            };
+
+                            let result = arith_values(op, &lhs, &rhs)
+                                .map_err(|e| arith_error(call_stack, e, (&lhs, b), (&rhs, c)))?;
+
+                            stack[base + a] = result;
+                        }
+                        27 => {
+                            // NOT
+                            //  R(A) := not R(B)
+
+                            let value = !stack[base + b].truthy();
+
+                            stack[base + a] = LValue::LPrimitive(LPrimitive::BOOL(value));
                         }
                         28 => {
+                            // LEN
+                            //  R(A) := length of R(B)
+
+                            let length = match &stack[base + b] {
+                                LValue::LPrimitive(LPrimitive::STRING(s)) => s.len(),
+                                LValue::Table(t) => t.borrow().border(),
+                                value => {
+                                    return Err(type_error(
+                                        call_stack,
+                                        format!(
+                                            "attempt to get length of a {} value",
+                                            value.type_name()
+                                        ),
+                                        Operand::Register(b),
+                                    ))
+                                }
+                            };
+
+                            stack[base + a] = LValue::LPrimitive(LPrimitive::INT(length as i64));
+                        }
+                        opcode @ 31..=33 => {
+                            // EQ, LT, LE
+                            //  if ((RK(B) op RK(C)) ~= A) then pc++
+                            //
+                            //  The comparison is always followed by a JMP which is skipped when
+                            //  the result of the comparison doesn't match A.
+
+                            let lhs = RK!(stack, base, proto, b);
+                            let rhs = RK!(stack, base, proto, c);
+
+                            let result = match opcode {
+                                31 => equals_values(&lhs, &rhs),
+                                opcode => {
+                                    let ordering = compare_values(&lhs, &rhs)
+                                        .map_err(|e| LuaError::runtime(call_stack, e))?;
+                                    match opcode {
+                                        32 => ordering == Some(Ordering::Less),
+                                        _ => matches!(
+                                            ordering,
+                                            Some(Ordering::Less | Ordering::Equal)
+                                        ),
+                                    }
+                                }
+                            };
+
+                            if result != (a != 0) {
+                                pc += 1;
+                            }
+                        }
+                        34 => {
+                            // TEST
+                            //  if not (R(A) <=> C) then pc++
+
+                            if stack[base + a].truthy() != (c != 0) {
+                                pc += 1;
+                            }
+                        }
+                        35 => {
+                            // TESTSET
+                            //  if (R(B) <=> C) then R(A) := R(B) else pc++
+
+                            let value = stack[base + b].clone();
+
+                            if value.truthy() == (c != 0) {
+                                stack[base + a] = value;
+                            } else {
+                                pc += 1;
+                            }
+                        }
+                        36 => {
                             // CALL
                             //  Performs a function call, with register R(A) holding the reference to the
                             //  function object to be called. Parameters to the function are placed in the
@@ -203,70 +490,108 @@ impl<'i> LClosure<'i> {
                             //  CALL always updates the top of stack value. CALL, RETURN, VARARG
                             //  and SETLIST can use multiple values (up to the top of the stack.)
 
-                            let register_a = stack[base + a].clone();
+                            let num_args = match b {
+                                0 => top - (base + a + 1),
+                                b => b - 1,
+                            };
 
-                            match &*register_a.borrow() {
-                                LValue::CClosure(ref function) => {
-                                    let (proto, closure) = function;
+                            let num_results = call(genv, stack, call_stack, base + a, num_args)?;
 
-                                    let num_args = match b {
-                                        0 => top - b - 1,
-                                        b => b - 1,
-                                    };
+                            match c {
+                                0 => top = base + a + num_results,
+                                c => {
+                                    //Adjust the results to the C-1 expected
+                                    for i in num_results..c - 1 {
+                                        stack[base + a + i] = LValue::default();
+                                    }
+                                }
+                            }
+                        }
+                        37 => {
+                            // TAILCALL
+                            //  Performs a tail call, which happens when a return statement has a single
+                            //  function call as the expression, e.g. return foo(bar). A tail call is
+                            //  effectively a goto, and avoids nesting calls another level deeper. Only
+                            //  Lua functions can be tailcalled.
+                            //
+                            //  Like CALL, register R(A) holds the reference to the function object to be
+                            //  called. B encodes the number of parameters in the same manner as a CALL
+                            //  instruction.
+                            //
+                            //  C isn’t used by TAILCALL, since all return results are significant. In any
+                            //  case, Lua always generates a 0 for C, to denote multiple return results.
+                            //
+                            //  A Lua callee takes over this frame: it and its arguments are moved down
+                            //  to func and execution restarts at its first instruction, so neither the
+                            //  stack nor the call stack grow. Host callees are called as if by CALL and
+                            //  their results are returned as if by RETURN.
 
-                                    println!("num_args, {}", num_args);
+                            let num_args = match b {
+                                0 => top - (base + a + 1),
+                                b => b - 1,
+                            };
 
-                                    let _num_varargs = num_args - proto.num_params as usize;
+                            genv.check_interrupt(call_stack)?;
+                            let callee = stack[base + a].clone();
 
-                                    let call_stack = &stack[base + a..];
-                                    closure(genv, call_stack);
-                                }
-                                LValue::LClosure(ref closure) => {
-                                    assert!(
-                                        closure.proto.vararg_flag == 0
-                                            || closure.proto.vararg_flag == 3,
-                                        "legacy vararg syntax unsupported"
-                                    );
+                            //This frame's registers are about to be overwritten
+                            close_frame_upvalues(genv, call_stack, stack, base);
 
-                                    let num_args = match b {
-                                        0 => {
-                                            todo!("CALL with variable number of arguments (top)")
-                                        }
-                                        b => b - 1,
+                            match callee {
+                                LValue::LClosure(callee) => {
+                                    //Move the callee and its arguments down into this frame
+                                    for i in 0..=num_args {
+                                        stack.swap(func + i, base + a + i);
+                                    }
+
+                                    let capacity = stack.capacity();
+                                    base = Self::prepare_frame(callee.proto, stack, func, num_args);
+
+                                    let frame = call_stack
+                                        .last_mut()
+                                        .expect("execute called without a CallInfo");
+                                    *frame = CallInfo {
+                                        tail_call: true,
+                                        ..CallInfo::lua(callee.proto, func, base)
                                     };
 
-                                    let num_varargs = num_args - closure.proto.num_params as usize; //TODO something here. For now just verbose
-
-                                    let closure_func = base + a;
-                                    let closure_base = closure_func + 1 + num_varargs;
-                                    let closure_top =
-                                        closure_base + closure.proto.max_stack as usize;
-
-                                    println!(
-                                        "num_args={} num_varargs={} c_func={}, c_base={}, c_top={} max_stack={}",
-                                        num_args,
-                                        num_varargs,
-                                        closure_func,
-                                        closure_base,
-                                        closure_top,
-                                        closure.proto.max_stack,
+                                    if stack.capacity() != capacity {
+                                        gc::check(genv, stack, call_stack)?;
+                                    }
+                                    if genv.hooks.mask & MASK_CALL != 0 {
+                                        hook::call_hooks(
+                                            genv,
+                                            stack,
+                                            call_stack,
+                                            HookEvent::TailCall,
+                                        )?;
+                                    }
+
+                                    genv.counters.count_call(callee.proto);
+                                    debug!(
+                                        source = %callee.proto.short_src(),
+                                        line = callee.proto.line_defined,
+                                        "tail call"
                                     );
+                                    closure = callee;
+                                    top = base;
+                                    pc = 0;
+                                    continue;
+                                }
+                                _ => {
+                                    let num_results =
+                                        call(genv, stack, call_stack, base + a, num_args)?;
 
-                                    //Ensure the stack is large enough
-                                    let delta_stack = closure_top - stack.len();
-                                    if delta_stack > 0 {
-                                        println!("extending stack by {}", delta_stack);
-                                        for _ in 0..delta_stack {
-                                            stack.push(Rc::new(RefCell::new(LValue::default())));
-                                        }
+                                    //Return the results
+                                    for i in 0..num_results {
+                                        stack.swap(func + i, base + a + i);
                                     }
 
-                                    closure.execute(genv, stack, top, closure_base, closure_func);
+                                    return Ok(num_results);
                                 }
-                                _ => panic!("CALL called with non-closure"),
-                            };
+                            }
                         }
-                        30 => {
+                        38 => {
                             // RETURN
                             //  Returns to the calling function, with optional return values. If B is 1, there
                             //  are no return values. If B is 2 or more, there are (B-1) return values,
@@ -279,42 +604,118 @@ impl<'i> LClosure<'i> {
                             //  RETURN also closes any open upvalues, equivalent to a CLOSE
                             //  instruction. See the CLOSE instruction for more information.
 
-                            // TODO: Close any open upvalues? is our makeshift gc gonna handle that for us
-                            //
+                            close_frame_upvalues(genv, call_stack, stack, base);
 
-                            match b {
+                            let num_results = match b {
+                                //Params from a to top
+                                0 => top - (base + a),
+                                b => b - 1,
+                            };
+
+                            //Return values are from R(A) onwards. Move them down to the func index
+                            for i in 0..num_results {
+                                stack.swap(func + i, base + a + i);
+                            }
+
+                            return Ok(num_results);
+                        }
+                        45 => {
+                            // VARARG
+                            //  VARARG implements the vararg operator ‘...’ in expressions. VARARG
+                            //  copies B-1 parameters into a number of registers starting from R(A),
+                            //  padding with nils if there aren’t enough values. If B is 0, VARARG copies
+                            //  as many values as it can based on the number of parameters passed. If a
+                            //  fixed number of values is required, B is a value greater than 1. If any
+                            //  number of values is required, B is 0.
+
+                            //Varargs start after func and end before base
+                            let num_varargs = base - func - 1;
+
+                            let num_values = match b {
                                 0 => {
-                                    //Params from a to top
-                                    todo!("return with indeterminate parameters");
-                                }
-                                1 => {
-                                    //No return values - clear the stack
-                                    for i in func..top {
-                                        stack[i] = Rc::new(RefCell::new(LValue::default()));
+                                    //Copy all of the varargs, growing the stack if there are more
+                                    //than the registers can hold
+                                    top = base + a + num_varargs;
+                                    if stack.len() < top {
+                                        stack.resize(top, LValue::default());
                                     }
+                                    num_varargs
                                 }
-                                b => {
-                                    //Return values are from R(A) to R(B-1). Move them down to the func index
-                                    let mut j = func;
-                                    for i in base + a..base + a + b - 1 {
-                                        stack[j] = Rc::new(RefCell::new(stack[i].take()));
-                                        j += 1;
-                                    }
+                                //Copy (b-1) varargs
+                                b => b - 1,
+                            };
 
-                                    //From a to b-1
-                                    // for a in &stack[base + a..base + b - 1] {}
+                            for i in 0..num_values {
+                                stack[base + a + i] = match i < num_varargs {
+                                    true => stack[func + 1 + i].clone(),
+                                    false => LValue::default(),
+                                };
+                            }
+                        }
+                        43 => {
+                            // SETLIST
+                            //  R(A)[(C-1)*FPF+i] := R(A+i), 1 <= i <= B
+                            //
+                            //  Sets the values for a range of array elements in a table referenced by
+                            //  R(A). Field B is the number of elements to set. Field C encodes the block
+                            //  number of the table to be initialized. The values used to initialize the
+                            //  table are located in registers R(A+1), R(A+2), and so on.
+                            //
+                            //  If B is 0, the table is set with a variable number of array elements, from
+                            //  register R(A+1) up to the top of the stack. This happens when the last
+                            //  element in the table constructor is a function call or a vararg operator.
 
-                                    // &stack[base + a..base + b - 1]
-                                    // todo!("return should write its returns to the stack?");
+                            let num_values = match b {
+                                0 => top - (base + a + 1),
+                                b => b,
+                            };
+                            //Block numbers too big for C are in the EXTRAARG after
+                            let block = match c {
+                                0 => {
+                                    pc += 1;
+                                    match proto.instructions.list.get(pc) {
+                                        Some(extra) if extra.opcode() == 46 => extra.ax() - 1,
+                                        _ => {
+                                            unreachable!("SETLIST with C 0 is followed by EXTRAARG")
+                                        }
+                                    }
                                 }
+                                c => c - 1,
+                            };
+
+                            let table = match &stack[base + a] {
+                                LValue::Table(t) => t.clone(),
+                                _ => unreachable!("SETLIST R(A) is always a table constructor"),
+                            };
+                            genv.gc.barrier_table(&table);
+                            let mut table = table.borrow_mut();
+                            let allocated = table.allocated_bytes();
+
+                            //Multiple results can hold nils, which mustn't move the border
+                            table.grow_array(block * FIELDS_PER_FLUSH + num_values);
+                            for i in 1..=num_values {
+                                table.set_int(
+                                    (block * FIELDS_PER_FLUSH + i) as i64,
+                                    stack[base + a + i].clone(),
+                                );
                             }
 
-                            return;
+                            let grown = table.allocated_bytes().saturating_sub(allocated);
+                            drop(table);
+                            if grown > 0 {
+                                genv.gc.charge_growth(grown);
+                                gc::check(genv, stack, call_stack)?;
+                            }
                         }
                         _ => todo!("instruction unhandled: {:?}", instruction),
                     }
                 }
-                BInstruction::ABx { line, opcode, a, b } => {
+                BInstruction::ABx {
+                    line: _,
+                    opcode,
+                    a,
+                    b,
+                } => {
                     let a = a as usize;
                     let b = b as usize;
                     match opcode {
@@ -323,33 +724,9 @@ impl<'i> LClosure<'i> {
                             //  Loads constant number Bx into register R(A). Constants are usually
                             //  numbers or strings. Each function has its own constant list, or pool.
 
-                            stack[base + a] = Rc::new(RefCell::new(LValue::LPrimitive(
-                                Kst!(self.proto, b).clone(),
-                            )));
-                        }
-                        3 => {
-                            // LOADNIL
-                            //  Sets a range of registers from R(A) to R(B) to nil. If a single register is to
-                            //  be assigned to, then R(A) = R(B). When two or more consecutive locals
-                            //  need to be assigned nil values, only a single LOADNIL is needed.
-
-                            for i in a..=b {
-                                stack[base + i] = Rc::new(RefCell::new(LValue::default()));
-                            }
-                        }
-                        5 => {
-                            // GETGLOBAL
-                            //  Copies the value of the global variable whose name is given in constant
-                            //  number Bx into register R(A). The name constant must be a string.
-
-                            if let LPrimitive::STRING(c) = Kst!(self.proto, b) {
-                                stack[base + a] =
-                                    Rc::new(RefCell::new(LValue::CClosure(genv!(genv, c).clone())));
-                            } else {
-                                panic!("GETGLOBAL Kst!(b) points to non-STRING primitive");
-                            }
+                            stack[base + a] = LValue::LPrimitive(Kst!(proto, b).clone());
                         }
-                        36 => {
+                        44 => {
                             // CLOSURE
                             //  Creates an instance (or closure) of a function. Bx is the function number of
                             //  the function to be instantiated in the table of function prototypes. This table
@@ -357,66 +734,221 @@ impl<'i> LClosure<'i> {
                             //  first function prototype is numbered 0. Register R(A) is assigned the
                             //  reference to the instantiated function object.
                             //
-                            //  For each upvalue used by the instance of the function KPROTO[Bx], there
-                            //  is a pseudo-instruction that follows CLOSURE. Each upvalue corresponds
-                            //  to either a MOVE or a GETUPVAL pseudo-instruction. Only the B field on
-                            //  either of these pseudo-instructions are significant.
-                            //
-                            //  A MOVE corresponds to local variable R(B) in the current lexical block,
-                            //  which will be used as an upvalue in the instantiated function. A
-                            //  GETUPVAL corresponds upvalue number B in the current lexical block.
-                            //  The VM uses these pseudo-instructions to manage upvalues.
+                            //  For each upvalue used by the instance of the function KPROTO[Bx], the
+                            //  proto has an upvalue descriptor. If its stack flag is set the upvalue is
+                            //  the local variable in register R(index) of the current function, otherwise
+                            //  it is upvalue number index of the current function.
 
                             //Fetch the proto to CLOSURE
-                            let proto = Proto!(self.proto, b);
+                            let proto = Proto!(proto, b);
 
                             //Prepare upvalues
+                            let open_upvalues = &mut call_stack
+                                .last_mut()
+                                .expect("execute called without a CallInfo")
+                                .open_upvalues;
+                            let upvalues = proto
+                                .upvalues
+                                .list
+                                .iter()
+                                .map(|upvalue| match upvalue.stack_flag {
+                                    0 => Upvalue!(closure, upvalue.index).clone(),
+                                    _ => find_upvalue(
+                                        open_upvalues,
+                                        base + upvalue.index as usize,
+                                        &mut genv.gc,
+                                    ),
+                                })
+                                .collect();
 
                             //Create the closure
-                            stack[base + a] = Rc::new(RefCell::new(LValue::LClosure(
-                                LClosure::new(proto, vec![]),
-                            )));
-                        }
-                        37 => {
-                            // VARARG
-                            //  VARARG implements the vararg operator ‘...’ in expressions. VARARG
-                            //  copies B-1 parameters into a number of registers starting from R(A),
-                            //  padding with nils if there aren’t enough values. If B is 0, VARARG copies
-                            //  as many values as it can based on the number of parameters passed. If a
-                            //  fixed number of values is required, B is a value greater than 1. If any
-                            //  number of values is required, B is 0.
+                            let new_closure = Rc::new(LClosure::new(proto, upvalues));
+                            genv.gc.track_closure(&new_closure);
 
-                            match b {
-                                0 => {
-                                    //Copy varargs starting after func and ending before base
-                                    let mut j = base + a;
-
-                                    // println!("stack {:#?}", stack);
-
-                                    for i in func + 1..base {
-                                        // println!("moving i={} to j={}", i, j);
-                                        stack[j] = stack[i].clone();
-                                        j += 1;
-                                    }
-                                }
-                                b => {
-                                    //Copy (b-1) varargs starting after func
-                                    todo!("VARARG with b-1 unsupported")
-                                }
-                            }
+                            stack[base + a] = LValue::LClosure(new_closure);
+                            gc::check(genv, stack, call_stack)?;
                         }
                         _ => todo!("instruction unhandled: {:?}", instruction),
                     }
                 }
-                BInstruction::AsBx { line, opcode, a, b } => match opcode {
-                    0 => {
-                        todo!()
+                BInstruction::AsBx {
+                    line: _,
+                    opcode,
+                    a,
+                    b,
+                } => match opcode {
+                    30 => {
+                        // JMP
+                        //  pc += sBx; if (A) close all upvalues >= R(A - 1)
+                        //
+                        //  A is non-zero when jumping out of a block whose locals were captured,
+                        //  such as at the end of each loop iteration.
+
+                        if a != 0 {
+                            close_frame_upvalues(genv, call_stack, stack, base + a as usize - 1);
+                        }
+                        //Every loop jumps backwards
+                        if b < 0 {
+                            genv.check_interrupt(call_stack)?;
+                        }
+
+                        pc = (pc as i64 + b as i64) as usize;
                     }
                     _ => todo!("instruction unhandled: {:?}", instruction),
                 },
             }
 
-            pc += 1;
+            //A jump to the first instruction leaves pc one before it
+            pc = pc.wrapping_add(1);
+        }
+    }
+}
+
+/// Raw table indexing, `object[key]`
+fn index<'i>(object: &LValue<'i>, key: LValue<'i>) -> Result<LValue<'i>, String> {
+    match object {
+        LValue::Table(t) => Ok(t.borrow().get(key)),
+        object => Err(format!("attempt to index a {} value", object.type_name())),
+    }
+}
+
+/// Raw table assignment, `object[key] = value`. Returns whether the table
+/// had to grow, in which case the collector should be checked
+fn set_index<'i>(
+    gc: &mut Collector<'i>,
+    object: &LValue<'i>,
+    key: LValue<'i>,
+    value: LValue<'i>,
+) -> Result<bool, String> {
+    match object {
+        LValue::Table(t) => {
+            gc.barrier_table(t);
+            let mut table = t.borrow_mut();
+            let allocated = table.allocated_bytes();
+            table.set(key, value)?;
+
+            let grown = table.allocated_bytes().saturating_sub(allocated);
+            gc.charge_growth(grown);
+            Ok(grown > 0)
+        }
+        object => Err(format!("attempt to index a {} value", object.type_name())),
+    }
+}
+
+/// Raises an error about the value of an operand of the instruction being
+/// executed, naming the variable it came from like luaG_typeerror
+fn type_error(call_stack: &CallStack, message: String, operand: Operand) -> LuaError {
+    LuaError::runtime(call_stack, message + &var_info(call_stack, operand))
+}
+
+/// Raises an error from an arithmetic instruction, given its RK operands.
+/// It names the first operand which isn't a number, or which isn't an
+/// integer for bitwise operators, like luaG_opinterror and luaG_tointerror
+fn arith_error(
+    call_stack: &CallStack,
+    message: String,
+    lhs: (&LValue, usize),
+    rhs: (&LValue, usize),
+) -> LuaError {
+    let is = |value: &LValue, integer: bool| match value {
+        LValue::LPrimitive(p) if integer => p.to_integer().is_some(),
+        LValue::LPrimitive(p) => p.to_number().is_some(),
+        _ => false,
+    };
+
+    //Division by zero isn't about a variable
+    if message == "attempt to divide by zero" || message == "attempt to perform 'n%0'" {
+        return LuaError::runtime(call_stack, message);
+    }
+    let integer = message == "number has no integer representation";
+    let culprit = match is(lhs.0, integer) {
+        true => rhs.1,
+        false => lhs.1,
+    };
+    let info = var_info(call_stack, Operand::rk(culprit));
+    match integer {
+        true => LuaError::runtime(
+            call_stack,
+            format!("number{} has no integer representation", info),
+        ),
+        false => LuaError::runtime(call_stack, message + &info),
+    }
+}
+
+/// Closes the upvalues of the innermost frame which refer to registers at
+/// or above `level`
+fn close_frame_upvalues<'i>(
+    genv: &mut GlobalEnv<'i>,
+    call_stack: &mut CallStack<'i>,
+    stack: &Stack<'i>,
+    level: usize,
+) {
+    let frame = call_stack
+        .last_mut()
+        .expect("execute called without a CallInfo");
+    close_upvalues(&mut frame.open_upvalues, stack, level, &mut genv.gc);
+}
+
+/// Calls the value at `func` with the `num_args` values following it as
+/// arguments. The results are left on the stack starting at `func` and
+/// their count is returned
+pub fn call<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    func: usize,
+    num_args: usize,
+) -> Result<usize, LuaError> {
+    genv.check_interrupt(call_stack)?;
+    let callee = stack[func].clone();
+
+    match callee {
+        LValue::CClosure(function) => {
+            let closure = *function;
+
+            genv.counters.count_host_call(&function);
+            call_stack.push(CallInfo::host(func, num_args));
+            let results = match genv.hooks.mask & MASK_CALL != 0 {
+                true => hook::call_hooks(genv, stack, call_stack, HookEvent::Call),
+                false => Ok(()),
+            };
+            let results = results.and_then(|()| {
+                let args = stack[func + 1..=func + num_args].to_vec();
+                closure(genv, stack, call_stack, args)
+            });
+
+            finish_host(genv, stack, call_stack, results)
+        }
+        LValue::LClosure(closure) => {
+            let capacity = (stack.capacity(), call_stack.capacity());
+            let base = LClosure::prepare_frame(closure.proto, stack, func, num_args);
+
+            genv.counters.count_call(closure.proto);
+            call_stack.push(CallInfo::lua(closure.proto, func, base));
+            let result = if (stack.capacity(), call_stack.capacity()) != capacity {
+                //Growing the stacks allocates, like luaD_growstack
+                gc::check(genv, stack, call_stack)
+            } else {
+                Ok(())
+            };
+            let result = result.and_then(|()| match genv.hooks.mask & MASK_CALL != 0 {
+                true => hook::call_hooks(genv, stack, call_stack, HookEvent::Call),
+                false => Ok(()),
+            });
+            let _call = call_span(closure.proto).entered();
+            let result = result.and_then(|()| LClosure::execute(genv, stack, call_stack));
+
+            finish_lua(genv, stack, call_stack, result)
+        }
+        callee => {
+            let message = format!("attempt to call a {} value", callee.type_name());
+            //Lua frames call the function in one of their registers
+            Err(match call_stack.last() {
+                Some(frame) if frame.proto.is_some() && (frame.base..frame.top).contains(&func) => {
+                    type_error(call_stack, message, Operand::Register(func - frame.base))
+                }
+                _ => LuaError::runtime(call_stack, message),
+            })
         }
     }
 }
@@ -475,3 +1007,132 @@ impl<'i> LClosure<'i> {
 //                         ),
 //                     }
 //                 }
+
+/// Span of a call to a Lua function, which its instructions are traced in.
+/// Its fields aren't computed unless it's enabled
+fn call_span(proto: &BProto) -> Span {
+    debug_span!(
+        "call",
+        source = %proto.short_src(),
+        line = proto.line_defined
+    )
+}
+
+/// Continues the suspended call at `level` of the call stack, returning
+/// like `call` once it's finished. The calls above it are continued first
+/// since it's waiting on their results
+pub fn resume<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    level: usize,
+) -> Result<usize, LuaError> {
+    let callee = match level + 1 < call_stack.len() {
+        true => match resume(genv, stack, call_stack, level + 1) {
+            Err(LuaError::Suspended) => return Err(LuaError::Suspended),
+            callee => Some(callee),
+        },
+        false => None,
+    };
+
+    let frame = &call_stack[level];
+    match frame.proto {
+        Some(proto) => {
+            let _call = call_span(proto).entered();
+            let result = match callee {
+                Some(callee) => callee.and_then(|num_results| {
+                    LClosure::finish_call(genv, stack, call_stack, num_results)
+                }),
+                None => LClosure::execute(genv, stack, call_stack),
+            };
+            finish_lua(genv, stack, call_stack, result)
+        }
+        None => {
+            let continuation = frame
+                .continuation
+                .expect("only host functions with a continuation are suspended");
+            let callee = callee.expect("host functions are suspended in a call to Lua");
+            let results = continuation(genv, stack, call_stack, callee);
+            finish_host(genv, stack, call_stack, results)
+        }
+    }
+}
+
+/// Pops the frame of a host function which returned, moving its results
+/// to its func. Suspended frames are left in place
+fn finish_host<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    results: Result<Vec<LValue<'i>>, LuaError>,
+) -> Result<usize, LuaError> {
+    let results = match results {
+        Err(LuaError::Suspended) => return Err(LuaError::Suspended),
+        Err(e) => {
+            let e = e.with_traceback(genv, stack, call_stack);
+            call_stack.pop();
+            return Err(e);
+        }
+        Ok(results) => results,
+    };
+
+    let frame = call_stack
+        .last_mut()
+        .expect("host function without a CallInfo");
+    let func = frame.func;
+    let top = func + results.len();
+    //The results belong to the frame until it's popped, for the return hook
+    frame.top = frame.top.max(top);
+
+    if stack.len() < top {
+        stack.resize_with(top, LValue::default);
+    }
+    for (i, result) in results.into_iter().enumerate() {
+        stack[func + i] = result;
+    }
+
+    let result = return_hook(genv, stack, call_stack, top - func);
+    call_stack.pop();
+    result
+}
+
+/// Pops the frame of a Lua function which returned or raised an error.
+/// Suspended frames are left in place
+fn finish_lua<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    result: Result<usize, LuaError>,
+) -> Result<usize, LuaError> {
+    if let Err(LuaError::Suspended) = result {
+        return result;
+    }
+    let result = result
+        .and_then(|num_results| return_hook(genv, stack, call_stack, num_results))
+        .map_err(|e| e.with_traceback(genv, stack, call_stack));
+
+    //RETURN closes the frame's upvalues but an error skips it
+    let mut frame = call_stack.pop().expect("Lua function without a CallInfo");
+    close_upvalues(&mut frame.open_upvalues, stack, 0, &mut genv.gc);
+
+    result
+}
+
+/// Sends the return event of the innermost frame, like luaD_poscall. Line
+/// events carry on from the call in the frame returned to
+fn return_hook<'i>(
+    genv: &mut GlobalEnv<'i>,
+    stack: &mut Stack<'i>,
+    call_stack: &mut CallStack<'i>,
+    num_results: usize,
+) -> Result<usize, LuaError> {
+    if genv.hooks.mask == 0 {
+        return Ok(num_results);
+    }
+
+    hook::call_hooks(genv, stack, call_stack, HookEvent::Return)?;
+    if let Some(caller) = call_stack.len().checked_sub(2) {
+        genv.hooks.old_pc = call_stack[caller].pc;
+    }
+    Ok(num_results)
+}
@@ -0,0 +1,444 @@
+use std::fmt::Write;
+
+use serde_json::{json, Value};
+
+use crate::{
+    bytecode::{binstruction::BInstruction, bproto::BProto},
+    lprimative::{LPrimitive, LValue},
+};
+
+use super::{
+    arith::equals_values,
+    callinfo::{CallStack, Callback},
+    genv::GlobalEnv,
+    lclosure::BITRK,
+    Stack,
+};
+
+/// Levels shown at the top and at the bottom of long tracebacks, like
+/// LEVELS1 and LEVELS2
+const LEVELS1: usize = 10;
+const LEVELS2: usize = 11;
+
+/// Where an operand of an instruction comes from, to describe it in errors
+#[derive(Debug, Clone, Copy)]
+pub enum Operand {
+    /// A register, relative to the frame's base
+    Register(usize),
+    Upvalue(usize),
+    /// A constant, which isn't a variable
+    Constant,
+}
+impl Operand {
+    /// The operand of an instruction which is either a register or a
+    /// constant
+    pub fn rk(n: usize) -> Self {
+        match n & BITRK {
+            0 => Operand::Register(n),
+            _ => Operand::Constant,
+        }
+    }
+}
+
+/// Index in the call stack of the frame at `level`, where level 0 is the
+/// innermost frame, like lua_getstack
+pub fn frame_index(call_stack: &CallStack, level: i64) -> Option<usize> {
+    let level = usize::try_from(level).ok()?;
+    call_stack.len().checked_sub(level + 1)
+}
+
+/// Name and stack index of local `n` of frame `i`, like findlocal. Locals
+/// count from 1 in the order they came into scope, registers which aren't
+/// named locals are temporaries and negative numbers are varargs
+pub fn find_local<'i>(
+    stack: &Stack<'i>,
+    call_stack: &CallStack<'i>,
+    i: usize,
+    n: i64,
+) -> Option<(&'i str, usize)> {
+    let frame = &call_stack[i];
+    let (name, base) = match frame.proto {
+        //The varargs are between func and base
+        Some(_) if n < 0 => {
+            let n = n.unsigned_abs() as usize;
+            return (n < frame.base - frame.func).then_some(("(*vararg)", frame.func + n));
+        }
+        Some(proto) => (
+            usize::try_from(n)
+                .ok()
+                .and_then(|n| proto.local_name(n, frame.pc)),
+            frame.base,
+        ),
+        None => (None, frame.func + 1),
+    };
+
+    let index = base + usize::try_from(n).ok()?.checked_sub(1)?;
+    match name {
+        Some(name) => Some((name, index)),
+        None => {
+            //Registers up to the function the frame is calling belong to it
+            let limit = match call_stack.get(i + 1) {
+                Some(next) => next.func,
+                None => frame.top.min(stack.len()),
+            };
+            let name = match frame.proto {
+                Some(_) => "(*temporary)",
+                None => "(*C temporary)",
+            };
+            (index < limit).then_some((name, index))
+        }
+    }
+}
+
+/// Names the variable an operand of the instruction being executed came
+/// from, " (local 'x')", for error messages about its value like varinfo.
+/// Empty if it isn't known
+pub fn var_info(call_stack: &CallStack, operand: Operand) -> String {
+    let Some(frame) = call_stack.last() else {
+        return String::new();
+    };
+    let Some(proto) = frame.proto else {
+        return String::new();
+    };
+
+    let info = match operand {
+        Operand::Register(reg) => object_name(proto, frame.pc, reg),
+        Operand::Upvalue(n) => Some(("upvalue", proto.upvalue_name(n).unwrap_or("?").to_owned())),
+        Operand::Constant => None,
+    };
+    match info {
+        Some((kind, name)) => format!(" ({} '{}')", kind, name),
+        None => String::new(),
+    }
+}
+
+/// Name of the function running in frame `i` and what kind of name it is,
+/// inferred from the instruction which called it like getfuncname
+pub fn func_name(call_stack: &CallStack, i: usize) -> Option<(&'static str, String)> {
+    //The reference marks the frame running the finalizer rather than the finalizer
+    if call_stack[i].callback == Some(Callback::Finalizer) {
+        return Some(("metamethod", "__gc".to_owned()));
+    }
+    let caller = &call_stack[i.checked_sub(1)?];
+    //The frame of the function which called a tail called one is gone
+    if call_stack[i].tail_call {
+        return None;
+    }
+    let proto = caller.proto?;
+    if caller.callback == Some(Callback::Hook) {
+        return Some(("hook", "?".to_owned()));
+    }
+
+    let instruction = &proto.instructions.list[caller.pc];
+    let event = match instruction.opcode() {
+        //CALL and TAILCALL
+        36 | 37 => return object_name(proto, caller.pc, instruction.a()),
+        //TFORCALL
+        41 => return Some(("for iterator", "for iterator".to_owned())),
+        //Other instructions call metamethods
+        6 | 7 | 12 => "__index",
+        8 | 10 => "__newindex",
+        opcode @ 13..=24 => [
+            "__add", "__sub", "__mul", "__mod", "__pow", "__div", "__idiv", "__band", "__bor",
+            "__bxor", "__shl", "__shr",
+        ][opcode as usize - 13],
+        25 => "__unm",
+        26 => "__bnot",
+        28 => "__len",
+        29 => "__concat",
+        31 => "__eq",
+        32 => "__lt",
+        33 => "__le",
+        _ => return None,
+    };
+    Some(("metamethod", event.to_owned()))
+}
+
+/// Describes the value register `reg` holds at `last_pc` by finding the
+/// instruction which set it, like getobjname
+fn object_name(proto: &BProto, last_pc: usize, reg: usize) -> Option<(&'static str, String)> {
+    if let Some(name) = proto.local_name(reg + 1, last_pc) {
+        return Some(("local", name.to_owned()));
+    }
+
+    let pc = find_set_reg(proto, last_pc, reg)?;
+    match proto.instructions.list[pc] {
+        //MOVE
+        BInstruction::ABC {
+            opcode: 0, a, b, ..
+        } if (b as usize) < a as usize => object_name(proto, pc, b as usize),
+        //GETTABUP and GETTABLE
+        BInstruction::ABC {
+            opcode: opcode @ (6 | 7),
+            b,
+            c,
+            ..
+        } => {
+            let table = match opcode {
+                7 => proto.local_name(b as usize + 1, pc),
+                _ => proto.upvalue_name(b as usize),
+            };
+            let name = constant_name(proto, pc, c as usize);
+            match table {
+                Some("_ENV") => Some(("global", name)),
+                _ => Some(("field", name)),
+            }
+        }
+        //GETUPVAL
+        BInstruction::ABC { opcode: 5, b, .. } => Some((
+            "upvalue",
+            proto.upvalue_name(b as usize).unwrap_or("?").to_owned(),
+        )),
+        //LOADK
+        BInstruction::ABx { opcode: 1, b, .. } => match &proto.constants.list[b as usize] {
+            LPrimitive::STRING(s) => Some(("constant", s.to_string())),
+            _ => None,
+        },
+        //SELF
+        BInstruction::ABC { opcode: 12, c, .. } => {
+            Some(("method", constant_name(proto, pc, c as usize)))
+        }
+        _ => None,
+    }
+}
+
+/// Name of a key which is a string constant, or a register holding one,
+/// like kname
+fn constant_name(proto: &BProto, pc: usize, c: usize) -> String {
+    if c & BITRK != 0 {
+        if let LPrimitive::STRING(s) = &proto.constants.list[c & !BITRK] {
+            return s.to_string();
+        }
+    } else if let Some(("constant", name)) = object_name(proto, pc, c) {
+        return name;
+    }
+    "?".to_owned()
+}
+
+/// Last instruction before `last_pc` which set register `reg`, unless it's
+/// skipped by a jump so that it may not have run, like findsetreg
+fn find_set_reg(proto: &BProto, last_pc: usize, reg: usize) -> Option<usize> {
+    let mut set_reg = None;
+    //Instructions before this may be skipped by a forward jump
+    let mut jump_target = 0;
+    for (pc, instruction) in proto.instructions.list[..last_pc].iter().enumerate() {
+        let a = instruction.a();
+        let sets = match *instruction {
+            //LOADNIL
+            BInstruction::ABC { opcode: 4, b, .. } => a <= reg && reg <= a + b as usize,
+            //TFORCALL
+            BInstruction::ABC { opcode: 41, .. } => reg >= a + 2,
+            //CALL and TAILCALL
+            BInstruction::ABC {
+                opcode: 36 | 37, ..
+            } => reg >= a,
+            //JMP
+            BInstruction::AsBx { opcode: 30, b, .. } => {
+                let dest = (pc as i64 + 1 + b as i64) as usize;
+                if pc < dest && dest <= last_pc {
+                    jump_target = jump_target.max(dest);
+                }
+                false
+            }
+            //Instructions which don't set R(A), like testAMode
+            _ if matches!(
+                instruction.opcode(),
+                8 | 9 | 10 | 30..=34 | 38 | 41 | 43 | 46
+            ) =>
+            {
+                false
+            }
+            _ => reg == a,
+        };
+        if sets {
+            set_reg = (pc >= jump_target).then_some(pc);
+        }
+    }
+    set_reg
+}
+
+/// Name of a function found in a loaded library, "debug.getinfo" or just
+/// "print" for globals, like pushglobalfuncname
+pub fn global_func_name<'i>(genv: &GlobalEnv<'i>, function: &LValue<'i>) -> Option<String> {
+    let loaded = genv.loaded.borrow();
+    for (library, table) in loaded.hash_entries() {
+        let (LValue::LPrimitive(LPrimitive::STRING(library)), LValue::Table(table)) =
+            (library, table)
+        else {
+            continue;
+        };
+        for (name, value) in table.borrow().hash_entries() {
+            match name {
+                LValue::LPrimitive(LPrimitive::STRING(name)) if equals_values(value, function) => {
+                    let library: &str = library;
+                    return Some(match library {
+                        "_G" => name.to_string(),
+                        library => format!("{}.{}", library, name),
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+    None
+}
+
+/// The function running in frame `i` as tracebacks name it, "function
+/// 'name'", "local 'f'", "main chunk" or "function <src:line>"
+fn function_description<'i>(
+    genv: &GlobalEnv<'i>,
+    stack: &Stack<'i>,
+    call_stack: &CallStack<'i>,
+    i: usize,
+) -> String {
+    let frame = &call_stack[i];
+    //A global name is preferred to the name it was called by
+    match (
+        global_func_name(genv, &stack[frame.func]),
+        func_name(call_stack, i),
+    ) {
+        (Some(name), _) => format!("function '{}'", name),
+        (None, Some((what, name))) => format!("{} '{}'", what, name),
+        (None, None) => match frame.proto {
+            Some(proto) if proto.line_defined == 0 => "main chunk".to_owned(),
+            Some(proto) => format!("function <{}:{}>", proto.short_src(), proto.line_defined),
+            None => "?".to_owned(),
+        },
+    }
+}
+
+/// Renders the call stack from `level` outwards in the format of
+/// luaL_traceback, eliding the middle of very deep stacks
+pub fn traceback<'i>(
+    genv: &GlobalEnv<'i>,
+    stack: &Stack<'i>,
+    call_stack: &CallStack<'i>,
+    level: i64,
+) -> String {
+    let mut traceback = String::from("stack traceback:");
+    let Some(first) = frame_index(call_stack, level) else {
+        return traceback;
+    };
+
+    //Frames are listed innermost first, which is the end of the call stack
+    let frames = (0..=first).rev();
+    let elide = match first > LEVELS1 + LEVELS2 {
+        true => LEVELS1..first + 1 - LEVELS2,
+        false => 0..0,
+    };
+    for (n, i) in frames.enumerate() {
+        if elide.contains(&n) {
+            if n == elide.start {
+                traceback.push_str("\n\t...");
+            }
+            continue;
+        }
+
+        let frame = &call_stack[i];
+        let _ = match frame.proto {
+            Some(proto) => write!(traceback, "\n\t{}:", proto.short_src()),
+            None => write!(traceback, "\n\t[C]:"),
+        };
+        if let Some(line) = frame.current_line() {
+            let _ = write!(traceback, "{}:", line);
+        }
+        let _ = write!(
+            traceback,
+            " in {}",
+            function_description(genv, stack, call_stack, i)
+        );
+
+        if frame.tail_call {
+            traceback.push_str("\n\t(...tail calls...)");
+        }
+    }
+
+    traceback
+}
+
+/// Dump of the call stack for an error with `message`, innermost frame
+/// first. Each frame has its source, line and pc, its named locals which
+/// are in scope, its upvalues and its varargs, with values shown like
+/// `tostring` would, strings quoted and long ones cut short
+pub fn crash_report<'i>(
+    genv: &GlobalEnv<'i>,
+    stack: &Stack<'i>,
+    call_stack: &CallStack<'i>,
+    message: &str,
+) -> Value {
+    let value = |value: &LValue<'i>| {
+        json!({
+            "type": value.type_name(),
+            "value": truncate(&match value {
+                LValue::LPrimitive(LPrimitive::STRING(s)) => format!("{:?}", s.to_string()),
+                value => value.to_string(),
+            }),
+        })
+    };
+
+    let frames: Vec<Value> = (0..call_stack.len())
+        .rev()
+        .map(|i| {
+            let frame = &call_stack[i];
+            let mut report = json!({
+                "function": function_description(genv, stack, call_stack, i),
+                "source": frame.proto.map_or("[C]".to_owned(), |proto| proto.short_src()),
+                "line": frame.current_line(),
+                "line_defined": frame.proto.map(|proto| proto.line_defined),
+                "pc": frame.proto.map(|_| frame.pc),
+                "tail_call": frame.tail_call,
+            });
+            let Some(proto) = frame.proto else {
+                return report;
+            };
+
+            let locals: Vec<Value> = (1..)
+                .map_while(|n| Some((n, proto.local_name(n, frame.pc)?)))
+                .filter_map(|(n, name)| {
+                    let local = stack.get(frame.base + n - 1)?;
+                    Some(json!({ "name": name, "register": n - 1, "value": value(local) }))
+                })
+                .collect();
+            let upvalues: Vec<Value> = match &stack[frame.func] {
+                LValue::LClosure(closure) => closure
+                    .upvalues
+                    .iter()
+                    .enumerate()
+                    .map(|(n, upvalue)| {
+                        json!({
+                            "name": proto.upvalue_name(n),
+                            "value": value(&upvalue.borrow().get(stack)),
+                        })
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            };
+            //The varargs are between func and base
+            let varargs: Vec<Value> = stack[frame.func + 1..frame.base]
+                .iter()
+                .map(value)
+                .collect();
+
+            report["locals"] = Value::Array(locals);
+            report["upvalues"] = Value::Array(upvalues);
+            report["varargs"] = Value::Array(varargs);
+            report
+        })
+        .collect();
+
+    json!({
+        "error": message,
+        "traceback": traceback(genv, stack, call_stack, 0),
+        "frames": frames,
+    })
+}
+
+/// Values longer than this many characters are cut short in crash reports
+const REPORT_VALUE_LEN: usize = 200;
+
+fn truncate(s: &str) -> String {
+    match s.char_indices().nth(REPORT_VALUE_LEN) {
+        Some((end, _)) => format!("{}...", &s[..end]),
+        None => s.to_owned(),
+    }
+}
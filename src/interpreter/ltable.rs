@@ -0,0 +1,296 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    mem::size_of,
+    rc::Rc,
+};
+
+use crate::lprimative::{float_to_integer, LPrimitive, LValue};
+
+use super::arith::equals_values;
+
+/// A value usable as a table key. Nil and NaN are never keys and floats
+/// with an integer value are stored as that integer, so that `t[1]` and
+/// `t[1.0]` are the same field
+#[derive(Clone, Debug)]
+pub struct LKey<'i>(LValue<'i>);
+impl<'i> LKey<'i> {
+    pub fn new(value: LValue<'i>) -> Result<Self, &'static str> {
+        match value {
+            LValue::LPrimitive(LPrimitive::NIL) => Err("table index is nil"),
+            LValue::LPrimitive(LPrimitive::FLOAT(n)) if n.is_nan() => Err("table index is NaN"),
+            LValue::LPrimitive(LPrimitive::FLOAT(n)) => Ok(Self(LValue::LPrimitive(
+                float_to_integer(n).map_or(LPrimitive::FLOAT(n), LPrimitive::INT),
+            ))),
+            value => Ok(Self(value)),
+        }
+    }
+
+    fn as_integer(&self) -> Option<i64> {
+        match self.0 {
+            LValue::LPrimitive(LPrimitive::INT(n)) => Some(n),
+            _ => None,
+        }
+    }
+}
+impl<'i> PartialEq for LKey<'i> {
+    fn eq(&self, other: &Self) -> bool {
+        equals_values(&self.0, &other.0)
+    }
+}
+impl<'i> Eq for LKey<'i> {}
+impl<'i> Hash for LKey<'i> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match &self.0 {
+            LValue::LPrimitive(LPrimitive::NIL) => unreachable!("nil is never a key"),
+            LValue::LPrimitive(LPrimitive::BOOL(b)) => (1, b).hash(state),
+            LValue::LPrimitive(LPrimitive::INT(n)) => (3, n).hash(state),
+            LValue::LPrimitive(LPrimitive::FLOAT(n)) => (4, n.to_bits()).hash(state),
+            LValue::LPrimitive(LPrimitive::STRING(s)) => (5, s.hash_value()).hash(state),
+            LValue::LClosure(c) => (6, Rc::as_ptr(c)).hash(state),
+            LValue::CClosure(c) => (7, **c as usize).hash(state),
+            LValue::Table(t) => (8, Rc::as_ptr(t)).hash(state),
+        }
+    }
+}
+
+/// Lua table. Keys 1..=n live in an array part and everything else lives
+/// in a hash part which remembers insertion order, so that traversals
+/// can walk it without having to collect the keys
+#[derive(Debug, Default)]
+pub struct LTable<'i> {
+    array: Vec<LValue<'i>>,
+    /// Index of each key's entry
+    hash: HashMap<LKey<'i>, usize>,
+    /// Entries whose value has been set to nil are kept until the next
+    /// compaction so that traversals can continue past them
+    entries: Vec<(LKey<'i>, LValue<'i>)>,
+    dead_entries: usize,
+    pub(crate) metatable: Option<Rc<RefCell<LTable<'i>>>>,
+}
+impl<'i> LTable<'i> {
+    /// Creates a table whose array part already has `array` nil slots, as
+    /// table constructors do, so that constructors with nil items have the
+    /// same borders as in the reference implementation
+    pub fn with_sizes(array: usize, hash: usize) -> Self {
+        Self {
+            array: vec![LValue::default(); array],
+            hash: HashMap::with_capacity(hash),
+            entries: Vec::with_capacity(hash),
+            dead_entries: 0,
+            metatable: None,
+        }
+    }
+
+    /// Grows the array part to at least `size` slots, moving keys in the
+    /// new range out of the hash part, as luaH_resizearray does for SETLIST
+    pub fn grow_array(&mut self, size: usize) {
+        while self.array.len() < size {
+            let key = LKey(LValue::LPrimitive(LPrimitive::INT(
+                self.array.len() as i64 + 1,
+            )));
+            let value = self.remove_entry(&key).unwrap_or_default();
+            self.array.push(value);
+        }
+    }
+
+    fn array_index(&self, key: &LKey) -> Option<usize> {
+        match key.as_integer() {
+            Some(n) if n >= 1 && n as usize <= self.array.len() => Some(n as usize - 1),
+            _ => None,
+        }
+    }
+
+    /// Raw get, without metamethods. Absent fields and invalid keys are nil
+    pub fn get(&self, key: LValue<'i>) -> LValue<'i> {
+        match LKey::new(key) {
+            Ok(key) => self.get_key(&key),
+            Err(_) => LValue::default(),
+        }
+    }
+
+    pub fn get_int(&self, n: i64) -> LValue<'i> {
+        self.get_key(&LKey(LValue::LPrimitive(LPrimitive::INT(n))))
+    }
+
+    fn get_key(&self, key: &LKey<'i>) -> LValue<'i> {
+        if let Some(i) = self.array_index(key) {
+            return self.array[i].clone();
+        }
+        match self.hash.get(key) {
+            Some(&i) => self.entries[i].1.clone(),
+            None => LValue::default(),
+        }
+    }
+
+    /// Raw set, without metamethods. Fails for nil and NaN keys
+    pub fn set(&mut self, key: LValue<'i>, value: LValue<'i>) -> Result<(), &'static str> {
+        let key = LKey::new(key)?;
+        self.set_key(key, value);
+        Ok(())
+    }
+
+    pub fn set_int(&mut self, n: i64, value: LValue<'i>) {
+        self.set_key(LKey(LValue::LPrimitive(LPrimitive::INT(n))), value)
+    }
+
+    fn set_key(&mut self, key: LKey<'i>, value: LValue<'i>) {
+        if let Some(i) = self.array_index(&key) {
+            self.array[i] = value;
+            return;
+        }
+
+        //Appending to the array part pulls any following keys out of the hash part
+        if key.as_integer() == Some(self.array.len() as i64 + 1)
+            && !matches!(value, LValue::LPrimitive(LPrimitive::NIL))
+        {
+            self.remove_entry(&key);
+            self.array.push(value);
+            loop {
+                let next = LKey(LValue::LPrimitive(LPrimitive::INT(
+                    self.array.len() as i64 + 1,
+                )));
+                match self.remove_entry(&next) {
+                    Some(value) => self.array.push(value),
+                    None => break,
+                }
+            }
+            return;
+        }
+
+        match self.hash.get(&key) {
+            Some(&i) => {
+                let entry = &mut self.entries[i].1;
+                let was_nil = matches!(entry, LValue::LPrimitive(LPrimitive::NIL));
+                let is_nil = matches!(value, LValue::LPrimitive(LPrimitive::NIL));
+                match (was_nil, is_nil) {
+                    (false, true) => self.dead_entries += 1,
+                    (true, false) => self.dead_entries -= 1,
+                    _ => {}
+                }
+                *entry = value;
+            }
+            None => {
+                if matches!(value, LValue::LPrimitive(LPrimitive::NIL)) {
+                    return;
+                }
+                //New keys can't be added during a traversal so this is a safe point to compact
+                if self.dead_entries > 0 && self.dead_entries >= self.entries.len() / 2 {
+                    self.compact();
+                }
+                self.hash.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    /// Takes a live entry out of the hash part, leaving a dead entry behind
+    fn remove_entry(&mut self, key: &LKey<'i>) -> Option<LValue<'i>> {
+        let i = *self.hash.get(key)?;
+        let value = std::mem::take(&mut self.entries[i].1);
+        if matches!(value, LValue::LPrimitive(LPrimitive::NIL)) {
+            return None;
+        }
+        self.dead_entries += 1;
+        Some(value)
+    }
+
+    fn compact(&mut self) {
+        self.entries
+            .retain(|(_, value)| !matches!(value, LValue::LPrimitive(LPrimitive::NIL)));
+        self.hash.clear();
+        for (i, (key, _)) in self.entries.iter().enumerate() {
+            self.hash.insert(key.clone(), i);
+        }
+        self.dead_entries = 0;
+    }
+
+    /// Every key and value held by the table, for the collector
+    pub fn contents(&self) -> impl Iterator<Item = &LValue<'i>> {
+        self.array.iter().chain(
+            self.entries
+                .iter()
+                .filter(|(_, value)| !matches!(value, LValue::LPrimitive(LPrimitive::NIL)))
+                .flat_map(|(key, value)| [&key.0, value]),
+        )
+    }
+
+    /// Values of the array part, whose keys are integers
+    pub fn array_values(&self) -> impl Iterator<Item = &LValue<'i>> {
+        self.array.iter()
+    }
+
+    /// Keys and values of the hash part
+    pub fn hash_entries(&self) -> impl Iterator<Item = (&LValue<'i>, &LValue<'i>)> {
+        self.entries
+            .iter()
+            .filter(|(_, value)| !matches!(value, LValue::LPrimitive(LPrimitive::NIL)))
+            .map(|(key, value)| (&key.0, value))
+    }
+
+    /// Sets the fields for which `cleared(key, value)` holds to nil, for
+    /// the collector to remove entries of weak tables
+    pub fn clear_where(&mut self, mut cleared: impl FnMut(&LValue<'i>, &LValue<'i>) -> bool) {
+        for (i, value) in self.array.iter_mut().enumerate() {
+            let key = LValue::LPrimitive(LPrimitive::INT(i as i64 + 1));
+            if cleared(&key, value) {
+                *value = LValue::default();
+            }
+        }
+        for (key, value) in self.entries.iter_mut() {
+            if !matches!(value, LValue::LPrimitive(LPrimitive::NIL)) && cleared(&key.0, value) {
+                *value = LValue::default();
+                self.dead_entries += 1;
+            }
+        }
+    }
+
+    /// Estimated bytes allocated for the array and hash parts
+    pub fn allocated_bytes(&self) -> usize {
+        self.array.capacity() * size_of::<LValue>()
+            + self.entries.capacity() * size_of::<(LKey, LValue)>()
+            + self.hash.capacity() * (size_of::<LKey>() + size_of::<usize>() + 1)
+    }
+
+    /// Border of the table as returned by the length operator: an index n
+    /// where t[n] is not nil and t[n+1] is nil, or 0 if t[1] is nil
+    pub fn border(&self) -> usize {
+        match self.array.last() {
+            //Binary search the array part for a border, like luaH_getn
+            Some(LValue::LPrimitive(LPrimitive::NIL)) => {
+                let (mut i, mut j) = (0, self.array.len());
+                while j - i > 1 {
+                    let m = (i + j) / 2;
+                    match self.array[m - 1] {
+                        LValue::LPrimitive(LPrimitive::NIL) => j = m,
+                        _ => i = m,
+                    }
+                }
+                i
+            }
+            //The array part is full so the border may continue into the hash part
+            _ => {
+                let mut n = self.array.len();
+                while !matches!(
+                    self.get_int(n as i64 + 1),
+                    LValue::LPrimitive(LPrimitive::NIL)
+                ) {
+                    n += 1;
+                }
+                n
+            }
+        }
+    }
+}
+
+/// Decodes the "floating point byte" table size hints of NEWTABLE, like
+/// luaO_fb2int
+pub fn fb2int(x: usize) -> usize {
+    let e = (x >> 3) & 0x1f;
+    if e == 0 {
+        x
+    } else {
+        ((x & 7) + 8) << (e - 1)
+    }
+}
@@ -0,0 +1,76 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::lprimative::LValue;
+
+use super::{gc::Collector, Stack};
+
+/// A local variable captured by a closure. While the frame declaring the
+/// variable is still running the upvalue is open and refers to the
+/// variable's register. When the frame returns (or the variable goes out
+/// of scope) the upvalue is closed and takes its own copy of the value.
+///
+/// Closures capturing the same variable share the same upvalue so they
+/// all see each others assignments
+#[derive(Debug)]
+pub enum LUpvalue<'i> {
+    Open(usize),
+    Closed(LValue<'i>),
+}
+impl<'i> LUpvalue<'i> {
+    pub fn get(&self, stack: &Stack<'i>) -> LValue<'i> {
+        match self {
+            LUpvalue::Open(i) => stack[*i].clone(),
+            LUpvalue::Closed(value) => value.clone(),
+        }
+    }
+
+    pub fn set(&mut self, stack: &mut Stack<'i>, value: LValue<'i>) {
+        match self {
+            LUpvalue::Open(i) => stack[*i] = value,
+            LUpvalue::Closed(v) => *v = value,
+        }
+    }
+}
+
+pub type UpvalueRef<'i> = Rc<RefCell<LUpvalue<'i>>>;
+
+/// Finds the open upvalue for register `index` among those of a frame,
+/// opening one if the register hasn't been captured yet
+pub fn find_upvalue<'i>(
+    open_upvalues: &mut Vec<UpvalueRef<'i>>,
+    index: usize,
+    gc: &mut Collector<'i>,
+) -> UpvalueRef<'i> {
+    let existing = open_upvalues
+        .iter()
+        .find(|upvalue| matches!(*upvalue.borrow(), LUpvalue::Open(i) if i == index));
+
+    match existing {
+        Some(upvalue) => upvalue.clone(),
+        None => {
+            let upvalue = Rc::new(RefCell::new(LUpvalue::Open(index)));
+            gc.track_upvalue(&upvalue);
+            open_upvalues.push(upvalue.clone());
+            upvalue
+        }
+    }
+}
+
+/// Closes the open upvalues of a frame which refer to registers at or
+/// above `level`
+pub fn close_upvalues<'i>(
+    open_upvalues: &mut Vec<UpvalueRef<'i>>,
+    stack: &Stack<'i>,
+    level: usize,
+    gc: &mut Collector<'i>,
+) {
+    open_upvalues.retain(|upvalue| {
+        let index = match *upvalue.borrow() {
+            LUpvalue::Open(i) if i >= level => i,
+            _ => return true,
+        };
+        gc.barrier_upvalue(upvalue);
+        *upvalue.borrow_mut() = LUpvalue::Closed(stack[index].clone());
+        false
+    });
+}
@@ -1,53 +1,185 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, io::Write, rc::Rc};
 
 use lclosure::LClosure;
+use lupvalue::LUpvalue;
 
-use self::genv::GlobalEnv;
-use crate::{
-    bytecode::bproto::BProto,
-    lprimative::{LPrimitive, LValue},
+use self::{
+    callinfo::CallStack,
+    counters::{CounterReport, Counters},
+    error::LuaError,
+    genv::{BudgetMode, GlobalEnv},
+    heap::HeapSnapshot,
+    hook::{Hook, HookMask},
+    interrupt::InterruptHandle,
 };
+use crate::{bytecode::bproto::BProto, lprimative::LValue, lstring::StringTable};
 
+pub mod arith;
+pub mod callinfo;
 pub mod cfunction;
+pub mod counters;
+pub mod debuglib;
+pub mod error;
+pub mod gc;
 pub mod genv;
+pub mod heap;
+pub mod hook;
+pub mod interrupt;
 pub mod lclosure;
+pub mod ldebug;
+pub mod ltable;
+pub mod lupvalue;
+#[cfg(test)]
+mod tests;
 
-pub type StackItem<'i> = Rc<RefCell<LValue<'i>>>;
-pub type Stack<'i> = Vec<StackItem<'i>>; //Rc<RefCell<>> temporary garbage collector I guess
+/// Register file shared by every active call. Each frame's registers are
+/// a window of it starting at the frame's base
+pub type Stack<'i> = Vec<LValue<'i>>;
+
+/// How a run of the interpreter ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Finished,
+    /// The instruction budget ran out, `resume` carries on from there
+    Suspended,
+}
 
 pub struct Interpreter<'i> {
     genv: GlobalEnv<'i>,
     stack: Stack<'i>,
-    top: Box<BProto>,
+    call_stack: CallStack<'i>,
+    top: &'i BProto,
 }
 impl<'i> Interpreter<'i> {
-    pub fn new(top: Box<BProto>) -> Interpreter<'i> {
-        let mut stack = Vec::with_capacity(top.max_stack as usize);
+    pub fn new(top: &'i BProto, strings: StringTable) -> Interpreter<'i> {
+        Self {
+            genv: GlobalEnv::new(strings),
+            stack: Vec::new(),
+            call_stack: Vec::new(),
+            top,
+        }
+    }
+
+    /// Runs the top proto. With a budget in `BudgetMode::Suspend` this
+    /// returns `Status::Suspended` when the budget runs out, in which case
+    /// `resume` must be used rather than starting over
+    pub fn interpret(&mut self) -> Result<Status, LuaError> {
+        assert!(
+            self.call_stack.is_empty(),
+            "interpret called on a suspended interpreter"
+        );
+
+        //Interrupts of earlier runs don't carry over
+        self.genv.interrupt.start_run();
 
-        for _ in 0..top.max_stack {
-            stack.push(Rc::new(RefCell::new(LValue::default())));
+        //Instantiate a closure for the top proto, its only upvalue is _ENV
+        let env = Rc::new(RefCell::new(LUpvalue::Closed(LValue::Table(
+            self.genv.globals.clone(),
+        ))));
+        let top_closure = Rc::new(LClosure::<'i>::new(self.top, vec![env.clone()]));
+        self.genv.gc.track_upvalue(&env);
+        self.genv.gc.track_closure(&top_closure);
+
+        //Call the top closure from the bottom of the stack, with no arguments.
+        //Whatever a previous run left on the stack is garbage
+        self.stack.clear();
+        self.stack.push(LValue::LClosure(top_closure));
+
+        let result = lclosure::call(&mut self.genv, &mut self.stack, &mut self.call_stack, 0, 0);
+        self.status(result)
+    }
+
+    /// Carries on a suspended run, after giving it more budget with
+    /// `set_instruction_budget`. Does nothing if it isn't suspended
+    pub fn resume(&mut self) -> Result<Status, LuaError> {
+        if self.call_stack.is_empty() {
+            return Ok(Status::Finished);
         }
 
-        Self {
-            genv: GlobalEnv::default(),
-            stack,
-            top,
+        let result = lclosure::resume(&mut self.genv, &mut self.stack, &mut self.call_stack, 0);
+        self.status(result)
+    }
+
+    fn status(&self, result: Result<usize, LuaError>) -> Result<Status, LuaError> {
+        match result {
+            Ok(_) => Ok(Status::Finished),
+            Err(LuaError::Suspended) => Ok(Status::Suspended),
+            Err(e) => Err(e),
         }
     }
 
-    pub fn interpret(&'i mut self) {
-        //Instantiate a closure for the top proto
-        let top_closure = LClosure::<'i>::new(&self.top, vec![]);
+    /// Sets the hook called from Rust on the events of `mask`, see the
+    /// `hook` module, or removes it. It's independent from the hook set by
+    /// debug.sethook. A count greater than 0 with MASK_COUNT sends a count
+    /// event every `count` instructions
+    pub fn set_hook(&mut self, hook: Option<Hook<'i>>, mask: u8, count: u64) {
+        self.genv
+            .hooks
+            .set_rust(hook.map(|hook| (hook, HookMask::new(mask, count))));
+    }
+
+    /// Handle to interrupt, from another thread, the suspended run or else
+    /// the next one `interpret` starts. Once the interrupted run has
+    /// returned its error the interpreter can be used again
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.genv.interrupt.handle(!self.call_stack.is_empty())
+    }
 
-        //Call the top closure
-        assert_eq!(self.top.max_stack as usize, self.stack.len());
+    /// Limits the number of instructions executed until the next call,
+    /// None to remove the limit. Once they're used up execution stops as
+    /// `mode` says
+    pub fn set_instruction_budget(&mut self, budget: Option<u64>, mode: BudgetMode) {
+        self.genv.budget = budget.unwrap_or(u64::MAX);
+        self.genv.budget_mode = budget.map(|_| mode);
+    }
 
-        top_closure.execute(
-            &mut self.genv,
-            &mut self.stack,
-            self.top.max_stack as usize,
-            0,
-            0,
-        );
+    /// Closes the state, running the finalizers of every object which has
+    /// one like lua_close
+    pub fn close(mut self) {
+        gc::close(&mut self.genv, &mut self.stack, &mut self.call_stack);
+    }
+
+    /// Estimated bytes in use by the state, counting its tables, closures,
+    /// upvalues, strings and stacks. `collectgarbage("count")` reports the
+    /// same amount in kilobytes
+    pub fn memory_used(&self) -> usize {
+        gc::memory_used(&self.genv, &self.stack, &self.call_stack)
+    }
+
+    /// Limits the bytes the state may use, None to remove the limit. Going
+    /// over it raises a "not enough memory" error, which `pcall` catches
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.genv.gc.memory_limit = limit;
+    }
+
+    /// Redirects what `print` writes, which goes to stdout by default
+    pub fn set_output(&mut self, output: Box<dyn Write + 'i>) {
+        self.genv.output = output;
+    }
+
+    /// Graph of the objects reachable from the globals, the registry and
+    /// the calls being executed, to find what retains them
+    pub fn heap_snapshot(&self) -> HeapSnapshot {
+        HeapSnapshot::new(&self.genv, &self.stack, &self.call_stack)
+    }
+
+    /// Makes runtime errors carry a crash report, with the locals, upvalues
+    /// and varargs of every frame they unwound. See `LuaError::crash_report`
+    pub fn set_crash_reports(&mut self, enabled: bool) {
+        self.genv.crash_reports = enabled;
+    }
+
+    /// Instructions executed by opcode, calls by function, and tables,
+    /// strings and collection cycles since the counters were last reset,
+    /// with the memory in use
+    pub fn counters(&self) -> CounterReport {
+        CounterReport::new(&self.genv, self.memory_used())
+    }
+
+    pub fn reset_counters(&mut self) {
+        self.genv.counters = Counters::default();
+        self.genv.gc.table_allocations = 0;
+        self.genv.gc.cycles = 0;
+        self.genv.strings.allocations = 0;
     }
 }
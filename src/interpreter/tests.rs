@@ -0,0 +1,231 @@
+use std::io;
+
+use super::Interpreter;
+use crate::{
+    bytecode::{
+        load_fixture,
+        optimize::{run_captured, Limits},
+    },
+    lprimative::LValue,
+    lstring::StringTable,
+};
+
+/// What the fixture prints when run without limits
+fn run(name: &str) -> String {
+    let mut strings = StringTable::default();
+    let top = load_fixture(name, &mut strings);
+    run_captured(&top, strings, Limits::default())
+}
+
+#[test]
+fn setlist_keeps_nils_from_multiple_results_in_the_array_part() {
+    let output = run("setlist");
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            " >> PRINT >> 3 1 nil 3 ",
+            " >> PRINT >> 4 0 4 ",
+            " >> PRINT >> 7 5 7 ",
+            " >> PRINT >> 53 50 nil 53 ",
+        ]
+    );
+}
+
+#[test]
+fn an_interrupt_stops_the_run_it_was_made_for() {
+    let mut strings = StringTable::default();
+    let top = load_fixture("count", &mut strings);
+    let mut interpreter = Interpreter::new(&top, strings);
+    interpreter.set_output(Box::new(io::sink()));
+
+    //A request made before the run starts applies to it
+    interpreter.interrupt_handle().interrupt();
+    let error = interpreter.interpret().unwrap_err();
+    assert!(error.to_string().contains("interrupted"), "{}", error);
+
+    //and not to the next one
+    assert!(interpreter.interpret().is_ok());
+
+    //A handle used after its run ended, like a late timer, leaves later ones be
+    let late = interpreter.interrupt_handle();
+    assert!(interpreter.interpret().is_ok());
+    late.interrupt();
+    assert!(interpreter.interpret().is_ok());
+    interpreter.close();
+}
+
+#[test]
+fn memory_used_rises_with_allocations_and_falls_once_collected() {
+    let mut strings = StringTable::default();
+    let top = load_fixture("memory", &mut strings);
+    let mut interpreter = Interpreter::new(&top, strings);
+    let before = interpreter.memory_used();
+
+    interpreter.interpret().unwrap();
+    let filled = interpreter.memory_used();
+    interpreter.interpret().unwrap();
+    let collected = interpreter.memory_used();
+    interpreter.close();
+
+    //10000 tables of one item each
+    assert!(
+        filled > before + 10_000 * size_of::<LValue>(),
+        "{} {}",
+        before,
+        filled
+    );
+    //Only the closures of the two runs are left
+    assert!(
+        collected < before + (filled - before) / 100,
+        "{} {}",
+        filled,
+        collected
+    );
+}
+
+#[test]
+fn counters_start_over_once_reset() {
+    let mut strings = StringTable::default();
+    let top = load_fixture("count", &mut strings);
+    let mut interpreter = Interpreter::new(&top, strings);
+    interpreter.set_output(Box::new(io::sink()));
+
+    interpreter.interpret().unwrap();
+    let first = interpreter.counters();
+    assert!(first.instructions() > 0);
+    //The libraries were loaded before the run
+    assert!(first.strings > 0);
+
+    interpreter.reset_counters();
+    let reset = interpreter.counters();
+    assert_eq!(reset.instructions(), 0);
+    assert!(reset.protos.is_empty() && reset.host_functions.is_empty());
+    assert_eq!((reset.tables, reset.strings, reset.gc_cycles), (0, 0, 0));
+
+    interpreter.interpret().unwrap();
+    let second = interpreter.counters();
+    assert_eq!(second.opcodes, first.opcodes);
+    assert_eq!(second.protos, first.protos);
+    assert_eq!(
+        second
+            .host_functions
+            .iter()
+            .map(|(_, calls)| calls)
+            .sum::<u64>(),
+        1
+    );
+    interpreter.close();
+}
+
+/// `text` with the addresses of objects, which change from run to run,
+/// replaced by "ADDR"
+fn mask_addresses(text: &str) -> String {
+    let mut masked = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("0x") {
+        masked.push_str(&rest[..start]);
+        masked.push_str("ADDR");
+        rest = rest[start + 2..].trim_start_matches(|c: char| c.is_ascii_hexdigit());
+    }
+    masked.push_str(rest);
+    masked
+}
+
+#[test]
+fn retainers_are_named_after_the_paths_retaining_them() {
+    let mut strings = StringTable::default();
+    let top = load_fixture("heap", &mut strings);
+    let mut interpreter = Interpreter::new(&top, strings);
+    interpreter.interpret().unwrap();
+    let snapshot = interpreter.heap_snapshot();
+    interpreter.close();
+
+    //The weak key of seen is a shorter path, but doesn't retain it
+    let nodes: Vec<usize> = snapshot.find("cache.entry.payload").collect();
+    assert_eq!(nodes.len(), 1);
+    let mut retainers = Vec::new();
+    snapshot.write_retainers(nodes[0], &mut retainers).unwrap();
+    assert_eq!(
+        mask_addresses(&String::from_utf8(retainers).unwrap()),
+        "table cache.entry.payload (ADDR, 136 bytes) is retained by:\n  \
+         field 'payload' of table cache.entry (ADDR)\n  \
+         field '[1]' of table archive.old (ADDR)\n  \
+         weak key '[table: ADDR]' of table seen (ADDR)\n"
+    );
+}
+
+#[test]
+fn crash_report_has_the_locals_upvalues_and_varargs_of_each_frame() {
+    let mut strings = StringTable::default();
+    let top = load_fixture("crash", &mut strings);
+    let mut interpreter = Interpreter::new(&top, strings);
+    interpreter.set_crash_reports(true);
+    let error = interpreter.interpret().unwrap_err();
+    let report = mask_addresses(&error.crash_report().unwrap().to_string());
+    interpreter.close();
+    let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+    assert_eq!(
+        report,
+        serde_json::json!({
+            "error": "fixtures/crash.lua:5: attempt to perform arithmetic on a table value",
+            "traceback": "stack traceback:\n\
+                \tfixtures/crash.lua:5: in local 'fail'\n\
+                \tfixtures/crash.lua:8: in main chunk",
+            "frames": [
+                {
+                    "function": "local 'fail'",
+                    "source": "fixtures/crash.lua",
+                    "line": 5,
+                    "line_defined": 3,
+                    "pc": 3,
+                    "tail_call": false,
+                    "locals": [
+                        { "name": "n", "register": 0, "value": { "type": "number", "value": "1" } },
+                        {
+                            "name": "tag",
+                            "register": 1,
+                            "value": { "type": "string", "value": "\"item\"" },
+                        },
+                        {
+                            "name": "doubled",
+                            "register": 2,
+                            "value": { "type": "number", "value": "2" },
+                        },
+                    ],
+                    "upvalues": [
+                        { "name": "prefix", "value": { "type": "string", "value": "\"item\"" } },
+                    ],
+                    "varargs": [
+                        { "type": "string", "value": "\"extra\"" },
+                        { "type": "boolean", "value": "true" },
+                    ],
+                },
+                {
+                    "function": "main chunk",
+                    "source": "fixtures/crash.lua",
+                    "line": 8,
+                    "line_defined": 0,
+                    "pc": 6,
+                    "tail_call": false,
+                    "locals": [
+                        {
+                            "name": "prefix",
+                            "register": 0,
+                            "value": { "type": "string", "value": "\"item\"" },
+                        },
+                        {
+                            "name": "fail",
+                            "register": 1,
+                            "value": { "type": "function", "value": "LClosure: ADDR" },
+                        },
+                    ],
+                    "upvalues": [
+                        { "name": "_ENV", "value": { "type": "table", "value": "table: ADDR" } },
+                    ],
+                    "varargs": [],
+                },
+            ],
+        })
+    );
+}
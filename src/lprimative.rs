@@ -1,15 +1,66 @@
-use std::fmt;
+use std::{cell::RefCell, fmt, rc::Rc};
 
-use crate::interpreter::{cfunction::CFunction, lclosure::LClosure};
+use crate::{
+    interpreter::{cfunction::CFunction, lclosure::LClosure, ltable::LTable},
+    lstring::LString,
+};
 
-///Lua primitive types
+///Lua primitive types. Nil, booleans and numbers are stored inline and
+///strings are a pointer to a shared LString, so cloning never copies
+///string contents. Strings must be created through the state's StringTable
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Debug)]
 pub enum LPrimitive {
-    NIL,            //0
-    BOOL(bool),     //1
-    FLOAT(f64),     //3 | (0 << 4)
-    INT(i64),       //3 | (1 << 4)
-    STRING(String), //4
+    NIL,                 //0
+    BOOL(bool),          //1
+    FLOAT(f64),          //3 | (0 << 4)
+    INT(i64),            //3 | (1 << 4)
+    STRING(Rc<LString>), //4
+}
+impl LPrimitive {
+    /// Name of the type as reported by `type()` and error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LPrimitive::NIL => "nil",
+            LPrimitive::BOOL(_) => "boolean",
+            LPrimitive::FLOAT(_) | LPrimitive::INT(_) => "number",
+            LPrimitive::STRING(_) => "string",
+        }
+    }
+
+    /// Only nil and false are falsy
+    pub fn truthy(&self) -> bool {
+        !matches!(self, LPrimitive::NIL | LPrimitive::BOOL(false))
+    }
+
+    /// Numeric value of the primitive, coercing strings the way the
+    /// arithmetic operators do
+    pub fn to_number(&self) -> Option<LPrimitive> {
+        match self {
+            LPrimitive::INT(_) | LPrimitive::FLOAT(_) => Some(self.clone()),
+            LPrimitive::STRING(s) => str_to_number(s),
+            _ => None,
+        }
+    }
+
+    /// Float value of the primitive, coercing integers and strings
+    pub fn to_float(&self) -> Option<f64> {
+        match self.to_number()? {
+            LPrimitive::INT(n) => Some(n as f64),
+            LPrimitive::FLOAT(n) => Some(n),
+            _ => unreachable!("to_number only returns numbers"),
+        }
+    }
+
+    /// Integer value of the primitive. Floats are only accepted when they
+    /// have an exact integer representation
+    pub fn to_integer(&self) -> Option<i64> {
+        match self.to_number()? {
+            LPrimitive::INT(n) => Some(n),
+            LPrimitive::FLOAT(n) => float_to_integer(n),
+            _ => unreachable!("to_number only returns numbers"),
+        }
+    }
 }
 impl fmt::Display for LPrimitive {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -17,49 +68,173 @@ impl fmt::Display for LPrimitive {
             LPrimitive::NIL => write!(f, "nil"),
             LPrimitive::BOOL(true) => write!(f, "true"),
             LPrimitive::BOOL(false) => write!(f, "false"),
-            LPrimitive::FLOAT(n) => write!(f, "{}", n),
+            LPrimitive::FLOAT(n) => write!(f, "{}", fmt_float(*n)),
             LPrimitive::INT(n) => write!(f, "{}", n),
             LPrimitive::STRING(s) => write!(f, "{}", s),
         }
     }
 }
 
-///Any lua value, including primitives
-#[derive(Debug)]
+/// Converts a float to an integer if it has an exact representation
+pub fn float_to_integer(n: f64) -> Option<i64> {
+    // -2^63 is exact as a float, 2^63 is the first value out of range
+    if n.floor() == n && (-9223372036854775808.0..9223372036854775808.0).contains(&n) {
+        Some(n as i64)
+    } else {
+        None
+    }
+}
+
+/// Formats a float the way Lua's LUAI_NUMFFORMAT ("%.14g") does, adding
+/// ".0" to integral values so they still read as floats
+pub fn fmt_float(n: f64) -> String {
+    if n.is_nan() {
+        return if n.is_sign_negative() { "-nan" } else { "nan" }.to_owned();
+    }
+    if n.is_infinite() {
+        return if n < 0.0 { "-inf" } else { "inf" }.to_owned();
+    }
+
+    // %g picks scientific notation when the exponent is < -4 or >= precision
+    let scientific = format!("{:.13e}", n);
+    let (mantissa, exponent) = scientific
+        .split_once('e')
+        .expect("scientific float formatting always has an exponent");
+    let exponent: i32 = exponent.parse().expect("float exponent is an integer");
+
+    let formatted = if !(-4..14).contains(&exponent) {
+        let mantissa = trim_fraction(mantissa);
+        format!(
+            "{}e{}{:02}",
+            mantissa,
+            if exponent < 0 { '-' } else { '+' },
+            exponent.abs()
+        )
+    } else {
+        trim_fraction(&format!("{:.*}", (13 - exponent) as usize, n)).to_owned()
+    };
+
+    if formatted.contains(['.', 'e']) {
+        formatted
+    } else {
+        formatted + ".0"
+    }
+}
+
+fn trim_fraction(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+/// Converts a string to a number following luaO_str2num: decimal and
+/// hexadecimal integers, falling back to floats. Surrounding whitespace
+/// is ignored
+pub fn str_to_number(s: &str) -> Option<LPrimitive> {
+    let s = s.trim_matches(|c: char| c.is_ascii_whitespace());
+    str_to_integer(s)
+        .map(LPrimitive::INT)
+        .or_else(|| str_to_float(s).map(LPrimitive::FLOAT))
+}
+
+fn str_to_integer(s: &str) -> Option<i64> {
+    let (negative, digits) = match s.as_bytes().first()? {
+        b'-' => (true, &s[1..]),
+        b'+' => (false, &s[1..]),
+        _ => (false, s),
+    };
+
+    let value = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        if hex.is_empty() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        // Hexadecimal integers wrap around on overflow
+        hex.bytes().fold(0u64, |acc, b| {
+            acc.wrapping_mul(16)
+                .wrapping_add((b as char).to_digit(16).unwrap() as u64)
+        })
+    } else {
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        // Decimal integers that overflow are read as floats instead
+        let value: u64 = digits.parse().ok()?;
+        if value > i64::MAX as u64 + negative as u64 {
+            return None;
+        }
+        value
+    };
+
+    Some(if negative {
+        0u64.wrapping_sub(value) as i64
+    } else {
+        value as i64
+    })
+}
+
+fn str_to_float(s: &str) -> Option<f64> {
+    // Rust would accept these but Lua rejects them
+    if s.contains(['n', 'N', 'x', 'X']) || s.ends_with(['e', 'E']) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+///Any lua value, including primitives. Reference types are a single
+///pointer so that the whole value fits in 16 bytes
+#[derive(Clone, Debug)]
 pub enum LValue<'i> {
     //Constants
     LPrimitive(LPrimitive),
 
     //Functions
-    LClosure(LClosure<'i>),
-    CClosure(CFunction<'i>),
-    //Table
+    LClosure(Rc<LClosure<'i>>),
+    CClosure(Rc<CFunction<'i>>),
 
+    //Table
+    Table(Rc<RefCell<LTable<'i>>>),
     //Thread
 
     //UserData is a pointer to user memory I guess for embedded applications
 }
-impl<'i> Default for LValue<'i> {
-    fn default() -> LValue<'i> {
-        LValue::LPrimitive(LPrimitive::NIL)
-    }
-}
-impl<'i> Clone for LValue<'i> {
-    fn clone(&self) -> Self {
+//Registers and table slots are LValues, keep them small
+const _: () = assert!(std::mem::size_of::<LValue>() <= 16);
+
+impl<'i> LValue<'i> {
+    /// Name of the type as reported by `type()` and error messages
+    pub fn type_name(&self) -> &'static str {
         match self {
-            LValue::LPrimitive(p) => LValue::LPrimitive(p.clone()),
+            LValue::LPrimitive(p) => p.type_name(),
+            LValue::LClosure(_) | LValue::CClosure(_) => "function",
+            LValue::Table(_) => "table",
+        }
+    }
 
-            LValue::LClosure(_) => panic!("cannot clone LCLosure primitive type"),
-            LValue::CClosure(_) => panic!("cannot clone LCLosure primitive type"),
+    /// Only nil and false are falsy
+    pub fn truthy(&self) -> bool {
+        match self {
+            LValue::LPrimitive(p) => p.truthy(),
+            _ => true,
         }
     }
 }
+impl<'i> Default for LValue<'i> {
+    fn default() -> LValue<'i> {
+        LValue::LPrimitive(LPrimitive::NIL)
+    }
+}
 impl<'i> fmt::Display for LValue<'i> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             LValue::LPrimitive(l) => write!(f, "{}", l),
-            LValue::CClosure(c) => write!(f, "CClosure: {:p}", c), // TODO: Addresses in rust aren't a great unique identifier since things move a tonne
-            LValue::LClosure(l) => write!(f, "LClosure: {:p}", l), // Maybe attach a unqiue ID to each proto for the LClosure's to be ID'ed and then make CClosures a full type and do something similar there
+            LValue::CClosure(c) => write!(f, "CClosure: {:p}", Rc::as_ptr(c)),
+            LValue::LClosure(l) => write!(f, "LClosure: {:p}", Rc::as_ptr(l)), // Maybe attach a unqiue ID to each proto for the LClosure's to be ID'ed and then make CClosures a full type and do something similar there
+            LValue::Table(t) => write!(f, "table: {:p}", Rc::as_ptr(t)),
         }
     }
 }
@@ -0,0 +1,189 @@
+use std::{
+    borrow::Borrow,
+    cell::Cell,
+    collections::{hash_map::RandomState, HashSet},
+    fmt,
+    hash::{BuildHasher, Hash, Hasher},
+    mem::size_of,
+    ops::Deref,
+    rc::{Rc, Weak},
+};
+
+/// Strings up to this length are interned, like LUAI_MAXSHORTLEN
+pub const MAX_SHORT_LEN: usize = 40;
+
+/// Long strings only hash every 2^HASH_LIMIT'th byte or so, like LUAI_HASHLIMIT
+const HASH_LIMIT: usize = 5;
+
+/// Immutable Lua string. Values refer to it through an `Rc` so copying a
+/// string value only copies a pointer
+///
+/// Short strings are interned by the StringTable, so two short strings are
+/// equal only if they are the same object and their hash is computed once
+/// when they are created. Long strings are compared by contents and hashed
+/// the first time they are used as a table key
+#[derive(Debug)]
+pub struct LString {
+    contents: Box<str>,
+    seed: u32,
+    hash: Cell<Option<u32>>,
+}
+impl LString {
+    fn new(contents: Box<str>, seed: u32) -> Self {
+        let string = Self {
+            contents,
+            seed,
+            hash: Cell::new(None),
+        };
+        if string.is_short() {
+            string.hash_value();
+        }
+        string
+    }
+
+    pub fn is_short(&self) -> bool {
+        self.contents.len() <= MAX_SHORT_LEN
+    }
+
+    /// Hash of the contents, like luaS_hash
+    pub fn hash_value(&self) -> u32 {
+        if let Some(hash) = self.hash.get() {
+            return hash;
+        }
+
+        let bytes = self.contents.as_bytes();
+        let mut hash = self.seed ^ bytes.len() as u32;
+        let step = (bytes.len() >> HASH_LIMIT) + 1;
+        let mut l = bytes.len();
+        while l >= step {
+            hash ^= (hash << 5)
+                .wrapping_add(hash >> 2)
+                .wrapping_add(bytes[l - 1] as u32);
+            l -= step;
+        }
+
+        self.hash.set(Some(hash));
+        hash
+    }
+}
+impl PartialEq for LString {
+    fn eq(&self, other: &Self) -> bool {
+        if self.is_short() && other.is_short() {
+            std::ptr::eq(self, other)
+        } else {
+            self.contents == other.contents
+        }
+    }
+}
+impl Eq for LString {}
+impl Deref for LString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.contents
+    }
+}
+impl fmt::Display for LString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.contents)
+    }
+}
+
+/// Entry of the StringTable, looked up by contents
+#[derive(Debug)]
+struct Interned(Rc<LString>);
+impl PartialEq for Interned {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.contents == other.0.contents
+    }
+}
+impl Eq for Interned {}
+impl Hash for Interned {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.contents.hash(state)
+    }
+}
+impl Borrow<str> for Interned {
+    fn borrow(&self) -> &str {
+        &self.0.contents
+    }
+}
+
+/// Per-state table of interned short strings. Every string of the state
+/// must be created through it so that short strings are unique
+///
+/// It also accounts for the memory of every string it creates. Long
+/// strings aren't interned but are kept track of to know when they're freed
+#[derive(Debug)]
+pub struct StringTable {
+    strings: HashSet<Interned>,
+    long_strings: Vec<Weak<LString>>,
+    seed: u32,
+    /// Bytes used by the strings alive at the last sweep and those created since
+    total: usize,
+    /// Strings created, not counting short ones which were already interned
+    pub(crate) allocations: u64,
+}
+impl Default for StringTable {
+    fn default() -> Self {
+        Self {
+            strings: HashSet::new(),
+            long_strings: Vec::new(),
+            //Randomised like luai_makeseed so that colliding keys can't be precomputed
+            seed: RandomState::new().hash_one(0) as u32,
+            total: 0,
+            allocations: 0,
+        }
+    }
+}
+impl StringTable {
+    /// Creates a string, returning the existing copy of short strings
+    pub fn intern(&mut self, s: &str) -> Rc<LString> {
+        if s.len() > MAX_SHORT_LEN {
+            let string = Rc::new(LString::new(s.into(), self.seed));
+            self.long_strings.push(Rc::downgrade(&string));
+            self.total += string_size(s);
+            self.allocations += 1;
+            return string;
+        }
+
+        if let Some(interned) = self.strings.get(s) {
+            return interned.0.clone();
+        }
+        let string = Rc::new(LString::new(s.into(), self.seed));
+        self.strings.insert(Interned(string.clone()));
+        self.total += string_size(s);
+        self.allocations += 1;
+        string
+    }
+
+    /// Removes the strings nothing but the table refers to anymore and
+    /// forgets the long strings which were freed
+    pub fn sweep(&mut self) {
+        self.strings
+            .retain(|interned| Rc::strong_count(&interned.0) > 1);
+        self.long_strings.retain(|string| string.strong_count() > 0);
+
+        self.total = self
+            .strings
+            .iter()
+            .map(|interned| string_size(&interned.0))
+            .chain(
+                self.long_strings
+                    .iter()
+                    .filter_map(Weak::upgrade)
+                    .map(|string| string_size(&string)),
+            )
+            .sum();
+    }
+
+    /// Estimated bytes used by strings
+    pub fn total(&self) -> usize {
+        self.total
+    }
+}
+
+/// Estimated size of a string's allocations, the Rc with its counts and the contents
+fn string_size(s: &str) -> usize {
+    size_of::<LString>() + 2 * size_of::<usize>() + s.len()
+}
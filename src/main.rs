@@ -1,14 +1,319 @@
-use bytecode::decode_bytecode;
-use interpreter::Interpreter;
+use bytecode::{decode_bytecode, optimize};
+use coverage::{Coverage, COVERAGE_MASK};
+use debugger::{Debugger, DEBUGGER_MASK};
+use interpreter::{genv::BudgetMode, Interpreter, Status};
+use lstring::StringTable;
+use profiler::{Profiler, Weight, PROFILER_MASK};
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{self, BufWriter, IsTerminal, Write},
+    path::PathBuf,
+    rc::Rc,
+    thread,
+    time::Duration,
+};
+use trace::{TraceRecorder, TRACE_MASK};
+use tracing_subscriber::EnvFilter;
 
 pub(crate) mod bytecode;
+pub(crate) mod coverage;
+pub(crate) mod dap;
+pub(crate) mod debugger;
+pub(crate) mod decompiler;
 pub(crate) mod interpreter;
 pub(crate) mod lprimative;
+pub(crate) mod lstring;
+pub(crate) mod profiler;
+pub(crate) mod trace;
 
 fn main() -> Result<(), anyhow::Error> {
-    let mut interpreter = Interpreter::new(decode_bytecode()?);
+    let mut memory_limit = None;
+    let mut instruction_limit = None;
+    let mut time_slice = None;
+    let mut timeout = None;
+    let mut debug = false;
+    let mut dap = false;
+    let mut profile = None;
+    let mut profile_weight = Weight::Time;
+    let mut profile_sample = 1;
+    let mut coverage = None;
+    let mut trace = None;
+    let mut replay = None;
+    let mut log = None;
+    let mut heap = None;
+    let mut retainers = Vec::new();
+    let mut crash_report = None;
+    let mut counters = None;
+    let mut optimize = false;
+    let mut diff_optimize = false;
+    let mut cfg = None;
+    let mut decompile = None;
+    for arg in std::env::args().skip(1) {
+        match arg.split_once('=') {
+            //Stops before the first instruction and reads debugger commands from stdin
+            None if arg == "debug" => debug = true,
+            //Speaks the Debug Adapter Protocol over stdio, the client launches the chunk
+            None if arg == "dap" => dap = true,
+            //Runs the chunk through the bytecode optimizer before running it
+            None if arg == "--optimize" => optimize = true,
+            //Runs the chunk both as compiled and optimized and compares their output
+            None if arg == "--diff-optimize" => diff_optimize = true,
+            //Dot graph of the basic blocks of every function, after optimizing
+            Some(("--cfg", path)) => cfg = Some(PathBuf::from(path)),
+            //Prints Lua source for a chunk precompiled by luac instead of running anything
+            Some(("--decompile", path)) => decompile = Some(PathBuf::from(path)),
+            //In kilobytes, like collectgarbage("count")
+            Some(("--memory-limit", limit)) => memory_limit = Some(limit.parse::<usize>()? * 1024),
+            Some(("--instruction-limit", limit)) => instruction_limit = Some(limit.parse()?),
+            //Runs the script in slices of this many instructions, as a scheduler would
+            Some(("--time-slice", slice)) => time_slice = Some(slice.parse()?),
+            //In milliseconds
+            Some(("--timeout", ms)) => timeout = Some(Duration::from_millis(ms.parse()?)),
+            //Folded stacks for flamegraphs, the summary goes to stderr
+            Some(("--profile", path)) => profile = Some(path.to_owned()),
+            Some(("--profile-weight", "time")) => profile_weight = Weight::Time,
+            Some(("--profile-weight", "instructions")) => profile_weight = Weight::Instructions,
+            //Samples the call stack every this many instructions rather than at each one
+            Some(("--profile-sample", n)) => profile_sample = n.parse::<u64>()?.max(1),
+            //An lcov file, which the counts of this run are added to if it exists
+            Some(("--coverage", path)) => coverage = Some(PathBuf::from(path)),
+            //A binary trace of every instruction executed and the registers it changed
+            Some(("--trace", path)) => trace = Some(PathBuf::from(path)),
+            //Steps through a trace instead of running the chunk
+            Some(("--replay", path)) => replay = Some(PathBuf::from(path)),
+            //Graph of the objects still reachable once the script has run, dot
+            //for files ending in .dot and JSON otherwise
+            Some(("--heap", path)) => heap = Some(PathBuf::from(path)),
+            //What retains the objects with this name or address, after the run
+            Some(("--heap-retainers", query)) => retainers.push(query.to_owned()),
+            //JSON dump of the frames an uncaught error unwound, with their locals
+            Some(("--crash-report", path)) => crash_report = Some(PathBuf::from(path)),
+            //Instructions by opcode, calls by function, allocations and memory in use, JSON for
+            //files ending in .json and tables otherwise
+            Some(("--counters", path)) => counters = Some(PathBuf::from(path)),
+            //Diagnostics of the loader and the VM on stderr, "debug" or "lua_interpretter::interpreter=trace"
+            Some(("--log", filter)) => log = Some(EnvFilter::try_new(filter)?),
+            _ => anyhow::bail!("unrecognized option '{}'", arg),
+        }
+    }
 
-    interpreter.interpret();
+    //Without a subscriber the diagnostics are skipped without being formatted
+    if let Some(filter) = log {
+        tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_writer(io::stderr)
+            .with_ansi(io::stderr().is_terminal())
+            .init();
+    }
+
+    //Each of these runs from the hook, which there's only one of
+    let tools = [
+        debug,
+        dap,
+        profile.is_some(),
+        coverage.is_some(),
+        trace.is_some(),
+    ];
+    if tools.into_iter().filter(|&tool| tool).count() > 1 {
+        anyhow::bail!(
+            "only one of debug, dap, --profile, --coverage and --trace can be used at a time"
+        );
+    }
+    if dap {
+        return dap::run();
+    }
+    if let Some(path) = replay {
+        return trace::replay(&path);
+    }
+
+    if let Some(path) = decompile {
+        let top = bytecode::load_chunk(&path, &mut StringTable::default())?;
+        print!("{}", decompiler::decompile(&top));
+        return Ok(());
+    }
+
+    if diff_optimize {
+        let bytecode = bytecode::dump_bytecode()?;
+        let limits = optimize::Limits {
+            instructions: instruction_limit,
+            memory: memory_limit,
+            timeout,
+        };
+        if !optimize::differential(&bytecode, limits)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let mut strings = StringTable::default();
+    let mut top = decode_bytecode(&mut strings)?;
+    if optimize {
+        optimize::optimize(&mut top);
+    }
+    if let Some(path) = cfg {
+        let mut file = BufWriter::new(File::create(path)?);
+        bytecode::cfg::write_dot(&top, &mut file)?;
+        file.flush()?;
+    }
+
+    let mut interpreter = Interpreter::new(&top, strings);
+    interpreter.set_memory_limit(memory_limit);
+    interpreter.set_crash_reports(crash_report.is_some());
+    if instruction_limit.is_some() {
+        interpreter.set_instruction_budget(instruction_limit, BudgetMode::Error);
+    } else if time_slice.is_some() {
+        interpreter.set_instruction_budget(time_slice, BudgetMode::Suspend);
+    }
+
+    if debug {
+        let mut debugger = Debugger::new(&top);
+        interpreter.set_hook(
+            Some(Box::new(move |event, stack, call_stack| {
+                debugger.event(event, stack, call_stack)
+            })),
+            DEBUGGER_MASK,
+            1,
+        );
+    }
+
+    let profiler = profile.as_ref().map(|_| {
+        Rc::new(RefCell::new(Profiler::new(
+            &top,
+            profile_weight,
+            profile_sample,
+        )))
+    });
+    if let Some(profiler) = &profiler {
+        let profiler = profiler.clone();
+        interpreter.set_hook(
+            Some(Box::new(move |event, stack, call_stack| {
+                profiler.borrow_mut().event(event, stack, call_stack);
+                Ok(())
+            })),
+            PROFILER_MASK,
+            profile_sample,
+        );
+    }
+
+    let recorder = coverage
+        .as_ref()
+        .map(|_| Rc::new(RefCell::new(Coverage::new(&top))));
+    if let Some(recorder) = &recorder {
+        let recorder = recorder.clone();
+        interpreter.set_hook(
+            Some(Box::new(move |event, _stack, call_stack| {
+                recorder.borrow_mut().event(event, call_stack);
+                Ok(())
+            })),
+            COVERAGE_MASK,
+            1,
+        );
+    }
+
+    let tracer = match &trace {
+        Some(path) => Some(Rc::new(RefCell::new(TraceRecorder::new(
+            &top,
+            BufWriter::new(File::create(path)?),
+        )?))),
+        None => None,
+    };
+    if let Some(tracer) = &tracer {
+        let tracer = tracer.clone();
+        interpreter.set_hook(
+            Some(Box::new(move |event, stack, call_stack| {
+                tracer.borrow_mut().event(event, stack, call_stack);
+                Ok(())
+            })),
+            TRACE_MASK,
+            1,
+        );
+    }
+
+    if let Some(timeout) = timeout {
+        let interrupt = interpreter.interrupt_handle();
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            interrupt.interrupt();
+        });
+    }
+
+    //The counters cover the script, not loading it and the libraries
+    if counters.is_some() {
+        interpreter.reset_counters();
+    }
+    let mut result = interpreter.interpret();
+    while let Ok(Status::Suspended) = result {
+        interpreter.set_instruction_budget(time_slice, BudgetMode::Suspend);
+        result = interpreter.resume();
+    }
+    if let Err(e) = &result {
+        eprintln!("lua: {}", e);
+        if !e.traceback().is_empty() {
+            eprintln!("{}", e.traceback());
+        }
+        if let Some(path) = &crash_report {
+            //Memory errors are raised without building anything
+            let report = match e.crash_report() {
+                Some(report) => report.clone(),
+                None => serde_json::json!({ "error": e.to_string(), "frames": [] }),
+            };
+            serde_json::to_writer_pretty(File::create(path)?, &report)?;
+        }
+    }
+
+    if heap.is_some() || !retainers.is_empty() {
+        let snapshot = interpreter.heap_snapshot();
+        if let Some(path) = heap {
+            let mut file = BufWriter::new(File::create(&path)?);
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("dot") => snapshot.write_dot(&mut file)?,
+                _ => serde_json::to_writer_pretty(&mut file, &snapshot.to_json())?,
+            }
+            file.flush()?;
+        }
+        for query in retainers {
+            let mut found = false;
+            for node in snapshot.find(&query) {
+                snapshot.write_retainers(node, &mut io::stderr())?;
+                found = true;
+            }
+            if !found {
+                eprintln!("no object named '{}' is reachable", query);
+            }
+        }
+    }
+    if let Some(path) = counters {
+        let report = interpreter.counters();
+        let mut file = BufWriter::new(File::create(&path)?);
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => serde_json::to_writer_pretty(&mut file, &report.to_json())?,
+            _ => report.write(&mut file)?,
+        }
+        file.flush()?;
+    }
+    interpreter.close();
+
+    if let (Some(path), Some(profiler)) = (profile, profiler) {
+        let mut profiler = profiler.borrow_mut();
+        profiler.finish();
+        profiler.write_folded(&mut File::create(path)?)?;
+        profiler.write_summary(&mut io::stderr())?;
+    }
+
+    if let (Some(path), Some(recorder)) = (coverage, recorder) {
+        let mut recorder = recorder.borrow_mut();
+        recorder.merge_file(&path)?;
+        recorder.write_lcov(&mut File::create(path)?)?;
+    }
+
+    if let Some(tracer) = tracer {
+        tracer.borrow_mut().finish()?;
+    }
+
+    if result.is_err() {
+        std::process::exit(1);
+    }
 
     Ok(())
 }
@@ -0,0 +1,405 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bytecode::{binstruction::BInstruction, bproto::BProto},
+    interpreter::{
+        callinfo::CallStack,
+        hook::{HookEvent, MASK_CALL, MASK_COUNT, MASK_RET},
+        lclosure::BITRK,
+        ldebug, Stack,
+    },
+    lprimative::{LPrimitive, LValue},
+};
+
+/// Calls are counted from call events, the rest is attributed at count
+/// events and when host functions return
+pub const PROFILER_MASK: u8 = MASK_CALL | MASK_RET | MASK_COUNT;
+
+/// Lines shown in the summary, hottest first
+const SUMMARY_LINES: usize = 20;
+
+/// What the folded stacks are weighted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weight {
+    /// Microseconds
+    Time,
+    Instructions,
+}
+
+/// A function, by the address of its proto or of its host function
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Function {
+    Lua(usize),
+    Host(usize),
+}
+
+/// A frame of a call stack, with the line a Lua function is at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Frame {
+    function: Function,
+    line: i64,
+}
+
+/// Time and instructions spent with a call stack
+#[derive(Debug, Default)]
+struct Sample {
+    time: Duration,
+    instructions: u64,
+}
+
+/// Instrumenting profiler run from the hook of the interpreter. With a
+/// hook count greater than 1 it samples the call stack every that many
+/// instructions instead, each sample standing for all of them
+pub struct Profiler {
+    weight: Weight,
+    /// Instructions between count events
+    interval: u64,
+    /// Names of the functions, derived from the proto tree for Lua ones
+    /// and from the first call for host ones
+    names: HashMap<Function, String>,
+    /// Where the proto of each Lua function comes from, "src:line_defined"
+    locations: HashMap<usize, String>,
+    calls: HashMap<Function, u64>,
+    stacks: Vec<(Vec<Frame>, Sample)>,
+    /// Index in `stacks` of each stack
+    index: HashMap<Vec<Frame>, usize>,
+    /// Stack being executed since the last event, charged the time until
+    /// the next one
+    current: Option<usize>,
+    last: Instant,
+    scratch: Vec<Frame>,
+}
+impl Profiler {
+    pub fn new(top: &BProto, weight: Weight, interval: u64) -> Self {
+        let mut profiler = Self {
+            weight,
+            interval,
+            names: HashMap::new(),
+            locations: HashMap::new(),
+            calls: HashMap::new(),
+            stacks: Vec::new(),
+            index: HashMap::new(),
+            current: None,
+            last: Instant::now(),
+            scratch: Vec::new(),
+        };
+        profiler.name_protos(top, "main chunk".to_owned());
+        profiler
+    }
+
+    /// Names every proto of the tree after the variable its closure is
+    /// stored in when it's created, if any
+    fn name_protos(&mut self, proto: &BProto, name: String) {
+        let address = proto as *const BProto as usize;
+        self.names.insert(Function::Lua(address), name);
+        self.locations.insert(
+            address,
+            format!("{}:{}", proto.short_src(), proto.line_defined),
+        );
+
+        for (pc, instruction) in proto.instructions.list.iter().enumerate() {
+            //CLOSURE
+            if let BInstruction::ABx {
+                opcode: 44, a, b, ..
+            } = *instruction
+            {
+                let child = &proto.protos.list[b as usize];
+                let name =
+                    closure_name(proto, pc, a as usize).unwrap_or_else(|| "function".to_owned());
+                self.name_protos(child, name);
+            }
+        }
+    }
+
+    /// Called by the hook with the innermost frame being the one the event
+    /// is about
+    pub fn event(&mut self, event: HookEvent, stack: &Stack, call_stack: &CallStack) {
+        let now = Instant::now();
+        if let Some(current) = self.current {
+            self.stacks[current].1.time += now - self.last;
+        }
+
+        //A returning frame's results are already where its function was, so
+        //the stack is the one of the previous event until the next one
+        if event != HookEvent::Return {
+            let current = self.stack_index(stack, call_stack);
+            match event {
+                HookEvent::Count => self.stacks[current].1.instructions += self.interval,
+                HookEvent::Call | HookEvent::TailCall => {
+                    if let Some(frame) = self.stacks[current].0.last() {
+                        *self.calls.entry(frame.function).or_default() += 1;
+                    }
+                }
+                _ => {}
+            }
+            self.current = Some(current);
+        }
+        //Time spent in the profiler isn't charged to the script
+        self.last = Instant::now();
+    }
+
+    /// Charges the time since the last event, once the script has finished
+    pub fn finish(&mut self) {
+        if let Some(current) = self.current.take() {
+            self.stacks[current].1.time += self.last.elapsed();
+        }
+    }
+
+    fn stack_index(&mut self, stack: &Stack, call_stack: &CallStack) -> usize {
+        self.scratch.clear();
+        for (i, frame) in call_stack.iter().enumerate() {
+            let (function, line) = match (frame.proto, &stack[frame.func]) {
+                (Some(proto), _) => (
+                    Function::Lua(proto as *const BProto as usize),
+                    frame.current_line().unwrap_or(0),
+                ),
+                (None, LValue::CClosure(function)) => {
+                    let function = Function::Host(**function as usize);
+                    self.names.entry(function).or_insert_with(|| {
+                        match ldebug::func_name(call_stack, i) {
+                            Some((_, name)) => format!("{} [C]", name),
+                            None => "? [C]".to_owned(),
+                        }
+                    });
+                    (function, 0)
+                }
+                (None, _) => unreachable!("host frames call host functions"),
+            };
+            self.scratch.push(Frame { function, line });
+        }
+
+        match self.index.get(&self.scratch) {
+            Some(&i) => i,
+            None => {
+                self.stacks.push((self.scratch.clone(), Sample::default()));
+                self.index
+                    .insert(self.scratch.clone(), self.stacks.len() - 1);
+                self.stacks.len() - 1
+            }
+        }
+    }
+
+    fn function_label(&self, function: Function) -> String {
+        let name = self.names.get(&function).map_or("?", String::as_str);
+        match function {
+            Function::Lua(address) => format!("{} ({})", name, self.locations[&address]),
+            Function::Host(_) => name.to_owned(),
+        }
+    }
+
+    /// Brendan Gregg's folded stacks, outermost frame first, one line per
+    /// call stack with its weight
+    pub fn write_folded(&self, output: &mut impl Write) -> io::Result<()> {
+        for (frames, sample) in &self.stacks {
+            let weight = match self.weight {
+                Weight::Time => sample.time.as_micros() as u64,
+                Weight::Instructions => sample.instructions,
+            };
+            if weight == 0 {
+                continue;
+            }
+
+            let mut line = String::new();
+            for (n, frame) in frames.iter().enumerate() {
+                if n > 0 {
+                    line.push(';');
+                }
+                //Semicolons separate the frames
+                let label = self.function_label(frame.function).replace(';', ":");
+                match frame.function {
+                    Function::Lua(_) => {
+                        let _ = write!(line, "{}:{}", label, frame.line);
+                    }
+                    Function::Host(_) => line.push_str(&label),
+                }
+            }
+            writeln!(output, "{} {}", line, weight)?;
+        }
+        Ok(())
+    }
+
+    /// Per function calls, self and total instructions and time, hottest
+    /// first, then the hottest lines
+    pub fn write_summary(&self, output: &mut impl Write) -> io::Result<()> {
+        #[derive(Default)]
+        struct Totals {
+            self_sample: Sample,
+            total: Sample,
+        }
+
+        let mut functions: HashMap<Function, Totals> = HashMap::new();
+        let mut lines: HashMap<Frame, Sample> = HashMap::new();
+        for (frames, sample) in &self.stacks {
+            let Some(leaf) = frames.last() else {
+                continue;
+            };
+            let totals = functions.entry(leaf.function).or_default();
+            totals.self_sample.time += sample.time;
+            totals.self_sample.instructions += sample.instructions;
+            let line = lines.entry(*leaf).or_default();
+            line.time += sample.time;
+            line.instructions += sample.instructions;
+
+            //Recursive functions only count once per stack
+            let mut seen = HashSet::new();
+            for frame in frames {
+                if seen.insert(frame.function) {
+                    let totals = functions.entry(frame.function).or_default();
+                    totals.total.time += sample.time;
+                    totals.total.instructions += sample.instructions;
+                }
+            }
+        }
+
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by_key(|(_, totals)| Reverse(totals.self_sample.time));
+        writeln!(
+            output,
+            "{:<40} {:>8} {:>12} {:>12} {:>10} {:>10}",
+            "function", "calls", "self instr", "total instr", "self ms", "total ms"
+        )?;
+        for (function, totals) in &functions {
+            writeln!(
+                output,
+                "{:<40} {:>8} {:>12} {:>12} {:>10.3} {:>10.3}",
+                self.function_label(*function),
+                self.calls.get(function).copied().unwrap_or(0),
+                totals.self_sample.instructions,
+                totals.total.instructions,
+                totals.self_sample.time.as_secs_f64() * 1000.0,
+                totals.total.time.as_secs_f64() * 1000.0,
+            )?;
+        }
+
+        let mut lines: Vec<_> = lines
+            .into_iter()
+            .filter(|(frame, _)| matches!(frame.function, Function::Lua(_)))
+            .collect();
+        lines.sort_by_key(|(_, sample)| Reverse(sample.time));
+        writeln!(output)?;
+        writeln!(output, "{:<40} {:>12} {:>10}", "line", "instr", "ms")?;
+        for (frame, sample) in lines.iter().take(SUMMARY_LINES) {
+            let Function::Lua(address) = frame.function else {
+                continue;
+            };
+            let src = self.locations[&address]
+                .rsplit_once(':')
+                .map_or("?", |(src, _)| src);
+            writeln!(
+                output,
+                "{:<40} {:>12} {:>10.3}",
+                format!("{}:{} in {}", src, frame.line, self.names[&frame.function]),
+                sample.instructions,
+                sample.time.as_secs_f64() * 1000.0,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of the variable the closure created in register `a` at `pc` is
+/// stored in: a local, a global, a field or an upvalue
+fn closure_name(proto: &BProto, pc: usize, a: usize) -> Option<String> {
+    let constant = |k: u16| match proto.constants.list.get((k as usize) & !BITRK) {
+        Some(LPrimitive::STRING(name)) if k as usize & BITRK != 0 => Some(name.to_string()),
+        _ => None,
+    };
+
+    match proto.instructions.list.get(pc + 1) {
+        //SETTABUP, a global when the table is _ENV
+        Some(BInstruction::ABC {
+            opcode: 8,
+            a: t,
+            b,
+            c,
+            ..
+        }) if *c as usize == a => {
+            let key = constant(*b)?;
+            match proto.upvalue_name(*t as usize) {
+                Some("_ENV") => Some(key),
+                Some(table) => Some(format!("{}.{}", table, key)),
+                None => Some(key),
+            }
+        }
+        //SETTABLE
+        Some(BInstruction::ABC {
+            opcode: 10,
+            a: t,
+            b,
+            c,
+            ..
+        }) if *c as usize == a => {
+            let key = constant(*b)?;
+            match proto.local_name(*t as usize + 1, pc + 1) {
+                Some(table) => Some(format!("{}.{}", table, key)),
+                None => Some(key),
+            }
+        }
+        //SETUPVAL
+        Some(BInstruction::ABC {
+            opcode: 9,
+            a: value,
+            b,
+            ..
+        }) if *value as usize == a => proto.upvalue_name(*b as usize).map(str::to_owned),
+        //A local function, which is in scope once it's created
+        _ => proto.local_name(a + 1, pc + 1).map(str::to_owned),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{bytecode::load_fixture, interpreter::Interpreter, lstring::StringTable};
+
+    #[test]
+    fn folded_stacks_weighted_by_instructions() {
+        let mut strings = StringTable::default();
+        let top = load_fixture("profile", &mut strings);
+        let profiler = Rc::new(RefCell::new(Profiler::new(&top, Weight::Instructions, 1)));
+        let mut interpreter = Interpreter::new(&top, strings);
+        let hook = profiler.clone();
+        interpreter.set_hook(
+            Some(Box::new(move |event, stack, call_stack| {
+                hook.borrow_mut().event(event, stack, call_stack);
+                Ok(())
+            })),
+            PROFILER_MASK,
+            1,
+        );
+        interpreter.interpret().unwrap();
+        interpreter.close();
+
+        let mut profiler = profiler.borrow_mut();
+        profiler.finish();
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded).unwrap();
+        //Host functions run no instructions, so only appear in the time
+        //weighted stacks
+        let folded = String::from_utf8(folded).unwrap();
+        let lines: Vec<&str> = folded.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "main chunk (fixtures/profile.lua:0):3 1",
+                "main chunk (fixtures/profile.lua:0):12 1",
+                "main chunk (fixtures/profile.lua:0):14 4",
+                "main chunk (fixtures/profile.lua:0):14;outer (fixtures/profile.lua:5):6 1",
+                "main chunk (fixtures/profile.lua:0):14;outer (fixtures/profile.lua:5):7 4",
+                "main chunk (fixtures/profile.lua:0):14;outer (fixtures/profile.lua:5):8 8",
+                "main chunk (fixtures/profile.lua:0):14;outer (fixtures/profile.lua:5):8;\
+                 leaf (fixtures/profile.lua:1):2 14",
+                "main chunk (fixtures/profile.lua:0):14;outer (fixtures/profile.lua:5):9 4",
+                "main chunk (fixtures/profile.lua:0):14;outer (fixtures/profile.lua:5):11 1",
+            ]
+        );
+    }
+}
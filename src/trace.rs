@@ -0,0 +1,833 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+    rc::Rc,
+};
+
+use crate::{
+    bytecode::{bopcode::OPNAMES, bproto::BProto},
+    interpreter::{
+        callinfo::CallStack,
+        hook::{HookEvent, MASK_CALL, MASK_COUNT, MASK_RET},
+        Stack,
+    },
+    lprimative::{LPrimitive, LValue},
+};
+
+/// Call events start the registers of a frame over, count events come
+/// before every instruction and so after the previous one of its frame
+/// finished, and return events end the last one
+pub const TRACE_MASK: u8 = MASK_CALL | MASK_RET | MASK_COUNT;
+
+const MAGIC: &[u8; 4] = b"LTRC";
+const VERSION: u8 = 2;
+
+/// Set on the first step of a frame, which also lists the registers the
+/// frame was called with
+const FRAME_START: u8 = 1 << 0;
+
+//Tags of the values of registers
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_INT: u8 = 3;
+const TAG_FLOAT: u8 = 4;
+const TAG_STRING: u8 = 5;
+const TAG_TABLE: u8 = 6;
+const TAG_LCLOSURE: u8 = 7;
+const TAG_CCLOSURE: u8 = 8;
+
+/// Records a binary trace with an entry per instruction executed, from the
+/// hook of the interpreter.
+///
+/// The trace starts with a header listing the protos, numbered in the
+/// order of the proto tree, with the line of each of their instructions.
+/// Each entry is then framed by its length before and after it, so it can
+/// be read in both directions, and holds the depth of the frame, its proto,
+/// pc and opcode, and the registers of the frame the instruction changed.
+/// Registers are compared as they're encoded, tables and functions by
+/// address.
+///
+/// An entry is written once its instruction has run, so a call comes after
+/// the steps of its callee, with the results as its changes. Steps cut
+/// short by an error are written without changes in the order they
+/// started, so a failed run ends with the instruction which raised
+pub struct TraceRecorder<W: Write> {
+    output: W,
+    /// Number of each proto, by address
+    protos: HashMap<usize, usize>,
+    /// State of each Lua frame, by depth
+    frames: Vec<Frame>,
+    /// First error writing the trace, after which nothing is recorded
+    error: Option<io::Error>,
+    entry: Vec<u8>,
+    scratch: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Frame {
+    /// Encoded registers as of the last step which finished
+    registers: Vec<Vec<u8>>,
+    /// Registers the frame was called with, until its first step takes them
+    initial: Option<Vec<u8>>,
+    /// Step which started and hasn't finished
+    running: Option<Step>,
+}
+
+struct Step {
+    proto: usize,
+    pc: usize,
+    opcode: u8,
+    /// Encoded registers the frame was called with, for its first step
+    initial: Option<Vec<u8>>,
+}
+
+impl<W: Write> TraceRecorder<W> {
+    /// Writes the header of the trace for the protos of `top`
+    pub fn new(top: &BProto, mut output: W) -> io::Result<Self> {
+        let mut protos = Vec::new();
+        collect_protos(top, &mut protos);
+
+        let mut header = Vec::new();
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        write_varint(&mut header, protos.len() as u64);
+        for proto in &protos {
+            write_bytes(&mut header, proto.short_src().as_bytes());
+            write_varint(&mut header, zigzag(proto.line_defined));
+            write_varint(&mut header, proto.instructions.list.len() as u64);
+            for instruction in &proto.instructions.list {
+                //0 for instructions without a line, which start at 1
+                write_varint(&mut header, zigzag(instruction.line().unwrap_or(0)));
+            }
+        }
+        output.write_all(&header)?;
+
+        Ok(Self {
+            output,
+            protos: protos
+                .iter()
+                .enumerate()
+                .map(|(n, &proto)| (proto as *const BProto as usize, n))
+                .collect(),
+            frames: Vec::new(),
+            error: None,
+            entry: Vec::new(),
+            scratch: Vec::new(),
+        })
+    }
+
+    /// Called by the hook with the innermost frame being the one the event
+    /// is about
+    pub fn event(&mut self, event: HookEvent, stack: &Stack, call_stack: &CallStack) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.record(event, stack, call_stack) {
+            self.error = Some(e);
+        }
+    }
+
+    fn record(
+        &mut self,
+        event: HookEvent,
+        stack: &Stack,
+        call_stack: &CallStack,
+    ) -> io::Result<()> {
+        let Some(frame) = call_stack.last() else {
+            return Ok(());
+        };
+        let depth = call_stack.len();
+        if self.frames.len() < depth {
+            self.frames.resize_with(depth, Frame::default);
+        }
+
+        match event {
+            //A frame at this depth left by an error, or replaced by a tail call,
+            //has nothing more to change
+            HookEvent::Call | HookEvent::TailCall => {
+                self.unwind(depth - 1)?;
+                if let Some(proto) = frame.proto {
+                    self.frames[depth - 1].registers.clear();
+                    let initial = self.diff(depth, proto, frame.base, stack);
+                    self.frames[depth - 1].initial = Some(initial);
+                }
+            }
+            //RETURN moves its results into the caller, where they're the
+            //changes of the call
+            HookEvent::Return => self.unwind(depth - 1)?,
+            HookEvent::Count => {
+                let Some(proto) = frame.proto else {
+                    return Ok(());
+                };
+                //Deeper frames were left by an error caught below this one
+                self.unwind(depth)?;
+                if let Some(step) = self.frames[depth - 1].running.take() {
+                    let changes = self.diff(depth, proto, frame.base, stack);
+                    self.write(depth, step, &changes)?;
+                }
+                let step = Step {
+                    proto: self.protos[&(proto as *const BProto as usize)],
+                    pc: frame.pc,
+                    opcode: proto.instructions.list[frame.pc].opcode(),
+                    initial: self.frames[depth - 1].initial.take(),
+                };
+                self.frames[depth - 1].running = Some(step);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Registers of the frame which changed since they were last compared,
+    /// encoded as a count followed by each register and its value
+    fn diff(&mut self, depth: usize, proto: &BProto, base: usize, stack: &Stack) -> Vec<u8> {
+        let mut changes = Vec::new();
+        let mut count = 0u64;
+        let registers = &mut self.frames[depth - 1].registers;
+        let end = (base + proto.max_stack as usize).min(stack.len()).max(base);
+        registers.resize_with(end - base, || vec![TAG_NIL]);
+        for (n, value) in stack[base..end].iter().enumerate() {
+            self.scratch.clear();
+            write_value(&mut self.scratch, value);
+            if registers[n] != self.scratch {
+                write_varint(&mut changes, n as u64);
+                changes.extend_from_slice(&self.scratch);
+                registers[n].clone_from(&self.scratch);
+                count += 1;
+            }
+        }
+        let mut encoded = Vec::with_capacity(changes.len() + 1);
+        write_varint(&mut encoded, count);
+        encoded.extend_from_slice(&changes);
+        encoded
+    }
+
+    /// Writes the steps still running in frames deeper than `depth`
+    /// without changes, outermost first
+    fn unwind(&mut self, depth: usize) -> io::Result<()> {
+        for n in depth..self.frames.len() {
+            if let Some(step) = self.frames[n].running.take() {
+                self.write(n + 1, step, &[0])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, depth: usize, step: Step, changes: &[u8]) -> io::Result<()> {
+        let entry = &mut self.entry;
+        entry.clear();
+        entry.push(match step.initial {
+            Some(_) => FRAME_START,
+            None => 0,
+        });
+        write_varint(entry, depth as u64);
+        write_varint(entry, step.proto as u64);
+        write_varint(entry, step.pc as u64);
+        entry.push(step.opcode);
+        if let Some(initial) = &step.initial {
+            entry.extend_from_slice(initial);
+        }
+        entry.extend_from_slice(changes);
+
+        let length = (entry.len() as u32).to_le_bytes();
+        self.output.write_all(&length)?;
+        self.output.write_all(entry)?;
+        self.output.write_all(&length)
+    }
+
+    /// Writes the steps left running by an error and flushes the trace,
+    /// failing if any of it couldn't be written
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.unwind(0)?;
+        self.output.flush()
+    }
+}
+
+/// A proto as listed in the header of a trace
+#[derive(Debug, Clone)]
+pub struct TraceProto {
+    pub source: String,
+    pub line_defined: i64,
+    /// Line of each instruction, if it has one
+    pub lines: Vec<Option<i64>>,
+}
+
+/// Value of a register, with tables and functions by their address
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceValue {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Table(usize),
+    LClosure(usize),
+    CClosure(usize),
+}
+impl fmt::Display for TraceValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceValue::Nil => write!(f, "nil"),
+            TraceValue::Bool(b) => write!(f, "{}", b),
+            TraceValue::Int(i) => write!(f, "{}", i),
+            TraceValue::Float(n) => write!(f, "{}", LPrimitive::FLOAT(*n)),
+            TraceValue::String(s) => write!(f, "{:?}", s),
+            TraceValue::Table(address) => write!(f, "table: {:#x}", address),
+            TraceValue::LClosure(address) => write!(f, "LClosure: {:#x}", address),
+            TraceValue::CClosure(address) => write!(f, "CClosure: {:#x}", address),
+        }
+    }
+}
+
+/// An instruction executed, with the registers of its frame it changed
+#[derive(Debug, Clone)]
+pub struct TraceEntry {
+    /// Number of the entry in the trace, from 0
+    pub step: usize,
+    /// Set for the first step of a call
+    pub frame_start: bool,
+    /// Registers the frame was called with, for the first step of a call
+    pub initial: Vec<(usize, TraceValue)>,
+    /// Number of frames on the call stack, counting host ones
+    pub depth: usize,
+    /// Index in the protos of the trace
+    pub proto: usize,
+    pub pc: usize,
+    pub opcode: u8,
+    pub changes: Vec<(usize, TraceValue)>,
+}
+
+/// Reads a trace written by `TraceRecorder`, moving forward and backward
+/// through its entries like a cursor which sits between two of them
+pub struct TraceReader<R: Read + Seek> {
+    input: R,
+    protos: Vec<TraceProto>,
+    /// Offset of the first entry
+    start: u64,
+    /// Offset and number of the entry `forward` reads
+    offset: u64,
+    step: usize,
+    len: usize,
+}
+impl<R: BufRead + Seek> TraceReader<R> {
+    /// Reads the header and counts the entries, leaving the cursor before
+    /// the first one
+    pub fn new(mut input: R) -> Result<Self, anyhow::Error> {
+        let mut magic = [0; 5];
+        input.read_exact(&mut magic)?;
+        if &magic[..4] != MAGIC || magic[4] != VERSION {
+            anyhow::bail!("not a trace of this version");
+        }
+
+        let mut protos = Vec::new();
+        for _ in 0..read_varint(&mut input)? {
+            let source = String::from_utf8(read_bytes(&mut input)?)?;
+            let line_defined = unzigzag(read_varint(&mut input)?);
+            let mut lines = Vec::new();
+            for _ in 0..read_varint(&mut input)? {
+                let line = unzigzag(read_varint(&mut input)?);
+                lines.push((line != 0).then_some(line));
+            }
+            protos.push(TraceProto {
+                source,
+                line_defined,
+                lines,
+            });
+        }
+
+        let start = input.stream_position()?;
+        let end = input.seek(SeekFrom::End(0))?;
+        let mut len = 0;
+        let mut offset = start;
+        while offset < end {
+            input.seek(SeekFrom::Start(offset))?;
+            offset += read_u32(&mut input)? as u64 + 8;
+            len += 1;
+        }
+        if offset != end {
+            anyhow::bail!("trace is truncated");
+        }
+        input.seek(SeekFrom::Start(start))?;
+
+        Ok(Self {
+            input,
+            protos,
+            start,
+            offset: start,
+            step: 0,
+            len,
+        })
+    }
+
+    /// Number of entries in the trace
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Reads the entry after the cursor and moves past it
+    pub fn forward(&mut self) -> Result<Option<TraceEntry>, anyhow::Error> {
+        if self.step == self.len {
+            return Ok(None);
+        }
+        self.input.seek(SeekFrom::Start(self.offset))?;
+        let length = read_u32(&mut self.input)?;
+        let entry = self.read_entry()?;
+        self.offset += length as u64 + 8;
+        self.step += 1;
+        Ok(Some(entry))
+    }
+
+    /// Moves the cursor before the entry preceding it and reads it
+    pub fn back(&mut self) -> Result<Option<TraceEntry>, anyhow::Error> {
+        if self.step == 0 {
+            return Ok(None);
+        }
+        self.input.seek(SeekFrom::Start(self.offset - 4))?;
+        let length = read_u32(&mut self.input)?;
+        self.offset -= length as u64 + 8;
+        self.step -= 1;
+        self.input.seek(SeekFrom::Start(self.offset + 4))?;
+        self.read_entry().map(Some)
+    }
+
+    /// Moves the cursor before entry `step`, or to the end if there are
+    /// fewer entries
+    pub fn seek(&mut self, step: usize) -> Result<(), anyhow::Error> {
+        let step = step.min(self.len);
+        if step < self.step.abs_diff(step) {
+            self.offset = self.start;
+            self.step = 0;
+        }
+        while self.step < step {
+            self.input.seek(SeekFrom::Start(self.offset))?;
+            self.offset += read_u32(&mut self.input)? as u64 + 8;
+            self.step += 1;
+        }
+        while self.step > step {
+            self.input.seek(SeekFrom::Start(self.offset - 4))?;
+            self.offset -= read_u32(&mut self.input)? as u64 + 8;
+            self.step -= 1;
+        }
+        Ok(())
+    }
+
+    /// Registers of the frame executing entry `step` as they were before it
+    /// executed, replayed from the start of the frame. Registers which
+    /// were never set aren't included. The cursor doesn't move
+    pub fn registers(&mut self, step: usize) -> Result<BTreeMap<usize, TraceValue>, anyhow::Error> {
+        let (offset, position) = (self.offset, self.step);
+        let registers = self.replay_frame(step);
+        (self.offset, self.step) = (offset, position);
+        registers
+    }
+
+    fn replay_frame(&mut self, step: usize) -> Result<BTreeMap<usize, TraceValue>, anyhow::Error> {
+        let mut registers = BTreeMap::new();
+        self.seek(step + 1)?;
+        let Some(entry) = self.back()? else {
+            return Ok(registers);
+        };
+        let depth = entry.depth;
+        let mut frame = vec![entry];
+        //Deeper entries are callees, shallower ones are calls cut short by an
+        //error, written after the frame's steps
+        while !frame.last().is_some_and(|entry| entry.frame_start) {
+            match self.back()? {
+                Some(entry) if entry.depth == depth => frame.push(entry),
+                Some(_) => {}
+                None => break,
+            }
+        }
+
+        //The step itself hasn't changed anything yet
+        let current = frame.remove(0);
+        for entry in frame.into_iter().rev() {
+            registers.extend(entry.initial);
+            registers.extend(entry.changes);
+        }
+        registers.extend(current.initial);
+        Ok(registers)
+    }
+
+    fn read_entry(&mut self) -> Result<TraceEntry, anyhow::Error> {
+        let input = &mut self.input;
+        let mut flags = [0];
+        input.read_exact(&mut flags)?;
+        let depth = read_varint(input)? as usize;
+        let proto = read_varint(input)? as usize;
+        let pc = read_varint(input)? as usize;
+        let mut opcode = [0];
+        input.read_exact(&mut opcode)?;
+        let frame_start = flags[0] & FRAME_START != 0;
+        let initial = match frame_start {
+            true => read_registers(input)?,
+            false => Vec::new(),
+        };
+        let changes = read_registers(input)?;
+        Ok(TraceEntry {
+            step: self.step,
+            frame_start,
+            initial,
+            depth,
+            proto,
+            pc,
+            opcode: opcode[0],
+            changes,
+        })
+    }
+
+    /// "src:line (function <src:line_defined>) pc OPCODE" for an entry
+    pub fn describe(&self, entry: &TraceEntry) -> String {
+        let proto = &self.protos[entry.proto];
+        let line = proto.lines[entry.pc].map_or("?".to_owned(), |line| line.to_string());
+        let function = match proto.line_defined {
+            0 => "main chunk".to_owned(),
+            line_defined => format!("function <{}:{}>", proto.source, line_defined),
+        };
+        format!(
+            "{}:{} ({}) pc {} {}",
+            proto.source, line, function, entry.pc, OPNAMES[entry.opcode as usize]
+        )
+    }
+}
+
+/// Browses a trace with commands read from stdin, starting at its last
+/// step, which is the instruction that failed if the run did
+pub fn replay(path: &Path) -> Result<(), anyhow::Error> {
+    let mut reader = TraceReader::new(BufReader::new(File::open(path)?))?;
+    if reader.len() == 0 {
+        anyhow::bail!("trace '{}' has no steps", path.display());
+    }
+    let mut current = reader.len() - 1;
+    show_step(&mut reader, current)?;
+
+    let stdin = io::stdin();
+    loop {
+        print!("(replay) ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+
+        let mut words = line.split_whitespace();
+        let count = |word: Option<&str>| word.map_or(Ok(1), str::parse::<usize>);
+        let step = match (words.next(), words.next()) {
+            (None, _) => continue,
+            (Some("next" | "n"), n) => match count(n) {
+                Ok(n) => current.saturating_add(n).min(reader.len() - 1),
+                Err(e) => {
+                    println!("{}", e);
+                    continue;
+                }
+            },
+            (Some("back" | "b"), n) => match count(n) {
+                Ok(n) => current.saturating_sub(n),
+                Err(e) => {
+                    println!("{}", e);
+                    continue;
+                }
+            },
+            (Some("goto" | "g"), Some(step)) => match step.parse::<usize>() {
+                Ok(step) if step < reader.len() => step,
+                _ => {
+                    println!("steps go from 0 to {}", reader.len() - 1);
+                    continue;
+                }
+            },
+            (Some("registers" | "r"), None) => {
+                for (register, value) in reader.registers(current)? {
+                    println!("  r{} = {}", register, value);
+                }
+                continue;
+            }
+            (Some("quit" | "q"), None) => return Ok(()),
+            (Some("help" | "h"), _) => {
+                println!("next/n [count]     go forward count steps");
+                println!("back/b [count]     go back count steps");
+                println!("goto/g <step>      go to a step");
+                println!("registers/r        registers of the frame before this step");
+                println!("quit/q             stop replaying");
+                continue;
+            }
+            _ => {
+                println!("unknown command, try help");
+                continue;
+            }
+        };
+        current = step;
+        show_step(&mut reader, current)?;
+    }
+}
+
+fn show_step<R: BufRead + Seek>(
+    reader: &mut TraceReader<R>,
+    step: usize,
+) -> Result<(), anyhow::Error> {
+    reader.seek(step)?;
+    let Some(entry) = reader.forward()? else {
+        return Ok(());
+    };
+    println!(
+        "[{}/{}] depth {} {}",
+        entry.step,
+        reader.len() - 1,
+        entry.depth,
+        reader.describe(&entry)
+    );
+    if entry.frame_start {
+        println!("  (called)");
+    }
+    for (register, value) in &entry.changes {
+        println!("  r{} = {}", register, value);
+    }
+    Ok(())
+}
+
+/// Every proto of the tree, each before its children, which is the order
+/// they're numbered in
+fn collect_protos<'p>(proto: &'p BProto, protos: &mut Vec<&'p BProto>) {
+    protos.push(proto);
+    for child in &proto.protos.list {
+        collect_protos(child, protos);
+    }
+}
+
+fn write_value(output: &mut Vec<u8>, value: &LValue) {
+    match value {
+        LValue::LPrimitive(LPrimitive::NIL) => output.push(TAG_NIL),
+        LValue::LPrimitive(LPrimitive::BOOL(false)) => output.push(TAG_FALSE),
+        LValue::LPrimitive(LPrimitive::BOOL(true)) => output.push(TAG_TRUE),
+        LValue::LPrimitive(LPrimitive::INT(i)) => {
+            output.push(TAG_INT);
+            write_varint(output, zigzag(*i));
+        }
+        LValue::LPrimitive(LPrimitive::FLOAT(n)) => {
+            output.push(TAG_FLOAT);
+            output.extend_from_slice(&n.to_le_bytes());
+        }
+        LValue::LPrimitive(LPrimitive::STRING(s)) => {
+            output.push(TAG_STRING);
+            write_bytes(output, s.as_bytes());
+        }
+        LValue::Table(table) => {
+            output.push(TAG_TABLE);
+            write_varint(output, Rc::as_ptr(table) as *const u8 as u64);
+        }
+        LValue::LClosure(closure) => {
+            output.push(TAG_LCLOSURE);
+            write_varint(output, Rc::as_ptr(closure) as *const u8 as u64);
+        }
+        LValue::CClosure(function) => {
+            output.push(TAG_CCLOSURE);
+            write_varint(output, **function as usize as u64);
+        }
+    }
+}
+
+fn read_registers(input: &mut impl Read) -> Result<Vec<(usize, TraceValue)>, anyhow::Error> {
+    let mut registers = Vec::new();
+    for _ in 0..read_varint(input)? {
+        let register = read_varint(input)? as usize;
+        registers.push((register, read_value(input)?));
+    }
+    Ok(registers)
+}
+
+fn read_value(input: &mut impl Read) -> Result<TraceValue, anyhow::Error> {
+    let mut tag = [0];
+    input.read_exact(&mut tag)?;
+    Ok(match tag[0] {
+        TAG_NIL => TraceValue::Nil,
+        TAG_FALSE => TraceValue::Bool(false),
+        TAG_TRUE => TraceValue::Bool(true),
+        TAG_INT => TraceValue::Int(unzigzag(read_varint(input)?)),
+        TAG_FLOAT => {
+            let mut bytes = [0; 8];
+            input.read_exact(&mut bytes)?;
+            TraceValue::Float(f64::from_le_bytes(bytes))
+        }
+        TAG_STRING => TraceValue::String(String::from_utf8(read_bytes(input)?)?),
+        TAG_TABLE => TraceValue::Table(read_varint(input)? as usize),
+        TAG_LCLOSURE => TraceValue::LClosure(read_varint(input)? as usize),
+        TAG_CCLOSURE => TraceValue::CClosure(read_varint(input)? as usize),
+        tag => anyhow::bail!("unknown value tag {} in trace", tag),
+    })
+}
+
+/// LEB128
+fn write_varint(output: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        output.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    output.push(n as u8);
+}
+
+fn read_varint(input: &mut impl Read) -> Result<u64, anyhow::Error> {
+    let mut n = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        input.read_exact(&mut byte)?;
+        n |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    anyhow::bail!("varint too long in trace")
+}
+
+/// Small negative numbers stay small as varints
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn unzigzag(n: u64) -> i64 {
+    (n >> 1) as i64 ^ -((n & 1) as i64)
+}
+
+fn write_bytes(output: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(output, bytes.len() as u64);
+    output.extend_from_slice(bytes);
+}
+
+fn read_bytes(input: &mut impl Read) -> Result<Vec<u8>, anyhow::Error> {
+    let mut bytes = vec![0; read_varint(input)? as usize];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io::Cursor, rc::Rc};
+
+    use super::*;
+    use crate::{bytecode::load_fixture, interpreter::Interpreter, lstring::StringTable};
+
+    /// Reader of a trace of the fixture, and whether the run succeeded
+    fn record(name: &str) -> (TraceReader<Cursor<Vec<u8>>>, bool) {
+        let mut strings = StringTable::default();
+        let top = load_fixture(name, &mut strings);
+        let recorder = Rc::new(RefCell::new(TraceRecorder::new(&top, Vec::new()).unwrap()));
+        let mut interpreter = Interpreter::new(&top, strings);
+        interpreter.set_output(Box::new(io::sink()));
+        let hook = recorder.clone();
+        interpreter.set_hook(
+            Some(Box::new(move |event, stack, call_stack| {
+                hook.borrow_mut().event(event, stack, call_stack);
+                Ok(())
+            })),
+            TRACE_MASK,
+            1,
+        );
+        let succeeded = interpreter.interpret().is_ok();
+        interpreter.close();
+
+        let mut recorder = recorder.borrow_mut();
+        recorder.finish().unwrap();
+        let trace = std::mem::take(&mut recorder.output);
+        (TraceReader::new(Cursor::new(trace)).unwrap(), succeeded)
+    }
+
+    fn entries(reader: &mut TraceReader<Cursor<Vec<u8>>>) -> Vec<TraceEntry> {
+        reader.seek(0).unwrap();
+        let mut entries = Vec::new();
+        while let Some(entry) = reader.forward().unwrap() {
+            entries.push(entry);
+        }
+        entries
+    }
+
+    fn steps(entries: &[TraceEntry]) -> Vec<(usize, &'static str)> {
+        entries
+            .iter()
+            .map(|entry| (entry.depth, OPNAMES[entry.opcode as usize]))
+            .collect()
+    }
+
+    #[test]
+    fn changes_belong_to_the_instruction_which_made_them() {
+        let (mut reader, succeeded) = record("trace");
+        assert!(succeeded);
+        let entries = entries(&mut reader);
+        assert_eq!(
+            steps(&entries),
+            [
+                (1, "CLOSURE"),
+                (1, "MOVE"),
+                (1, "LOADK"),
+                (1, "LOADK"),
+                (2, "ADD"),
+                (2, "RETURN"),
+                (1, "CALL"),
+                (1, "MUL"),
+                (1, "RETURN"),
+            ]
+        );
+
+        assert!(matches!(
+            entries[0].changes[..],
+            [(0, TraceValue::LClosure(_))]
+        ));
+        assert_eq!(entries[2].changes, [(2, TraceValue::Int(1))]);
+        assert_eq!(entries[3].changes, [(3, TraceValue::Int(2))]);
+
+        let add = &entries[4];
+        assert!(add.frame_start);
+        assert_eq!(
+            add.initial,
+            [(0, TraceValue::Int(1)), (1, TraceValue::Int(2))]
+        );
+        assert_eq!(add.changes, [(2, TraceValue::Int(3))]);
+        assert!(entries[5].changes.is_empty());
+
+        //The results of a call are its changes, after the steps of the callee
+        assert!(entries[6].changes.contains(&(1, TraceValue::Int(3))));
+        assert_eq!(entries[7].changes, [(2, TraceValue::Int(30))]);
+        //The last instruction is recorded too
+        assert!(entries[8].changes.is_empty());
+    }
+
+    #[test]
+    fn registers_are_replayed_up_to_before_a_step() {
+        let (mut reader, _) = record("trace");
+
+        //ADD, with only the arguments
+        let add = reader.registers(4).unwrap();
+        assert_eq!(
+            add.into_iter().collect::<Vec<_>>(),
+            [(0, TraceValue::Int(1)), (1, TraceValue::Int(2))]
+        );
+
+        //MUL, after the call and callee but before its own change
+        let mul = reader.registers(7).unwrap();
+        assert_eq!(mul[&1], TraceValue::Int(3));
+        assert_eq!(mul[&2], TraceValue::Int(1));
+    }
+
+    #[test]
+    fn a_failed_run_ends_with_the_instruction_which_raised() {
+        let (mut reader, succeeded) = record("trace_error");
+        assert!(!succeeded);
+        let entries = entries(&mut reader);
+        let last = entries.len() - 1;
+        //The call cut short is written before it
+        assert_eq!(steps(&entries[last - 1..]), [(1, "CALL"), (2, "GETTABUP")]);
+        assert!(entries[last].changes.is_empty());
+        assert!(reader.registers(last).unwrap().is_empty());
+    }
+}